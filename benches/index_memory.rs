@@ -0,0 +1,50 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kvs::kv::IndexKind;
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+
+//counts the bytes currently allocated so the index size can be read off after open
+struct CountingAllocator;
+
+static LIVE_BYTES:AtomicUsize=AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR:CountingAllocator=CountingAllocator;
+
+const KEY_COUNT:usize=100_000;
+
+fn main(){
+    let tmp_dir=TempDir::new().unwrap();
+    {
+        let mut store=KvStore::open(tmp_dir.path()).unwrap();
+        for key_id in 0..KEY_COUNT{
+            store.set(format!("some/longer/key/prefix/{}",key_id), format!("value{}",key_id)).unwrap();
+        }
+    }
+
+    //the totals include the read buffers of every segment, the same for both kinds
+    let mut totals=Vec::new();
+    for kind in [IndexKind::Hash,IndexKind::Compact]{
+        let before=LIVE_BYTES.load(Ordering::Relaxed);
+        let store=KvStore::open_with_index(tmp_dir.path(), kind).unwrap();
+        let used=LIVE_BYTES.load(Ordering::Relaxed)-before;
+        println!("{:?}: {} keys, {} bytes, {:.1} bytes per key",kind,KEY_COUNT,used,used as f64/KEY_COUNT as f64);
+        totals.push(used);
+        drop(store);
+    }
+    println!("Compact saves {:.1} bytes per key",(totals[0] as f64-totals[1] as f64)/KEY_COUNT as f64);
+}
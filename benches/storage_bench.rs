@@ -1,7 +1,7 @@
 use std::ops::Range;
 
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
-use kvs::{KvStore, KvsEngine};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{kv::Admission, KvStore, KvsEngine};
 use rand::{thread_rng, Rng,distributions::Alphanumeric,seq::SliceRandom};
 use tempfile::TempDir;
 
@@ -48,7 +48,7 @@ pub fn storage_benchmark(c:&mut Criterion){
                 let db=KvStore::open(tmp_dir.path()).unwrap();
                 (tmp_dir,db)
             },
-            |(tmp_dir,mut storage)|{
+            |(_tmp_dir,mut storage)|{
                 for (k,v) in set_input.iter(){
                     storage.set(k.clone(), v.clone()).unwrap();
                 }
@@ -61,6 +61,36 @@ pub fn storage_benchmark(c:&mut Criterion){
     );
 }
 
+//random gets of sealed segments, through the file and through a mapping
+pub fn read_benchmark(c:&mut Criterion){
+    let mut group=c.benchmark_group("read");
+    let tmp_dir=TempDir::new().unwrap();
+    let set_input=(0..1000).map(|_|(generate_string(1..100),generate_string(100..1000))).collect::<Vec<_>>();
+    {
+        let mut storage=KvStore::open(tmp_dir.path()).unwrap();
+        for (k,v) in set_input.iter(){
+            storage.set(k.clone(), v.clone()).unwrap();
+        }
+    }
+    let mut get_input=set_input.iter().map(|(k,_)|k.clone()).collect::<Vec<_>>();
+    get_input.shuffle(&mut thread_rng());
+
+    for mmap in [false,true]{
+        //reopened so every segment is sealed, the value cache would hide the reads
+        let mut storage=KvStore::open(tmp_dir.path()).unwrap();
+        storage.set_value_cache(0, Admission::Always);
+        storage.set_mmap_reads(mmap).unwrap();
+        group.bench_function(
+            BenchmarkId::new(if mmap {"kv_mmap"} else {"kv_file"}, 1000),
+            |b| b.iter(||{
+                for key in get_input.iter(){
+                    storage.get(key.clone()).unwrap();
+                }
+            })
+        );
+    }
+}
+
 
-criterion_group!(benches,storage_benchmark);
+criterion_group!(benches,storage_benchmark,read_benchmark);
 criterion_main!(benches);
\ No newline at end of file
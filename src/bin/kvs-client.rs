@@ -1,24 +1,113 @@
+use std::process::ExitCode;
+
 use clap::Parser;
-use kvs::client::{command::ClientArgs, Client,Result,ClientError};
+use kvs::client::{command::{ClientArgs, ClientCommand}, sharded::ShardedClient, Client, ClientError, Result};
+use kvs::kv::{command::{IndexCommand, KVCommand, NamespaceCommand}, export::EntryWriter, namespace::NamespaceOptions};
 
-fn main()->Result<()>{
+fn main()->ExitCode{
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::from(error.exit_code())
+        },
+    }
+}
+
+fn try_main()->Result<()>{
     let args=ClientArgs::parse();
+    if !args.cluster.is_empty(){
+        return run_sharded(ShardedClient::new(&args.cluster), args.command);
+    }
+
     let client=Client::new(args.addr);
-    match args.kv_command{
-        kvs::kv::command::KVCommand::Get { key } => {
-            let res=client.get(&key)?;
+    match args.command{
+        ClientCommand::Kv(KVCommand::Get { key, ns }) => {
+            let res=client.with_namespace(ns).get(&key)?;
             match res{
                 Some(v)=>println!("{v}"),
                 None=>println!("Key not found")
             }
         },
-        kvs::kv::command::KVCommand::Set { key, value } => {
+        ClientCommand::Kv(KVCommand::Set { key, value, ns }) => {
+            client.with_namespace(ns).set(&key, &value)?
+        },
+        ClientCommand::Kv(KVCommand::Rm { key, ns }) => {
+            client.with_namespace(ns).remove(&key)?
+        },
+        ClientCommand::Kv(KVCommand::Ns(NamespaceCommand::Create { name, merge_threshold })) => {
+            client.create_namespace(&name, NamespaceOptions{merge_threshold})?
+        },
+        ClientCommand::Kv(KVCommand::Ns(NamespaceCommand::Drop { name })) => {
+            client.drop_namespace(&name)?
+        },
+        ClientCommand::Kv(KVCommand::Ns(NamespaceCommand::List)) => {
+            for name in client.list_namespaces()?{
+                println!("{name}");
+            }
+        },
+        ClientCommand::Kv(KVCommand::History { key, ns }) => {
+            for version in client.with_namespace(ns).history(&key)?{
+                println!("{} {} {}",version.seq,version.timestamp,version.value.as_deref().unwrap_or("(removed)"));
+            }
+        },
+        ClientCommand::Kv(KVCommand::Query { index, value, ns }) => {
+            for key in client.with_namespace(ns).query_index(&index, &value)?{
+                println!("{key}");
+            }
+        },
+        ClientCommand::Kv(KVCommand::Index(IndexCommand::Create { name, pointer, ns })) => {
+            client.with_namespace(ns).create_index(&name, &pointer)?
+        },
+        ClientCommand::Kv(KVCommand::Index(IndexCommand::Drop { name, ns })) => {
+            client.with_namespace(ns).drop_index(&name)?
+        },
+        ClientCommand::Export(args) => {
+            let mut writer=EntryWriter::new(args.format, args.writer()?)?;
+            client.with_namespace(args.ns.clone()).export(&args.prefix, &mut writer)?;
+            eprintln!("exported {} entries",writer.finish()?);
+        },
+        ClientCommand::Import(args) => {
+            let report=client.with_namespace(args.ns.clone()).import(args.entries()?, args.on_conflict(), args.batch_size)?;
+            println!("{report}");
+        },
+        ClientCommand::AddNode { .. }|ClientCommand::RemoveNode { .. } => {
+            return Err(ClientError::Unsupported("node changes need --cluster"))
+        },
+    }
+
+    Ok(())
+}
+
+fn run_sharded(mut client:ShardedClient,command:ClientCommand)->Result<()>{
+    match command{
+        ClientCommand::Kv(KVCommand::Get { key, ns:None }) => {
+            match client.get(&key)?{
+                Some(v)=>println!("{v}"),
+                None=>println!("Key not found")
+            }
+        },
+        ClientCommand::Kv(KVCommand::Set { key, value, ns:None }) => {
             client.set(&key, &value)?
         },
-        kvs::kv::command::KVCommand::Rm { key } => {
+        ClientCommand::Kv(KVCommand::Rm { key, ns:None }) => {
             client.remove(&key)?
         },
+        ClientCommand::Kv(_) => {
+            return Err(ClientError::Unsupported("only get, set and rm on the default namespace are sharded"))
+        },
+        ClientCommand::Export(_)|ClientCommand::Import(_) => {
+            return Err(ClientError::Unsupported("export and import talk to a single server"))
+        },
+        ClientCommand::AddNode { addr } => {
+            let report=client.add_node(addr)?;
+            println!("moved {} keys to {}",report.moved_keys,addr);
+        },
+        ClientCommand::RemoveNode { addr } => {
+            let report=client.remove_node(addr)?;
+            println!("moved {} keys off {}",report.moved_keys,addr);
+        },
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,64 +1,81 @@
-use std::{fs::{DirBuilder, OpenOptions}, io::BufReader, os::unix::fs::DirBuilderExt, path::PathBuf};
+use std::{fs::DirBuilder, path::PathBuf, process::ExitCode};
 
 use clap::Parser;
-use kvs::{kv::config::Config, server::{command::{ServerArgs, StorageEngine}, Result, Server, ServerError, StorageMetaData}, KvStore, KvsEngine};
+use kvs::{kv::{config::Config, history::VersionPolicy, Context, ErrorContext}, server::{command::{ServerArgs, ServerCommand, StorageEngine}, migrate::migrate, shutdown::{block_signals, stop_on_signal}, Result, Server, ServerError, StorageMetaData}, KvStore, KvsEngine, MemoryStore};
 
-fn main()->Result<()>{
+fn main()->ExitCode{
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::from(error.exit_code())
+        },
+    }
+}
+
+fn try_main()->Result<()>{
     let args=ServerArgs::parse();
     //need to verify ip later
     let engine=args.engine.unwrap_or(StorageEngine::Kv);
-    let config=Config::open("config.json".into());
+    let config=Config::open("config.json".into())
+    .context(ServerError::EngineStartUpError, "config.json")?;
+    let options=args.store.options(&config);
 
     let data_path:PathBuf=PathBuf::from(config.db_dir).join("data");
-    let db_path=data_path.join("db");
-    let meta_path=data_path.join("metadata");
+    let raft_path=data_path.join("raft");
+    let replication_path=data_path.join("replication.json");
     DirBuilder::new()
     .recursive(true)
-    .create(data_path)
-    .map_err(|_|ServerError::EngineOperationError("failed to create/open data dir"))?;
+    .create(&data_path)
+    .map_err(|error|ServerError::EngineStartUpError(ErrorContext::new("create data directory").with_path(&data_path).with_source(error)))?;
 
-    let meta_file=OpenOptions::new()
-    .create(true)
-    .read(true)
-    .write(true)
-    .open(meta_path)
-    .map_err(|_|ServerError::EngineOperationError("failed to create/open metadata file"))?;
+    if let Some(ServerCommand::Migrate { from, to, samples })=args.command{
+        let report=migrate(&data_path, from, to, &options, samples)?;
+        println!("{report}");
+        return Ok(());
+    }
 
-    let buf=BufReader::new(&meta_file);
-    let storage_meta:StorageMetaData=match serde_json::from_reader(buf){
-        Ok(v) => v,
-        Err(e) => {
-            if e.is_data()||e.is_syntax(){
-                return Err(ServerError::EngineOperationError("failed to parse metadata file"));
-            }
-            StorageMetaData{
-                engine:None
-            }
-        },
-    };
+    let versioned=args.max_versions.is_some()||args.retention_secs.is_some();
+    if versioned&&engine!=StorageEngine::Kv{
+        return Err(ServerError::ConfigError("versioning needs the kvs engine"));
+    }
+    if args.snapshot&&engine!=StorageEngine::Memory{
+        return Err(ServerError::ConfigError("--snapshot needs the memory engine"));
+    }
+    //before the engine starts any thread, so that they all leave the signals to the server
+    block_signals().context(ServerError::EngineStartUpError, "block signals")?;
 
+    let storage_meta=StorageMetaData::load(&data_path)?;
+    let db_path=storage_meta.db_path(&data_path);
     let engine:Box<dyn KvsEngine>=match (engine,&storage_meta.engine) {
-        (StorageEngine::Kv,None|Some(StorageEngine::Kv)) => {
-            if storage_meta.engine.is_none(){
-                serde_json::to_writer(&meta_file, &StorageMetaData{engine:Some(StorageEngine::Kv)})
-                .map_err(|_|ServerError::EngineOperationError("failed to write metadata"))?
+        //without a snapshot nothing is kept, so the data directory is left alone
+        (StorageEngine::Memory,_) if !args.snapshot => Box::new(MemoryStore::new()),
+        (engine,Some(owner)) if engine!=*owner => return Err(ServerError::ConfigError("the data directory belongs to another engine")),
+        (engine,owner) => {
+            if owner.is_none(){
+                StorageMetaData{engine:Some(engine),db:None}.save(&data_path)?;
             }
-            let kv=KvStore::open(db_path)
-            .map_err(|_|ServerError::EngineStartUpError("kvs"))?;
-            Box::new(kv)
-        },
-        (StorageEngine::Sled,None|Some(StorageEngine::Sled)) => {
-            if storage_meta.engine.is_none(){
-                serde_json::to_writer(&meta_file, &StorageMetaData{engine:Some(StorageEngine::Sled)})
-                .map_err(|_|ServerError::EngineOperationError("failed to write metadata"))?
+            match engine {
+                StorageEngine::Kv => {
+                    let mut kv=KvStore::open_with(db_path, options)
+                    .context(ServerError::EngineStartUpError, "kvs")?;
+                    if versioned{
+                        let policy=VersionPolicy{max_versions:args.max_versions,retention_secs:args.retention_secs};
+                        kv.enable_versioning(policy).context(ServerError::EngineStartUpError, "kvs versioning")?;
+                    }
+                    Box::new(kv)
+                },
+                StorageEngine::Sled => Box::new(sled::open(db_path).context(ServerError::EngineStartUpError, "sled")?),
+                StorageEngine::Memory => Box::new(MemoryStore::open(db_path).context(ServerError::EngineStartUpError, "memory")?),
             }
-            let sled=sled::open(db_path)
-            .map_err(|_|ServerError::EngineStartUpError("sled"))?;
-            Box::new(sled)
         },
-        _=> return Err(ServerError::EngineOperationError("invalid engine"))
     };
-    let mut server=Server::new(args.addr, engine)?;
+    let mut server=match args.replica_of {
+        Some(leader) => Server::new_replica(args.addr, engine, leader, replication_path)?,
+        None if !args.raft_peers.is_empty() => Server::new_cluster(args.addr, engine, args.raft_peers, raft_path)?,
+        None => Server::new(args.addr, engine)?,
+    };
+    stop_on_signal(server.stop_handle()?);
     server.start();
 
     Ok(())
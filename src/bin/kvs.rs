@@ -1,28 +1,116 @@
-
+use std::{fmt::Display, path::PathBuf, process::ExitCode};
 
 use clap::Parser;
-use kvs::kv::{command,KVError,config::Config,Result,KvStore};
-use kvs::KvsEngine;
+use serde::Serialize;
+use kvs::kv::{command::{self, IndexCommand, InspectCommand, NamespaceCommand},KVError,config::Config,namespace::NamespaceOptions,recovery::recover,export::{export, import},Result,KvStore};
+use kvs::{KvsEngine, DEFAULT_NAMESPACE, EXIT_DATA};
 
-fn main() ->Result<()> {
-    let config=Config::open("config.json".into());
-    let args=command::KVArgs::parse();
+fn main()->ExitCode{
+    match try_main() {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {error}");
+            if error.exit_code()==EXIT_DATA{
+                eprintln!("hint: `kvs repair` moves unreadable records aside so the store opens again");
+            }
+            ExitCode::from(error.exit_code())
+        },
+    }
+}
 
-    let mut kv_store=KvStore::open(config.db_dir)?;
+fn try_main()->Result<ExitCode>{
+    let config=Config::open("config.json".into())?;
+    let args=command::KVArgs::parse();
+    let options=args.store.options(&config);
 
     match args.operations {
-        command::KVCommand::Get { key } => {
-            match kv_store.get(key)?{
+        command::KvsCommand::Recover { until, to, backup } => {
+            let sources:Vec<PathBuf>=backup.into_iter().chain([PathBuf::from(config.db_dir)]).collect();
+            let report=recover(&sources, until, to)?;
+            println!("{report}");
+        },
+        command::KvsCommand::Repair { dir } => {
+            let report=KvStore::repair(dir.unwrap_or_else(||PathBuf::from(config.db_dir)))?;
+            println!("{report}");
+        },
+        command::KvsCommand::Verify { dir } => {
+            let report=KvStore::verify(dir.unwrap_or_else(||PathBuf::from(config.db_dir)))?;
+            println!("{}",serde_json::to_string_pretty(&report)?);
+            if !report.is_ok(){
+                return Ok(ExitCode::from(EXIT_DATA));
+            }
+        },
+        command::KvsCommand::Export(args) => {
+            let mut kv_store=KvStore::open_with(config.db_dir, options.read_only(true))?;
+            let mut engine=kv_store.namespace(args.ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?;
+            let exported=export(&mut *engine, &args.prefix, args.format, args.writer()?)?;
+            eprintln!("exported {exported} entries");
+        },
+        command::KvsCommand::Import(args) => {
+            let mut kv_store=KvStore::open_with(config.db_dir, options)?;
+            let mut engine=kv_store.namespace(args.ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?;
+            let report=import(&mut *engine, args.entries()?, args.on_conflict(), args.batch_size)?;
+            println!("{report}");
+        },
+        command::KvsCommand::Inspect { command, ns, json } => {
+            let kv_store=KvStore::open_with(config.db_dir, options.read_only(true))?;
+            let inspector=kv_store.inspector(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?;
+            match command {
+                InspectCommand::Segments => print_all(inspector.segments()?, json)?,
+                InspectCommand::Dump { segment } => print_all(inspector.dump(segment)?, json)?,
+                InspectCommand::Key { key } => print_all(inspector.key(&key)?, json)?,
+            }
+        },
+        command::KvsCommand::Kv(command) => run(&mut KvStore::open_with(config.db_dir, options)?, command)?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_all<T:Display+Serialize>(items:Vec<T>,json:bool)->Result<()>{
+    if json{
+        println!("{}",serde_json::to_string_pretty(&items)?);
+    } else {
+        for item in items{
+            println!("{item}");
+        }
+    }
+    Ok(())
+}
+
+fn run(kv_store:&mut KvStore,command:command::KVCommand)->Result<()>{
+    match command {
+        command::KVCommand::Get { key, ns } => {
+            match kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.get(key)?{
                 Some(val) => println!("{val}"),
                 None =>  println!("Key not found"),
             }
         },
-        command::KVCommand::Set { key, value } => kv_store.set(key, value)?,
-        command::KVCommand::Rm { key } => {
-            kv_store.remove(key).inspect_err(|err|{if matches!(err,KVError::KeyNotFound(_)) {println!("Key not found")}})?;
+        command::KVCommand::Set { key, value, ns } => kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.set(key, value)?,
+        command::KVCommand::Rm { key, ns } => {
+            kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?
+            .remove(key).inspect_err(|err|{if matches!(err,KVError::KeyNotFound(_)) {println!("Key not found")}})?;
+        },
+        command::KVCommand::History { key, ns } => {
+            for version in kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.history(key)?{
+                println!("{} {} {}",version.seq,version.timestamp,version.value.as_deref().unwrap_or("(removed)"));
+            }
+        },
+        command::KVCommand::Query { index, value, ns } => {
+            for key in kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.query_index(&index, &value)?{
+                println!("{key}");
+            }
+        },
+        command::KVCommand::Index(IndexCommand::Create { name, pointer, ns }) => kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.create_index(&name, &pointer)?,
+        command::KVCommand::Index(IndexCommand::Drop { name, ns }) => kv_store.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE))?.drop_index(&name)?,
+        command::KVCommand::Ns(NamespaceCommand::Create { name, merge_threshold }) => kv_store.create_namespace(&name, NamespaceOptions{merge_threshold})?,
+        command::KVCommand::Ns(NamespaceCommand::Drop { name }) => kv_store.drop_namespace(&name)?,
+        command::KVCommand::Ns(NamespaceCommand::List) => {
+            for name in kv_store.list_namespaces()?{
+                println!("{name}");
+            }
         },
     }
 
-
     Ok(())
 }
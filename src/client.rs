@@ -1,75 +1,304 @@
-use std::{io::BufReader, net::{SocketAddr, TcpStream}, result};
+use std::{cell::Cell, error::Error, fmt, io::{self, BufReader, Write}, net::{SocketAddr, TcpStream}, result, thread, time::Duration};
 
-use crate::{kv::command::KVCommand, server};
+use serde::{de::DeserializeOwned, Serialize};
+
+use self::sharded::HashRange;
+use crate::{kv::{self, command::{IndexCommand, KVCommand, NamespaceCommand}, export::{EntryWriter, ImportReport, OnConflict}, KVError, history::Version, namespace::NamespaceOptions, replication::{LogPosition, ReplicationBatch}, ErrorContext}, server::{self, command::{AdminCommand, ScanPage}, ServerStats}, common::{EXIT_NOT_FOUND, EXIT_SOFTWARE, EXIT_TEMPFAIL, EXIT_UNAVAILABLE, EXIT_USAGE}, DEFAULT_NAMESPACE};
 
 
 pub mod config;
 pub mod command;
+pub mod sharded;
 
 pub type Result<T>=result::Result<T,ClientError>;
 
+//how often a request follows a not leader answer before giving up
+const MAX_LEADER_HOPS:usize=20;
+//entries asked for in one export request
+const EXPORT_PAGE:usize=1000;
+
 #[derive(Debug)]
 pub enum ClientError{
-    ConnectionError(&'static str),
-    OperationError(&'static str),
-    KeyNotFound(&'static str)
+    /// The server at `addr` could not be reached, or the connection broke while sending.
+    ConnectionError{addr:SocketAddr,source:io::Error},
+    /// The server did not answer the way the request expects.
+    OperationError(ErrorContext),
+    KeyNotFound(String),
+    NamespaceNotFound(String),
+    IndexNotFound(String),
+    Redirect(SocketAddr),
+    /// A request that cannot be made as asked, with why.
+    Unsupported(&'static str),
+    /// Reading or writing the local side of an export or import failed.
+    FileError(KVError)
+}
+
+impl ClientError {
+    /// The exit code of a client failing with this error.
+    pub fn exit_code(&self)->u8{
+        match self {
+            ClientError::ConnectionError{..} => EXIT_UNAVAILABLE,
+            ClientError::OperationError(_) => EXIT_SOFTWARE,
+            ClientError::KeyNotFound(_)|ClientError::NamespaceNotFound(_)|ClientError::IndexNotFound(_) => EXIT_NOT_FOUND,
+            ClientError::Redirect(_) => EXIT_TEMPFAIL,
+            ClientError::Unsupported(_) => EXIT_USAGE,
+            ClientError::FileError(error) => error.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            ClientError::ConnectionError{addr,source} => write!(f,"could not talk to {addr}: {source}"),
+            ClientError::OperationError(context) => write!(f,"unexpected answer from the server{context}"),
+            ClientError::KeyNotFound(key) => write!(f,"Key not found: {key}"),
+            ClientError::NamespaceNotFound(name) => write!(f,"namespace not found: {name}"),
+            ClientError::IndexNotFound(name) => write!(f,"index not found: {name}"),
+            ClientError::Redirect(leader) => write!(f,"writes go to the leader at {leader}"),
+            ClientError::Unsupported(message) => write!(f,"unsupported: {message}"),
+            ClientError::FileError(error) => write!(f,"{error}"),
+        }
+    }
 }
 
+impl From<KVError> for ClientError {
+    fn from(error:KVError)->Self{
+        ClientError::FileError(error)
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self)->Option<&(dyn Error+'static)>{
+        match self {
+            ClientError::ConnectionError{source,..} => Some(source),
+            ClientError::OperationError(context) => context.source.as_deref().map(|source|source as &(dyn Error+'static)),
+            ClientError::FileError(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Talks to a single server, or to the leader of the raft cluster that server belongs to.
 pub struct Client{
-    addr:SocketAddr
+    addr:SocketAddr,
+    leader:Cell<Option<SocketAddr>>,
+    namespace:Option<String>
 }
 
 impl Client {
     pub fn new(addr:SocketAddr)->Client{
-        
+
         Client{
-            addr
+            addr,
+            leader:Cell::new(None),
+            namespace:None
         }
     }
 
-    pub fn get(&self,key:&str)->Result<Option<String>>{
-        let sock=TcpStream::connect(self.addr).map_err(|_|ClientError::ConnectionError("Client::get1"))?;
-        let cmd=KVCommand::Get { key:key.to_string()};
-        serde_json::to_writer(&sock, &cmd)
-        .map_err(|_|ClientError::OperationError("Client::get1"))?;
-        
+    /// Sends key operations to namespace `namespace` instead of the default one.
+    pub fn with_namespace(mut self,namespace:Option<String>)->Client{
+        self.namespace=namespace;
+        self
+    }
 
-        let buf=BufReader::new(&sock);
-        let response:result::Result<server::ServerResponse<Option<String>>,_>=serde_json::from_reader(buf);
-        match response {
-            Ok(server::ServerResponse::Success(v)) => Ok(v),
-            _ => Err(ClientError::OperationError("Client::get2")),
+    fn namespace_name(&self)->String{
+        self.namespace.clone().unwrap_or(DEFAULT_NAMESPACE.to_string())
+    }
+
+    pub fn get(&self,key:&str)->Result<Option<String>>{
+        let cmd=KVCommand::Get { key:key.to_string(), ns:self.namespace.clone() };
+        match self.request(&cmd)? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(self.namespace_name())),
+            _ => Err(ClientError::OperationError("Client::get2".into())),
         }
     }
 
     pub fn set(&self,key:&str,value:&str)->Result<()>{
-        let sock=TcpStream::connect(self.addr).map_err(|_|ClientError::ConnectionError("Client::set1"))?;
-        let cmd=KVCommand::Set { key: key.to_string(), value: value.to_string() };
-        serde_json::to_writer(&sock, &cmd)
-        .map_err(|_|ClientError::OperationError("Client::set1"))?;
-        
-
-        let buf=BufReader::new(&sock);
-        let response:result::Result<server::ServerResponse<()>,_>=serde_json::from_reader(buf);
-        match response {
-            Ok(server::ServerResponse::Success(_)) => Ok(()),
-            _ => Err(ClientError::OperationError("Client::set2")),
+        let cmd=KVCommand::Set { key: key.to_string(), value: value.to_string(), ns:self.namespace.clone() };
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(self.namespace_name())),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::set2".into())),
         }
     }
 
     pub fn remove(&self,key:&str)->Result<()>{
-        let sock=TcpStream::connect(self.addr).map_err(|_|ClientError::ConnectionError("Client::remove1"))?;
-        let cmd=KVCommand::Rm{ key: key.to_string()};
-        serde_json::to_writer(&sock, &cmd)
-        .map_err(|_|ClientError::OperationError("Client::remove1"))?;
-        
+        let cmd=KVCommand::Rm{ key: key.to_string(), ns:self.namespace.clone() };
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::KeyNotFound))=>Err(ClientError::KeyNotFound(key.to_string())),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(self.namespace_name())),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::remove2".into())),
+        }
+    }
 
-        let buf=BufReader::new(&sock);
-        let response:result::Result<server::ServerResponse<()>,_>=serde_json::from_reader(buf);
-        match response {
-            Ok(server::ServerResponse::Success(_)) => Ok(()),
-            Ok(server::ServerResponse::Error(server::ErrorType::KeyNotFound))=>Err(ClientError::KeyNotFound("Key not found")),
-            _ => Err(ClientError::OperationError("Client::remove2")),
+    pub fn create_namespace(&self,name:&str,options:NamespaceOptions)->Result<()>{
+        let cmd=KVCommand::Ns(NamespaceCommand::Create { name: name.to_string(), merge_threshold: options.merge_threshold });
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::create_namespace2".into())),
+        }
+    }
+
+    pub fn drop_namespace(&self,name:&str)->Result<()>{
+        let cmd=KVCommand::Ns(NamespaceCommand::Drop { name: name.to_string() });
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(name.to_string())),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::drop_namespace2".into())),
         }
     }
-}
\ No newline at end of file
+
+    pub fn list_namespaces(&self)->Result<Vec<String>>{
+        match self.request(&KVCommand::Ns(NamespaceCommand::List))? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            _ => Err(ClientError::OperationError("Client::list_namespaces2".into())),
+        }
+    }
+
+    pub fn history(&self,key:&str)->Result<Vec<Version>>{
+        let cmd=KVCommand::History { key: key.to_string(), ns:self.namespace.clone() };
+        match self.request(&cmd)? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(self.namespace_name())),
+            _ => Err(ClientError::OperationError("Client::history2".into())),
+        }
+    }
+
+    pub fn create_index(&self,name:&str,pointer:&str)->Result<()>{
+        let cmd=KVCommand::Index(IndexCommand::Create { name: name.to_string(), pointer: pointer.to_string(), ns:self.namespace.clone() });
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::create_index2".into())),
+        }
+    }
+
+    pub fn drop_index(&self,name:&str)->Result<()>{
+        let cmd=KVCommand::Index(IndexCommand::Drop { name: name.to_string(), ns:self.namespace.clone() });
+        match self.request::<()>(&cmd)? {
+            Some(server::ServerResponse::Success(_)) => Ok(()),
+            Some(server::ServerResponse::Error(server::ErrorType::IndexNotFound))=>Err(ClientError::IndexNotFound(name.to_string())),
+            Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>Err(ClientError::Redirect(leader)),
+            _ => Err(ClientError::OperationError("Client::drop_index2".into())),
+        }
+    }
+
+    pub fn query_index(&self,name:&str,value:&str)->Result<Vec<String>>{
+        let cmd=KVCommand::Query { index: name.to_string(), value: value.to_string(), ns:self.namespace.clone() };
+        match self.request(&cmd)? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            Some(server::ServerResponse::Error(server::ErrorType::IndexNotFound))=>Err(ClientError::IndexNotFound(name.to_string())),
+            Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>Err(ClientError::NamespaceNotFound(self.namespace_name())),
+            _ => Err(ClientError::OperationError("Client::query_index2".into())),
+        }
+    }
+
+    pub fn stats(&self)->Result<ServerStats>{
+        match self.request_at(self.addr, &AdminCommand::Stats)? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            _ => Err(ClientError::OperationError("Client::stats2".into())),
+        }
+    }
+
+    pub fn replicate(&self,position:Option<LogPosition>,snapshot:Option<LogPosition>)->Result<ReplicationBatch>{
+        match self.request_at(self.addr, &AdminCommand::Replicate { position, snapshot, ns:self.namespace.clone() })? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            _ => Err(ClientError::OperationError("Client::replicate2".into())),
+        }
+    }
+
+    /// Up to `limit` entries whose key hashes into one of `ranges`, in key order from after the key `after`.
+    pub fn scan_ranges(&self,ranges:Vec<HashRange>,after:Option<String>,limit:usize)->Result<ScanPage>{
+        match self.request_at(self.addr, &AdminCommand::ScanRanges { ranges, after, limit })? {
+            Some(server::ServerResponse::Success(v)) => Ok(v),
+            _ => Err(ClientError::OperationError("Client::scan_ranges2".into())),
+        }
+    }
+
+    /// Writes the entries of the namespace whose key starts with `prefix` to `writer` in key order,
+    /// each page as it arrives.
+    pub fn export<W:Write>(&self,prefix:&str,writer:&mut EntryWriter<W>)->Result<()>{
+        let mut after=None;
+        loop {
+            let cmd=AdminCommand::Export { prefix: prefix.to_string(), after, limit:EXPORT_PAGE, ns:self.namespace.clone() };
+            let page:ScanPage=match self.request_at(self.addr, &cmd)? {
+                Some(server::ServerResponse::Success(v)) => v,
+                Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>return Err(ClientError::NamespaceNotFound(self.namespace_name())),
+                _ => return Err(ClientError::OperationError("Client::export2".into())),
+            };
+            for (key,value) in &page.entries{
+                writer.write(key, value)?;
+            }
+            match page.next {
+                Some(next) => after=Some(next),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Sends `entries` to the server in requests of `batch_size`.
+    pub fn import(&self,entries:impl IntoIterator<Item = kv::Result<(String,String)>>,on_conflict:OnConflict,batch_size:usize)->Result<ImportReport>{
+        let mut report=ImportReport::default();
+        let mut entries=entries.into_iter().peekable();
+        while entries.peek().is_some(){
+            let batch=entries.by_ref().take(batch_size.max(1)).collect::<kv::Result<Vec<_>>>()?;
+            let cmd=AdminCommand::Import { entries: batch, on_conflict, ns:self.namespace.clone() };
+            match self.request_at(self.addr, &cmd)? {
+                Some(server::ServerResponse::Success(batch_report)) => report.add(&batch_report),
+                Some(server::ServerResponse::Error(server::ErrorType::NamespaceNotFound))=>return Err(ClientError::NamespaceNotFound(self.namespace_name())),
+                Some(server::ServerResponse::Error(server::ErrorType::Redirect(leader)))=>return Err(ClientError::Redirect(leader)),
+                _ => return Err(ClientError::OperationError("Client::import2".into())),
+            }
+        }
+        Ok(report)
+    }
+
+    //sends to the last known leader, following hints until a server other than a raft follower answers
+    fn request<T>(&self,cmd:&impl Serialize)->Result<Option<server::ServerResponse<T>>>
+    where
+        T:DeserializeOwned
+    {
+        for _ in 0..MAX_LEADER_HOPS{
+            let addr=self.leader.get().unwrap_or(self.addr);
+            let response=match self.request_at(addr, cmd) {
+                Err(ClientError::ConnectionError{..}) if addr!=self.addr => {
+                    //the leader we knew about is gone, ask the configured server again
+                    self.leader.set(None);
+                    continue;
+                },
+                res=>res?
+            };
+            match response {
+                Some(server::ServerResponse::Error(server::ErrorType::NotLeader(hint))) => {
+                    if hint.is_none(){
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    self.leader.set(hint);
+                },
+                response => return Ok(response),
+            }
+        }
+        Err(ClientError::OperationError("Client::request".into()))
+    }
+
+    fn request_at<T>(&self,addr:SocketAddr,cmd:&impl Serialize)->Result<Option<server::ServerResponse<T>>>
+    where
+        T:DeserializeOwned
+    {
+        let sock=TcpStream::connect(addr).map_err(|source|ClientError::ConnectionError{addr,source})?;
+        serde_json::to_writer(&sock, cmd)
+        .map_err(|error|ClientError::ConnectionError{addr,source:error.into()})?;
+
+
+        let buf=BufReader::new(&sock);
+        let response:result::Result<server::ServerResponse<T>,_>=serde_json::from_reader(buf);
+        Ok(response.ok())
+    }
+}
@@ -1,9 +1,8 @@
 use std::net::SocketAddr;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::kv::command;
-use crate::kv::command::KVCommand;
+use crate::kv::command::{ExportArgs, ImportArgs, KVCommand};
 
 
 #[derive(Parser)]
@@ -11,6 +10,23 @@ use crate::kv::command::KVCommand;
 pub struct ClientArgs{
     #[arg(global=true,long,default_value="127.0.0.1:4000")]
     pub addr:SocketAddr,
+    /// Shard keys across these servers instead of talking to --addr
+    #[arg(global=true,long,value_delimiter=',')]
+    pub cluster:Vec<SocketAddr>,
     #[command(subcommand)]
-    pub kv_command:KVCommand,
-}
\ No newline at end of file
+    pub command:ClientCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ClientCommand{
+    #[command(flatten)]
+    Kv(KVCommand),
+    /// Add a server to the --cluster and move the keys it now owns onto it
+    AddNode{addr:SocketAddr},
+    /// Move every key off a server and drop it from the --cluster
+    RemoveNode{addr:SocketAddr},
+    /// Write the entries of the server to a file or stdout
+    Export(ExportArgs),
+    /// Set the entries of an export on the server
+    Import(ImportArgs)
+}
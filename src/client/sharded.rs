@@ -0,0 +1,228 @@
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr};
+
+use super::{Client, ClientError, Result};
+
+pub const DEFAULT_VNODES:usize=64;
+/// Entries `transfer` reads from the source in one request.
+pub const TRANSFER_PAGE_SIZE:usize=1000;
+
+/// Range of ring positions `(start, end]`, wrapping past `u64::MAX` when `start>=end`.
+pub type HashRange=(u64,u64);
+
+/// Stable 64 bit FNV-1a, clients and servers have to agree on where a key lives.
+///
+/// FNV alone leaves similar inputs like `addr#0` and `addr#1` close together on
+/// the ring, so the result goes through the murmur3 finalizer to spread it out.
+pub fn key_hash(key:&[u8])->u64{
+    let mut hash=key.iter().fold(0xcbf29ce484222325,|hash,byte|(hash^*byte as u64).wrapping_mul(0x100000001b3));
+    hash^=hash>>33;
+    hash=hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash^=hash>>33;
+    hash=hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash^hash>>33
+}
+
+pub fn in_range(hash:u64,(start,end):HashRange)->bool{
+    if start<end{
+        start<hash&&hash<=end
+    } else {
+        hash>start||hash<=end
+    }
+}
+
+/// Consistent hash ring where every node owns `vnodes` points.
+pub struct HashRing{
+    vnodes:usize,
+    points:BTreeMap<u64,SocketAddr>
+}
+
+impl HashRing {
+    pub fn new(vnodes:usize)->HashRing{
+        HashRing{
+            vnodes,
+            points:BTreeMap::new()
+        }
+    }
+
+    pub fn nodes(&self)->Vec<SocketAddr>{
+        let mut nodes:Vec<_>=self.points.values().copied().collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    pub fn contains(&self,node:SocketAddr)->bool{
+        self.points.values().any(|owner|*owner==node)
+    }
+
+    pub fn add(&mut self,node:SocketAddr){
+        for point in self.points_of(node){
+            self.points.insert(point, node);
+        }
+    }
+
+    pub fn remove(&mut self,node:SocketAddr){
+        self.points.retain(|_,owner|*owner!=node);
+    }
+
+    pub fn node_for(&self,key:&str)->Option<SocketAddr>{
+        self.owner_of(key_hash(key.as_bytes()))
+    }
+
+    pub fn owner_of(&self,hash:u64)->Option<SocketAddr>{
+        self.points
+        .range(hash..)
+        .next()
+        .or_else(||self.points.iter().next())
+        .map(|(_,owner)|*owner)
+    }
+
+    /// The ranges `node` owns, keyed by the node owning them in `other`.
+    pub fn ranges_owned_in(&self,node:SocketAddr,other:&HashRing)->HashMap<SocketAddr,Vec<HashRange>>{
+        let mut moves:HashMap<SocketAddr,Vec<HashRange>>=HashMap::new();
+        for (point,owner) in self.points.iter(){
+            if *owner!=node{
+                continue;
+            }
+            let start=self.points
+            .range(..*point)
+            .next_back()
+            .or_else(||self.points.iter().next_back())
+            .map(|(start,_)|*start)
+            .expect("Ring contains point");
+            if let Some(other_owner)=other.owner_of(*point){
+                moves.entry(other_owner).or_default().push((start,*point));
+            }
+        }
+        moves
+    }
+
+    fn points_of(&self,node:SocketAddr)->Vec<u64>{
+        (0..self.vnodes)
+        .map(|vnode|key_hash(format!("{}#{}",node,vnode).as_bytes()))
+        .collect()
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct RebalanceReport{
+    pub moved_keys:usize,
+    //keys moved per (source, destination) pair
+    pub transfers:Vec<(SocketAddr,SocketAddr,usize)>
+}
+
+/// Routes every key to one of several servers through a `HashRing`.
+pub struct ShardedClient{
+    ring:HashRing,
+    clients:HashMap<SocketAddr,Client>
+}
+
+impl ShardedClient {
+    pub fn new(addrs:&[SocketAddr])->ShardedClient{
+        Self::with_vnodes(addrs, DEFAULT_VNODES)
+    }
+
+    pub fn with_vnodes(addrs:&[SocketAddr],vnodes:usize)->ShardedClient{
+        let mut ring=HashRing::new(vnodes);
+        for addr in addrs{
+            ring.add(*addr);
+        }
+        ShardedClient{
+            ring,
+            clients:addrs.iter().map(|addr|(*addr,Client::new(*addr))).collect()
+        }
+    }
+
+    pub fn nodes(&self)->Vec<SocketAddr>{
+        self.ring.nodes()
+    }
+
+    pub fn get(&self,key:&str)->Result<Option<String>>{
+        self.client_for(key)?.get(key)
+    }
+
+    pub fn set(&self,key:&str,value:&str)->Result<()>{
+        self.client_for(key)?.set(key, value)
+    }
+
+    pub fn remove(&self,key:&str)->Result<()>{
+        self.client_for(key)?.remove(key)
+    }
+
+    /// Adds `addr` to the ring and moves the keys it now owns over from their previous owners.
+    pub fn add_node(&mut self,addr:SocketAddr)->Result<RebalanceReport>{
+        if self.ring.contains(addr){
+            return Ok(RebalanceReport::default());
+        }
+        let mut new_ring=HashRing::new(self.ring.vnodes);
+        for node in self.ring.nodes().into_iter().chain([addr]){
+            new_ring.add(node);
+        }
+        self.clients.insert(addr, Client::new(addr));
+
+        let mut report=RebalanceReport::default();
+        for (source,ranges) in new_ring.ranges_owned_in(addr, &self.ring){
+            let moved=self.transfer(source, addr, ranges)?;
+            report.moved_keys+=moved;
+            report.transfers.push((source,addr,moved));
+        }
+        self.ring=new_ring;
+        Ok(report)
+    }
+
+    /// Moves every key off `addr` to the nodes owning it without `addr`, then drops it from the ring.
+    pub fn remove_node(&mut self,addr:SocketAddr)->Result<RebalanceReport>{
+        if !self.ring.contains(addr){
+            return Ok(RebalanceReport::default());
+        }
+        let mut new_ring=HashRing::new(self.ring.vnodes);
+        for node in self.ring.nodes().into_iter().filter(|node|*node!=addr){
+            new_ring.add(node);
+        }
+        if new_ring.points.is_empty(){
+            return Err(ClientError::Unsupported("the last node of the ring cannot be removed"));
+        }
+
+        let mut report=RebalanceReport::default();
+        for (destination,ranges) in self.ring.ranges_owned_in(addr, &new_ring){
+            let moved=self.transfer(addr, destination, ranges)?;
+            report.moved_keys+=moved;
+            report.transfers.push((addr,destination,moved));
+        }
+        self.ring=new_ring;
+        self.clients.remove(&addr);
+        Ok(report)
+    }
+
+    //copies first so a failure half way never loses a key, then deletes from the source
+    fn transfer(&self,source:SocketAddr,destination:SocketAddr,ranges:Vec<HashRange>)->Result<usize>{
+        let source=&self.clients[&source];
+        let destination=&self.clients[&destination];
+        let mut moved=0;
+        let mut after=None;
+        loop {
+            let page=source.scan_ranges(ranges.clone(), after, TRANSFER_PAGE_SIZE)?;
+            for (key,value) in page.entries.iter(){
+                destination.set(key, value)?;
+            }
+            for (key,_) in page.entries.iter(){
+                match source.remove(key) {
+                    Ok(_)|Err(ClientError::KeyNotFound(_)) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            moved+=page.entries.len();
+            after=page.next;
+            if after.is_none(){
+                return Ok(moved);
+            }
+        }
+    }
+
+    fn client_for(&self,key:&str)->Result<&Client>{
+        self.ring
+        .node_for(key)
+        .and_then(|addr|self.clients.get(&addr))
+        .ok_or(ClientError::OperationError("ShardedClient::client_for".into()))
+    }
+}
@@ -1,4 +1,13 @@
 
 pub const KILOBYTE:usize=1000;
 pub const MEGABYTE:usize=KILOBYTE*1000;
-pub const GIGABYTE:usize=MEGABYTE*1000;
\ No newline at end of file
+pub const GIGABYTE:usize=MEGABYTE*1000;
+//process exit codes of the tools, from sysexits.h where one fits
+pub const EXIT_NOT_FOUND:u8=1;
+pub const EXIT_USAGE:u8=64;
+pub const EXIT_DATA:u8=65;
+pub const EXIT_UNAVAILABLE:u8=69;
+pub const EXIT_SOFTWARE:u8=70;
+pub const EXIT_IO:u8=74;
+pub const EXIT_TEMPFAIL:u8=75;
+pub const EXIT_CONFIG:u8=78;
@@ -0,0 +1,15 @@
+//! What the fuzz targets in `fuzz/` run, each takes bytes from outside and must fail without panicking.
+
+use std::io;
+
+use crate::{server::Server, KvsEngine, MemoryStore};
+
+pub use crate::kv::fuzz::{record, segment};
+
+/// Decodes `bytes` as a request from a client, and answers it from an empty in-memory server if it is one.
+pub fn request(bytes:&[u8]){
+    if let Ok(request)=Server::parse_request(bytes){
+        let mut server=Server::in_process(Box::new(MemoryStore::new()) as Box<dyn KvsEngine>);
+        server.handle_request(request, &mut io::sink());
+    }
+}
@@ -1,36 +1,61 @@
-use std::{path::PathBuf, result};
-use crate::{common::KILOBYTE, KvsEngine};
+use std::{collections::{BTreeMap, VecDeque}, ops::Range, path::PathBuf};
+use crate::{EngineStats, EntryStream, KvsEngine, DEFAULT_NAMESPACE};
 use serde::{Deserialize, Serialize};
-use self::{index::Index, storage::LogStorage};
+use self::{bloom::{Bloom, DEFAULT_FALSE_POSITIVE_RATE}, compaction::{Compaction, CompactionJob, CompactionPlan}, cache::ValueCache, history::{History, Version, VersionPolicy}, index::{build_index, Index}, record::{Record, StoredRecord}, util::now_millis, namespace::NamespaceOptions, options::KvStoreOptions, secondary::{IndexDefinition, SecondaryIndexes}, replication::{LogPosition, ReplicatedSchema, ReplicationBatch}, storage::{LogPointer, LogStorage}};
 
 mod index;
 mod storage;
+mod bloom;
+mod cache;
+mod compaction;
 mod util;
+mod error;
 pub mod config;
+pub mod options;
 pub mod command;
+pub mod replication;
+pub mod namespace;
+pub mod secondary;
+pub mod history;
+pub mod record;
+pub mod recovery;
+pub mod repair;
+pub mod verify;
+pub mod inspect;
+pub mod export;
+pub mod filesystem;
+pub(crate) mod fuzz;
 
+pub use self::index::IndexKind;
+pub use self::bloom::BloomStats;
+pub use self::cache::{Admission, CacheStats};
+pub use self::options::Durability;
+pub use self::error::{Context, ErrorContext, KVError, Result};
 
-pub type Result<T>=result::Result<T,KVError>;
-
-#[derive(Debug,PartialEq)]
-pub enum KVError{
-    IOError(&'static str),
-    ConfigError(&'static str),
-    ReadError(&'static str),
-    WriteError(&'static str),
-    KeyNotFound(&'static str),
-    ParseError(&'static str)
-}
 
 pub struct KvStore{
+    root:PathBuf,
     storage:LogStorage,
-    index:Index,
-    merge_threshold:usize
+    index:Box<dyn Index>,
+    options:KvStoreOptions,
+    //storage size right after the last compaction
+    merged_size:usize,
+    compaction:Option<Compaction>,
+    namespaces:BTreeMap<String,KvStore>,
+    //the options of the namespace this store holds, the defaults for the store itself
+    ns_options:NamespaceOptions,
+    secondary:SecondaryIndexes,
+    history:Option<History>,
+    bloom:Bloom,
+    cache:ValueCache,
+    next_seq:u64,
+    //the live positions up to each snapshot being served, sorted once for all of its pages
+    snapshot_positions:BTreeMap<LogPosition,Vec<LogPosition>>
 }
 
 
-#[derive(Deserialize,Serialize,Debug)]
-enum Operation{
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub enum Operation{
     Get(String),
     Remove(String),
     Set(String,String)
@@ -38,79 +63,445 @@ enum Operation{
 
 impl KvStore {
     pub fn open(path:impl Into<PathBuf>)->Result<KvStore>{
-        let mut path:PathBuf=path.into();
-        path.push("data");
-        let storage=LogStorage::load(path)?;
-        let mut index=Index::new();
-        index.build_index(storage.iter_entries())?;
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store keeping its key directory, and the ones of its namespaces, in an `index_kind` index.
+    pub fn open_with_index(path:impl Into<PathBuf>,index_kind:IndexKind)->Result<KvStore>{
+        Self::open_with(path, KvStoreOptions::new().index_kind(index_kind))
+    }
+
+    /// Opens the store and its namespaces with `options`, after validating them.
+    pub fn open_with(path:impl Into<PathBuf>,options:KvStoreOptions)->Result<KvStore>{
+        options.validate()?;
+        let path:PathBuf=path.into();
+        let exists=options.file_system.get().is_dir(&path.join("data"));
+        if exists&&options.error_if_exists{
+            return Err(KVError::ConfigError("store already exists"));
+        }
+        if !exists&&(options.read_only||!options.create_if_missing){
+            return Err(KVError::ConfigError("store does not exist"));
+        }
+        let mut store=Self::open_segments(path,&options)?;
+        store.namespaces=Self::load_namespaces(&store.root,&options)?;
+
+        Ok(store)
+    }
+
+    fn open_segments(root:PathBuf,options:&KvStoreOptions)->Result<KvStore>{
+        let mut storage=LogStorage::open(root.join("data"), options)?;
+        let mut index=options.index_kind.create(&storage)?;
+        //seq 0 is left to records written before records were stamped, and the seqs of removes a
+        //compaction dropped are not given out again
+        let mut next_seq=recovery::dropped_removes(&root)?+1;
+        //a filter left by a crash during merge covers segments that are gone
+        let mut bloom=Bloom::load(&root)?
+        .filter(|bloom|storage.contains_segment(bloom.covers().segment))
+        .filter(|bloom|options.bloom_false_positive_rate.is_none_or(|rate|rate==bloom.false_positive_rate()));
+        build_index(
+            index.as_mut(),
+            &storage,
+            storage
+            .iter_entries::<StoredRecord>()
+            .map(|entry|entry.map(|(log_ptr,stored)|{
+                let record=stored.into_record();
+                next_seq=next_seq.max(record.seq+1);
+                if let (Some(bloom),Operation::Set(key,_))=(bloom.as_mut(),&record.op){
+                    if log_ptr.position()>=bloom.covers(){
+                        bloom.insert(key);
+                    }
+                }
+                (log_ptr,record.op)
+            }))
+        )?;
+        let bloom=match bloom {
+            Some(bloom) => bloom,
+            None => {
+                let rate=options.bloom_false_positive_rate.unwrap_or(DEFAULT_FALSE_POSITIVE_RATE);
+                let keys=index.keys(&storage)?;
+                if options.read_only {
+                    Bloom::create(&root, rate, keys, storage.head_position())?
+                } else {
+                    Bloom::build(&root, rate, keys, storage.head_position())?
+                }
+            },
+        };
+        storage.set_mmap_reads(options.mmap_reads)?;
+        let mut secondary=SecondaryIndexes::load(&root)?;
+        if !secondary.is_empty(){
+            secondary.build(index.iter(&storage))?;
+        }
+        let history=History::open(&root, options)?;
         
         Ok(KvStore{
+            root,
             storage,
             index,
-            merge_threshold:10*KILOBYTE
+            options:options.clone(),
+            merged_size:0,
+            compaction:None,
+            namespaces:BTreeMap::new(),
+            ns_options:NamespaceOptions::default(),
+            secondary,
+            history,
+            bloom,
+            cache:ValueCache::new(options.cache_capacity, options.admission),
+            next_seq,
+            snapshot_positions:BTreeMap::new()
         })
 
     }
     
-    //just merge every right now
-    fn merge(&mut self)->Result<()>{
-        let file_serial:Vec<_>=self.storage
-        .iter_read_files()
-        .map(|(serial,_)|serial)
-        .cloned()
-        .collect();
+    /// The secondary indexes defined on this store.
+    pub fn index_definitions(&self)->Vec<IndexDefinition>{
+        self.secondary.definitions()
+    }
 
-        //just collect all operation in memory right now
-        //In real system needs to limit operation in memory using take or take_while
-        let operations=self.index.iter().collect::<Result<Vec<_>>>()?;
-        let merge_result=self.storage.merge(&file_serial,operations)?;
+    /// Starts keeping old versions of every key according to `policy`, or changes the policy.
+    pub fn enable_versioning(&mut self,policy:VersionPolicy)->Result<()>{
+        self.check_writable()?;
+        let history=History::enable(&self.root, &self.options, policy, self.index.records(&self.storage))?;
+        self.history=Some(history);
+        Ok(())
+    }
 
-        assert!(self.index.len()==merge_result.len(),"Number of key before and after merge is not the same");
+    /// Stops versioning and deletes every old version.
+    pub fn disable_versioning(&mut self)->Result<()>{
+        self.check_writable()?;
+        if self.history.take().is_some(){
+            History::disable(&self.root)?;
+        }
+        Ok(())
+    }
 
-        
-        for (log_ptr,op) in merge_result{
-            if let Operation::Set(key,_)=op{
-                self.index.set(key, log_ptr);
-            } else {
-                panic!("Merged result should only have set")
+    pub fn version_policy(&self)->Option<&VersionPolicy>{
+        self.history.as_ref().map(History::policy)
+    }
+
+    /// Rebuilds the Bloom filter for `false_positive_rate`, which also applies after reopening.
+    pub fn set_bloom_false_positive_rate(&mut self,false_positive_rate:f64)->Result<()>{
+        self.check_writable()?;
+        let keys=self.index.keys(&self.storage)?;
+        self.bloom=Bloom::build(&self.root, false_positive_rate, keys, self.storage.head_position())?;
+        Ok(())
+    }
+
+    /// Replaces the value cache of the store and its namespaces, each gets `capacity` bytes.
+    /// A capacity of 0 turns caching off.
+    pub fn set_value_cache(&mut self,capacity:usize,admission:Admission){
+        self.cache=ValueCache::new(capacity, admission);
+        self.options.cache_capacity=capacity;
+        self.options.admission=admission;
+        for store in self.namespaces.values_mut(){
+            store.set_value_cache(capacity, admission);
+        }
+    }
+
+    /// Limits how many bytes per second a background compaction of the store and its namespaces
+    /// reads and writes, from the next compaction on. `None` lifts the limit.
+    pub fn set_compaction_rate(&mut self,bytes_per_sec:Option<u64>){
+        self.options.compaction_rate=bytes_per_sec;
+        for store in self.namespaces.values_mut(){
+            store.set_compaction_rate(bytes_per_sec);
+        }
+    }
+
+    pub fn is_compacting(&self)->bool{
+        self.compaction.is_some()
+    }
+
+    /// Compacts the log now, waiting for a compaction already running first.
+    pub fn compact(&mut self)->Result<()>{
+        self.check_writable()?;
+        self.finish_compaction()?;
+        self.start_compaction()?;
+        self.finish_compaction()
+    }
+
+    /// Reads sealed segments of the store and its namespaces through a memory mapping.
+    pub fn set_mmap_reads(&mut self,enabled:bool)->Result<()>{
+        self.storage.set_mmap_reads(enabled)?;
+        self.options.mmap_reads=enabled;
+        for store in self.namespaces.values_mut(){
+            store.set_mmap_reads(enabled)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_read_only(&self)->bool{
+        self.options.read_only
+    }
+
+    fn check_writable(&self)->Result<()>{
+        if self.options.read_only {Err(KVError::Unsupported("store is read only"))} else {Ok(())}
+    }
+
+    fn contains(&self,key:&str)->Result<bool>{
+        Ok(self.bloom.may_contain(key)&&self.index.contains(&self.storage, key)?)
+    }
+
+    //stamps op with the next seq and the current time and appends it
+    fn write(&mut self,op:Operation)->Result<(LogPointer,Record)>{
+        self.check_writable()?;
+        let record=Record::new(self.next_seq, now_millis(), op);
+        self.next_seq+=1;
+        let log_ptr=self.storage.write(&record)?;
+        Ok((log_ptr,record))
+    }
+
+    //a set without the compaction check
+    fn insert(&mut self,key:String,value:String)->Result<()>{
+        let (log_ptr,record)=self.write(Operation::Set(key,value))?;
+        self.track(log_ptr, &record)
+    }
+
+    //brings the index, the secondary indexes, the history, the filter and the cache up to a record just written
+    fn track(&mut self,log_ptr:LogPointer,record:&Record)->Result<()>{
+        match &record.op {
+            Operation::Set(key,value) => {
+                self.secondary.set(key, value);
+                if let Some(history)=self.history.as_mut(){
+                    history.record(record)?;
+                }
+                self.bloom.insert(key);
+                self.cache.invalidate(key);
+                self.index.set(&self.storage, key.clone(), log_ptr)
+            },
+            Operation::Remove(key) => {
+                self.index.remove(&self.storage, key)?;
+                self.cache.invalidate(key);
+                self.secondary.remove(key);
+                match self.history.as_mut() {
+                    Some(history) => history.record(record),
+                    None => Ok(()),
+                }
+            },
+            Operation::Get(_) => Ok(()),
+        }
+    }
+
+    //appends a record keeping its stamps, for replaying another store's log
+    fn apply_record(&mut self,record:Record)->Result<()>{
+        self.check_writable()?;
+        let applies=match &record.op {
+            Operation::Set(..) => true,
+            Operation::Remove(key) => self.contains(key)?,
+            Operation::Get(_) => false,
+        };
+        if applies{
+            let log_ptr=self.storage.write(&record)?;
+            self.track(log_ptr, &record)?;
+        }
+        self.next_seq=self.next_seq.max(record.seq+1);
+        Ok(())
+    }
+
+    //moves a compaction on once its chunk is done, and starts one once the log grew by the threshold or by its size after the last one
+    fn maybe_compact(&mut self)->Result<()>{
+        if self.compaction.as_ref().is_some_and(|compaction|compaction.job.is_finished()){
+            self.finish_chunk()?;
+        }
+        if self.compaction.is_none()&&self.storage.storage_size()>self.merged_size+self.options.merge_size.max(self.merged_size){
+            self.start_compaction()?;
+            if !self.options.background_compaction{
+                self.finish_compaction()?;
             }
         }
+        Ok(())
+    }
 
+    fn start_compaction(&mut self)->Result<()>{
+        let (inputs,outputs)=self.storage.seal_for_compaction()?;
+        self.compaction=Some(self.start_chunk(inputs.into(), outputs)?);
+        Ok(())
+    }
+
+    //spawns a job for the oldest pending segments, as many as fit in a chunk
+    fn start_chunk(&self,mut pending:VecDeque<(usize,PathBuf)>,outputs:Range<usize>)->Result<Compaction>{
+        let mut inputs=Vec::new();
+        let mut size=0;
+        while let Some((serial,_))=pending.front(){
+            let segment_size=self.storage.segment_size(*serial)? as usize;
+            if !inputs.is_empty()&&size+segment_size>self.options.compaction_chunk{
+                break;
+            }
+            size+=segment_size;
+            inputs.extend(pending.pop_front());
+        }
+        let (Some((first,_)),Some((last,_)))=(inputs.first(),inputs.last()) else {
+            return Err(KVError::IOError("KvStore::start_chunk".into()));
+        };
+        //every segment between the first and the last input is one
+        let chunk=*first..=*last;
+        let live=self.index.positions().filter(|position|chunk.contains(&position.segment)).collect();
+        let job=CompactionJob::spawn(CompactionPlan{
+            file_system:self.storage.file_system(),
+            inputs,
+            live,
+            directory:self.storage.directory().to_path_buf(),
+            outputs:outputs.clone(),
+            file_size_limit:self.storage.file_size_limit(),
+            bytes_per_sec:self.options.compaction_rate,
+            keep_removes_since:self.options.remove_retention.map(|secs|now_millis().saturating_sub(secs.saturating_mul(1000)))
+        });
+        Ok(Compaction{job,pending,outputs})
+    }
+
+    //waits for the running chunk, swaps in the pointers of the keys not written since it started,
+    //deletes its inputs and starts the next chunk
+    fn finish_chunk(&mut self)->Result<()>{
+        let Some(mut compaction)=self.compaction.take() else {
+            return Ok(());
+        };
+        let compacted=compaction.job.wait()?;
+        for output in compacted.outputs.iter(){
+            self.storage.install_compacted(*output)?;
+        }
+        //the compacted segments are in place for good before their inputs go
+        self.storage.sync_directory()?;
+        if compacted.dropped_removes>0{
+            recovery::note_dropped_removes(&self.root, compacted.dropped_removes)?;
+        }
+        for moved in compacted.moved{
+            if self.index.position(&self.storage, &moved.key)?==Some(moved.from){
+                let log_ptr=self.storage.pointer(moved.to)?;
+                self.index.set(&self.storage, moved.key, log_ptr)?;
+            }
+        }
+        //appended after everything, a kept remove can only follow the sets of its key
+        let mut appended=false;
+        for remove in compacted.removes{
+            if let Operation::Remove(key)=&remove.op{
+                if !self.contains(key)?{
+                    self.storage.write(&remove)?;
+                    appended=true;
+                }
+            }
+        }
+        if appended{
+            self.storage.sync()?;
+        }
+        self.storage.remove_segments(compaction.job.sealed())?;
+        //the moved records are listed again by the next page
+        self.snapshot_positions.clear();
+
+        if let Some(last)=compacted.outputs.last(){
+            compaction.outputs.start=last+1;
+        }
+        if !compaction.pending.is_empty(){
+            self.compaction=Some(self.start_chunk(compaction.pending, compaction.outputs)?);
+            return Ok(());
+        }
+        self.cache.clear();
+        //drops the removed keys from the filter
+        let keys=self.index.keys(&self.storage)?;
+        self.bloom=Bloom::build(&self.root, self.bloom.false_positive_rate(), keys, self.storage.head_position())?;
+        self.merged_size=self.storage.storage_size();
+        Ok(())
+    }
+
+    //waits for every chunk of the running compaction
+    fn finish_compaction(&mut self)->Result<()>{
+        while self.compaction.is_some(){
+            self.finish_chunk()?;
+        }
         Ok(())
     }
 }
 
+const REPLICATION_BATCH_LIMIT:usize=1000;
+//snapshots whose positions are kept at once, a follower that gave up on one leaves it behind
+const SNAPSHOTS_KEPT:usize=4;
+
+impl KvStore {
+    //the live records in log order from `from` up to `position`
+    fn snapshot_page(&mut self,position:LogPosition,from:LogPosition,first:bool)->Result<ReplicationBatch>{
+        if first||!self.snapshot_positions.contains_key(&position){
+            let mut live:Vec<_>=self.index.positions().filter(|live|*live>=from&&*live<position).collect();
+            live.sort_unstable();
+            if self.snapshot_positions.len()>=SNAPSHOTS_KEPT{
+                self.snapshot_positions.pop_first();
+            }
+            self.snapshot_positions.insert(position, live);
+        }
+        let live=&self.snapshot_positions[&position];
+        let start=live.partition_point(|live|*live<from);
+        let end=(start+REPLICATION_BATCH_LIMIT).min(live.len());
+        let page=&live[start..end];
+        let next=page.last().filter(|_|end<live.len()).map(|last|LogPosition{segment:last.segment,offset:last.offset+1});
+        let entries=page
+        .iter()
+        .map(|live|match self.storage.pointer(*live)?.read::<StoredRecord>()?.into_record().op {
+            Operation::Set(key, value) => Ok((key,value)),
+            _=>Err(KVError::ReadError("KvStore::snapshot_page".into()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+        if next.is_none(){
+            self.snapshot_positions.remove(&position);
+        }
+        let lag_bytes=match next {
+            Some(next) => self.storage.bytes_after(next)?.max(1),
+            None => self.storage.bytes_after(position)?,
+        };
+        Ok(ReplicationBatch::Snapshot { entries, position, next, first, lag_bytes, schema: self.replicated_schema()? })
+    }
+
+    fn replicated_schema(&self)->Result<ReplicatedSchema>{
+        let namespaces=self.namespaces
+        .iter()
+        .map(|(name,store)|(name.clone(),store.ns_options.clone()))
+        .collect();
+        Ok(ReplicatedSchema{namespaces,indexes:self.index_definitions()})
+    }
+}
+
 impl KvsEngine for KvStore {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let set_op=Operation::Set(key.clone(),value);
-        let log_ptr=self.storage.write(set_op)?;
-        self.index.set(key, log_ptr);
-        if self.storage.storage_size()>self.merge_threshold{
-            self.merge()?;
+        self.insert(key, value)?;
+        self.maybe_compact()
+    }
+
+    //the values are read through handles of their own, so the caller can go on writing meanwhile
+    fn snapshot_entries(&mut self)->Result<EntryStream> {
+        let mut positions:Vec<_>=self.index.positions().collect();
+        positions.sort_unstable();
+        let records=self.storage.detached_reader::<StoredRecord>(positions)?;
+        Ok(Box::new(records.map(|stored|match stored?.into_record().op {
+            Operation::Set(key,value) => Ok((key,value)),
+            _ => Err(KVError::ReadError("KvStore::snapshot_entries".into())),
+        })))
+    }
+
+    //one compaction check for the whole batch
+    fn set_batch(&mut self,entries:Vec<(String,String)>)->Result<()> {
+        for (key,value) in entries{
+            self.insert(key, value)?;
         }
-        Ok(())
+        self.maybe_compact()
     }
 
     fn get(&mut self,key:String)->Result<Option<String>>{
-        if !self.index.contains(&key){
-            Ok(None)
-        } else {
-            Ok(Some(self.index.get(&key)?))
+        if !self.bloom.check(&key){
+            return Ok(None);
+        }
+        if let Some(value)=self.cache.get(&key){
+            return Ok(Some(value));
         }
+        let value=self.index.get(&self.storage, &key)?;
+        match &value {
+            Some(value) => self.cache.insert(key, value.clone()),
+            None => self.bloom.false_positive(),
+        }
+        Ok(value)
     }
 
     fn remove(&mut self,key:String)->Result<()>{
-        let rm_op=Operation::Remove(key.clone());
-
-        if !self.index.contains(&key){
-            Err(KVError::KeyNotFound("KvStore::remove"))
+        if !self.contains(&key)?{
+            Err(KVError::KeyNotFound(key))
         } else {
-            self.storage.write(rm_op)?;
-            self.index.remove(&key)?;
-            
-            if self.storage.storage_size()>self.merge_threshold{
-                self.merge()?;
-            }
+            let (log_ptr,record)=self.write(Operation::Remove(key))?;
+            self.track(log_ptr, &record)?;
+            self.maybe_compact()?;
             Ok(())
         }
     }
@@ -118,4 +509,90 @@ impl KvsEngine for KvStore {
     fn name(&self)->String {
         "kvs".to_string()
     }
+
+    fn keys(&mut self)->Result<Vec<String>> {
+        self.index.keys(&self.storage)
+    }
+
+    fn namespace<'a>(&'a mut self,name:&str)->Result<Box<dyn KvsEngine+'a>> {
+        if name==DEFAULT_NAMESPACE{
+            return Ok(Box::new(self));
+        }
+        self.namespaces
+        .get_mut(name)
+        .map(|store|Box::new(store) as Box<dyn KvsEngine>)
+        .ok_or_else(||KVError::NamespaceNotFound(name.to_string()))
+    }
+
+    fn create_namespace(&mut self,name:&str,options:NamespaceOptions)->Result<()> {
+        self.check_writable()?;
+        if name==DEFAULT_NAMESPACE||self.namespaces.contains_key(name){
+            return Err(KVError::ConfigError("namespace already exists"));
+        }
+        let store=Self::create_namespace_dir(&self.root, name, &options, &self.options)?;
+        self.namespaces.insert(name.to_string(), store);
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self,name:&str)->Result<()> {
+        self.check_writable()?;
+        //closes the segment files before they are deleted
+        self.namespaces.remove(name).ok_or_else(||KVError::NamespaceNotFound(name.to_string()))?;
+        Self::remove_namespace_dir(&self.root, name, &self.options)
+    }
+
+    fn list_namespaces(&mut self)->Result<Vec<String>> {
+        Ok(std::iter::once(DEFAULT_NAMESPACE.to_string()).chain(self.namespaces.keys().cloned()).collect())
+    }
+
+    fn history(&mut self,key:String)->Result<Vec<Version>> {
+        self.history
+        .as_ref()
+        .ok_or(KVError::Unsupported("versioning is not enabled"))?
+        .versions(&key)
+    }
+
+    fn create_index(&mut self,name:&str,pointer:&str)->Result<()> {
+        self.check_writable()?;
+        let definition=IndexDefinition{name:name.to_string(),pointer:pointer.to_string()};
+        self.secondary.create(&self.root, definition, self.index.iter(&self.storage))
+    }
+
+    fn drop_index(&mut self,name:&str)->Result<()> {
+        self.check_writable()?;
+        self.secondary.drop(&self.root, name)
+    }
+
+    fn query_index(&mut self,name:&str,value:&str)->Result<Vec<String>> {
+        self.secondary.query(name, value)
+    }
+
+    fn list_indexes(&mut self)->Result<Vec<IndexDefinition>> {
+        Ok(self.index_definitions())
+    }
+
+    fn stats(&self)->EngineStats {
+        EngineStats{
+            bloom:Some(self.bloom.stats()),
+            cache:Some(self.cache.stats())
+        }
+    }
+
+    fn replicate(&mut self,position:Option<LogPosition>,snapshot:Option<LogPosition>)->Result<ReplicationBatch> {
+        match (position,snapshot) {
+            (Some(position),None) if self.storage.contains_segment(position.segment) => {
+                let (records,position)=self.storage.read_from::<StoredRecord>(position, REPLICATION_BATCH_LIMIT)?;
+                let operations=records.into_iter().map(|record|record.into_record().op).collect();
+                let lag_bytes=self.storage.bytes_after(position)?;
+                Ok(ReplicationBatch::Records { operations, position, lag_bytes, schema: self.replicated_schema()? })
+            },
+            //compaction only moves records past the segments it seals, so while the snapshot's
+            //segment is there nothing it copies moved behind the page it is at
+            (Some(position),Some(from)) if self.storage.contains_segment(position.segment) => {
+                self.snapshot_page(position, from, false)
+            },
+            //either a new follower or its segment was compacted away
+            _ => self.snapshot_page(self.storage.head_position(), LogPosition{segment:0,offset:0}, true),
+        }
+    }
 }
\ No newline at end of file
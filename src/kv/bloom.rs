@@ -0,0 +1,181 @@
+use std::{fs::{rename, File}, io::{BufReader, BufWriter, ErrorKind}, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+use super::{filesystem::{FileSystem, OsFileSystem}, replication::LogPosition, Context, ErrorContext, KVError, Result};
+
+const BLOOM_FILE:&str="bloom.json";
+pub const DEFAULT_FALSE_POSITIVE_RATE:f64=0.01;
+//filters are sized for at least this many keys so a small store does not rebuild on every merge
+const MIN_CAPACITY:usize=1024;
+
+/// Counters of the lookups a store's Bloom filter answered.
+#[derive(Deserialize,Serialize,Debug,Clone,Default,PartialEq)]
+pub struct BloomStats{
+    pub false_positive_rate:f64,
+    pub bits:usize,
+    pub lookups:u64,
+    //lookups the filter answered without touching the index
+    pub hits:u64,
+    //lookups the filter let through for a key the store does not have
+    pub false_positives:u64
+}
+
+#[derive(Deserialize,Serialize)]
+struct BloomFilter{
+    bits:Vec<u64>,
+    hashes:u32
+}
+
+impl BloomFilter {
+    fn new(capacity:usize,false_positive_rate:f64)->BloomFilter{
+        let capacity=capacity.max(MIN_CAPACITY) as f64;
+        let ln2=std::f64::consts::LN_2;
+        let bit_count=(-capacity*false_positive_rate.ln()/(ln2*ln2)).ceil().max(64.0) as usize;
+        BloomFilter{
+            bits:vec![0;bit_count.div_ceil(64)],
+            hashes:((bit_count as f64/capacity)*ln2).round().max(1.0) as u32
+        }
+    }
+
+    //double hashing, the positions only depend on the key so a persisted filter stays valid
+    fn positions(&self,key:&str)->impl Iterator<Item = usize>{
+        let first=fnv1a(key.as_bytes());
+        let second=mix(first)|1;
+        let bit_count=(self.bits.len()*64) as u64;
+        (0..self.hashes as u64).map(move|i|(first.wrapping_add(i.wrapping_mul(second))%bit_count) as usize)
+    }
+
+    fn insert(&mut self,key:&str){
+        for position in self.positions(key){
+            self.bits[position/64]|=1<<(position%64);
+        }
+    }
+
+    fn may_contain(&self,key:&str)->bool{
+        self.positions(key).all(|position|self.bits[position/64]&(1<<(position%64))!=0)
+    }
+}
+
+#[derive(Deserialize,Serialize)]
+struct PersistedBloom{
+    false_positive_rate:f64,
+    //every key set before this position is in the filter
+    covers:LogPosition,
+    filter:BloomFilter
+}
+
+/// A filter over every key a store has set, so most lookups of absent keys skip the index.
+/// It is persisted next to the segments with the log position it covers, and rebuilt from
+/// the live keys on merge since removed keys cannot be taken out.
+pub(super) struct Bloom{
+    path:PathBuf,
+    persisted:PersistedBloom,
+    stats:BloomStats
+}
+
+impl Bloom {
+    /// The persisted filter of the store at `root`, if there is one.
+    pub fn load(root:&Path)->Result<Option<Bloom>>{
+        let persisted:PersistedBloom=match File::open(root.join(BLOOM_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).context(KVError::ParseError, "Bloom::load1").map_err(|e|e.with_path(root.join(BLOOM_FILE)))?,
+            Err(e) if e.kind()==ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(KVError::IOError(ErrorContext::new("Bloom::load2").with_path(root.join(BLOOM_FILE)).with_source(e))),
+        };
+        Ok(Some(Bloom::new(root, persisted)))
+    }
+
+    /// A filter sized for `keys` holding them, covering the log up to `covers`, and saves it.
+    pub fn build(root:&Path,false_positive_rate:f64,keys:Vec<String>,covers:LogPosition)->Result<Bloom>{
+        let bloom=Bloom::create(root, false_positive_rate, keys, covers)?;
+        bloom.save()?;
+        Ok(bloom)
+    }
+
+    /// Like `build` without saving, for stores that are read only.
+    pub fn create(root:&Path,false_positive_rate:f64,keys:Vec<String>,covers:LogPosition)->Result<Bloom>{
+        if !(false_positive_rate>0.0&&false_positive_rate<1.0){
+            return Err(KVError::ConfigError("false positive rate must be between 0 and 1"));
+        }
+        let mut filter=BloomFilter::new(keys.len()*2, false_positive_rate);
+        for key in keys.iter(){
+            filter.insert(key);
+        }
+        Ok(Bloom::new(root, PersistedBloom{false_positive_rate,covers,filter}))
+    }
+
+    fn new(root:&Path,persisted:PersistedBloom)->Bloom{
+        Bloom{
+            path:root.join(BLOOM_FILE),
+            stats:BloomStats{
+                false_positive_rate:persisted.false_positive_rate,
+                bits:persisted.filter.bits.len()*64,
+                ..Default::default()
+            },
+            persisted
+        }
+    }
+
+    pub fn false_positive_rate(&self)->f64{
+        self.persisted.false_positive_rate
+    }
+
+    pub fn path(&self)->&Path{
+        &self.path
+    }
+
+    pub fn covers(&self)->LogPosition{
+        self.persisted.covers
+    }
+
+    pub fn insert(&mut self,key:&str){
+        self.persisted.filter.insert(key);
+    }
+
+    pub fn may_contain(&self,key:&str)->bool{
+        self.persisted.filter.may_contain(key)
+    }
+
+    /// Like `may_contain`, counted in the stats.
+    pub fn check(&mut self,key:&str)->bool{
+        self.stats.lookups+=1;
+        let may_contain=self.persisted.filter.may_contain(key);
+        if !may_contain{
+            self.stats.hits+=1;
+        }
+        may_contain
+    }
+
+    /// Records that a key `check` let through was not there.
+    pub fn false_positive(&mut self){
+        self.stats.false_positives+=1;
+    }
+
+    pub fn stats(&self)->BloomStats{
+        self.stats.clone()
+    }
+
+    //the filter is synced before it replaces the old one, or a crash could leave a torn one claiming what it covers
+    fn save(&self)->Result<()>{
+        let tmp_path=self.path.with_extension("tmp");
+        let file=File::create(&tmp_path).context(KVError::IOError, "Bloom::save1")?;
+        let mut writer=BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &self.persisted).context(KVError::WriteError, "Bloom::save2")?;
+        let file=writer.into_inner().map_err(|e|e.into_error()).context(KVError::WriteError, "Bloom::save3")?;
+        file.sync_all().context(KVError::WriteError, "Bloom::save4")?;
+        rename(tmp_path, &self.path).context(KVError::IOError, "Bloom::save5")?;
+        let dir=self.path.parent().unwrap_or(Path::new("."));
+        OsFileSystem.sync_dir(dir).context(KVError::IOError, "Bloom::save6").map_err(|e|e.with_path(dir))
+    }
+}
+
+fn fnv1a(bytes:&[u8])->u64{
+    bytes.iter().fold(0xcbf29ce484222325,|hash,byte|(hash^*byte as u64).wrapping_mul(0x100000001b3))
+}
+
+//splitmix64 finalizer, a second hash independent enough of the first
+fn mix(hash:u64)->u64{
+    let hash=(hash^(hash>>30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let hash=(hash^(hash>>27)).wrapping_mul(0x94d049bb133111eb);
+    hash^(hash>>31)
+}
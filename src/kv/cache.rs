@@ -0,0 +1,183 @@
+use std::{collections::{hash_map::DefaultHasher, BTreeMap, HashMap}, hash::{Hash, Hasher}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::MEGABYTE;
+
+pub const DEFAULT_CACHE_CAPACITY:usize=MEGABYTE;
+//bookkeeping of an entry on top of its key and value
+const ENTRY_OVERHEAD:usize=64;
+const SKETCH_DEPTH:usize=4;
+const MAX_FREQUENCY:u8=15;
+
+/// Which values the cache takes in once it is full.
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum Admission{
+    /// Every value read, evicting the least recently used ones.
+    #[default]
+    Always,
+    /// Only values read more often than the one they would evict, so a scan does not flush hot values.
+    TinyLfu
+}
+
+/// Counters of a store's value cache.
+#[derive(Deserialize,Serialize,Debug,Clone,Default,PartialEq)]
+pub struct CacheStats{
+    pub capacity_bytes:usize,
+    pub used_bytes:usize,
+    pub entries:usize,
+    pub hits:u64,
+    pub misses:u64,
+    //values left out by the admission policy
+    pub rejected:u64
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self)->f64{
+        let lookups=self.hits+self.misses;
+        if lookups==0 {0.0} else {self.hits as f64/lookups as f64}
+    }
+}
+
+//approximate read counts, halved every sample so old popularity fades
+struct FrequencySketch{
+    counters:Vec<[u8;SKETCH_DEPTH]>,
+    additions:usize,
+    sample:usize
+}
+
+impl FrequencySketch {
+    fn new(width:usize)->FrequencySketch{
+        let width=width.next_power_of_two();
+        FrequencySketch{
+            counters:vec![[0;SKETCH_DEPTH];width],
+            additions:0,
+            sample:width*10
+        }
+    }
+
+    fn slots(&self,key:&str)->[usize;SKETCH_DEPTH]{
+        let mut slots=[0;SKETCH_DEPTH];
+        for (row,slot) in slots.iter_mut().enumerate(){
+            let mut hasher=DefaultHasher::new();
+            (row,key).hash(&mut hasher);
+            *slot=hasher.finish() as usize&(self.counters.len()-1);
+        }
+        slots
+    }
+
+    fn increment(&mut self,key:&str){
+        for (row,slot) in self.slots(key).into_iter().enumerate(){
+            let counter=&mut self.counters[slot][row];
+            *counter=(*counter+1).min(MAX_FREQUENCY);
+        }
+        self.additions+=1;
+        if self.additions>=self.sample{
+            for counter in self.counters.iter_mut().flatten(){
+                *counter/=2;
+            }
+            self.additions/=2;
+        }
+    }
+
+    fn frequency(&self,key:&str)->u8{
+        self.slots(key).into_iter().enumerate().map(|(row,slot)|self.counters[slot][row]).min().unwrap_or(0)
+    }
+}
+
+struct CacheEntry{
+    value:String,
+    //position in the recency order
+    tick:u64
+}
+
+/// Values read from the log, bounded by their size in bytes and evicted least recently used first.
+pub(super) struct ValueCache{
+    capacity:usize,
+    entries:HashMap<String,CacheEntry>,
+    recency:BTreeMap<u64,String>,
+    next_tick:u64,
+    sketch:Option<FrequencySketch>,
+    stats:CacheStats
+}
+
+impl ValueCache {
+    pub fn new(capacity:usize,admission:Admission)->ValueCache{
+        let sketch=match admission {
+            Admission::Always => None,
+            Admission::TinyLfu => Some(FrequencySketch::new((capacity/ENTRY_OVERHEAD).clamp(64, MEGABYTE))),
+        };
+        ValueCache{
+            capacity,
+            entries:HashMap::new(),
+            recency:BTreeMap::new(),
+            next_tick:0,
+            sketch,
+            stats:CacheStats{capacity_bytes:capacity,..Default::default()}
+        }
+    }
+
+    pub fn get(&mut self,key:&str)->Option<String>{
+        if let Some(sketch)=self.sketch.as_mut(){
+            sketch.increment(key);
+        }
+        let Some(entry)=self.entries.get_mut(key) else {
+            self.stats.misses+=1;
+            return None;
+        };
+        self.stats.hits+=1;
+        self.recency.remove(&entry.tick);
+        entry.tick=self.next_tick;
+        self.recency.insert(self.next_tick, key.to_string());
+        self.next_tick+=1;
+        Some(entry.value.clone())
+    }
+
+    /// Caches the value just read for `key`, if it fits and the admission policy lets it in.
+    pub fn insert(&mut self,key:String,value:String){
+        let size=entry_size(&key, &value);
+        if size>self.capacity{
+            return;
+        }
+        self.invalidate(&key);
+        if let (Some(sketch),Some((_,victim)))=(self.sketch.as_ref(),self.recency.first_key_value()){
+            if self.stats.used_bytes+size>self.capacity&&sketch.frequency(&key)<=sketch.frequency(victim){
+                self.stats.rejected+=1;
+                return;
+            }
+        }
+        while self.stats.used_bytes+size>self.capacity{
+            let Some((_,victim))=self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry)=self.entries.remove(&victim){
+                self.stats.used_bytes-=entry_size(&victim, &entry.value);
+            }
+        }
+        self.recency.insert(self.next_tick, key.clone());
+        self.entries.insert(key, CacheEntry{value,tick:self.next_tick});
+        self.next_tick+=1;
+        self.stats.used_bytes+=size;
+    }
+
+    pub fn invalidate(&mut self,key:&str){
+        if let Some(entry)=self.entries.remove(key){
+            self.recency.remove(&entry.tick);
+            self.stats.used_bytes-=entry_size(key, &entry.value);
+        }
+    }
+
+    pub fn clear(&mut self){
+        self.entries.clear();
+        self.recency.clear();
+        self.stats.used_bytes=0;
+    }
+
+    pub fn stats(&self)->CacheStats{
+        CacheStats{entries:self.entries.len(),..self.stats.clone()}
+    }
+}
+
+fn entry_size(key:&str,value:&str)->usize{
+    key.len()+value.len()+ENTRY_OVERHEAD
+}
@@ -1,20 +1,257 @@
-use clap::{Parser, Subcommand};
+use std::{fs::File, io::{stdin, stdout, BufRead, BufReader, Write}, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
+use super::{config::Config, export::{EntryReader, Format, OnConflict, DEFAULT_BATCH_SIZE}, options::{Durability, KvStoreOptions}, recovery::Cutoff, Context, KVError, Result};
+
 
 
 #[derive(Parser)]
 #[command(about,version)]
 pub struct KVArgs{
+    #[command(flatten)]
+    pub store:StoreArgs,
     #[command(subcommand)]
-    pub operations:KVCommand
+    pub operations:KvsCommand
+}
+
+/// Store options given on the command line. A flag wins over its environment variable,
+/// which wins over `config.json`, which wins over the defaults.
+/// The switches take `=false` to turn off what the config or environment turned on.
+#[derive(Args,Default)]
+pub struct StoreArgs{
+    /// Bytes a segment grows to before writes go to a new one
+    #[arg(long,global=true,env="KVS_FILE_SIZE")]
+    pub file_size:Option<usize>,
+    /// Bytes the log grows by before it is compacted
+    #[arg(long,global=true,env="KVS_MERGE_SIZE")]
+    pub merge_size:Option<usize>,
+    /// When a write returns, once the OS has it or once it is synced to disk
+    #[arg(long,global=true,value_enum,env="KVS_DURABILITY")]
+    pub durability:Option<Durability>,
+    /// Bytes of values cached in memory, 0 turns caching off
+    #[arg(long,global=true,env="KVS_CACHE_SIZE")]
+    pub cache_size:Option<usize>,
+    /// Open the store without writing to it
+    #[arg(long,global=true,env="KVS_READ_ONLY",num_args=0..=1,require_equals=true,default_missing_value="true")]
+    pub read_only:Option<bool>,
+    /// Fail instead of creating a missing store
+    #[arg(long,global=true,env="KVS_NO_CREATE",num_args=0..=1,require_equals=true,default_missing_value="true")]
+    pub no_create:Option<bool>,
+    /// Fail if the store already exists
+    #[arg(long,global=true,env="KVS_ERROR_IF_EXISTS",num_args=0..=1,require_equals=true,default_missing_value="true")]
+    pub error_if_exists:Option<bool>
+}
+
+impl StoreArgs {
+    /// The options of `config` with the flags and environment variables applied.
+    pub fn options(&self,config:&Config)->KvStoreOptions{
+        let mut options=config.apply(KvStoreOptions::new());
+        if let Some(file_size)=self.file_size{
+            options=options.file_size(file_size);
+        }
+        if let Some(merge_size)=self.merge_size{
+            options=options.merge_size(merge_size);
+        }
+        if let Some(durability)=self.durability{
+            options=options.durability(durability);
+        }
+        if let Some(cache_size)=self.cache_size{
+            options=options.cache_size(cache_size);
+        }
+        if let Some(read_only)=self.read_only{
+            options=options.read_only(read_only);
+        }
+        if let Some(no_create)=self.no_create{
+            options=options.create_if_missing(!no_create);
+        }
+        if let Some(error_if_exists)=self.error_if_exists{
+            options=options.error_if_exists(error_if_exists);
+        }
+        options
+    }
+}
+
+/// Commands of the local `kvs` tool, the kv operations plus ones that only work on the files.
+#[derive(Subcommand)]
+pub enum KvsCommand{
+    #[command(flatten)]
+    Kv(KVCommand),
+    /// Replay backups and the store's own log into a new store, stopping at a point in time
+    Recover{
+        /// Last record to replay, seq:<n> for a sequence number or milliseconds since the unix epoch
+        #[arg(long)]
+        until:Cutoff,
+        /// Directory of the new store
+        #[arg(long)]
+        to:PathBuf,
+        /// Copy of the store taken earlier, replayed before the store itself
+        #[arg(long)]
+        backup:Vec<PathBuf>
+    },
+    /// Move unreadable records of a closed store to its lost+found file so the store opens again
+    Repair{
+        /// Directory of the store, the configured db_dir by default
+        dir:Option<PathBuf>
+    },
+    /// Check a closed store without changing it, print a JSON report and fail if it found problems
+    Verify{
+        /// Directory of the store, the configured db_dir by default
+        dir:Option<PathBuf>
+    },
+    /// Write the entries of the store to a file or stdout
+    Export(ExportArgs),
+    /// Set the entries of an export on the store
+    Import(ImportArgs),
+    /// Look at the segments of a store record by record
+    Inspect{
+        #[command(subcommand)]
+        command:InspectCommand,
+        /// Namespace to inspect, the default one otherwise
+        #[arg(long,global=true)]
+        ns:Option<String>,
+        /// Print JSON instead of text
+        #[arg(long,global=true)]
+        json:bool
+    }
+}
+
+/// What `export` writes and where, shared by `kvs` and `kvs-client`.
+#[derive(Args)]
+pub struct ExportArgs{
+    /// Only export the keys starting with this
+    #[arg(long,default_value="")]
+    pub prefix:String,
+    #[arg(long,value_enum,default_value_t)]
+    pub format:Format,
+    /// File to write, stdout by default
+    #[arg(long,short)]
+    pub output:Option<PathBuf>,
+    #[arg(long)]
+    pub ns:Option<String>
+}
+
+impl ExportArgs {
+    pub fn writer(&self)->Result<Box<dyn Write>>{
+        match &self.output {
+            Some(path) => Ok(Box::new(File::create(path).context(KVError::IOError, "ExportArgs::writer").map_err(|e|e.with_path(path))?)),
+            None => Ok(Box::new(stdout().lock())),
+        }
+    }
+}
+
+/// What `import` reads and how it writes, shared by `kvs` and `kvs-client`.
+#[derive(Args)]
+pub struct ImportArgs{
+    /// File to read, stdin by default
+    pub input:Option<PathBuf>,
+    #[arg(long,value_enum,default_value_t)]
+    pub format:Format,
+    /// Leave the keys the store already has alone instead of overwriting them
+    #[arg(long)]
+    pub skip_existing:bool,
+    /// Entries written together
+    #[arg(long,default_value_t=DEFAULT_BATCH_SIZE)]
+    pub batch_size:usize,
+    #[arg(long)]
+    pub ns:Option<String>
+}
+
+impl ImportArgs {
+    pub fn entries(&self)->Result<EntryReader<Box<dyn BufRead>>>{
+        let input:Box<dyn BufRead>=match &self.input {
+            Some(path) => Box::new(BufReader::new(File::open(path).context(KVError::IOError, "ImportArgs::entries").map_err(|e|e.with_path(path))?)),
+            None => Box::new(stdin().lock()),
+        };
+        EntryReader::new(self.format, input)
+    }
+
+    pub fn on_conflict(&self)->OnConflict{
+        if self.skip_existing {OnConflict::Skip} else {OnConflict::Overwrite}
+    }
+}
+
+#[derive(Subcommand)]
+pub enum InspectCommand{
+    /// List the segments with their sizes, record counts and live ratios
+    Segments,
+    /// Print every record of a segment
+    Dump{segment:usize},
+    /// Print every record of a key still in the log, oldest first
+    Key{key:String}
 }
 
 #[derive(Subcommand,Deserialize,Serialize)]
 pub enum KVCommand{
-    Get{key:String},
-    Set{key:String,value:String},
-    Rm{key:String}
+    Get{
+        key:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    Set{
+        key:String,
+        value:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    Rm{
+        key:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    /// List the retained versions of a key
+    History{
+        key:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    /// List the keys whose indexed field equals a value
+    Query{
+        index:String,
+        value:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    /// Create, drop or list namespaces
+    #[command(subcommand)]
+    Ns(NamespaceCommand),
+    /// Create or drop secondary indexes on JSON values
+    #[command(subcommand)]
+    Index(IndexCommand)
 }
 
+#[derive(Subcommand,Deserialize,Serialize)]
+pub enum NamespaceCommand{
+    Create{
+        name:String,
+        /// Compact the namespace once its segments exceed this many bytes
+        #[arg(long)]
+        merge_threshold:Option<usize>
+    },
+    Drop{name:String},
+    List
+}
 
+#[derive(Subcommand,Deserialize,Serialize)]
+pub enum IndexCommand{
+    Create{
+        name:String,
+        /// JSON pointer to the indexed field, e.g. /user_id
+        pointer:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    },
+    Drop{
+        name:String,
+        #[arg(long)]
+        #[serde(default,skip_serializing_if="Option::is_none")]
+        ns:Option<String>
+    }
+}
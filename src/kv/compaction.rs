@@ -0,0 +1,277 @@
+use std::{collections::{HashSet, VecDeque}, io::{BufReader, BufWriter, Write}, ops::Range, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::value::RawValue;
+
+use super::{filesystem::{FileReader, FileSystem, LogFile}, record::{Record, StoredRecord}, replication::LogPosition, storage::{compaction_path, SegmentFiles}, Context, ErrorContext, KVError, Operation, Result};
+
+/// What a compaction copies and where to, one chunk of the sealed segments.
+pub(super) struct CompactionPlan{
+    pub file_system:Arc<dyn FileSystem>,
+    pub inputs:SegmentFiles,
+    //positions in the inputs that were live when the chunk started
+    pub live:HashSet<LogPosition>,
+    pub directory:PathBuf,
+    //serials the compacted segments may take, in order
+    pub outputs:Range<usize>,
+    pub file_size_limit:usize,
+    pub bytes_per_sec:Option<u64>,
+    //removes stamped at or after this are handed back, the others dropped
+    pub keep_removes_since:Option<u64>
+}
+
+/// A live record the worker copied into a compacted segment.
+pub(super) struct Moved{
+    pub key:String,
+    pub from:LogPosition,
+    pub to:LogPosition
+}
+
+/// What a finished chunk wrote.
+pub(super) struct Compacted{
+    pub outputs:Vec<usize>,
+    pub moved:Vec<Moved>,
+    //removes to keep, the store appends those of keys still removed
+    pub removes:Vec<Record>,
+    //the highest seq of the removes it dropped, 0 if it dropped none
+    pub dropped_removes:u64
+}
+
+/// A compaction of the sealed segments a chunk at a time, so the live positions and moved
+/// records it holds never cover more than a chunk. Chunks go oldest first, each one's
+/// inputs are deleted once its outputs are in place.
+pub(super) struct Compaction{
+    pub job:CompactionJob,
+    //sealed segments no chunk took yet, oldest first
+    pub pending:VecDeque<(usize,PathBuf)>,
+    //serials left for the outputs of the chunks still to come
+    pub outputs:Range<usize>
+}
+
+/// A compaction running on its own thread. It only sees the sealed segment files and which
+/// positions in them were live when it started, the store swaps the pointers once it is done.
+/// Dropping an unfinished job cancels it.
+pub(super) struct CompactionJob{
+    handle:Option<JoinHandle<Result<Compacted>>>,
+    cancel:Arc<AtomicBool>,
+    sealed:Vec<usize>
+}
+
+impl CompactionJob {
+    /// Streams the live records of the plan's inputs, in log order, into new segments,
+    /// reading and writing at most `bytes_per_sec` if set.
+    pub fn spawn(plan:CompactionPlan)->CompactionJob{
+        let cancel=Arc::new(AtomicBool::new(false));
+        let sealed=plan.inputs.iter().map(|(serial,_)|*serial).collect();
+        let handle={
+            let cancel=cancel.clone();
+            thread::spawn(move||run(plan, &cancel))
+        };
+        CompactionJob{
+            handle:Some(handle),
+            cancel,
+            sealed
+        }
+    }
+
+    pub fn is_finished(&self)->bool{
+        self.handle.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// The segments being compacted.
+    pub fn sealed(&self)->&[usize]{
+        &self.sealed
+    }
+
+    /// Blocks until the worker is done.
+    pub fn wait(&mut self)->Result<Compacted>{
+        let handle=self.handle.take().ok_or(KVError::IOError("CompactionJob::wait1".into()))?;
+        handle.join().map_err(|_|KVError::IOError("compaction worker panicked".into()))?
+    }
+}
+
+impl Drop for CompactionJob {
+    fn drop(&mut self){
+        if let Some(handle)=self.handle.take(){
+            self.cancel.store(true, Ordering::Relaxed);
+            let _=handle.join();
+        }
+    }
+}
+
+//just enough of a record to find its key, the value is skipped over
+#[derive(Deserialize)]
+enum KeyOp{
+    Get(IgnoredAny),
+    Remove(IgnoredAny),
+    Set(String,IgnoredAny)
+}
+
+#[derive(Deserialize)]
+struct StampedKey{
+    seq:u64,
+    timestamp:u64,
+    op:KeyOp
+}
+
+fn run(plan:CompactionPlan,cancel:&AtomicBool)->Result<Compacted>{
+    let mut output=Output::new(&plan);
+    let mut compacted=Compacted{outputs:Vec::new(),moved:Vec::new(),removes:Vec::new(),dropped_removes:0};
+    let compacted=copy_live(&plan, &mut output, &mut compacted, cancel)
+    .and_then(|()|output.finish())
+    .map(|outputs|Compacted{outputs,..compacted});
+    if compacted.is_err(){
+        output.discard();
+    }
+    compacted
+}
+
+fn copy_live(plan:&CompactionPlan,output:&mut Output,compacted:&mut Compacted,cancel:&AtomicBool)->Result<()>{
+    let mut throttle=Throttle::new(plan.bytes_per_sec);
+
+    for (segment,path) in plan.inputs.iter(){
+        let file=plan.file_system.open_read(path).context(KVError::IOError, "compaction::copy_live1").map_err(|e|e.with_path(path))?;
+        throttle.consume(file.size().context(KVError::IOError, "compaction::copy_live2")?, cancel);
+        let mut stream=serde_json::Deserializer::from_reader(BufReader::new(FileReader::new(file))).into_iter::<Box<RawValue>>();
+        loop {
+            let from=LogPosition{segment:*segment,offset:stream.byte_offset() as u64};
+            let Some(raw)=stream.next() else {
+                break;
+            };
+            if cancel.load(Ordering::Relaxed){
+                return Err(KVError::IOError("compaction cancelled".into()));
+            }
+            let raw=raw.context(KVError::ParseError, "compaction::copy_live3").map_err(|e|e.at(from))?;
+            if !plan.live.contains(&from){
+                //a remove stays while a backup from before it may still be replayed, it is handed back
+                //rather than copied so it never lands after a set of its key in a later chunk
+                if let Ok(StampedKey{seq,timestamp,op:KeyOp::Remove(_)})=serde_json::from_str(raw.get()){
+                    if plan.keep_removes_since.is_some_and(|since|timestamp>=since){
+                        let record=serde_json::from_str::<Record>(raw.get()).context(KVError::ParseError, "compaction::copy_live8").map_err(|e|e.at(from))?;
+                        compacted.removes.push(record);
+                    } else {
+                        compacted.dropped_removes=compacted.dropped_removes.max(seq);
+                    }
+                }
+                continue;
+            }
+            let (key,to)=match serde_json::from_str::<StampedKey>(raw.get()) {
+                //stamped records are copied as they are
+                Ok(StampedKey{op:KeyOp::Set(key,_),..}) => (key,output.write(raw.get().as_bytes())?),
+                Ok(_) => return Err(KVError::ReadError(ErrorContext::new("compaction::copy_live4").at(from))),
+                //records from before stamping are stamped on the way
+                Err(_) => {
+                    let record=serde_json::from_str::<StoredRecord>(raw.get())
+                    .context(KVError::ParseError, "compaction::copy_live5")
+                    .map_err(|e|e.at(from))?
+                    .into_record();
+                    let Operation::Set(key,_)=&record.op else {
+                        return Err(KVError::ReadError(ErrorContext::new("compaction::copy_live6").at(from)));
+                    };
+                    let bytes=serde_json::to_vec(&record).context(KVError::ParseError, "compaction::copy_live7")?;
+                    (key.clone(),output.write(&bytes)?)
+                }
+            };
+            throttle.consume(raw.get().len() as u64, cancel);
+            compacted.moved.push(Moved{key,from,to});
+        }
+    }
+    Ok(())
+}
+
+type OutputWriter=BufWriter<Box<dyn LogFile>>;
+
+//the compacted segments, a new one started whenever the next record would cross the size limit
+struct Output{
+    file_system:Arc<dyn FileSystem>,
+    directory:PathBuf,
+    serials:Range<usize>,
+    file_size_limit:usize,
+    current:Option<(usize,OutputWriter,usize)>,
+    written:Vec<usize>
+}
+
+impl Output {
+    fn new(plan:&CompactionPlan)->Output{
+        Output{
+            file_system:plan.file_system.clone(),
+            directory:plan.directory.clone(),
+            serials:plan.outputs.clone(),
+            file_size_limit:plan.file_size_limit,
+            current:None,
+            written:Vec::new()
+        }
+    }
+
+    fn write(&mut self,bytes:&[u8])->Result<LogPosition>{
+        let full=self.current.as_ref().is_some_and(|(_,_,size)|*size>0&&size+bytes.len()>self.file_size_limit);
+        if self.current.is_none()||full{
+            self.seal()?;
+            let serial=self.serials.next().ok_or(KVError::WriteError("Output::write1".into()))?;
+            let mut file=self.file_system.open_append(&compaction_path(&self.directory, serial)).context(KVError::IOError, "Output::write2")?;
+            file.truncate(0).context(KVError::IOError, "Output::write4")?;
+            self.written.push(serial);
+            self.current=Some((serial,BufWriter::new(file),0));
+        }
+        let (serial,writer,size)=self.current.as_mut().expect("started above");
+        writer.write_all(bytes).context(KVError::WriteError, "Output::write3")?;
+        let position=LogPosition{segment:*serial,offset:*size as u64};
+        *size+=bytes.len();
+        Ok(position)
+    }
+
+    fn seal(&mut self)->Result<()>{
+        if let Some((_,mut writer,_))=self.current.take(){
+            writer.flush().context(KVError::WriteError, "Output::seal1")?;
+            writer.get_mut().sync().context(KVError::WriteError, "Output::seal2")?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self)->Result<Vec<usize>>{
+        self.seal()?;
+        Ok(self.written.clone())
+    }
+
+    fn discard(&mut self){
+        self.current=None;
+        for serial in self.written.iter(){
+            let _=self.file_system.remove(&compaction_path(&self.directory, *serial));
+        }
+    }
+}
+
+const THROTTLE_STEP:Duration=Duration::from_millis(50);
+
+//sleeps whenever more bytes went through than the budget allows for the time since the start
+struct Throttle{
+    bytes_per_sec:Option<u64>,
+    start:Instant,
+    bytes:u64
+}
+
+impl Throttle {
+    fn new(bytes_per_sec:Option<u64>)->Throttle{
+        Throttle{
+            bytes_per_sec,
+            start:Instant::now(),
+            bytes:0
+        }
+    }
+
+    //sleeps in short steps so a cancelled job stops quickly
+    fn consume(&mut self,bytes:u64,cancel:&AtomicBool){
+        let Some(bytes_per_sec)=self.bytes_per_sec.filter(|rate|*rate>0) else {
+            return;
+        };
+        self.bytes+=bytes;
+        let due=Duration::from_secs_f64(self.bytes as f64/bytes_per_sec as f64);
+        while !cancel.load(Ordering::Relaxed){
+            let elapsed=self.start.elapsed();
+            if due<=elapsed{
+                break;
+            }
+            thread::sleep((due-elapsed).min(THROTTLE_STEP));
+        }
+    }
+}
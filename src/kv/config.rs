@@ -1,31 +1,77 @@
-
 use std::{fs::OpenOptions, io::BufReader, path::PathBuf};
 
 use serde::Deserialize;
 
-use crate::common::{KILOBYTE, MEGABYTE};
+use super::{options::{Durability, KvStoreOptions}, Context, KVError, Result};
+
+/// Settings read from `config.json`, every one but `db_dir` falls back to the `KvStoreOptions` default.
+/// Flags and their `KVS_*` environment variables override them.
 #[derive(Deserialize)]
+#[serde(default)]
 pub struct Config{
     pub db_dir:String,
-    pub file_size:usize,
-    pub merge_size:usize,
+    pub file_size:Option<usize>,
+    pub merge_size:Option<usize>,
+    pub durability:Option<Durability>,
+    pub cache_size:Option<usize>,
+    pub read_only:Option<bool>,
+    pub create_if_missing:Option<bool>,
+    pub error_if_exists:Option<bool>
 }
 
 
 impl Default for Config{
     fn default() -> Self {
-        Self { db_dir: ".".to_string(), file_size: 4*MEGABYTE, merge_size: 10*KILOBYTE }
+        Self {
+            db_dir: ".".to_string(),
+            file_size: None,
+            merge_size: None,
+            durability: None,
+            cache_size: None,
+            read_only: None,
+            create_if_missing: None,
+            error_if_exists: None
+        }
     }
 }
 
 impl Config {
-    pub fn open(config_path:PathBuf)->Config{
-        let file=OpenOptions::new().read(true).open(config_path);
+    /// The config at `config_path`, or the default one if there is no such file.
+    pub fn open(config_path:PathBuf)->Result<Config>{
+        let file=OpenOptions::new().read(true).open(&config_path);
         match file {
             Ok(file) => {
-                serde_json::from_reader(BufReader::new(file)).unwrap_or_default()
+                serde_json::from_reader(BufReader::new(file))
+                .context(KVError::ParseError, "Config::open")
+                .map_err(|e|e.with_path(&config_path))
             },
-            Err(_) => Config::default(),
+            Err(_) => Ok(Config::default()),
         }
     }
-}
\ No newline at end of file
+
+    /// `options` with the settings of the config applied.
+    pub fn apply(&self,mut options:KvStoreOptions)->KvStoreOptions{
+        if let Some(file_size)=self.file_size{
+            options=options.file_size(file_size);
+        }
+        if let Some(merge_size)=self.merge_size{
+            options=options.merge_size(merge_size);
+        }
+        if let Some(durability)=self.durability{
+            options=options.durability(durability);
+        }
+        if let Some(cache_size)=self.cache_size{
+            options=options.cache_size(cache_size);
+        }
+        if let Some(read_only)=self.read_only{
+            options=options.read_only(read_only);
+        }
+        if let Some(create_if_missing)=self.create_if_missing{
+            options=options.create_if_missing(create_if_missing);
+        }
+        if let Some(error_if_exists)=self.error_if_exists{
+            options=options.error_if_exists(error_if_exists);
+        }
+        options
+    }
+}
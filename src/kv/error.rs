@@ -0,0 +1,179 @@
+use std::{error::Error, fmt, io, path::{Path, PathBuf}, result};
+
+use crate::common::{EXIT_CONFIG, EXIT_DATA, EXIT_IO, EXIT_NOT_FOUND, EXIT_USAGE};
+
+use super::replication::LogPosition;
+
+pub type Result<T>=result::Result<T,KVError>;
+
+/// The error underneath another one.
+pub type Source=Box<dyn Error+Send+Sync>;
+
+/// Where an operation failed: the function, numbered where it can fail at several points,
+/// and as far as it is known the file, the log position and the error underneath.
+#[derive(Debug,Default)]
+pub struct ErrorContext{
+    pub location:&'static str,
+    pub path:Option<PathBuf>,
+    pub position:Option<LogPosition>,
+    pub source:Option<Source>
+}
+
+impl ErrorContext {
+    pub fn new(location:&'static str)->ErrorContext{
+        ErrorContext{location,..Default::default()}
+    }
+
+    pub fn with_source(mut self,source:impl Into<Source>)->Self{
+        self.source=Some(source.into());
+        self
+    }
+
+    pub fn with_path(mut self,path:impl AsRef<Path>)->Self{
+        self.path=Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn at(mut self,position:LogPosition)->Self{
+        self.position=Some(position);
+        self
+    }
+
+    fn source(&self)->Option<&(dyn Error+'static)>{
+        self.source.as_deref().map(|source|source as &(dyn Error+'static))
+    }
+}
+
+impl From<&'static str> for ErrorContext {
+    fn from(location:&'static str)->Self{
+        ErrorContext::new(location)
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        if let Some(path)=&self.path{
+            write!(f," on {}",path.display())?;
+        }
+        if let Some(position)=self.position{
+            write!(f," at segment {} offset {}",position.segment,position.offset)?;
+        }
+        if let Some(source)=&self.source{
+            write!(f,": {source}")?;
+        }
+        if !self.location.is_empty(){
+            write!(f," ({})",self.location)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns the error of a result into an error variant holding an `ErrorContext` with it as the source.
+pub trait Context<T>{
+    fn context<E>(self,variant:fn(ErrorContext)->E,location:&'static str)->result::Result<T,E>;
+}
+
+impl<T,S:Into<Source>> Context<T> for result::Result<T,S> {
+    fn context<E>(self,variant:fn(ErrorContext)->E,location:&'static str)->result::Result<T,E>{
+        self.map_err(|source|variant(ErrorContext::new(location).with_source(source)))
+    }
+}
+
+#[derive(Debug)]
+pub enum KVError{
+    /// A file or directory could not be opened, read or synced.
+    IOError(ErrorContext),
+    /// Options or arguments that cannot work, with what is wrong about them.
+    ConfigError(&'static str),
+    /// The log or an index does not hold what it should.
+    ReadError(ErrorContext),
+    WriteError(ErrorContext),
+    KeyNotFound(String),
+    NamespaceNotFound(String),
+    IndexNotFound(String),
+    /// Data on disk or in a request that does not deserialize.
+    ParseError(ErrorContext),
+    /// An operation the engine or the store as opened does not do.
+    Unsupported(&'static str)
+}
+
+impl KVError {
+    /// Records the file the error is about.
+    pub fn with_path(mut self,path:impl AsRef<Path>)->Self{
+        if let Some(context)=self.context_mut(){
+            context.path=Some(path.as_ref().to_path_buf());
+        }
+        self
+    }
+
+    /// Records the log position the error is about.
+    pub fn at(mut self,position:LogPosition)->Self{
+        if let Some(context)=self.context_mut(){
+            context.position=Some(position);
+        }
+        self
+    }
+
+    pub fn context(&self)->Option<&ErrorContext>{
+        match self {
+            KVError::IOError(context)|KVError::ReadError(context)|KVError::WriteError(context)|KVError::ParseError(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    fn context_mut(&mut self)->Option<&mut ErrorContext>{
+        match self {
+            KVError::IOError(context)|KVError::ReadError(context)|KVError::WriteError(context)|KVError::ParseError(context) => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The exit code of a tool failing with this error.
+    pub fn exit_code(&self)->u8{
+        match self {
+            KVError::IOError(_)|KVError::WriteError(_) => EXIT_IO,
+            KVError::ReadError(_)|KVError::ParseError(_) => EXIT_DATA,
+            KVError::ConfigError(_) => EXIT_CONFIG,
+            KVError::KeyNotFound(_)|KVError::NamespaceNotFound(_)|KVError::IndexNotFound(_) => EXIT_NOT_FOUND,
+            KVError::Unsupported(_) => EXIT_USAGE,
+        }
+    }
+}
+
+impl fmt::Display for KVError {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            KVError::IOError(context) => write!(f,"I/O error{context}"),
+            KVError::ConfigError(message) => write!(f,"invalid configuration: {message}"),
+            KVError::ReadError(context) => write!(f,"unexpected data{context}"),
+            KVError::WriteError(context) => write!(f,"write failed{context}"),
+            KVError::KeyNotFound(key) => write!(f,"Key not found: {key}"),
+            KVError::NamespaceNotFound(name) => write!(f,"namespace not found: {name}"),
+            KVError::IndexNotFound(name) => write!(f,"index not found: {name}"),
+            KVError::ParseError(context) => write!(f,"malformed data{context}"),
+            KVError::Unsupported(message) => write!(f,"unsupported: {message}"),
+        }
+    }
+}
+
+impl Error for KVError {
+    fn source(&self)->Option<&(dyn Error+'static)>{
+        self.context().and_then(ErrorContext::source)
+    }
+}
+
+impl From<io::Error> for KVError {
+    fn from(error:io::Error)->Self{
+        KVError::IOError(ErrorContext::default().with_source(error))
+    }
+}
+
+impl From<serde_json::Error> for KVError {
+    fn from(error:serde_json::Error)->Self{
+        if error.is_io() {
+            KVError::IOError(ErrorContext::default().with_source(error))
+        } else {
+            KVError::ParseError(ErrorContext::default().with_source(error))
+        }
+    }
+}
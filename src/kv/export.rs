@@ -0,0 +1,279 @@
+use std::{fmt::Display, io::{BufRead, BufWriter, Read, Write}, mem};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::KvsEngine;
+
+use super::{Context, KVError, Result};
+
+/// Entries go to the engine in writes of this many by default.
+pub const DEFAULT_BATCH_SIZE:usize=1000;
+//first bytes of a binary export, the digit is the version of the format
+const BINARY_MAGIC:&[u8;4]=b"KVX1";
+const CSV_HEADER:&str="key,value";
+
+/// How exported entries are written.
+#[derive(ValueEnum,Clone,Copy,Debug,Default,PartialEq,Deserialize,Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum Format{
+    /// One {"key":..,"value":..} object per line
+    #[default]
+    Jsonl,
+    /// A key,value header then one row per entry, quoted where needed
+    Csv,
+    /// A magic header then every key and value as a little endian u32 length and its bytes
+    Binary
+}
+
+/// What an import does with a key the engine already has.
+#[derive(ValueEnum,Clone,Copy,Debug,Default,PartialEq,Deserialize,Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum OnConflict{
+    #[default]
+    Overwrite,
+    Skip
+}
+
+#[derive(Serialize,Deserialize,Debug,Default,Clone,PartialEq)]
+pub struct ImportReport{
+    pub read:usize,
+    pub written:usize,
+    //entries left out because their key existed
+    pub skipped:usize
+}
+
+impl ImportReport {
+    pub fn add(&mut self,other:&ImportReport){
+        self.read+=other.read;
+        self.written+=other.written;
+        self.skipped+=other.skipped;
+    }
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"read {} entries, wrote {}, skipped {} existing",self.read,self.written,self.skipped)
+    }
+}
+
+#[derive(Serialize,Deserialize)]
+struct JsonEntry<S>{
+    key:S,
+    value:S
+}
+
+/// Writes entries one at a time in a `Format`.
+pub struct EntryWriter<W:Write>{
+    format:Format,
+    out:BufWriter<W>,
+    written:usize
+}
+
+impl<W:Write> EntryWriter<W> {
+    pub fn new(format:Format,out:W)->Result<EntryWriter<W>>{
+        let mut out=BufWriter::new(out);
+        match format {
+            Format::Jsonl => {},
+            Format::Csv => writeln!(out,"{CSV_HEADER}").context(KVError::WriteError, "EntryWriter::new1")?,
+            Format::Binary => out.write_all(BINARY_MAGIC).context(KVError::WriteError, "EntryWriter::new2")?,
+        }
+        Ok(EntryWriter{format,out,written:0})
+    }
+
+    pub fn write(&mut self,key:&str,value:&str)->Result<()>{
+        match self.format {
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.out, &JsonEntry{key,value}).context(KVError::WriteError, "EntryWriter::write1")?;
+                writeln!(self.out).context(KVError::WriteError, "EntryWriter::write2")?;
+            },
+            Format::Csv => writeln!(self.out,"{},{}",csv_field(key),csv_field(value)).context(KVError::WriteError, "EntryWriter::write3")?,
+            Format::Binary => {
+                for field in [key,value]{
+                    let len=u32::try_from(field.len()).map_err(|_|KVError::Unsupported("a key or value longer than 4 GiB"))?;
+                    self.out.write_all(&len.to_le_bytes()).context(KVError::WriteError, "EntryWriter::write4")?;
+                    self.out.write_all(field.as_bytes()).context(KVError::WriteError, "EntryWriter::write5")?;
+                }
+            },
+        }
+        self.written+=1;
+        Ok(())
+    }
+
+    /// Flushes what is buffered, returns how many entries were written.
+    pub fn finish(mut self)->Result<usize>{
+        self.out.flush().context(KVError::WriteError, "EntryWriter::finish")?;
+        Ok(self.written)
+    }
+}
+
+fn csv_field(field:&str)->String{
+    if field.contains([',','"','\n','\r']){
+        format!("\"{}\"",field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Reads the entries of an export back one at a time.
+pub struct EntryReader<R:BufRead>{
+    format:Format,
+    input:R
+}
+
+impl<R:BufRead> EntryReader<R> {
+    /// Checks the header of `input`, the formats other than jsonl have one.
+    pub fn new(format:Format,mut input:R)->Result<EntryReader<R>>{
+        match format {
+            Format::Jsonl => {},
+            Format::Csv => {
+                let mut header=String::new();
+                input.read_line(&mut header).context(KVError::IOError, "EntryReader::new1")?;
+                if header.trim_end_matches(['\r','\n'])!=CSV_HEADER{
+                    return Err(KVError::ParseError("EntryReader::new2: the csv header is not key,value".into()));
+                }
+            },
+            Format::Binary => {
+                let mut magic=[0;4];
+                input.read_exact(&mut magic).context(KVError::ParseError, "EntryReader::new3")?;
+                if &magic!=BINARY_MAGIC{
+                    return Err(KVError::ParseError("EntryReader::new4: not a binary export".into()));
+                }
+            },
+        }
+        Ok(EntryReader{format,input})
+    }
+
+    fn next_jsonl(&mut self)->Result<Option<(String,String)>>{
+        let mut line=String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line).context(KVError::IOError, "EntryReader::next_jsonl1")?==0{
+                return Ok(None);
+            }
+            if !line.trim().is_empty(){
+                break;
+            }
+        }
+        let entry:JsonEntry<String>=serde_json::from_str(&line).context(KVError::ParseError, "EntryReader::next_jsonl2")?;
+        Ok(Some((entry.key,entry.value)))
+    }
+
+    //a quoted field may span lines, so lines are read until every quote is closed
+    fn next_csv(&mut self)->Result<Option<(String,String)>>{
+        let mut record=String::new();
+        loop {
+            let read=self.input.read_line(&mut record).context(KVError::IOError, "EntryReader::next_csv1")?;
+            if read==0&&record.trim().is_empty(){
+                return Ok(None);
+            }
+            match parse_csv_record(record.strip_suffix('\n').map(|record|record.strip_suffix('\r').unwrap_or(record)).unwrap_or(&record)) {
+                Some(fields) if fields.len()==2 => {
+                    let mut fields=fields.into_iter();
+                    return Ok(fields.next().zip(fields.next()));
+                },
+                Some(_) if record.trim().is_empty() => record.clear(),
+                Some(_) => return Err(KVError::ParseError("EntryReader::next_csv2: a row without exactly a key and a value".into())),
+                None if read==0 => return Err(KVError::ParseError("EntryReader::next_csv3: an unclosed quote".into())),
+                None => {},
+            }
+        }
+    }
+
+    fn next_binary(&mut self)->Result<Option<(String,String)>>{
+        if self.input.fill_buf().context(KVError::IOError, "EntryReader::next_binary")?.is_empty(){
+            return Ok(None);
+        }
+        Ok(Some((self.read_binary_field()?,self.read_binary_field()?)))
+    }
+
+    fn read_binary_field(&mut self)->Result<String>{
+        let mut len=[0;4];
+        self.input.read_exact(&mut len).context(KVError::ParseError, "EntryReader::read_binary_field1: a truncated entry")?;
+        let len=u32::from_le_bytes(len) as u64;
+        let mut bytes=Vec::new();
+        if (self.input.by_ref().take(len).read_to_end(&mut bytes).context(KVError::IOError, "EntryReader::read_binary_field2")? as u64)<len{
+            return Err(KVError::ParseError("EntryReader::read_binary_field3: a truncated entry".into()));
+        }
+        String::from_utf8(bytes).context(KVError::ParseError, "EntryReader::read_binary_field4")
+    }
+}
+
+impl<R:BufRead> Iterator for EntryReader<R> {
+    type Item=Result<(String,String)>;
+
+    fn next(&mut self)->Option<Self::Item>{
+        let next=match self.format {
+            Format::Jsonl => self.next_jsonl(),
+            Format::Csv => self.next_csv(),
+            Format::Binary => self.next_binary(),
+        };
+        next.transpose()
+    }
+}
+
+//the fields of a csv record, None while a quote is still open
+fn parse_csv_record(record:&str)->Option<Vec<String>>{
+    let mut fields=vec![String::new()];
+    let mut chars=record.chars().peekable();
+    let mut quoted=false;
+    while let Some(c)=chars.next(){
+        let field=fields.last_mut().expect("starts with a field");
+        match (c,quoted) {
+            ('"',true) if chars.peek()==Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            ('"',true) => quoted=false,
+            ('"',false) if field.is_empty() => quoted=true,
+            (',',false) => fields.push(String::new()),
+            (c,_) => field.push(c),
+        }
+    }
+    (!quoted).then_some(fields)
+}
+
+/// The entries of `engine` whose key starts with `prefix`, in key order.
+/// Writes the entries of `engine` whose key starts with `prefix` to `out` in key order,
+/// reading one value at a time. Returns how many were written.
+pub fn export(engine:&mut dyn KvsEngine,prefix:&str,format:Format,out:impl Write)->Result<usize>{
+    let mut writer=EntryWriter::new(format, out)?;
+    for_each_entry(engine, prefix, |key,value|writer.write(&key, &value))?;
+    writer.finish()
+}
+
+fn for_each_entry(engine:&mut dyn KvsEngine,prefix:&str,mut visit:impl FnMut(String,String)->Result<()>)->Result<()>{
+    let mut keys=engine.keys()?;
+    keys.retain(|key|key.starts_with(prefix));
+    keys.sort();
+    for key in keys{
+        //a key removed since the listing is left out
+        if let Some(value)=engine.get(key.clone())?{
+            visit(key,value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets `entries` on `engine` in batches of `batch_size`, leaving existing keys alone if `on_conflict` says so.
+pub fn import(engine:&mut dyn KvsEngine,entries:impl IntoIterator<Item = Result<(String,String)>>,on_conflict:OnConflict,batch_size:usize)->Result<ImportReport>{
+    let batch_size=batch_size.max(1);
+    let mut report=ImportReport::default();
+    let mut batch=Vec::with_capacity(batch_size);
+    for entry in entries{
+        let (key,value)=entry?;
+        report.read+=1;
+        if on_conflict==OnConflict::Skip&&engine.get(key.clone())?.is_some(){
+            report.skipped+=1;
+            continue;
+        }
+        batch.push((key,value));
+        if batch.len()==batch_size{
+            report.written+=batch.len();
+            engine.set_batch(mem::take(&mut batch))?;
+        }
+    }
+    report.written+=batch.len();
+    engine.set_batch(batch)?;
+    Ok(report)
+}
@@ -0,0 +1,204 @@
+use std::{ffi::OsString, fmt, fs::{self, DirBuilder, File, OpenOptions}, io::{self, ErrorKind, Read, Seek, SeekFrom, Write}, path::Path, sync::Arc};
+
+pub mod simulated;
+
+/// The file operations of a store's logs, so they can run on something other than the disk.
+pub trait FileSystem:Send+Sync{
+    fn create_dir_all(&self,path:&Path)->io::Result<()>;
+    fn is_dir(&self,path:&Path)->bool;
+    /// Names of the entries of `dir`.
+    fn list(&self,dir:&Path)->io::Result<Vec<OsString>>;
+    /// Opens `path` for appending and reading, creating it if it is missing.
+    fn open_append(&self,path:&Path)->io::Result<Box<dyn LogFile>>;
+    fn open_read(&self,path:&Path)->io::Result<Box<dyn LogFile>>;
+    fn rename(&self,from:&Path,to:&Path)->io::Result<()>;
+    fn remove(&self,path:&Path)->io::Result<()>;
+    fn remove_dir_all(&self,path:&Path)->io::Result<()>;
+    /// Returns once the files created, renamed and removed in `dir` stay that way across a power loss.
+    fn sync_dir(&self,dir:&Path)->io::Result<()>;
+}
+
+/// An open file of a `FileSystem`.
+pub trait LogFile:Send{
+    /// Writes `bytes` at the end, when it fails part of them may have been written.
+    fn append(&mut self,bytes:&[u8])->io::Result<()>;
+    fn read_at(&self,offset:u64,buf:&mut [u8])->io::Result<usize>;
+    fn size(&self)->io::Result<u64>;
+    /// Cuts the file down to `size` bytes.
+    fn truncate(&mut self,size:u64)->io::Result<()>;
+    /// Returns once what was appended is on disk.
+    fn sync(&mut self)->io::Result<()>;
+    /// The file underneath, for mapping it, `None` where there is no real one.
+    fn as_file(&self)->Option<&File>{
+        None
+    }
+}
+
+//buffered writers append whole buffers
+impl Write for dyn LogFile {
+    fn write(&mut self,buf:&[u8])->io::Result<usize>{
+        self.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self)->io::Result<()>{
+        Ok(())
+    }
+}
+
+/// Reads a `LogFile` from a position moved by seeking.
+pub struct FileReader{
+    file:Box<dyn LogFile>,
+    position:u64
+}
+
+impl FileReader {
+    pub fn new(file:Box<dyn LogFile>)->FileReader{
+        FileReader{file,position:0}
+    }
+
+    pub fn file(&self)->&dyn LogFile{
+        self.file.as_ref()
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self,buf:&mut [u8])->io::Result<usize>{
+        let read=self.file.read_at(self.position, buf)?;
+        self.position+=read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self,pos:SeekFrom)->io::Result<u64>{
+        let position=match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.file.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position=position.ok_or_else(||io::Error::new(ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.position)
+    }
+}
+
+/// The `FileSystem` the logs of a store are kept on, two are equal only if they are the same one.
+#[derive(Clone,Default)]
+pub enum FileSystemRef{
+    #[default]
+    Os,
+    Other(Arc<dyn FileSystem>)
+}
+
+impl FileSystemRef {
+    pub fn get(&self)->Arc<dyn FileSystem>{
+        match self {
+            FileSystemRef::Os => Arc::new(OsFileSystem),
+            FileSystemRef::Other(fs) => fs.clone(),
+        }
+    }
+}
+
+impl PartialEq for FileSystemRef {
+    fn eq(&self,other:&Self)->bool{
+        match (self,other) {
+            (FileSystemRef::Os,FileSystemRef::Os) => true,
+            (FileSystemRef::Other(fs),FileSystemRef::Other(other)) => Arc::ptr_eq(fs, other),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for FileSystemRef {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            FileSystemRef::Os => f.write_str("Os"),
+            FileSystemRef::Other(_) => f.write_str("Other"),
+        }
+    }
+}
+
+/// The disk.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn create_dir_all(&self,path:&Path)->io::Result<()>{
+        DirBuilder::new().recursive(true).create(path)
+    }
+
+    fn is_dir(&self,path:&Path)->bool{
+        path.is_dir()
+    }
+
+    fn list(&self,dir:&Path)->io::Result<Vec<OsString>>{
+        fs::read_dir(dir)?.map(|entry|entry.map(|entry|entry.file_name())).collect()
+    }
+
+    fn open_append(&self,path:&Path)->io::Result<Box<dyn LogFile>>{
+        let file=OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_read(&self,path:&Path)->io::Result<Box<dyn LogFile>>{
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn rename(&self,from:&Path,to:&Path)->io::Result<()>{
+        fs::rename(from, to)
+    }
+
+    fn remove(&self,path:&Path)->io::Result<()>{
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self,path:&Path)->io::Result<()>{
+        match fs::remove_dir_all(path) {
+            Err(e) if e.kind()!=ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self,dir:&Path)->io::Result<()>{
+        File::open(dir)?.sync_all()
+    }
+
+    //directories cannot be opened as files there
+    #[cfg(windows)]
+    fn sync_dir(&self,_dir:&Path)->io::Result<()>{
+        Ok(())
+    }
+}
+
+impl LogFile for File {
+    fn append(&mut self,bytes:&[u8])->io::Result<()>{
+        self.write_all(bytes)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self,offset:u64,buf:&mut [u8])->io::Result<usize>{
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self,offset:u64,buf:&mut [u8])->io::Result<usize>{
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn size(&self)->io::Result<u64>{
+        Ok(self.metadata()?.len())
+    }
+
+    fn truncate(&mut self,size:u64)->io::Result<()>{
+        self.set_len(size)
+    }
+
+    fn sync(&mut self)->io::Result<()>{
+        self.sync_data()
+    }
+
+    fn as_file(&self)->Option<&File>{
+        Some(self)
+    }
+}
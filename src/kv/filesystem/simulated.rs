@@ -0,0 +1,291 @@
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, ffi::OsString, io::{self, ErrorKind}, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, PoisonError}};
+
+use rand::Rng;
+
+use super::{FileSystem, LogFile};
+
+/// A failure the simulated file system hands to the next operation it applies to.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Fault{
+    /// The next append writes nothing and fails as if the disk were full.
+    NoSpace,
+    /// The next append writes the first half of its bytes, then fails.
+    ShortWrite,
+    /// The next sync fails, what it was to sync stays exposed to a power loss.
+    SyncFailure
+}
+
+/// A file system in memory that fails on request and can lose power.
+/// Clones share the same files, handles opened before a power loss fail from then on.
+/// Directories are created and removed durably, the entries in them only once they are synced.
+#[derive(Clone,Default)]
+pub struct SimulatedFileSystem{
+    state:Arc<Mutex<State>>
+}
+
+#[derive(Default)]
+struct State{
+    //an unlinked file lives on for the handles still open on it until the next power loss
+    inodes:BTreeMap<u64,Inode>,
+    names:BTreeMap<PathBuf,u64>,
+    //the names as of the last sync of their directory, and the changes made since, in order
+    durable:BTreeMap<PathBuf,u64>,
+    unsynced:Vec<NameChange>,
+    dirs:BTreeSet<PathBuf>,
+    next_inode:u64,
+    generation:u64,
+    faults:VecDeque<Fault>,
+    capacity:Option<u64>
+}
+
+//what a create, rename or remove did to the names, applied whole or not at all
+type NameChange=Vec<(PathBuf,Option<u64>)>;
+
+#[derive(Default)]
+struct Inode{
+    data:Vec<u8>,
+    //bytes that survive a power loss
+    synced:usize
+}
+
+impl SimulatedFileSystem {
+    pub fn new()->SimulatedFileSystem{
+        SimulatedFileSystem::default()
+    }
+
+    /// Queues `fault` for the next operation it applies to, faults of a kind are used in order.
+    pub fn inject(&self,fault:Fault){
+        self.state().faults.push_back(fault);
+    }
+
+    pub fn clear_faults(&self){
+        self.state().faults.clear();
+    }
+
+    /// Bytes all files together may hold, appends past it write what fits and fail.
+    pub fn set_capacity(&self,capacity:Option<u64>){
+        self.state().capacity=capacity;
+    }
+
+    /// Every file keeps what was synced and a random part of what was appended since, each change
+    /// to a directory since its last sync is kept or lost on its own, unlinked files are gone,
+    /// open handles fail and queued faults are dropped.
+    pub fn power_loss(&self,rng:&mut impl Rng){
+        let mut state=self.state();
+        let mut names=state.durable.clone();
+        for change in std::mem::take(&mut state.unsynced){
+            if rng.gen_bool(0.5){
+                apply(&mut names, change);
+            }
+        }
+        state.durable=names.clone();
+        state.names=names;
+        let linked:BTreeSet<u64>=state.names.values().copied().collect();
+        state.inodes.retain(|inode,_|linked.contains(inode));
+        for inode in state.inodes.values_mut(){
+            let kept=inode.synced+rng.gen_range(0..=inode.data.len()-inode.synced);
+            inode.data.truncate(kept);
+            inode.synced=kept;
+        }
+        state.generation+=1;
+        state.faults.clear();
+    }
+
+    fn state(&self)->MutexGuard<'_,State>{
+        //a panicking test thread leaves the files as they were
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn open(&self,path:&Path,writable:bool)->io::Result<Box<dyn LogFile>>{
+        let mut state=self.state();
+        let inode=match state.names.get(path) {
+            Some(inode) => *inode,
+            None if writable => {
+                if !path.parent().is_some_and(|parent|state.dirs.contains(parent)){
+                    return Err(not_found(path));
+                }
+                let inode=state.next_inode;
+                state.next_inode+=1;
+                state.inodes.insert(inode, Inode::default());
+                state.change(vec![(path.to_path_buf(),Some(inode))]);
+                inode
+            },
+            None => return Err(not_found(path)),
+        };
+        Ok(Box::new(SimulatedFile{state:self.state.clone(),inode,generation:state.generation,writable}))
+    }
+}
+
+impl State {
+    fn take_fault(&mut self,applies:impl Fn(Fault)->bool)->Option<Fault>{
+        let index=self.faults.iter().position(|fault|applies(*fault))?;
+        self.faults.remove(index)
+    }
+
+    fn change(&mut self,change:NameChange){
+        apply(&mut self.names, change.clone());
+        self.unsynced.push(change);
+    }
+
+    fn used(&self)->u64{
+        self.names.values().filter_map(|inode|self.inodes.get(inode)).map(|inode|inode.data.len() as u64).sum()
+    }
+}
+
+fn apply(names:&mut BTreeMap<PathBuf,u64>,change:NameChange){
+    for (path,inode) in change{
+        match inode {
+            Some(inode) => names.insert(path, inode),
+            None => names.remove(&path),
+        };
+    }
+}
+
+fn not_found(path:&Path)->io::Error{
+    io::Error::new(ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+impl FileSystem for SimulatedFileSystem {
+    fn create_dir_all(&self,path:&Path)->io::Result<()>{
+        let mut state=self.state();
+        for dir in path.ancestors(){
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn is_dir(&self,path:&Path)->bool{
+        self.state().dirs.contains(path)
+    }
+
+    fn list(&self,dir:&Path)->io::Result<Vec<OsString>>{
+        let state=self.state();
+        if !state.dirs.contains(dir){
+            return Err(not_found(dir));
+        }
+        Ok(
+            state.names.keys()
+            .chain(state.dirs.iter())
+            .filter(|path|path.parent()==Some(dir))
+            .filter_map(|path|path.file_name().map(|name|name.to_os_string()))
+            .collect()
+        )
+    }
+
+    fn open_append(&self,path:&Path)->io::Result<Box<dyn LogFile>>{
+        self.open(path, true)
+    }
+
+    fn open_read(&self,path:&Path)->io::Result<Box<dyn LogFile>>{
+        self.open(path, false)
+    }
+
+    fn rename(&self,from:&Path,to:&Path)->io::Result<()>{
+        let mut state=self.state();
+        let inode=*state.names.get(from).ok_or_else(||not_found(from))?;
+        state.change(vec![(from.to_path_buf(),None),(to.to_path_buf(),Some(inode))]);
+        Ok(())
+    }
+
+    fn remove(&self,path:&Path)->io::Result<()>{
+        let mut state=self.state();
+        if !state.names.contains_key(path){
+            return Err(not_found(path));
+        }
+        state.change(vec![(path.to_path_buf(),None)]);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self,path:&Path)->io::Result<()>{
+        let mut state=self.state();
+        state.names.retain(|name,_|!name.starts_with(path));
+        state.durable.retain(|name,_|!name.starts_with(path));
+        state.unsynced.retain(|change|!change.iter().any(|(name,_)|name.starts_with(path)));
+        state.dirs.retain(|dir|!dir.starts_with(path));
+        Ok(())
+    }
+
+    fn sync_dir(&self,dir:&Path)->io::Result<()>{
+        let mut state=self.state();
+        if !state.dirs.contains(dir){
+            return Err(not_found(dir));
+        }
+        let (synced,unsynced)=std::mem::take(&mut state.unsynced)
+        .into_iter()
+        .partition(|change:&NameChange|change.iter().any(|(name,_)|name.parent()==Some(dir)));
+        state.unsynced=unsynced;
+        for change in synced{
+            apply(&mut state.durable, change);
+        }
+        Ok(())
+    }
+}
+
+struct SimulatedFile{
+    state:Arc<Mutex<State>>,
+    inode:u64,
+    generation:u64,
+    writable:bool
+}
+
+impl SimulatedFile {
+    //the state, if this handle outlived no power loss
+    fn state(&self)->io::Result<MutexGuard<'_,State>>{
+        let state=self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.generation!=self.generation{
+            return Err(io::Error::other("the file was open when power was lost"));
+        }
+        Ok(state)
+    }
+}
+
+impl LogFile for SimulatedFile {
+    fn append(&mut self,bytes:&[u8])->io::Result<()>{
+        if !self.writable{
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "the file is open for reading"));
+        }
+        let mut state=self.state()?;
+        let fault=state.take_fault(|fault|matches!(fault,Fault::NoSpace|Fault::ShortWrite));
+        let room=state.capacity.map_or(usize::MAX,|capacity|capacity.saturating_sub(state.used()) as usize);
+        let (written,error)=match fault {
+            Some(Fault::NoSpace) => (0,Some(io::Error::new(ErrorKind::StorageFull, "simulated full disk"))),
+            Some(_) => (bytes.len()/2,Some(io::Error::other("simulated short write"))),
+            None if bytes.len()>room => (room,Some(io::Error::new(ErrorKind::StorageFull, "simulated full disk"))),
+            None => (bytes.len(),None),
+        };
+        let inode=state.inodes.get_mut(&self.inode).expect("open handles keep their inode");
+        inode.data.extend_from_slice(&bytes[..written]);
+        error.map_or(Ok(()),Err)
+    }
+
+    fn read_at(&self,offset:u64,buf:&mut [u8])->io::Result<usize>{
+        let state=self.state()?;
+        let data=&state.inodes.get(&self.inode).expect("open handles keep their inode").data;
+        let start=data.len().min(offset as usize);
+        let read=buf.len().min(data.len()-start);
+        buf[..read].copy_from_slice(&data[start..start+read]);
+        Ok(read)
+    }
+
+    fn size(&self)->io::Result<u64>{
+        Ok(self.state()?.inodes.get(&self.inode).expect("open handles keep their inode").data.len() as u64)
+    }
+
+    fn truncate(&mut self,size:u64)->io::Result<()>{
+        let mut state=self.state()?;
+        let inode=state.inodes.get_mut(&self.inode).expect("open handles keep their inode");
+        inode.data.resize(size as usize, 0);
+        inode.synced=inode.synced.min(inode.data.len());
+        Ok(())
+    }
+
+    fn sync(&mut self)->io::Result<()>{
+        let mut state=self.state()?;
+        if state.take_fault(|fault|fault==Fault::SyncFailure).is_some(){
+            return Err(io::Error::other("simulated fsync failure"));
+        }
+        let inode=state.inodes.get_mut(&self.inode).expect("open handles keep their inode");
+        inode.synced=inode.data.len();
+        Ok(())
+    }
+}
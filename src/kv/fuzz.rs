@@ -0,0 +1,49 @@
+use std::{path::Path, sync::Arc};
+
+use super::{filesystem::{simulated::SimulatedFileSystem, FileSystem}, index::build_index, options::KvStoreOptions, record::StoredRecord, replication::LogPosition, storage::LogStorage, Context, IndexKind, KVError, Result};
+
+/// Opens `bytes` as the only segment of a log and reads back every key it indexes, with each index kind.
+pub fn segment(bytes:&[u8]){
+    for index_kind in [IndexKind::Hash,IndexKind::Compact]{
+        let _=read_segment(bytes, index_kind);
+    }
+}
+
+/// Reads `bytes` as the record at the start of a segment, the way a lookup reads a value.
+pub fn record(bytes:&[u8]){
+    let _=storage_with(bytes).and_then(|storage|{
+        let record=storage.pointer(LogPosition{segment:0,offset:0})?.read::<StoredRecord>()?.into_record();
+        record.verify();
+        Ok(())
+    });
+}
+
+fn read_segment(bytes:&[u8],index_kind:IndexKind)->Result<()>{
+    let storage=storage_with(bytes)?;
+    let mut index=index_kind.create(&storage)?;
+    build_index(
+        index.as_mut(),
+        &storage,
+        storage
+        .iter_entries::<StoredRecord>()
+        .map(|entry|entry.map(|(log_ptr,stored)|(log_ptr,stored.into_record().op)))
+    )?;
+    for key in index.keys(&storage)?{
+        index.get(&storage, &key)?;
+    }
+    for record in index.records(&storage){
+        record?;
+    }
+    Ok(())
+}
+
+//a log on a simulated file system holding `bytes` as segment 0
+fn storage_with(bytes:&[u8])->Result<LogStorage>{
+    let fs=SimulatedFileSystem::new();
+    let directory=Path::new("/fuzz/data");
+    fs.create_dir_all(directory).context(KVError::IOError, "fuzz::storage_with1")?;
+    fs.open_append(&directory.join("0"))
+    .and_then(|mut file|file.append(bytes))
+    .context(KVError::IOError, "fuzz::storage_with2")?;
+    LogStorage::open(directory.to_path_buf(), &KvStoreOptions::new().file_system(Arc::new(fs)))
+}
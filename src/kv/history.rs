@@ -0,0 +1,274 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::{remove_dir_all, remove_file, File}, io::{BufReader, ErrorKind}, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{options::KvStoreOptions, record::Record, repair::{repair_segments, RepairReport}, verify::{check_segments, VerifyReport}, storage::{LogPointer, LogStorage}, util::now_millis, Context, ErrorContext, KVError, Operation, Result};
+
+const HISTORY_DIR:&str="history";
+const POLICY_FILE:&str="versioning.json";
+
+/// How many old versions of a key are kept. Unset limits keep everything,
+/// the latest version of a key is kept regardless.
+#[derive(Deserialize,Serialize,Debug,Clone,Default,PartialEq)]
+pub struct VersionPolicy{
+    pub max_versions:Option<usize>,
+    pub retention_secs:Option<u64>
+}
+
+/// One value a key had, `None` when the key was removed.
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub struct Version{
+    //the seq of the data log record that wrote it, the one `recover --until seq:<n>` takes
+    pub seq:u64,
+    //milliseconds since the unix epoch
+    pub timestamp:u64,
+    pub value:Option<String>
+}
+
+#[derive(Deserialize,Serialize)]
+struct VersionRecord{
+    key:String,
+    version:Version
+}
+
+struct VersionPointer{
+    seq:u64,
+    timestamp:u64,
+    log_ptr:LogPointer
+}
+
+//what is left of the versions written to a segment of the history log
+#[derive(Default)]
+struct SegmentUsage{
+    records:usize,
+    //versions a later one of their key replaced, and with their timestamp the newest of them
+    superseded:usize,
+    superseded_newest:u64,
+    //superseded versions pruned past max_versions, or copies left by a rewrite cut short
+    dead:usize
+}
+
+impl SegmentUsage {
+    //superseded versions are all expired once the newest of them is
+    fn garbage(&self,cutoff:u64)->usize{
+        if self.superseded>0&&self.superseded_newest<cutoff {self.superseded} else {self.dead}
+    }
+}
+
+/// Every retained version of every key in a log of its own next to the data log,
+/// so the data log and its merge stay the same whether versioning is on or not.
+///
+/// Segments are rewritten one at a time once at least half of what they hold is garbage,
+/// at most one for every version recorded, so a write never waits for more than a segment.
+pub(super) struct History{
+    policy:VersionPolicy,
+    storage:LogStorage,
+    versions:HashMap<String,Vec<VersionPointer>>,
+    usage:BTreeMap<usize,SegmentUsage>,
+    //sealed segments found worth rewriting, and the segment written when they were last looked for
+    candidates:BTreeSet<usize>,
+    head:usize
+}
+
+impl History {
+    /// Opens the history of the store at `root` if versioning was enabled on it, with the options of the store.
+    pub fn open(root:&Path,options:&KvStoreOptions)->Result<Option<History>>{
+        let Some(policy)=Self::load_policy(root)? else {
+            return Ok(None);
+        };
+        let storage=LogStorage::open(root.join(HISTORY_DIR), options)?;
+        let head=storage.head_position().segment;
+        let mut history=History{
+            policy,
+            storage,
+            versions:HashMap::new(),
+            usage:BTreeMap::new(),
+            candidates:BTreeSet::new(),
+            head
+        };
+        let mut records=Vec::new();
+        for entry in history.storage.iter_entries::<VersionRecord>(){
+            let (log_ptr,record)=entry?;
+            history.usage.entry(log_ptr.position().segment).or_default().records+=1;
+            records.push((record.key,VersionPointer{seq:record.version.seq,timestamp:record.version.timestamp,log_ptr}));
+        }
+        //rewrites move versions out of seq order
+        records.sort_by_key(|(_,version)|version.seq);
+        for (key,version) in records{
+            //a rewrite cut short leaves two copies of a version, the second one is garbage
+            if history.versions.get(&key).and_then(|versions|versions.last()).is_some_and(|last|last.seq==version.seq){
+                let usage=history.usage.entry(version.log_ptr.position().segment).or_default();
+                usage.superseded+=1;
+                usage.superseded_newest=usage.superseded_newest.max(version.timestamp);
+                usage.dead+=1;
+                continue;
+            }
+            history.push(key, version);
+        }
+        history.find_candidates();
+        Ok(Some(history))
+    }
+
+    /// The policy of the store at `root`, `None` if versioning is off.
+    pub fn load_policy(root:&Path)->Result<Option<VersionPolicy>>{
+        match File::open(root.join(POLICY_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).context(KVError::ParseError, "History::load_policy1").map_err(|e|e.with_path(root.join(POLICY_FILE))),
+            Err(e) if e.kind()==ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(KVError::IOError(ErrorContext::new("History::load_policy2").with_path(root.join(POLICY_FILE)).with_source(e))),
+        }
+    }
+
+    /// Turns versioning on for the store at `root`, the records of `current` become the first version of every key.
+    pub fn enable(root:&Path,options:&KvStoreOptions,policy:VersionPolicy,current:impl Iterator<Item = Result<Record>>)->Result<History>{
+        let file=File::create(root.join(POLICY_FILE)).context(KVError::IOError, "History::enable1")?;
+        serde_json::to_writer(file, &policy).context(KVError::WriteError, "History::enable2")?;
+        let mut history=Self::open(root, options)?.ok_or(KVError::IOError("History::enable3".into()))?;
+        if history.versions.is_empty(){
+            for record in current{
+                history.record(&record?)?;
+            }
+        }
+        Ok(history)
+    }
+
+    /// Quarantines whatever in the history log of the store at `root` is not a version.
+    pub fn repair(root:&Path,report:&mut RepairReport)->Result<()>{
+        repair_segments(&root.join(HISTORY_DIR), root, report, |_:VersionRecord|None)
+    }
+
+    /// Checks the history log of the store at `root` the way `KvStore::verify` checks the data log.
+    pub fn verify(root:&Path,report:&mut VerifyReport)->Result<()>{
+        check_segments(&root.join(HISTORY_DIR), report, |_:VersionRecord|None)
+    }
+
+    pub fn disable(root:&Path)->Result<()>{
+        remove_file(root.join(POLICY_FILE)).context(KVError::IOError, "History::disable1")?;
+        remove_dir_all(root.join(HISTORY_DIR)).context(KVError::IOError, "History::disable2")
+    }
+
+    pub fn policy(&self)->&VersionPolicy{
+        &self.policy
+    }
+
+    /// Keeps what the data log `record` did as a version of its key.
+    pub fn record(&mut self,record:&Record)->Result<()>{
+        let (key,value)=match &record.op {
+            Operation::Set(key,value) => (key.clone(),Some(value.clone())),
+            Operation::Remove(key) => (key.clone(),None),
+            Operation::Get(_) => return Ok(()),
+        };
+        let (seq,timestamp)=(record.seq,record.timestamp);
+        let version=Version{seq,timestamp,value};
+        let log_ptr=self.storage.write(VersionRecord{key:key.clone(),version})?;
+        self.usage.entry(log_ptr.position().segment).or_default().records+=1;
+        self.push(key, VersionPointer{seq,timestamp,log_ptr});
+
+        //expired versions are only found by looking, which is done whenever a segment fills up
+        if self.storage.head_position().segment!=self.head{
+            self.find_candidates();
+        }
+        match self.candidates.pop_first() {
+            Some(serial) => self.rewrite(serial),
+            None => Ok(()),
+        }
+    }
+
+    /// The retained versions of `key`, oldest first.
+    pub fn versions(&self,key:&str)->Result<Vec<Version>>{
+        let Some(versions)=self.versions.get(key) else {
+            return Ok(Vec::new());
+        };
+        let cutoff=self.cutoff();
+        let latest=versions.len()-1;
+        versions
+        .iter()
+        .enumerate()
+        .filter(|(position,version)|*position==latest||version.timestamp>=cutoff)
+        .map(|(_,version)|version.log_ptr.read::<VersionRecord>().map(|record|record.version))
+        .collect()
+    }
+
+    //makes version the latest of key, dropping versions past max_versions,
+    //the time window is applied on read and when a segment is rewritten
+    fn push(&mut self,key:String,version:VersionPointer){
+        let versions=self.versions.entry(key).or_default();
+        if let Some(last)=versions.last(){
+            let serial=last.log_ptr.position().segment;
+            let usage=self.usage.entry(serial).or_default();
+            usage.superseded+=1;
+            usage.superseded_newest=usage.superseded_newest.max(last.timestamp);
+            Self::check(&mut self.candidates, self.head, serial, usage, 0);
+        }
+        versions.push(version);
+        let Some(max_versions)=self.policy.max_versions else {
+            return;
+        };
+        let excess=versions.len().saturating_sub(max_versions.max(1));
+        for pruned in versions.drain(..excess){
+            let serial=pruned.log_ptr.position().segment;
+            let usage=self.usage.entry(serial).or_default();
+            usage.dead+=1;
+            Self::check(&mut self.candidates, self.head, serial, usage, 0);
+        }
+    }
+
+    //looks through every segment for garbage, expired versions included
+    fn find_candidates(&mut self){
+        self.head=self.storage.head_position().segment;
+        let cutoff=self.cutoff();
+        for (serial,usage) in self.usage.iter(){
+            Self::check(&mut self.candidates, self.head, *serial, usage, cutoff);
+        }
+    }
+
+    fn check(candidates:&mut BTreeSet<usize>,head:usize,serial:usize,usage:&SegmentUsage,cutoff:u64){
+        if serial<head&&usage.garbage(cutoff)*2>=usage.records{
+            candidates.insert(serial);
+        }
+    }
+
+    //copies what is still retained of a segment to the one being written, then deletes it
+    fn rewrite(&mut self,serial:usize)->Result<()>{
+        if !self.storage.contains_segment(serial){
+            return Ok(());
+        }
+        let cutoff=self.cutoff();
+        for record in self.storage.read_segment::<VersionRecord>(serial)?{
+            let Some(versions)=self.versions.get_mut(&record.key) else {
+                continue;
+            };
+            let latest=versions.len()-1;
+            let found=versions
+            .iter()
+            .position(|version|version.seq==record.version.seq&&version.log_ptr.position().segment==serial);
+            let Some(position)=found else {
+                continue;
+            };
+            if position!=latest&&versions[position].timestamp<cutoff{
+                versions.remove(position);
+                continue;
+            }
+            let timestamp=record.version.timestamp;
+            let log_ptr=self.storage.write(record)?;
+            let usage=self.usage.entry(log_ptr.position().segment).or_default();
+            usage.records+=1;
+            if position!=latest{
+                usage.superseded+=1;
+                usage.superseded_newest=usage.superseded_newest.max(timestamp);
+            }
+            versions[position].log_ptr=log_ptr;
+        }
+        //the copies are kept before the segment goes
+        self.storage.sync()?;
+        self.storage.remove_segments(&[serial])?;
+        self.usage.remove(&serial);
+        Ok(())
+    }
+
+    fn cutoff(&self)->u64{
+        self.policy
+        .retention_secs
+        .map(|secs|now_millis().saturating_sub(secs*1000))
+        .unwrap_or(0)
+    }
+}
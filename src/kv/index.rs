@@ -1,69 +1,294 @@
-use std::collections::HashMap;
+use std::{collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}};
 
-use super::{KVError, Result};
+use super::{ErrorContext, KVError, Result};
 
-use super::{storage::LogPointer, Operation};
+use super::{record::{Record, StoredRecord}, replication::LogPosition, storage::{LogPointer, LogStorage}, Operation};
 
 
+/// Which key directory a `KvStore` keeps in memory.
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum IndexKind{
+    /// Every key with a pointer into its segment, lookups never touch the disk.
+    #[default]
+    Hash,
+    /// Only a hash of every key and its packed position, a lookup reads the record to confirm the key.
+    Compact
+}
 
-pub struct Index{
-    index:HashMap<String,LogPointer>,
+impl IndexKind {
+    pub(super) fn create(&self,storage:&LogStorage)->Result<Box<dyn Index>>{
+        Ok(match self {
+            IndexKind::Hash => Box::new(HashIndex::new()),
+            IndexKind::Compact => Box::new(CompactIndex::new(storage.offset_bound()?)),
+        })
+    }
 }
 
+/// Maps every live key to the record holding its value.
+///
+/// Implementations that do not keep the keys themselves read them back from `storage`.
+pub(super) trait Index{
+    fn get(&self,storage:&LogStorage,key:&str)->Result<Option<String>>;
+    fn set(&mut self,storage:&LogStorage,key:String,log_ptr:LogPointer)->Result<()>;
+    /// Returns whether the key was present.
+    fn remove(&mut self,storage:&LogStorage,key:&str)->Result<bool>;
+    fn contains(&self,storage:&LogStorage,key:&str)->Result<bool>;
+    /// Where the record holding the value of `key` is.
+    fn position(&self,storage:&LogStorage,key:&str)->Result<Option<LogPosition>>;
+    /// Where every live record is.
+    fn positions<'a>(&'a self)->Box<dyn Iterator<Item = LogPosition>+'a>;
+    fn records<'a>(&'a self,storage:&'a LogStorage)->Box<dyn Iterator<Item = Result<Record>>+'a>;
+    fn keys(&self,storage:&LogStorage)->Result<Vec<String>>;
+
+    fn iter<'a>(&'a self,storage:&'a LogStorage)->Box<dyn Iterator<Item = Result<Operation>>+'a>{
+        Box::new(self.records(storage).map(|record|record.map(|record|record.op)))
+    }
+}
 
+pub(super) fn build_index(index:&mut dyn Index,storage:&LogStorage,log_iter:impl Iterator<Item = Result<(LogPointer,Operation)>>)->Result<()>{
+    for parse_result in log_iter{
+        let (log_ptr,operation)=parse_result?;
 
+        match operation {
+            Operation::Get(_) => return Err(KVError::ReadError(ErrorContext::new("build_index: a get in the log").at(log_ptr.position()))),
+            //the set it removes may have been compacted away already
+            Operation::Remove(key) => {
+                index.remove(storage, &key)?;
+            },
+            Operation::Set(key,_) => {
+                index.set(storage, key, log_ptr)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+fn read_record(log_ptr:&LogPointer)->Result<Record>{
+    log_ptr.read::<StoredRecord>().map(StoredRecord::into_record)
+}
+
+fn set_value(record:Record)->Result<String>{
+    match record.op {
+        Operation::Set(_, val) => Ok(val),
+        _=>Err(KVError::ReadError("set_value: pointer to a record that is not a set".into()))
+    }
+}
+
+
+pub struct HashIndex{
+    index:HashMap<String,LogPointer>,
+}
 
-impl Index {
-    pub fn new()->Index{
-        Index{
+impl HashIndex {
+    pub fn new()->HashIndex{
+        HashIndex{
             index:HashMap::new()
         }
     }
+}
 
-    pub fn build_index(&mut self,log_iter:impl Iterator<Item = Result<(LogPointer,Operation)>>)->Result<()>{
-        for parse_result in log_iter{
-            let (log_ptr,operation)=parse_result?;
-            
-            match operation {
-                Operation::Get(_) => panic!("Get operation should never be on file"),
-                Operation::Remove(key) => {
-                    self.index.remove(&key).expect("key does not exist during index building");
-                },
-                Operation::Set(key,_) => {
-                    self.index.insert(key, log_ptr);
-                },
-            }
+impl Index for HashIndex {
+    fn get(&self,_storage:&LogStorage,key:&str)->Result<Option<String>>{
+        match self.index.get(key) {
+            Some(pointer) => Ok(Some(set_value(read_record(pointer)?)?)),
+            None => Ok(None),
         }
+    }
+
+    fn set(&mut self,_storage:&LogStorage,key:String,log_ptr:LogPointer)->Result<()>{
+        self.index.insert(key, log_ptr);
         Ok(())
     }
 
-    pub fn get(&self,key:&String)->Result<String>{
-        let pointer=self.index.get(key).ok_or(KVError::KeyNotFound("index::get"))?;
-        let operation:Operation=pointer.read()?;
-        match operation {
-            Operation::Set(_, val) => Ok(val),
-            _=>panic!("Log pointer should only point to set operations")
+    fn remove(&mut self,_storage:&LogStorage,key:&str)->Result<bool>{
+        Ok(self.index.remove(key).is_some())
+    }
+
+    fn contains(&self,_storage:&LogStorage,key:&str)->Result<bool>{
+        Ok(self.index.contains_key(key))
+    }
+
+    fn position(&self,_storage:&LogStorage,key:&str)->Result<Option<LogPosition>>{
+        Ok(self.index.get(key).map(LogPointer::position))
+    }
+
+    fn positions<'a>(&'a self)->Box<dyn Iterator<Item = LogPosition>+'a>{
+        Box::new(self.index.values().map(LogPointer::position))
+    }
+
+    fn records<'a>(&'a self,_storage:&'a LogStorage)->Box<dyn Iterator<Item = Result<Record>>+'a>{
+        Box::new(self.index.values().map(read_record))
+    }
+
+    fn keys(&self,_storage:&LogStorage)->Result<Vec<String>>{
+        Ok(self.index.keys().cloned().collect())
+    }
+}
+
+
+//offset in the low offset_bits, segment serial in the rest
+#[derive(Clone,Copy,PartialEq)]
+struct PackedPosition(u64);
+
+//leaves the serial at least 16 bits whatever the segment size
+const MAX_OFFSET_BITS:u32=48;
+
+impl PackedPosition {
+    fn pack(position:LogPosition,offset_bits:u32)->Result<PackedPosition>{
+        if position.segment as u64>=1<<(64-offset_bits)||position.offset>=1<<offset_bits{
+            return Err(KVError::WriteError("PackedPosition::pack".into()));
         }
+        Ok(PackedPosition((position.segment as u64)<<offset_bits|position.offset))
     }
 
-    pub fn set(&mut self,key:String,log_ptr:LogPointer){
-        self.index.insert(key, log_ptr);
+    fn unpack(self,offset_bits:u32)->LogPosition{
+        LogPosition{
+            segment:(self.0>>offset_bits) as usize,
+            offset:self.0&((1<<offset_bits)-1)
+        }
+    }
+}
+
+/// Keeps a 16 byte entry per key instead of the key itself, at the cost of a read to confirm every hit.
+///
+/// A position packs the offset in just the bits a segment of the store needs, leaving the rest
+/// to the serial, so that serials compactions skip over run out no sooner with small segments.
+pub struct CompactIndex{
+    offset_bits:u32,
+    positions:HashMap<u64,PackedPosition>,
+    //keys whose hash another key already holds in positions
+    collisions:Vec<(u64,PackedPosition)>
+}
+
+enum Slot{
+    Primary,
+    Collision(usize)
+}
+
+impl CompactIndex {
+    /// An index for a log whose records all start before `offset_bound` in their segment.
+    pub fn new(offset_bound:u64)->CompactIndex{
+        CompactIndex{
+            offset_bits:(u64::BITS-offset_bound.saturating_sub(1).leading_zeros()).clamp(1, MAX_OFFSET_BITS),
+            positions:HashMap::new(),
+            collisions:Vec::new()
+        }
+    }
+
+    //the slot holding key with its record, reading every candidate sharing its hash
+    fn find(&self,storage:&LogStorage,hash:u64,key:&str)->Result<Option<(Slot,Record)>>{
+        let Some(position)=self.positions.get(&hash) else {
+            return Ok(None);
+        };
+        let record=self.read(storage, *position)?;
+        if record_key(&record)==Some(key){
+            return Ok(Some((Slot::Primary,record)));
+        }
+        for (slot,(_,position)) in self.collisions.iter().enumerate().filter(|(_,(other,_))|*other==hash){
+            let record=self.read(storage, *position)?;
+            if record_key(&record)==Some(key){
+                return Ok(Some((Slot::Collision(slot),record)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(&self,storage:&LogStorage,position:PackedPosition)->Result<Record>{
+        read_record(&storage.pointer(position.unpack(self.offset_bits))?)
+    }
+}
+
+impl Index for CompactIndex {
+    fn get(&self,storage:&LogStorage,key:&str)->Result<Option<String>>{
+        match self.find(storage, key_hash(key), key)? {
+            Some((_,record)) => Ok(Some(set_value(record)?)),
+            None => Ok(None),
+        }
     }
 
-    pub fn remove(&mut self,key:&String)->Result<()>{
-        self.index.remove(key).ok_or(KVError::KeyNotFound("index::remove"))?;
+    fn set(&mut self,storage:&LogStorage,key:String,log_ptr:LogPointer)->Result<()>{
+        let hash=key_hash(&key);
+        let position=PackedPosition::pack(log_ptr.position(), self.offset_bits)?;
+        match self.find(storage, hash, &key)? {
+            Some((Slot::Primary,_)) => {self.positions.insert(hash, position);},
+            Some((Slot::Collision(slot),_)) => self.collisions[slot]=(hash,position),
+            None if self.positions.contains_key(&hash) => self.collisions.push((hash,position)),
+            None => {self.positions.insert(hash, position);},
+        }
         Ok(())
     }
 
-    pub fn contains(&self,key:&String)->bool{
-        self.index.contains_key(key)
+    fn remove(&mut self,storage:&LogStorage,key:&str)->Result<bool>{
+        let hash=key_hash(key);
+        match self.find(storage, hash, key)? {
+            Some((Slot::Primary,_)) => {
+                //another key with the same hash takes over the primary slot
+                match self.collisions.iter().position(|(other,_)|*other==hash) {
+                    Some(slot) => {
+                        let (_,position)=self.collisions.swap_remove(slot);
+                        self.positions.insert(hash, position);
+                    },
+                    None => {self.positions.remove(&hash);},
+                }
+                Ok(true)
+            },
+            Some((Slot::Collision(slot),_)) => {
+                self.collisions.swap_remove(slot);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
     }
 
-    pub fn iter(&self)->impl Iterator<Item = Result<Operation>>+'_{
-        self.index.values().map(|log_ptr| log_ptr.read())
+    fn contains(&self,storage:&LogStorage,key:&str)->Result<bool>{
+        Ok(self.find(storage, key_hash(key), key)?.is_some())
     }
 
-    pub fn len(&self)->usize{
-        self.index.len()
+    fn position(&self,storage:&LogStorage,key:&str)->Result<Option<LogPosition>>{
+        let hash=key_hash(key);
+        Ok(self.find(storage, hash, key)?.map(|(slot,_)|match slot {
+            Slot::Primary => self.positions[&hash].unpack(self.offset_bits),
+            Slot::Collision(slot) => self.collisions[slot].1.unpack(self.offset_bits),
+        }))
     }
-}
\ No newline at end of file
+
+    fn positions<'a>(&'a self)->Box<dyn Iterator<Item = LogPosition>+'a>{
+        Box::new(
+            self.positions
+            .values()
+            .chain(self.collisions.iter().map(|(_,position)|position))
+            .map(|position|position.unpack(self.offset_bits))
+        )
+    }
+
+    fn records<'a>(&'a self,storage:&'a LogStorage)->Box<dyn Iterator<Item = Result<Record>>+'a>{
+        Box::new(
+            self.positions
+            .values()
+            .chain(self.collisions.iter().map(|(_,position)|position))
+            .map(|position|self.read(storage, *position))
+        )
+    }
+
+    fn keys(&self,storage:&LogStorage)->Result<Vec<String>>{
+        self.records(storage)
+        .map(|record|match record?.op {
+            Operation::Set(key,_) => Ok(key),
+            _=>Err(KVError::ReadError("CompactIndex::keys".into()))
+        })
+        .collect()
+    }
+}
+
+fn key_hash(key:&str)->u64{
+    let mut hasher=DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn record_key(record:&Record)->Option<&str>{
+    match &record.op {
+        Operation::Set(key,_)|Operation::Remove(key) => Some(key),
+        Operation::Get(_) => None,
+    }
+}
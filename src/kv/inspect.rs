@@ -0,0 +1,154 @@
+use std::{collections::{BTreeMap, HashSet}, fmt::Display, fs::metadata};
+
+use serde::Serialize;
+
+use crate::DEFAULT_NAMESPACE;
+
+use super::{record::{Record, StoredRecord}, replication::LogPosition, Context, KVError, KvStore, Operation, Result};
+
+/// Size and record counts of one segment.
+#[derive(Serialize,Debug,Clone,PartialEq)]
+pub struct SegmentSummary{
+    pub segment:usize,
+    pub size:u64,
+    pub records:usize,
+    //records the index still points at, the rest is garbage for the next compaction
+    pub live:usize,
+    pub live_ratio:f64
+}
+
+impl Display for SegmentSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"segment {:>6} {:>10} bytes {:>8} records {:>8} live {:>5.1}%",self.segment,self.size,self.records,self.live,self.live_ratio*100.0)
+    }
+}
+
+/// One record of the log, its value is only kept when a key is looked up.
+#[derive(Serialize,Debug,Clone,PartialEq)]
+pub struct RecordSummary{
+    pub segment:usize,
+    pub offset:u64,
+    pub seq:u64,
+    pub timestamp:u64,
+    pub op:&'static str,
+    pub key:String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub value_len:Option<usize>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub value:Option<String>,
+    pub live:bool
+}
+
+impl Display for RecordSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"{}:{} seq {} at {} {} {}",self.segment,self.offset,self.seq,self.timestamp,self.op,self.key)?;
+        match (&self.value,self.value_len) {
+            (Some(value),_) => write!(f," = {value}")?,
+            (None,Some(len)) => write!(f," ({len} bytes)")?,
+            _ => {},
+        }
+        if self.live{
+            write!(f," live")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the segments of one namespace of an open store record by record.
+pub struct Inspector<'a>{
+    store:&'a KvStore
+}
+
+impl KvStore {
+    pub fn inspector(&self,namespace:&str)->Result<Inspector<'_>>{
+        let store=if namespace==DEFAULT_NAMESPACE {
+            self
+        } else {
+            self.namespaces.get(namespace).ok_or_else(||KVError::NamespaceNotFound(namespace.to_string()))?
+        };
+        Ok(Inspector{store})
+    }
+}
+
+impl Inspector<'_> {
+    /// Every segment in log order.
+    pub fn segments(&self)->Result<Vec<SegmentSummary>>{
+        let mut counts:BTreeMap<usize,(usize,usize)>=self.store.storage.iter_read_files().map(|(serial,_)|(*serial,(0,0))).collect();
+        let live=self.live_positions();
+        self.visit(|position,_|{
+            let (records,live_records)=counts.entry(position.segment).or_default();
+            *records+=1;
+            if live.contains(&position){
+                *live_records+=1;
+            }
+        })?;
+        counts.into_iter().map(|(segment,(records,live))|{
+            let path=self.store.storage.directory().join(segment.to_string());
+            let size=metadata(&path).context(KVError::IOError, "Inspector::segments1").map_err(|e|e.with_path(&path))?.len();
+            let live_ratio=if records==0 {0.0} else {live as f64/records as f64};
+            Ok(SegmentSummary{segment,size,records,live,live_ratio})
+        })
+        .collect()
+    }
+
+    /// Every record of `segment`.
+    pub fn dump(&self,segment:usize)->Result<Vec<RecordSummary>>{
+        if !self.store.storage.contains_segment(segment){
+            return Err(KVError::ConfigError("no such segment"));
+        }
+        let live=self.live_positions();
+        let mut records=Vec::new();
+        //the log is only scanned whole, the records of other segments are skipped
+        self.visit(|position,op|if position.segment==segment{
+            records.push(summary(position, op, live.contains(&position), false));
+        })?;
+        Ok(records)
+    }
+
+    /// Every record of `key` still in the log, oldest first, with its value.
+    pub fn key(&self,key:&str)->Result<Vec<RecordSummary>>{
+        let live=self.live_positions();
+        let mut records=Vec::new();
+        self.visit(|position,op|if op_key(&op.op)==key{
+            records.push(summary(position, op, live.contains(&position), true));
+        })?;
+        Ok(records)
+    }
+
+    fn live_positions(&self)->HashSet<LogPosition>{
+        self.store.index.positions().collect()
+    }
+
+    fn visit(&self,mut visit:impl FnMut(LogPosition,Record))->Result<()>{
+        for entry in self.store.storage.iter_entries::<StoredRecord>(){
+            let (log_ptr,stored)=entry?;
+            visit(log_ptr.position(),stored.into_record());
+        }
+        Ok(())
+    }
+}
+
+fn op_key(op:&Operation)->&str{
+    match op {
+        Operation::Get(key)|Operation::Remove(key)|Operation::Set(key,_) => key,
+    }
+}
+
+fn summary(position:LogPosition,record:Record,live:bool,with_value:bool)->RecordSummary{
+    let (op,value)=match record.op {
+        Operation::Get(_) => ("get",None),
+        Operation::Remove(_) => ("remove",None),
+        Operation::Set(_,ref value) => ("set",Some(value.clone())),
+    };
+    RecordSummary{
+        segment:position.segment,
+        offset:position.offset,
+        seq:record.seq,
+        timestamp:record.timestamp,
+        op,
+        key:op_key(&record.op).to_string(),
+        value_len:value.as_ref().map(String::len),
+        value:value.filter(|_|with_value),
+        live
+    }
+}
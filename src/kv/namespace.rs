@@ -0,0 +1,73 @@
+use std::{collections::BTreeMap, fs::{read_dir, remove_dir_all, DirBuilder, File}, io::BufReader, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{options::KvStoreOptions, Context, ErrorContext, KVError, KvStore, Result};
+
+pub const NAMESPACE_DIR:&str="namespaces";
+const OPTIONS_FILE:&str="options.json";
+
+/// Settings a namespace may override, stored next to its segments.
+#[derive(Deserialize,Serialize,Debug,Clone,Default,PartialEq)]
+pub struct NamespaceOptions{
+    pub merge_threshold:Option<usize>
+}
+
+impl KvStore {
+    pub(super) fn load_namespaces(root:&Path,store_options:&KvStoreOptions)->Result<BTreeMap<String,KvStore>>{
+        let mut namespaces=BTreeMap::new();
+        let dir=match read_dir(root.join(NAMESPACE_DIR)) {
+            Ok(dir) => dir,
+            Err(_) => return Ok(namespaces),
+        };
+        for entry in dir{
+            let entry=entry.context(KVError::IOError, "KvStore::load_namespaces1")?;
+            let name=entry.file_name().into_string().map_err(|_|KVError::ParseError(ErrorContext::new("KvStore::load_namespaces2").with_path(entry.path())))?;
+            let options=Self::namespace_options(root, &name)?;
+            namespaces.insert(name, Self::open_namespace(&entry.path(), &options, store_options)?);
+        }
+        Ok(namespaces)
+    }
+
+    /// The options namespace `name` was created with.
+    pub(super) fn namespace_options(root:&Path,name:&str)->Result<NamespaceOptions>{
+        let options_file=File::open(root.join(NAMESPACE_DIR).join(name).join(OPTIONS_FILE)).context(KVError::IOError, "KvStore::namespace_options1")?;
+        serde_json::from_reader(BufReader::new(options_file)).context(KVError::ParseError, "KvStore::namespace_options2")
+    }
+
+    pub(super) fn create_namespace_dir(root:&Path,name:&str,options:&NamespaceOptions,store_options:&KvStoreOptions)->Result<KvStore>{
+        validate_name(name)?;
+        let dir=root.join(NAMESPACE_DIR).join(name);
+        DirBuilder::new().recursive(true).create(&dir).context(KVError::IOError, "KvStore::create_namespace_dir1")?;
+        let options_file=File::create(dir.join(OPTIONS_FILE)).context(KVError::IOError, "KvStore::create_namespace_dir2")?;
+        serde_json::to_writer(options_file, options).context(KVError::WriteError, "KvStore::create_namespace_dir3")?;
+        Self::open_namespace(&dir, options, store_options)
+    }
+
+    //the segments go away with the directory, nothing is left for compaction to reclaim
+    pub(super) fn remove_namespace_dir(root:&Path,name:&str,store_options:&KvStoreOptions)->Result<()>{
+        let dir=root.join(NAMESPACE_DIR).join(name);
+        //the log may be kept apart from the rest of the namespace
+        store_options.file_system.get().remove_dir_all(&dir.join("data")).context(KVError::IOError, "KvStore::remove_namespace_dir1")?;
+        remove_dir_all(dir).context(KVError::IOError, "KvStore::remove_namespace_dir2")
+    }
+
+    //a namespace takes the store's options, but its own merge threshold
+    fn open_namespace(dir:&Path,options:&NamespaceOptions,store_options:&KvStoreOptions)->Result<KvStore>{
+        let mut store_options=store_options.clone();
+        if let Some(merge_threshold)=options.merge_threshold{
+            store_options.merge_size=merge_threshold;
+        }
+        let mut store=Self::open_segments(dir.to_path_buf(),&store_options)?;
+        store.ns_options=options.clone();
+        Ok(store)
+    }
+}
+
+fn validate_name(name:&str)->Result<()>{
+    let valid_chars=name.chars().all(|c|c.is_ascii_alphanumeric()||c=='-'||c=='_');
+    if name.is_empty()||name.len()>64||!valid_chars||name==crate::DEFAULT_NAMESPACE{
+        return Err(KVError::ConfigError("invalid namespace name"));
+    }
+    Ok(())
+}
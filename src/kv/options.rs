@@ -0,0 +1,186 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{KILOBYTE, MEGABYTE};
+
+use std::sync::Arc;
+
+use super::{cache::DEFAULT_CACHE_CAPACITY, filesystem::{FileSystem, FileSystemRef}, Admission, IndexKind, KVError, Result};
+
+/// When a write to the log returns.
+#[derive(ValueEnum,Deserialize,Serialize,Debug,Clone,Copy,Default,PartialEq)]
+#[serde(rename_all="lowercase")]
+pub enum Durability{
+    /// Once the OS has the record, it survives the process crashing but not the machine.
+    #[default]
+    Write,
+    /// Once the record is synced to disk.
+    Sync
+}
+
+/// How `KvStore::open_with` opens a store, its namespaces and their logs.
+/// The defaults are the ones `KvStore::open` uses.
+#[derive(Debug,Clone,PartialEq)]
+pub struct KvStoreOptions{
+    pub(super) file_size:usize,
+    pub(super) merge_size:usize,
+    pub(super) durability:Durability,
+    pub(super) index_kind:IndexKind,
+    //the persisted filter's rate is kept if unset
+    pub(super) bloom_false_positive_rate:Option<f64>,
+    pub(super) cache_capacity:usize,
+    pub(super) admission:Admission,
+    pub(super) mmap_reads:bool,
+    pub(super) compaction_rate:Option<u64>,
+    pub(super) compaction_chunk:usize,
+    pub(super) background_compaction:bool,
+    pub(super) remove_retention:Option<u64>,
+    pub(super) read_only:bool,
+    pub(super) create_if_missing:bool,
+    pub(super) error_if_exists:bool,
+    pub(super) file_system:FileSystemRef
+}
+
+impl Default for KvStoreOptions {
+    fn default()->Self{
+        KvStoreOptions{
+            file_size:4*KILOBYTE,
+            merge_size:10*KILOBYTE,
+            durability:Durability::default(),
+            index_kind:IndexKind::default(),
+            bloom_false_positive_rate:None,
+            cache_capacity:DEFAULT_CACHE_CAPACITY,
+            admission:Admission::default(),
+            mmap_reads:false,
+            compaction_rate:None,
+            compaction_chunk:64*MEGABYTE,
+            background_compaction:true,
+            remove_retention:None,
+            read_only:false,
+            create_if_missing:true,
+            error_if_exists:false,
+            file_system:FileSystemRef::Os
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new()->KvStoreOptions{
+        Self::default()
+    }
+
+    /// Bytes a segment grows to before writes go to a new one.
+    pub fn file_size(mut self,bytes:usize)->Self{
+        self.file_size=bytes;
+        self
+    }
+
+    /// Bytes the log may grow by, or its size after the last compaction if larger, before it is compacted.
+    pub fn merge_size(mut self,bytes:usize)->Self{
+        self.merge_size=bytes;
+        self
+    }
+
+    pub fn durability(mut self,durability:Durability)->Self{
+        self.durability=durability;
+        self
+    }
+
+    pub fn index_kind(mut self,index_kind:IndexKind)->Self{
+        self.index_kind=index_kind;
+        self
+    }
+
+    /// Rebuilds the Bloom filter on open if it was built for another rate.
+    pub fn bloom_false_positive_rate(mut self,false_positive_rate:f64)->Self{
+        self.bloom_false_positive_rate=Some(false_positive_rate);
+        self
+    }
+
+    /// Bytes of values cached for the store and each namespace, 0 turns caching off.
+    pub fn cache_size(mut self,bytes:usize)->Self{
+        self.cache_capacity=bytes;
+        self
+    }
+
+    pub fn admission(mut self,admission:Admission)->Self{
+        self.admission=admission;
+        self
+    }
+
+    pub fn mmap_reads(mut self,enabled:bool)->Self{
+        self.mmap_reads=enabled;
+        self
+    }
+
+    /// Bytes per second a background compaction may read and write.
+    pub fn compaction_rate(mut self,bytes_per_sec:u64)->Self{
+        self.compaction_rate=Some(bytes_per_sec);
+        self
+    }
+
+    /// Bytes of sealed segments a compaction copies before swapping in what it copied and deleting
+    /// them, what it holds in memory grows with it. A segment larger than this is a chunk of its own.
+    pub fn compaction_chunk(mut self,bytes:usize)->Self{
+        self.compaction_chunk=bytes;
+        self
+    }
+
+    /// Whether a compaction runs alongside writes or the write starting it waits until it is done.
+    /// Waiting makes a store behave the same on every run, which simulations rely on.
+    pub fn background_compaction(mut self,enabled:bool)->Self{
+        self.background_compaction=enabled;
+        self
+    }
+
+    /// Seconds a compaction keeps the records of removes for, so replaying a backup taken before
+    /// them followed by the store (`recover`) does not bring the removed keys back.
+    /// Unset, they are dropped by the first compaction and such a replay is refused.
+    pub fn remove_retention(mut self,secs:u64)->Self{
+        self.remove_retention=Some(secs);
+        self
+    }
+
+    /// Opens an existing store without writing to its directory, every write fails.
+    pub fn read_only(mut self,read_only:bool)->Self{
+        self.read_only=read_only;
+        self
+    }
+
+    pub fn create_if_missing(mut self,create_if_missing:bool)->Self{
+        self.create_if_missing=create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self,error_if_exists:bool)->Self{
+        self.error_if_exists=error_if_exists;
+        self
+    }
+
+    /// Where the logs of the store and its namespaces are kept, the disk by default.
+    /// The other files of the store stay on the disk.
+    pub fn file_system(mut self,file_system:Arc<dyn FileSystem>)->Self{
+        self.file_system=FileSystemRef::Other(file_system);
+        self
+    }
+
+    /// Checks the options make sense together, `KvStore::open_with` does so before touching the disk.
+    pub fn validate(&self)->Result<()>{
+        if self.file_size==0{
+            return Err(KVError::ConfigError("file size must be greater than 0"));
+        }
+        if self.merge_size==0{
+            return Err(KVError::ConfigError("merge size must be greater than 0"));
+        }
+        if self.bloom_false_positive_rate.is_some_and(|rate|!(rate>0.0&&rate<1.0)){
+            return Err(KVError::ConfigError("false positive rate must be between 0 and 1"));
+        }
+        if self.compaction_rate==Some(0){
+            return Err(KVError::ConfigError("compaction rate must be greater than 0"));
+        }
+        if self.read_only&&self.error_if_exists{
+            return Err(KVError::ConfigError("a read only store must already exist"));
+        }
+        Ok(())
+    }
+}
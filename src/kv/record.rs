@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use super::{util::crc32, Operation};
+
+/// What the data log holds, an operation stamped with its place in the store's history.
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub struct Record{
+    pub seq:u64,
+    //milliseconds since the unix epoch
+    pub timestamp:u64,
+    //crc32 of the serialized operation
+    pub crc:u32,
+    pub op:Operation
+}
+
+impl Record {
+    pub fn new(seq:u64,timestamp:u64,op:Operation)->Record{
+        Record{
+            seq,
+            timestamp,
+            crc:checksum(&op),
+            op
+        }
+    }
+
+    pub fn verify(&self)->bool{
+        self.crc==checksum(&self.op)
+    }
+}
+
+/// A record as read back, logs written before records were stamped hold bare operations.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum StoredRecord{
+    Stamped(Record),
+    Legacy(Operation)
+}
+
+impl StoredRecord {
+    //legacy records all get seq 0, ahead of every stamped record
+    pub fn into_record(self)->Record{
+        match self {
+            StoredRecord::Stamped(record) => record,
+            StoredRecord::Legacy(op) => Record::new(0, 0, op),
+        }
+    }
+}
+
+fn checksum(op:&Operation)->u32{
+    crc32(&serde_json::to_vec(op).expect("Operations always serialize"))
+}
@@ -0,0 +1,252 @@
+use std::{cmp::Reverse, collections::{BTreeMap, BinaryHeap}, fmt::Display, fs::{read_dir, rename, File}, io::{BufReader, ErrorKind, Seek, SeekFrom, Write}, path::{Path, PathBuf}, rc::Rc, result, str::FromStr};
+
+use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
+
+use crate::KvsEngine;
+
+use super::{filesystem::OsFileSystem, history::History, namespace::NAMESPACE_DIR, record::{Record, StoredRecord}, replication::LogPosition, secondary::SecondaryIndexes, storage::{get_sorted_file_names, scan_segments}, Context, ErrorContext, KVError, KvStore, Result};
+
+const DROPPED_REMOVES_FILE:&str="dropped_removes.json";
+
+/// Where a recovery stops, the last seq or the last timestamp (milliseconds since the unix epoch) replayed.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Cutoff{
+    Seq(u64),
+    Timestamp(u64)
+}
+
+impl Cutoff {
+    fn includes(&self,record:&Record)->bool{
+        match self {
+            Cutoff::Seq(seq) => record.seq<=*seq,
+            Cutoff::Timestamp(timestamp) => record.timestamp<=*timestamp,
+        }
+    }
+}
+
+/// `seq:<n>` for a sequence number, a bare number for a timestamp.
+impl FromStr for Cutoff {
+    type Err=String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.strip_prefix("seq:") {
+            Some(seq) => seq.parse().map(Cutoff::Seq).map_err(|_|format!("invalid seq {}",seq)),
+            None => s.parse().map(Cutoff::Timestamp).map_err(|_|format!("invalid timestamp {}",s)),
+        }
+    }
+}
+
+#[derive(Debug,Default,PartialEq)]
+pub struct RecoveryReport{
+    pub replayed:usize,
+    //records past the cutoff
+    pub skipped:usize,
+    pub last_seq:Option<u64>,
+    pub last_timestamp:Option<u64>
+}
+
+impl Display for RecoveryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"replayed {} records, skipped {}",self.replayed,self.skipped)?;
+        if let (Some(seq),Some(timestamp))=(self.last_seq,self.last_timestamp){
+            write!(f,", up to seq {} at {}",seq,timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+/// The highest seq of the removes compactions dropped from the store at `root`, 0 if none.
+pub(super) fn dropped_removes(root:&Path)->Result<u64>{
+    let path=root.join(DROPPED_REMOVES_FILE);
+    match File::open(&path) {
+        Ok(file) => serde_json::from_reader(file).context(KVError::ParseError, "dropped_removes1").map_err(|e|e.with_path(&path)),
+        Err(e) if e.kind()==ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(KVError::IOError(ErrorContext::new("dropped_removes2").with_path(&path).with_source(e))),
+    }
+}
+
+/// Remembers that a compaction dropped removes up to `seq`, synced before the segments holding them go.
+pub(super) fn note_dropped_removes(root:&Path,seq:u64)->Result<()>{
+    let seq=seq.max(dropped_removes(root)?);
+    let path=root.join(DROPPED_REMOVES_FILE);
+    let tmp_path=path.with_extension("tmp");
+    let mut file=File::create(&tmp_path).context(KVError::IOError, "note_dropped_removes1")?;
+    file.write_all(seq.to_string().as_bytes()).context(KVError::WriteError, "note_dropped_removes2")?;
+    file.sync_all().context(KVError::WriteError, "note_dropped_removes3")?;
+    rename(tmp_path, &path).context(KVError::IOError, "note_dropped_removes4")
+}
+
+/// Rebuilds the stores at `sources`, oldest first (typically a backup followed by the live store),
+/// into a new store at `to`, replaying records up to `until`.
+///
+/// Records are replayed in seq order with records present in several sources replayed once.
+/// The new store keeps the secondary indexes and the version policy of the last source,
+/// so the versions it recorded are recorded again with their seqs.
+/// A merge keeps only the latest value of every key, so the state at a cutoff before the
+/// last merge of every source can only be rebuilt from a backup taken before it.
+/// A source that dropped removes newer than every source before it would bring their keys
+/// back, it is refused; `KvStoreOptions::remove_retention` keeps them for as long as backups are.
+///
+/// Namespaces are rebuilt the same way from the sources holding them. Their seqs are their own,
+/// so only a timestamp cutoff covers them; the report counts them, its last seq is the default namespace's.
+pub fn recover(sources:&[PathBuf],until:Cutoff,to:PathBuf)->Result<RecoveryReport>{
+    if to.join("data").read_dir().is_ok_and(|mut dir|dir.next().is_some()){
+        return Err(KVError::ConfigError("recovery target is not empty"));
+    }
+    let mut namespaces=BTreeMap::new();
+    for source in sources{
+        let Ok(dir)=read_dir(source.join(NAMESPACE_DIR)) else {
+            continue;
+        };
+        for entry in dir{
+            let entry=entry.context(KVError::IOError, "recover1").map_err(|e|e.with_path(source))?;
+            let name=entry.file_name().into_string().map_err(|_|KVError::ParseError(ErrorContext::new("recover2").with_path(entry.path())))?;
+            namespaces.entry(name).or_insert_with(Vec::new).push(entry.path());
+        }
+    }
+    if !namespaces.is_empty()&&matches!(until,Cutoff::Seq(_)){
+        return Err(KVError::ConfigError("the sources have namespaces, whose seqs a seq cutoff does not order, recover them until a timestamp"));
+    }
+
+    let mut store=KvStore::open(to)?;
+    let mut report=replay(sources, until, &mut store)?;
+    for (name,sources) in namespaces{
+        let root=sources.last().and_then(|dir|dir.parent()?.parent()).expect("A namespace has a source");
+        store.create_namespace(&name, KvStore::namespace_options(root, &name)?)?;
+        let namespace=store.namespaces.get_mut(&name).expect("Namespace just created");
+        let namespace_report=replay(&sources, until, namespace)?;
+        report.replayed+=namespace_report.replayed;
+        report.skipped+=namespace_report.skipped;
+    }
+    Ok(report)
+}
+
+//replays the logs of sources into store, which takes their last secondary indexes and version policy
+fn replay(sources:&[PathBuf],until:Cutoff,store:&mut KvStore)->Result<RecoveryReport>{
+    //a source is read once to check it and find where its seqs go down, then merged a record of every run at a time
+    let mut runs:Vec<Run>=Vec::new();
+    let mut last_seq=0;
+    for (index,source) in sources.iter().enumerate(){
+        if index>0&&dropped_removes(source)?>last_seq{
+            return Err(KVError::ConfigError("a source dropped removes newer than the backups before it, they would come back"));
+        }
+        let directory=source.join("data");
+        let segments:Rc<[(usize,PathBuf)]>=get_sorted_file_names(&OsFileSystem, &directory)?
+        .into_iter()
+        .map(|(serial,name)|(serial,directory.join(name)))
+        .collect();
+        let first_run=runs.len();
+        let mut previous=None;
+        scan_segments(&directory, |position,stored:StoredRecord|{
+            let record=stored.into_record();
+            if !record.verify(){
+                return Err(KVError::ReadError(ErrorContext::new("recover: checksum mismatch").with_path(source).at(position)));
+            }
+            last_seq=last_seq.max(record.seq);
+            if previous.is_none_or(|seq|record.seq<seq){
+                if let Some(run)=runs[first_run..].last_mut(){
+                    run.end=Some(position);
+                }
+                runs.push(Run::new(segments.clone(), position));
+            }
+            previous=Some(record.seq);
+            Ok(())
+        })?;
+    }
+
+    if let Some(source)=sources.last(){
+        for definition in SecondaryIndexes::load(source)?.definitions(){
+            store.create_index(&definition.name, &definition.pointer)?;
+        }
+        if let Some(policy)=History::load_policy(source)?{
+            store.enable_versioning(policy)?;
+        }
+    }
+
+    //unstamped records have seq 0, they come first in the order of their run
+    let mut heads=BinaryHeap::new();
+    let mut pending=Vec::new();
+    for (index,run) in runs.iter_mut().enumerate(){
+        let record=run.next()?;
+        if let Some(record)=&record{
+            heads.push(Reverse((record.seq,index)));
+        }
+        pending.push(record);
+    }
+    let mut report=RecoveryReport::default();
+    let mut replaying=true;
+    let mut last=None;
+    while let Some(Reverse((seq,index)))=heads.pop(){
+        let record=pending[index].take().expect("Pending record of a run in the heap");
+        if let Some(next)=runs[index].next()?{
+            heads.push(Reverse((next.seq,index)));
+            pending[index]=Some(next);
+        }
+        //the same record from another source
+        if seq!=0&&last==Some(seq){
+            continue;
+        }
+        last=Some(seq);
+        replaying=replaying&&until.includes(&record);
+        if !replaying{
+            report.skipped+=1;
+            continue;
+        }
+        report.replayed+=1;
+        report.last_seq=Some(record.seq);
+        report.last_timestamp=Some(record.timestamp);
+        store.apply_record(record)?;
+    }
+    Ok(report)
+}
+
+type SegmentStream=StreamDeserializer<'static,IoRead<BufReader<File>>,StoredRecord>;
+
+//records of a source from start on whose seqs only go up, until end where the next run starts
+struct Run{
+    start:LogPosition,
+    end:Option<LogPosition>,
+    //the segments of the source, and the next one of them to read
+    segments:Rc<[(usize,PathBuf)]>,
+    next_segment:usize,
+    stream:Option<(LogPosition,SegmentStream)>
+}
+
+impl Run {
+    fn new(segments:Rc<[(usize,PathBuf)]>,start:LogPosition)->Run{
+        Run{
+            start,
+            end:None,
+            next_segment:segments.partition_point(|(serial,_)|*serial<start.segment),
+            segments,
+            stream:None
+        }
+    }
+
+    fn next(&mut self)->Result<Option<Record>>{
+        loop {
+            let Some((start,stream))=self.stream.as_mut() else {
+                let Some((segment,path))=self.segments.get(self.next_segment) else {
+                    return Ok(None);
+                };
+                let segment=*segment;
+                self.next_segment+=1;
+                let offset=if segment==self.start.segment {self.start.offset} else {0};
+                let mut file=File::open(path).context(KVError::IOError, "Run::next1").map_err(|e|e.with_path(path))?;
+                file.seek(SeekFrom::Start(offset)).context(KVError::IOError, "Run::next2").map_err(|e|e.with_path(path))?;
+                self.stream=Some((LogPosition{segment,offset},Deserializer::from_reader(BufReader::new(file)).into_iter()));
+                continue;
+            };
+            let position=LogPosition{segment:start.segment,offset:start.offset+stream.byte_offset() as u64};
+            if self.end.is_some_and(|end|position>=end){
+                self.stream=None;
+                self.next_segment=self.segments.len();
+                return Ok(None);
+            }
+            match stream.next() {
+                Some(stored) => return Ok(Some(stored.context(KVError::ParseError, "Run::next3").map_err(|e|e.at(position))?.into_record())),
+                None => self.stream=None,
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fmt::Display, fs::{read, read_dir, rename, File, OpenOptions}, io::Write, ops::Range, path::{Path, PathBuf}};
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+use super::{filesystem::OsFileSystem, history::History, namespace::NAMESPACE_DIR, record::StoredRecord, storage::{compaction_path, get_sorted_file_names, remove_unfinished_compactions}, Context, ErrorContext, KVError, KvStore, KvsEngine, Operation, Result};
+
+pub const LOST_FOUND_FILE:&str="lost+found";
+
+/// Bytes of a segment a repair could not make sense of and moved to `lost+found`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct LostRegion{
+    pub segment:PathBuf,
+    pub offset:u64,
+    pub length:u64,
+    pub reason:&'static str
+}
+
+/// What a repair kept and what it set aside.
+#[derive(Debug,Default,PartialEq)]
+pub struct RepairReport{
+    pub segments:usize,
+    //segments rewritten without their lost regions
+    pub rewritten:usize,
+    pub records:usize,
+    pub lost:Vec<LostRegion>,
+    //live keys of the default namespace once repaired
+    pub keys:usize
+}
+
+impl RepairReport {
+    pub fn lost_bytes(&self)->u64{
+        self.lost.iter().map(|region|region.length).sum()
+    }
+}
+
+impl Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"scanned {} segments, kept {} records, {} live keys",self.segments,self.records,self.keys)?;
+        if self.lost.is_empty(){
+            return write!(f,", nothing lost");
+        }
+        write!(f,"\nlost {} bytes in {} regions, rewrote {} segments, the bytes are in {LOST_FOUND_FILE}",self.lost_bytes(),self.lost.len(),self.rewritten)?;
+        for region in self.lost.iter(){
+            write!(f,"\n  {} offset {}, {} bytes: {}",region.segment.display(),region.offset,region.length,region.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Scans every segment of the closed store at `root` and its namespaces, moves whatever
+    /// is not a valid record into a `lost+found` file next to the segments, and rewrites the
+    /// damaged segments with the records around it. The repaired store is opened to check it.
+    pub fn repair(root:impl Into<PathBuf>)->Result<RepairReport>{
+        let root:PathBuf=root.into();
+        if !root.join("data").is_dir(){
+            return Err(KVError::ConfigError("store does not exist"));
+        }
+        let mut report=RepairReport::default();
+        for store in store_dirs(&root)?{
+            repair_segments(&store.join("data"), &store, &mut report, check_record)?;
+            History::repair(&store, &mut report)?;
+        }
+        report.keys=KvStore::open(root)?.keys()?.len();
+        Ok(report)
+    }
+}
+
+/// The directories of the store at `root` and of its namespaces.
+pub(super) fn store_dirs(root:&Path)->Result<Vec<PathBuf>>{
+    let mut stores=vec![root.to_path_buf()];
+    if let Ok(namespaces)=read_dir(root.join(NAMESPACE_DIR)){
+        for entry in namespaces{
+            stores.push(entry.context(KVError::IOError, "store_dirs1")?.path());
+        }
+    }
+    Ok(stores)
+}
+
+pub(super) fn check_record(stored:StoredRecord)->Option<&'static str>{
+    match stored {
+        StoredRecord::Stamped(record) if !record.verify() => Some("checksum mismatch"),
+        StoredRecord::Stamped(record) if matches!(record.op,Operation::Get(_)) => Some("a get in the log"),
+        StoredRecord::Legacy(Operation::Get(_)) => Some("a get in the log"),
+        _ => None,
+    }
+}
+
+/// Repairs the segments in `directory` holding `T` records, `check` tells why a record that parses is still not valid.
+pub(super) fn repair_segments<T,F>(directory:&Path,root:&Path,report:&mut RepairReport,check:F)->Result<()>
+where
+    T: DeserializeOwned,
+    F: Fn(T)->Option<&'static str>
+{
+    if !directory.is_dir(){
+        return Ok(());
+    }
+    remove_unfinished_compactions(&OsFileSystem, directory)?;
+    for (serial,file_name) in get_sorted_file_names(&OsFileSystem, directory)?{
+        let path=directory.join(file_name);
+        let bytes=read(&path).context(KVError::IOError, "repair_segments1").map_err(|e|e.with_path(&path))?;
+        let (kept,lost)=scan::<T,F>(&bytes, &check);
+        report.segments+=1;
+        report.records+=kept.len();
+        if lost.is_empty(){
+            continue;
+        }
+        quarantine(root, &path, &bytes, &lost)?;
+        //the segment is replaced whole, a crash before the rename leaves a tmp file the next open deletes
+        let tmp_path=compaction_path(directory, serial);
+        let mut file=File::create(&tmp_path).context(KVError::IOError, "repair_segments2")?;
+        for range in kept{
+            file.write_all(&bytes[range]).context(KVError::WriteError, "repair_segments3")?;
+        }
+        file.sync_all().context(KVError::WriteError, "repair_segments4")?;
+        rename(&tmp_path, &path).context(KVError::IOError, "repair_segments5").map_err(|e|e.with_path(&path))?;
+        report.rewritten+=1;
+        report.lost.extend(lost.into_iter().map(|(range,reason)|LostRegion{
+            segment:path.clone(),
+            offset:range.start as u64,
+            length:range.len() as u64,
+            reason
+        }));
+    }
+    Ok(())
+}
+
+//splits a segment into the byte ranges of valid records and of everything else
+#[allow(clippy::type_complexity)]
+pub(super) fn scan<T,F>(bytes:&[u8],check:&F)->(Vec<Range<usize>>,Vec<(Range<usize>,&'static str)>)
+where
+    T: DeserializeOwned,
+    F: Fn(T)->Option<&'static str>
+{
+    let mut kept=Vec::new();
+    let mut lost=Vec::new();
+    let mut lost_from:Option<(usize,&'static str)>=None;
+    let mut pos=0;
+    while pos<bytes.len(){
+        if bytes[pos].is_ascii_whitespace(){
+            pos+=1;
+            continue;
+        }
+        match parse_one(&bytes[pos..], check) {
+            Ok(len) => {
+                if let Some((start,reason))=lost_from.take(){
+                    lost.push((start..pos,reason));
+                }
+                kept.push(pos..pos+len);
+                pos+=len;
+            },
+            Err((skip,reason)) => {
+                lost_from.get_or_insert((pos,reason));
+                pos=match skip {
+                    Some(len) => pos+len,
+                    None => next_record_start(bytes, pos+1),
+                };
+            },
+        }
+    }
+    if let Some((start,reason))=lost_from{
+        lost.push((start..bytes.len(),reason));
+    }
+    (kept,lost)
+}
+
+//the length of the record at the start of `bytes`, or why it is not one and,
+//when it is still well formed json, the length of the value to skip
+fn parse_one<T,F>(bytes:&[u8],check:&F)->std::result::Result<usize,(Option<usize>,&'static str)>
+where
+    T: DeserializeOwned,
+    F: Fn(T)->Option<&'static str>
+{
+    let mut stream=serde_json::Deserializer::from_slice(bytes).into_iter::<T>();
+    match stream.next() {
+        Some(Ok(record)) => match check(record) {
+            None => Ok(stream.byte_offset()),
+            Some(reason) => Err((Some(stream.byte_offset()),reason)),
+        },
+        Some(Err(error)) if error.is_eof() => Err((None,"truncated record")),
+        _ => {
+            let mut values=serde_json::Deserializer::from_slice(bytes).into_iter::<IgnoredAny>();
+            match values.next() {
+                Some(Ok(_)) => Err((Some(values.byte_offset()),"not a record")),
+                _ => Err((None,"unreadable bytes")),
+            }
+        },
+    }
+}
+
+//a '{' right after ':', ',' or '[' is an object nested in a damaged record,
+//any other one may start the next record
+fn next_record_start(bytes:&[u8],from:usize)->usize{
+    (from..bytes.len())
+    .find(|&pos|bytes[pos]==b'{'&&!matches!(bytes[..pos].iter().rev().find(|byte|!byte.is_ascii_whitespace()),Some(b':'|b','|b'[')))
+    .unwrap_or(bytes.len())
+}
+
+//appends every lost region of a segment to the store's lost+found file, each after a line saying where it came from
+fn quarantine(root:&Path,segment:&Path,bytes:&[u8],lost:&[(Range<usize>,&'static str)])->Result<()>{
+    let lost_found=root.join(LOST_FOUND_FILE);
+    let mut file=OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&lost_found)
+    .map_err(|error|KVError::IOError(ErrorContext::new("quarantine1").with_path(&lost_found).with_source(error)))?;
+    for (range,reason) in lost{
+        writeln!(file,"--- {} offset {} length {}: {}",segment.display(),range.start,range.len(),reason).context(KVError::WriteError, "quarantine2")?;
+        file.write_all(&bytes[range.clone()]).context(KVError::WriteError, "quarantine3")?;
+        writeln!(file).context(KVError::WriteError, "quarantine4")?;
+    }
+    file.sync_all().context(KVError::WriteError, "quarantine5")
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use super::{namespace::NamespaceOptions, secondary::IndexDefinition, Operation};
+
+/// Position in a store's log, the segment serial and the byte offset inside it.
+#[derive(Deserialize,Serialize,Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct LogPosition{
+    pub segment:usize,
+    pub offset:u64
+}
+
+/// What a follower sets up besides the keys: the leader's namespaces, listed on the
+/// batches of the default one only, and the secondary indexes of the store replicated.
+#[derive(Deserialize,Serialize,Debug,Clone,Default,PartialEq)]
+pub struct ReplicatedSchema{
+    pub namespaces:Vec<(String,NamespaceOptions)>,
+    pub indexes:Vec<IndexDefinition>
+}
+
+/// Unit of data sent from a leader to a follower.
+///
+/// A follower without a position, or whose position points at a segment that
+/// has since been compacted away, copies a `Snapshot` of every live key a page at a time.
+/// Otherwise it receives the log records written after its position.
+#[derive(Deserialize,Serialize,Debug)]
+pub enum ReplicationBatch{
+    //pages only hold records written before `position`, the ones after it are replayed once the copy is done
+    Snapshot{
+        entries:Vec<(String,String)>,
+        position:LogPosition,
+        //where the next page starts, none on the last one
+        next:Option<LogPosition>,
+        //the first page of a snapshot, what the follower held before is dropped
+        first:bool,
+        lag_bytes:u64,
+        schema:ReplicatedSchema
+    },
+    Records{
+        operations:Vec<Operation>,
+        position:LogPosition,
+        lag_bytes:u64,
+        schema:ReplicatedSchema
+    }
+}
+
+impl ReplicationBatch {
+    pub fn position(&self)->LogPosition{
+        match self {
+            ReplicationBatch::Snapshot { position, .. } => *position,
+            ReplicationBatch::Records { position, .. } => *position,
+        }
+    }
+
+    pub fn schema(&self)->&ReplicatedSchema{
+        match self {
+            ReplicationBatch::Snapshot { schema, .. } => schema,
+            ReplicationBatch::Records { schema, .. } => schema,
+        }
+    }
+
+    pub fn lag_bytes(&self)->u64{
+        match self {
+            ReplicationBatch::Snapshot { lag_bytes, .. } => *lag_bytes,
+            ReplicationBatch::Records { lag_bytes, .. } => *lag_bytes,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::{rename, File}, io::{BufReader, BufWriter, ErrorKind}, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{filesystem::{FileSystem, OsFileSystem}, Context, ErrorContext, KVError, Operation, Result};
+
+const INDEX_FILE:&str="indexes.json";
+
+/// A secondary index over the JSON field `pointer` points to, e.g. `/user_id`.
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub struct IndexDefinition{
+    pub name:String,
+    pub pointer:String
+}
+
+struct SecondaryIndex{
+    pointer:String,
+    //indexed value -> keys holding it
+    entries:HashMap<String,BTreeSet<String>>,
+    //key -> indexed value, so an overwrite can find the entry it replaces
+    values:HashMap<String,String>
+}
+
+impl SecondaryIndex {
+    fn new(pointer:String)->SecondaryIndex{
+        SecondaryIndex{
+            pointer,
+            entries:HashMap::new(),
+            values:HashMap::new()
+        }
+    }
+
+    fn set(&mut self,key:&str,value:&str){
+        self.remove(key);
+        if let Some(indexed)=indexed_value(value, &self.pointer){
+            self.entries.entry(indexed.clone()).or_default().insert(key.to_string());
+            self.values.insert(key.to_string(), indexed);
+        }
+    }
+
+    fn remove(&mut self,key:&str){
+        let Some(indexed)=self.values.remove(key) else {
+            return;
+        };
+        if let Some(keys)=self.entries.get_mut(&indexed){
+            keys.remove(key);
+            if keys.is_empty(){
+                self.entries.remove(&indexed);
+            }
+        }
+    }
+}
+
+/// The secondary indexes of one store, only their definitions are persisted,
+/// the entries are rebuilt from the live values on open.
+pub(super) struct SecondaryIndexes{
+    indexes:BTreeMap<String,SecondaryIndex>
+}
+
+impl SecondaryIndexes {
+    pub fn load(root:&Path)->Result<SecondaryIndexes>{
+        let definitions:Vec<IndexDefinition>=match File::open(root.join(INDEX_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).context(KVError::ParseError, "SecondaryIndexes::load1").map_err(|e|e.with_path(root.join(INDEX_FILE)))?,
+            Err(e) if e.kind()==ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(KVError::IOError(ErrorContext::new("SecondaryIndexes::load2").with_path(root.join(INDEX_FILE)).with_source(e))),
+        };
+        Ok(SecondaryIndexes{
+            indexes:definitions
+            .into_iter()
+            .map(|definition|(definition.name,SecondaryIndex::new(definition.pointer)))
+            .collect()
+        })
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.indexes.is_empty()
+    }
+
+    pub fn build(&mut self,operations:impl Iterator<Item = Result<Operation>>)->Result<()>{
+        for operation in operations{
+            if let Operation::Set(key,value)=operation?{
+                self.set(&key, &value);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self,key:&str,value:&str){
+        for index in self.indexes.values_mut(){
+            index.set(key, value);
+        }
+    }
+
+    pub fn remove(&mut self,key:&str){
+        for index in self.indexes.values_mut(){
+            index.remove(key);
+        }
+    }
+
+    pub fn create(&mut self,root:&Path,definition:IndexDefinition,operations:impl Iterator<Item = Result<Operation>>)->Result<()>{
+        if self.indexes.contains_key(&definition.name){
+            return Err(KVError::ConfigError("index already exists"));
+        }
+        if !definition.pointer.is_empty()&&!definition.pointer.starts_with('/'){
+            return Err(KVError::ConfigError("index pointer must start with /"));
+        }
+        let mut index=SecondaryIndex::new(definition.pointer);
+        for operation in operations{
+            if let Operation::Set(key,value)=operation?{
+                index.set(&key, &value);
+            }
+        }
+        self.indexes.insert(definition.name, index);
+        self.save(root)
+    }
+
+    pub fn drop(&mut self,root:&Path,name:&str)->Result<()>{
+        self.indexes.remove(name).ok_or(KVError::IndexNotFound(name.to_string()))?;
+        self.save(root)
+    }
+
+    pub fn query(&self,name:&str,value:&str)->Result<Vec<String>>{
+        let index=self.indexes.get(name).ok_or(KVError::IndexNotFound(name.to_string()))?;
+        Ok(index.entries.get(value).map(|keys|keys.iter().cloned().collect()).unwrap_or_default())
+    }
+
+    pub fn definitions(&self)->Vec<IndexDefinition>{
+        self.indexes
+        .iter()
+        .map(|(name,index)|IndexDefinition{name:name.clone(),pointer:index.pointer.clone()})
+        .collect()
+    }
+
+    //written and synced next to the old file, then renamed over it so a crash never leaves half a definition list
+    fn save(&self,root:&Path)->Result<()>{
+        let tmp_path=root.join(format!("{}.tmp",INDEX_FILE));
+        let file=File::create(&tmp_path).context(KVError::IOError, "SecondaryIndexes::save1")?;
+        let mut writer=BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &self.definitions()).context(KVError::WriteError, "SecondaryIndexes::save2")?;
+        let file=writer.into_inner().map_err(|e|e.into_error()).context(KVError::WriteError, "SecondaryIndexes::save3")?;
+        file.sync_all().context(KVError::WriteError, "SecondaryIndexes::save4")?;
+        rename(tmp_path, root.join(INDEX_FILE)).context(KVError::IOError, "SecondaryIndexes::save5")?;
+        OsFileSystem.sync_dir(root).context(KVError::IOError, "SecondaryIndexes::save6").map_err(|e|e.with_path(root))
+    }
+}
+
+/// The field of `value` at `pointer` as it is matched by queries, strings without their quotes
+/// and anything else in its JSON form. Values that are not JSON or lack the field are not indexed.
+pub fn indexed_value(value:&str,pointer:&str)->Option<String>{
+    let document:Value=serde_json::from_str(value).ok()?;
+    match document.pointer(pointer)? {
+        Value::Null => None,
+        Value::String(field) => Some(field.clone()),
+        field => Some(field.to_string()),
+    }
+}
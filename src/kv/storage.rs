@@ -1,23 +1,43 @@
-use std::{cell::RefCell, collections::BTreeMap, ffi::OsString, fs::{read_dir, remove_file, DirBuilder, File, OpenOptions}, io::{BufReader, Read, Seek, Write}, ops::DerefMut, path::PathBuf, rc::Rc, result, str::FromStr};
+use std::{cell::RefCell, collections::BTreeMap, ffi::OsString, io::{BufReader, Read, Seek}, ops::{DerefMut, Range}, path::{Path, PathBuf}, rc::Rc, str::FromStr, sync::Arc};
 
+use memmap2::Mmap;
 
+
+use super::filesystem::{FileReader, FileSystem, LogFile, OsFileSystem};
+use super::replication::LogPosition;
 use super::util::OffsetStreamSerializer;
-use super::{Result,KVError};
-use crate::common::KILOBYTE;
+use super::options::{Durability, KvStoreOptions};
+use super::{Context,ErrorContext,Result,KVError};
 
 const _: () = assert!(std::mem::size_of::<u64>()<=std::mem::size_of::<usize>());
 
-type FileReadBufRef=Rc<RefCell<BufReader<File>>>;
+type FileReadBufRef=Rc<Segment>;
+
+/// Segment serials with the path of their file.
+pub type SegmentFiles=Vec<(usize,PathBuf)>;
+
+const TMP_EXTENSION:&str="tmp";
+
+/// One segment file's reader, and once the segment is sealed and mmap reads are on, its mapping.
+pub struct Segment{
+    reader:RefCell<BufReader<FileReader>>,
+    mmap:RefCell<Option<Mmap>>
+}
 
 pub struct LogStorage{
+    fs:Arc<dyn FileSystem>,
     directory:PathBuf,
     cur_file_size:usize,
     file_size_limit:usize,
 
     cur_storage_size:usize,
 
-    cur_write_file:File,
+    //none when the store is read only
+    cur_write_file:Option<Box<dyn LogFile>>,
+    durability:Durability,
     read_file_buffers:BTreeMap<usize,FileReadBufRef>,
+    //whether sealed segments, every one but the last, are read through a mapping
+    mmap_reads:bool
 }
 
 
@@ -31,29 +51,57 @@ pub struct LogPointer{
 struct FileReadBufRefWrapper(FileReadBufRef);
 
 impl LogStorage {
-    pub fn load(directory:PathBuf)->Result<LogStorage>{
+    /// Loads the segments in `directory`, a read only storage neither creates it nor starts a new segment.
+    pub fn open(directory:PathBuf,options:&KvStoreOptions)->Result<LogStorage>{
         assert!(!directory.exists()||directory.is_dir(),"Expected directory for database {:?}",directory);
-        DirBuilder::new().recursive(true).create(directory.clone()).map_err(|_|KVError::IOError("LogStorage::load1"))?;
+        let fs=options.file_system.get();
+        if options.read_only{
+            let (cur_storage_size,read_file_pool)=Self::load_persisted_files(fs.as_ref(), &directory)?;
+            if read_file_pool.is_empty(){
+                return Err(KVError::ConfigError("a read only store needs at least one segment"));
+            }
+            return Ok(LogStorage{
+                fs,
+                directory,
+                cur_file_size:0,
+                file_size_limit:options.file_size,
+                cur_storage_size,
+                cur_write_file:None,
+                durability:options.durability,
+                read_file_buffers:read_file_pool,
+                mmap_reads:false
+            });
+        }
+        fs.create_dir_all(&directory).context(KVError::IOError, "LogStorage::load1").map_err(|e|e.with_path(&directory))?;
         
-        let (cur_storage_size,mut read_file_pool)=Self::load_persisted_files(&directory)?;
-        let new_file_serial=read_file_pool
-        .last_entry()
-        .map(|entry|entry.key()+1)
-        .unwrap_or(0);
+        remove_unfinished_compactions(fs.as_ref(), &directory)?;
+        let (cur_storage_size,mut read_file_pool)=Self::load_persisted_files(fs.as_ref(), &directory)?;
+        let new_file_serial=match read_file_pool.last_key_value() {
+            //left empty by the open before, so opening again adds no segment
+            Some((serial,segment)) if segment.size().context(KVError::IOError, "LogStorage::load3")?==0 => *serial,
+            Some((serial,_)) => serial+1,
+            None => 0,
+        };
 
         let cur_file_path=directory.join(new_file_serial.to_string());
-        let cur_write_file=Self::new_log_file(cur_file_path.clone())?;
-        let cur_read_file=new_file_read_buf_ref(Self::get_log_file(cur_file_path)?);
+        let cur_write_file=Self::new_log_file(fs.as_ref(), &cur_file_path)?;
+        if options.durability==Durability::Sync{
+            fs.sync_dir(&directory).context(KVError::IOError, "LogStorage::load2").map_err(|e|e.with_path(&directory))?;
+        }
+        let cur_read_file=new_file_read_buf_ref(Self::get_log_file(fs.as_ref(), &cur_file_path)?);
 
         read_file_pool.insert(new_file_serial,cur_read_file.clone());
 
         Ok(LogStorage{
+            fs,
             directory,
             cur_file_size:0,
-            file_size_limit:4*KILOBYTE,
+            file_size_limit:options.file_size,
             cur_storage_size,
-            cur_write_file,
-            read_file_buffers:read_file_pool
+            cur_write_file:Some(cur_write_file),
+            durability:options.durability,
+            read_file_buffers:read_file_pool,
+            mmap_reads:false
         })
     }
 
@@ -61,54 +109,31 @@ impl LogStorage {
     where 
         T:serde::ser::Serialize
     {
-        let output_bytes=serde_json::to_vec(&data).map_err(|_|KVError::ParseError("LogStorage::write1"))?;
+        let output_bytes=serde_json::to_vec(&data).context(KVError::ParseError, "LogStorage::write1")?;
         self.write_bytes(&output_bytes)
     }
     
-    pub fn write_iter<T,D>(&mut self,iter:T)->Result<Vec<(LogPointer,D)>>
-    where
-        T: Iterator<Item = D>,
-        D: serde::ser::Serialize
-    {
-        let parsed=iter
-        .map(|data|serde_json::to_vec(&data).map(|bytes|(bytes,data)))
-        .collect::<result::Result<Vec<_>,_>>()
-        .map_err(|_|KVError::ParseError("LogStorage::write_iter1"))?;
-
-        parsed
-        .into_iter()
-        .map(|(parsed,data)|self.write_bytes(&parsed).map(|log_ptr|(log_ptr,data)))
-        .collect()
-    }
-
-    pub fn merge<T,D>(&mut self,file_serials:&[usize],merged_data:T)->Result<Vec<(LogPointer,D)>>
-    where
-        T: IntoIterator<Item = D>,
-        D: serde::ser::Serialize
-    {
-        self.replace_write_file()?;
-        let res=self.write_iter(merged_data.into_iter());
-        for serial in file_serials{
-            let path=self.directory.join(serial.to_string());
-            self.read_file_buffers.remove(serial);
-            remove_file(path).map_err(|_|KVError::IOError("LogStorage::merge1"))?
-        }
-
-        res
-    }
-
     pub fn iter_entries<'a,T>(&'a self)->impl Iterator<Item = Result<(LogPointer,T)>>+'a
     where
         T: serde::de::DeserializeOwned+'a
     {
+        //every segment is scanned through its own handle, so reads through the pointers can seek meanwhile
         self.read_file_buffers
         .iter()
         .flat_map(|(serial,file_buf)|{
-            let wrapped_buf_ref=FileReadBufRefWrapper(file_buf.clone());
-            let stream=serde_json::Deserializer::from_reader(wrapped_buf_ref);
-            let offset_stream=OffsetStreamSerializer::new(stream.into_iter::<T>());
-            
-            offset_stream
+            let path=self.directory.join(serial.to_string());
+            let scan_file=self.fs.open_read(&path).context(KVError::IOError, "LogStorage::iter_entries1").map_err(|e|e.with_path(&path));
+            let (scan_file,open_error)=match scan_file {
+                Ok(file) => (Some(file),None),
+                Err(err) => (None,Some(Err(err))),
+            };
+            let offset_stream=scan_file
+            .into_iter()
+            .flat_map(|file|OffsetStreamSerializer::new(*serial,serde_json::Deserializer::from_reader(BufReader::new(FileReader::new(file))).into_iter::<T>()));
+
+            open_error
+            .into_iter()
+            .chain(offset_stream)
             .map(|res|
                 res.map(|(offset,parsed)|(LogPointer::new(*serial,offset,file_buf.clone()),parsed))
             )
@@ -123,15 +148,230 @@ impl LogStorage {
         self.cur_storage_size
     }
 
+    pub fn head_position(&self)->LogPosition{
+        let (segment,_)=self.read_file_buffers.last_key_value().expect("Always at least 1 file");
+        LogPosition{
+            segment:*segment,
+            offset:self.cur_file_size as u64
+        }
+    }
+
+    pub fn pointer(&self,position:LogPosition)->Result<LogPointer>{
+        let file_buf=self.read_file_buffers.get(&position.segment).ok_or(KVError::ReadError("LogStorage::pointer".into()))?;
+        Ok(LogPointer::new(position.segment, position.offset, file_buf.clone()))
+    }
+
+    /// Reads sealed segments through a memory mapping instead of seeking their file,
+    /// the segment being written keeps using the file.
+    pub fn set_mmap_reads(&mut self,enabled:bool)->Result<()>{
+        self.mmap_reads=enabled;
+        let sealed=self.read_file_buffers.len()-1;
+        for segment in self.read_file_buffers.values().take(sealed){
+            if enabled{
+                segment.map()?;
+            } else {
+                segment.mmap.borrow_mut().take();
+            }
+        }
+        Ok(())
+    }
+
+    /// Seals every segment for a background compaction, returning them with the serials left free
+    /// for the compacted segments so they sort after them and before everything written meanwhile.
+    pub fn seal_for_compaction(&mut self)->Result<(SegmentFiles,Range<usize>)>{
+        let sealed:Vec<_>=self.read_file_buffers
+        .keys()
+        .map(|serial|(*serial,self.directory.join(serial.to_string())))
+        .collect();
+        let first=sealed.last().map(|(serial,_)|serial+1).expect("Always at least 1 file");
+        //filling segments in order, any two consecutive ones hold more than the limit,
+        //and every chunk, which takes at least one segment, may leave a last one part full
+        let outputs=2*self.cur_storage_size.div_ceil(self.file_size_limit)+3*sealed.len()+1;
+        self.start_segment(first+outputs)?;
+        Ok((sealed,first..first+outputs))
+    }
+
+    pub fn directory(&self)->&Path{
+        &self.directory
+    }
+
+    pub fn file_system(&self)->Arc<dyn FileSystem>{
+        self.fs.clone()
+    }
+
+    pub fn file_size_limit(&self)->usize{
+        self.file_size_limit
+    }
+
+    /// Moves the compacted segment `serial` in place, its records can be pointed to from then on.
+    pub fn install_compacted(&mut self,serial:usize)->Result<()>{
+        let path=self.directory.join(serial.to_string());
+        self.fs.rename(&compaction_path(&self.directory, serial), &path).context(KVError::IOError, "LogStorage::install_compacted1")?;
+        let file=Self::get_log_file(self.fs.as_ref(), &path)?;
+        self.cur_storage_size+=file.size().context(KVError::IOError, "LogStorage::install_compacted2")? as usize;
+        let segment=new_file_read_buf_ref(file);
+        if self.mmap_reads{
+            segment.map()?;
+        }
+        self.read_file_buffers.insert(serial, segment);
+        Ok(())
+    }
+
+    /// Deletes segments nothing points into anymore, oldest first. Each removal is synced before the
+    /// next, so a power loss never keeps a set whose newer remove was deleted.
+    pub fn remove_segments(&mut self,serials:&[usize])->Result<()>{
+        let mut serials=serials.to_vec();
+        serials.sort_unstable();
+        for serial in serials{
+            if let Some(file_buf)=self.read_file_buffers.remove(&serial){
+                let len=file_buf.size().context(KVError::IOError, "LogStorage::remove_segments1")?;
+                self.cur_storage_size=self.cur_storage_size.saturating_sub(len as usize);
+            }
+            self.fs.remove(&self.directory.join(serial.to_string())).context(KVError::IOError, "LogStorage::remove_segments2")?;
+            self.sync_directory()?;
+        }
+        Ok(())
+    }
+
+    /// Makes the segments created, installed and removed so far survive a power loss.
+    pub fn sync_directory(&self)->Result<()>{
+        self.fs.sync_dir(&self.directory).context(KVError::IOError, "LogStorage::sync_directory").map_err(|e|e.with_path(&self.directory))
+    }
+
+    /// Every record starts before this offset in its segment: new ones before the size limit,
+    /// those of a segment written under a larger limit before its size.
+    pub fn offset_bound(&self)->Result<u64>{
+        let mut bound=self.file_size_limit as u64;
+        for file_buf in self.read_file_buffers.values(){
+            bound=bound.max(file_buf.size().context(KVError::IOError, "LogStorage::offset_bound")?);
+        }
+        Ok(bound)
+    }
+
+    pub fn segment_size(&self,segment:usize)->Result<u64>{
+        let file_buf=self.read_file_buffers.get(&segment).ok_or(KVError::ReadError("LogStorage::segment_size1".into()))?;
+        file_buf.size().context(KVError::IOError, "LogStorage::segment_size2")
+    }
+
+    /// Returns once what was written to the current segment is on disk, whatever the durability.
+    pub fn sync(&mut self)->Result<()>{
+        match self.cur_write_file.as_mut() {
+            Some(file) => file.sync().context(KVError::WriteError, "LogStorage::sync"),
+            None => Ok(()),
+        }
+    }
+
+    pub fn contains_segment(&self,segment:usize)->bool{
+        self.read_file_buffers.contains_key(&segment)
+    }
+
+    /// Reads the records at `positions`, in their order, through handles of their own opened now,
+    /// so it can go on from another thread while the log is written and compacted meanwhile.
+    pub fn detached_reader<T>(&self,positions:Vec<LogPosition>)->Result<impl Iterator<Item = Result<T>>+Send+use<T>>
+    where
+        T: serde::de::DeserializeOwned
+    {
+        let mut readers=BTreeMap::new();
+        for position in positions.iter(){
+            if let std::collections::btree_map::Entry::Vacant(entry)=readers.entry(position.segment){
+                let path=self.directory.join(position.segment.to_string());
+                let file=self.fs.open_read(&path).context(KVError::IOError, "LogStorage::detached_reader1").map_err(|e|e.with_path(&path))?;
+                entry.insert(BufReader::new(FileReader::new(file)));
+            }
+        }
+        Ok(positions.into_iter().map(move |position|{
+            let reader=readers.get_mut(&position.segment).expect("Opened above");
+            //positions in order mostly move forward within what is buffered
+            let current=reader.stream_position().context(KVError::IOError, "LogStorage::detached_reader2")?;
+            match position.offset.checked_sub(current) {
+                Some(forward) => reader.seek_relative(forward as i64),
+                None => reader.seek(std::io::SeekFrom::Start(position.offset)).map(|_|()),
+            }.context(KVError::IOError, "LogStorage::detached_reader3")?;
+            serde_json::Deserializer::from_reader(reader)
+            .into_iter::<T>()
+            .next()
+            .unwrap_or_else(||Err(serde::de::Error::custom("no record")))
+            .context(KVError::ParseError, "LogStorage::detached_reader4")
+            .map_err(|e|e.at(position))
+        }))
+    }
+
+    /// Every record of one segment, so it can be rewritten on its own.
+    pub fn read_segment<T>(&self,segment:usize)->Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned
+    {
+        let file_buf=self.read_file_buffers.get(&segment).ok_or(KVError::ReadError("LogStorage::read_segment1".into()))?;
+        file_buf.reader.borrow_mut().seek(std::io::SeekFrom::Start(0)).context(KVError::IOError, "LogStorage::read_segment2")?;
+        serde_json::Deserializer::from_reader(FileReadBufRefWrapper(file_buf.clone()))
+        .into_iter::<T>()
+        .map(|parsed|parsed.context(KVError::ParseError, "LogStorage::read_segment3"))
+        .collect()
+    }
+
+    //reads at most limit records written after position, returns them with the position after the last one
+    pub fn read_from<T>(&self,position:LogPosition,limit:usize)->Result<(Vec<T>,LogPosition)>
+    where
+        T: serde::de::DeserializeOwned
+    {
+        let mut records=Vec::new();
+        let mut end=position;
+
+        for (serial,file_buf) in self.read_file_buffers.range(position.segment..){
+            if records.len()>=limit{
+                break;
+            }
+            let start=if *serial==position.segment {position.offset} else {0};
+            end=LogPosition{segment:*serial,offset:start};
+
+            file_buf.reader.borrow_mut().seek(std::io::SeekFrom::Start(start)).context(KVError::IOError, "LogStorage::read_from1")?;
+            let wrapped_buf_ref=FileReadBufRefWrapper(file_buf.clone());
+            let mut stream=serde_json::Deserializer::from_reader(wrapped_buf_ref).into_iter::<T>();
+            while records.len()<limit{
+                match stream.next() {
+                    Some(parsed) => {
+                        records.push(parsed.context(KVError::ParseError, "LogStorage::read_from2")?);
+                        end.offset=start+stream.byte_offset() as u64;
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        Ok((records,end))
+    }
+
+    //number of bytes written after position
+    pub fn bytes_after(&self,position:LogPosition)->Result<u64>{
+        let mut total=0;
+        for (serial,file_buf) in self.read_file_buffers.range(position.segment..){
+            let len=file_buf.size().context(KVError::IOError, "LogStorage::bytes_after")?;
+            total+=if *serial==position.segment {len.saturating_sub(position.offset)} else {len};
+        }
+        Ok(total)
+    }
+
     fn write_bytes(&mut self,bytes:&[u8])->Result<LogPointer>{
+        if self.cur_write_file.is_none(){
+            return Err(KVError::Unsupported("store is read only"));
+        }
         let data_size=bytes.len();
         if data_size+self.cur_file_size>self.file_size_limit{
             self.replace_write_file()?
         }
 
         let (file_serial,read_buf_ref)=self.read_file_buffers.last_key_value().expect("Always at least 1 file");
-        let offset=self.cur_write_file.stream_position().map_err(|_|KVError::IOError("LogStorage::write_bytes1"))?;
-        self.cur_write_file.write_all(bytes).map_err(|_|KVError::WriteError("LogStorage::write_bytes2"))?;
+        let write_file=self.cur_write_file.as_mut().expect("checked above");
+        let offset=write_file.size().context(KVError::IOError, "LogStorage::write_bytes1")?;
+        let mut written=write_file.append(bytes).context(KVError::WriteError, "LogStorage::write_bytes2");
+        if written.is_ok()&&self.durability==Durability::Sync{
+            written=write_file.sync().context(KVError::WriteError, "LogStorage::write_bytes3");
+        }
+        if let Err(error)=written{
+            //a record that failed is not left half written in front of the next one
+            write_file.truncate(offset).context(KVError::WriteError, "LogStorage::write_bytes4")?;
+            return Err(error);
+        }
         self.cur_file_size+=data_size;
         self.cur_storage_size+=data_size;
 
@@ -146,56 +386,63 @@ impl LogStorage {
 
     fn replace_write_file(&mut self)->Result<()>{
         let new_file_serial=self.read_file_buffers.last_entry().expect("Always at least 1 file").key()+1;
+        self.start_segment(new_file_serial)
+    }
+
+    fn start_segment(&mut self,new_file_serial:usize)->Result<()>{
         let new_file_path=self.directory.join(new_file_serial.to_string());
 
-        let new_write_file=Self::new_log_file(new_file_path.clone())?;
+        let new_write_file=Self::new_log_file(self.fs.as_ref(), &new_file_path)?;
+        //a synced record is only kept if the segment holding it is
+        if self.durability==Durability::Sync{
+            self.sync_directory()?;
+        }
 
-        let new_read_file_buf=new_file_read_buf_ref(Self::get_log_file(new_file_path)?);
+        let new_read_file_buf=new_file_read_buf_ref(Self::get_log_file(self.fs.as_ref(), &new_file_path)?);
 
+        //the current last segment is sealed from now on
+        if self.mmap_reads{
+            if let Some((_,sealed))=self.read_file_buffers.last_key_value(){
+                sealed.map()?;
+            }
+        }
         self.read_file_buffers.insert(new_file_serial,new_read_file_buf);
-        self.cur_write_file=new_write_file;
+        self.cur_write_file=Some(new_write_file);
         self.cur_file_size=0;
 
         Ok(())
     }
 
 
-    fn load_persisted_files(directory:&PathBuf)->Result<(usize,BTreeMap<usize,FileReadBufRef>)>{
-        let sorted_file_names=get_sorted_file_names(directory)?;
+    fn load_persisted_files(fs:&dyn FileSystem,directory:&Path)->Result<(usize,BTreeMap<usize,FileReadBufRef>)>{
+        let sorted_file_names=get_sorted_file_names(fs, directory)?;
         let mut size=0;
 
         let mut read_file_pool=BTreeMap::new();
         
         for (num,sorted_file_names) in sorted_file_names.iter(){
             let file_name=directory.join(sorted_file_names);
-            let file=OpenOptions::new()
-            .read(true)
-            .open(file_name)
-            .map_err(|_|KVError::IOError("LogStorage::load_persited_files1"))?;
-            size+=file.metadata().map_err(|_|KVError::IOError("LogStorage::load_persisted_files2"))?.len() as usize;
-
-            let file_buf=Rc::new(RefCell::new(BufReader::new(file)));
-            read_file_pool.insert(*num,file_buf);
+            let file=fs.open_read(&file_name)
+            .context(KVError::IOError, "LogStorage::load_persited_files1")
+            .map_err(|e|e.with_path(&file_name))?;
+            size+=file.size().context(KVError::IOError, "LogStorage::load_persisted_files2")? as usize;
+
+            read_file_pool.insert(*num,new_file_read_buf_ref(file));
         }
 
         Ok((size,read_file_pool))
     }
     
-    fn new_log_file(path:PathBuf)->Result<File>{
-        OpenOptions::new()
-        .create(true)
-        .read(true)
-        .append(true)
-        .open(path)
-        .inspect_err(|e|eprintln!("{e}"))
-        .map_err(|_|KVError::IOError("LogStorage::new_log_file"))
-    }
-
-    fn get_log_file(path:PathBuf)->Result<File>{
-        OpenOptions::new()
-        .read(true)
-        .open(path)
-        .map_err(|_|KVError::IOError("LogStorage::get_log_file"))
+    fn new_log_file(fs:&dyn FileSystem,path:&Path)->Result<Box<dyn LogFile>>{
+        fs.open_append(path)
+        .context(KVError::IOError, "LogStorage::new_log_file")
+        .map_err(|e|e.with_path(path))
+    }
+
+    fn get_log_file(fs:&dyn FileSystem,path:&Path)->Result<Box<dyn LogFile>>{
+        fs.open_read(path)
+        .context(KVError::IOError, "LogStorage::get_log_file")
+        .map_err(|e|e.with_path(path))
     }
     
 }
@@ -208,17 +455,37 @@ impl LogPointer {
             file_serial
         }
     }
+    pub fn position(&self)->LogPosition{
+        LogPosition{
+            segment:self.file_serial,
+            offset:self.offset
+        }
+    }
+
     pub fn read<T: serde::de::DeserializeOwned>(&self)->Result<T>{
-        self.file_buf.borrow_mut().seek(std::io::SeekFrom::Start(self.offset)).map_err(|_|KVError::IOError("LogPointer::read"))?;
+        self.read_record().map_err(|e|e.at(self.position()))
+    }
+
+    fn read_record<T: serde::de::DeserializeOwned>(&self)->Result<T>{
+        if let Some(mmap)=self.file_buf.mmap.borrow().as_ref(){
+            let bytes=mmap.get(self.offset as usize..).ok_or(KVError::ReadError("LogPointer::read3".into()))?;
+            return serde_json::Deserializer::from_slice(bytes)
+            .into_iter()
+            .next()
+            .ok_or(KVError::ReadError("LogPointer::read4".into()))?
+            .context(KVError::ParseError, "LogPointer::read5");
+        }
+
+        self.file_buf.reader.borrow_mut().seek(std::io::SeekFrom::Start(self.offset)).context(KVError::IOError, "LogPointer::read")?;
         
-        let mut cell_ref=self.file_buf.borrow_mut();
+        let mut cell_ref=self.file_buf.reader.borrow_mut();
         let deserializer=serde_json::Deserializer::from_reader(cell_ref.deref_mut());
         let mut stream_deserializer=deserializer.into_iter();
         
         let operation:T=stream_deserializer
         .next()
-        .ok_or(KVError::ReadError("LogPointer::read2"))?
-        .map_err(|_|KVError::ParseError("LogPointer::read"))?;
+        .ok_or(KVError::ReadError("LogPointer::read2".into()))?
+        .context(KVError::ParseError, "LogPointer::read")?;
 
         Ok(operation)
     }
@@ -226,37 +493,95 @@ impl LogPointer {
 
 impl Read for FileReadBufRefWrapper{
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.0.borrow_mut().read(buf)
+        self.0.reader.borrow_mut().read(buf)
     }
 }
 
 
-fn new_file_read_buf_ref(file:File)->FileReadBufRef{
-    Rc::new(RefCell::new(BufReader::new(file)))
+/// Reads the records of every segment in `directory` in log order without opening it for writing.
+pub fn scan_segments<T,F>(directory:&Path,mut visit:F)->Result<()>
+where
+    T: serde::de::DeserializeOwned,
+    F: FnMut(LogPosition,T)->Result<()>
+{
+    for (segment,file_name) in get_sorted_file_names(&OsFileSystem, directory)?{
+        let path=directory.join(file_name);
+        let file=std::fs::File::open(&path).context(KVError::IOError, "scan_segments1").map_err(|e|e.with_path(&path))?;
+        let stream=serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter::<T>();
+        for parsed in OffsetStreamSerializer::new(segment,stream){
+            let (offset,record)=parsed?;
+            visit(LogPosition{segment,offset},record)?;
+        }
+    }
+    Ok(())
+}
+
+fn new_file_read_buf_ref(file:Box<dyn LogFile>)->FileReadBufRef{
+    Rc::new(Segment{
+        reader:RefCell::new(BufReader::new(FileReader::new(file))),
+        mmap:RefCell::new(None)
+    })
+}
+
+impl Segment {
+    fn size(&self)->std::io::Result<u64>{
+        self.reader.borrow().get_ref().file().size()
+    }
+
+    //a segment with no file on disk underneath keeps being read through its reader
+    fn map(&self)->Result<()>{
+        let reader=self.reader.borrow();
+        let Some(file)=reader.get_ref().file().as_file() else {
+            return Ok(());
+        };
+        let len=file.metadata().context(KVError::IOError, "Segment::map1")?.len();
+        //an empty segment cannot be mapped, and has nothing to read anyway
+        if len>0&&self.mmap.borrow().is_none(){
+            // SAFETY: a sealed segment is never written again, merge only unlinks it and the mapping outlives that
+            let mmap=unsafe {Mmap::map(file)}.context(KVError::IOError, "Segment::map2")?;
+            *self.mmap.borrow_mut()=Some(mmap);
+        }
+        Ok(())
+    }
 }
 
 pub fn osstring_parse<T>(osstring:&OsString)->Result<T>
     where T:FromStr
 {
-    let string=osstring.clone().into_string().map_err(|_|KVError::ParseError("osstring_parsea"))?;
-    let res=string.parse().map_err(|_|KVError::ParseError("osstring_parseb"))?;
+    let string=osstring.clone().into_string().map_err(|_|KVError::ParseError(ErrorContext::new("osstring_parsea").with_path(osstring)))?;
+    let res=string.parse().map_err(|_|KVError::ParseError(ErrorContext::new("osstring_parseb").with_path(osstring)))?;
 
     Ok(res)
 }
 
-fn get_sorted_file_names(dir_path:&PathBuf)->Result<Vec<(usize,OsString)>>{
-    let dir=read_dir(dir_path).map_err(|_|KVError::IOError("KvStore::get_sorted_file_names"))?;
-    let mut file_names=dir
+/// Where the compacted segment `serial` is written before it is installed.
+pub fn compaction_path(directory:&Path,serial:usize)->PathBuf{
+    directory.join(format!("{}.{}",serial,TMP_EXTENSION))
+}
+
+pub fn is_tmp(name:&OsString)->bool{
+    Path::new(name).extension().is_some_and(|extension|extension==TMP_EXTENSION)
+}
+
+//what a compaction cut short by a crash left behind
+pub fn remove_unfinished_compactions(fs:&dyn FileSystem,dir_path:&Path)->Result<()>{
+    for name in fs.list(dir_path).context(KVError::IOError, "remove_unfinished_compactions1")?{
+        if is_tmp(&name){
+            fs.remove(&dir_path.join(name)).context(KVError::IOError, "remove_unfinished_compactions2")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get_sorted_file_names(fs:&dyn FileSystem,dir_path:&Path)->Result<Vec<(usize,OsString)>>{
+    let mut file_names=fs.list(dir_path).context(KVError::IOError, "KvStore::get_sorted_file_names")?
     .into_iter()
-    .map(|entry| 
-        entry
-        .map(|entry|entry.file_name())
-        .map_err(|_|KVError::ReadError("KvStore::get_sorted_file_names"))
-        .and_then(|name|Ok((osstring_parse(&name)?,name)))
-    )
+    //a compaction that did not finish is not a segment
+    .filter(|name|!is_tmp(name))
+    .map(|name|Ok((osstring_parse(&name)?,name)))
     .collect::<Result<Vec<(usize,OsString)>>>()?;
     
     file_names.sort();
 
     Ok(file_names)
-}
\ No newline at end of file
+}
@@ -1,14 +1,18 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde_json::StreamDeserializer;
 
-use super::{KVError, Result};
+use super::{replication::LogPosition, ErrorContext, KVError, Result};
 
 pub struct OffsetStreamSerializer<'de,R,T>{
+    segment:usize,
     stream:StreamDeserializer<'de,R,T>
 }
 
 impl<'de,R,T>  OffsetStreamSerializer<'de,R,T> {
-    pub fn new(stream:StreamDeserializer<'de,R,T>)->OffsetStreamSerializer<'de,R,T>{
+    pub fn new(segment:usize,stream:StreamDeserializer<'de,R,T>)->OffsetStreamSerializer<'de,R,T>{
         OffsetStreamSerializer{
+            segment,
             stream
         }
     }
@@ -26,7 +30,23 @@ impl<'de,R,T> Iterator for OffsetStreamSerializer<'de,R,T>
         let val=self.stream.next()?;
         match val {
             Ok(val) => Some(Ok((offset,val))),
-            Err(_) => Some(Err(KVError::ParseError("OffsetStreamSerializer::next"))),
+            Err(error) => {
+                let position=LogPosition{segment:self.segment,offset};
+                Some(Err(KVError::ParseError(ErrorContext::new("OffsetStreamSerializer::next").at(position).with_source(error))))
+            },
         }
     }
 }
+
+/// Milliseconds since the unix epoch, 0 if the clock is before it.
+pub fn now_millis()->u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed|elapsed.as_millis() as u64).unwrap_or(0)
+}
+
+/// CRC-32 (IEEE), bitwise since records are small and this is off the read path.
+pub fn crc32(bytes:&[u8])->u32{
+    let crc=bytes.iter().fold(!0u32,|crc,byte|{
+        (0..8).fold(crc^*byte as u32,|crc,_|if crc&1==1 {(crc>>1)^0xEDB88320} else {crc>>1})
+    });
+    !crc
+}
@@ -0,0 +1,149 @@
+use std::{fs::{metadata, read, read_dir}, path::{Path, PathBuf}};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{bloom::Bloom, history::History, options::KvStoreOptions, record::StoredRecord, repair::{check_record, scan, store_dirs}, replication::LogPosition, storage::{is_tmp, osstring_parse}, Context, KVError, KvStore, KvsEngine, Operation, Result};
+
+#[derive(Serialize,Debug,Clone,Copy,PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum ProblemKind{
+    /// A file among the segments that is not named after a serial.
+    FileName,
+    /// The output of a compaction that did not finish.
+    UnfinishedCompaction,
+    /// Bytes that are not a whole record.
+    Framing,
+    Checksum,
+    /// A persisted Bloom filter that disagrees with the segments.
+    Bloom,
+    /// An indexed key whose pointer is not a set of that key.
+    Index,
+    /// The store does not open even though its segments are whole.
+    Open
+}
+
+/// One thing wrong with a store, `offset` is within the file at `path`.
+#[derive(Serialize,Debug,Clone,PartialEq)]
+pub struct Problem{
+    pub kind:ProblemKind,
+    pub path:PathBuf,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub offset:Option<u64>,
+    pub message:String
+}
+
+/// What a verify looked at and every problem it found.
+#[derive(Serialize,Debug,Default)]
+pub struct VerifyReport{
+    pub segments:usize,
+    pub records:usize,
+    //live keys of the default namespace, when the store opens
+    pub keys:usize,
+    pub problems:Vec<Problem>
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self)->bool{
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self,kind:ProblemKind,path:impl Into<PathBuf>,offset:Option<u64>,message:impl Into<String>){
+        self.problems.push(Problem{kind,path:path.into(),offset,message:message.into()});
+    }
+}
+
+impl KvStore {
+    /// Checks the closed store at `root` and its namespaces without changing a byte: segment names,
+    /// record framing and checksums, the persisted Bloom filters, and that every indexed key points
+    /// at a set of that key. The indexes are only checked when every segment is whole.
+    pub fn verify(root:impl Into<PathBuf>)->Result<VerifyReport>{
+        let root:PathBuf=root.into();
+        if !root.join("data").is_dir(){
+            return Err(KVError::ConfigError("store does not exist"));
+        }
+        let mut report=VerifyReport::default();
+        for store in store_dirs(&root)?{
+            check_segments(&store.join("data"), &mut report, check_record)?;
+            History::verify(&store, &mut report)?;
+        }
+        //the open would fail on what the scan found, a leftover compaction it ignores
+        if report.problems.iter().any(|problem|problem.kind!=ProblemKind::UnfinishedCompaction){
+            return Ok(report);
+        }
+        match KvStore::open_with(&root, KvStoreOptions::new().read_only(true)) {
+            Ok(mut store) => {
+                store.check_indexes(&mut report)?;
+                for namespace in store.namespaces.values(){
+                    namespace.check_indexes(&mut report)?;
+                }
+                report.keys=store.keys()?.len();
+            },
+            Err(error) => report.problem(ProblemKind::Open, &root, None, error.to_string()),
+        }
+        Ok(report)
+    }
+
+    fn check_indexes(&self,report:&mut VerifyReport)->Result<()>{
+        let segment_path=|position:LogPosition|self.storage.directory().join(position.segment.to_string());
+        for key in self.index.keys(&self.storage)?{
+            if !self.bloom.may_contain(&key){
+                report.problem(ProblemKind::Bloom, self.bloom.path(), None, format!("live key {key} is not in the filter"));
+            }
+            let Some(position)=self.index.position(&self.storage, &key)? else {
+                report.problem(ProblemKind::Index, self.storage.directory(), None, format!("key {key} has no position"));
+                continue;
+            };
+            let read=self.storage.pointer(position).and_then(|log_ptr|log_ptr.read::<StoredRecord>());
+            let message=match read.map(|stored|stored.into_record().op) {
+                Ok(Operation::Set(set_key,_)) if set_key==key => continue,
+                Ok(op) => format!("key {key} points at {op:?}"),
+                Err(error) => format!("key {key} points at an unreadable record: {error}"),
+            };
+            report.problem(ProblemKind::Index, segment_path(position), Some(position.offset), message);
+        }
+        //a filter is only reloaded if its position is in the log, see open_segments
+        if let Some(bloom)=Bloom::load(&self.root)?{
+            let covers=bloom.covers();
+            let covered_len=metadata(segment_path(covers)).map(|metadata|metadata.len());
+            if !covered_len.is_ok_and(|len|covers.offset<=len){
+                report.problem(ProblemKind::Bloom, bloom.path(), None, format!("covers segment {} offset {} which is not in the log",covers.segment,covers.offset));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the names and records of the segments in `directory` holding `T` records.
+pub(super) fn check_segments<T,F>(directory:&Path,report:&mut VerifyReport,check:F)->Result<()>
+where
+    T: DeserializeOwned,
+    F: Fn(T)->Option<&'static str>
+{
+    if !directory.is_dir(){
+        return Ok(());
+    }
+    let mut segments=Vec::new();
+    for entry in read_dir(directory).context(KVError::IOError, "check_segments1").map_err(|e|e.with_path(directory))?{
+        let entry=entry.context(KVError::IOError, "check_segments2")?;
+        let name=entry.file_name();
+        if is_tmp(&name){
+            report.problem(ProblemKind::UnfinishedCompaction, entry.path(), None, "left by a compaction that did not finish");
+        } else if !entry.path().is_file()||osstring_parse::<usize>(&name).is_err(){
+            report.problem(ProblemKind::FileName, entry.path(), None, "not a segment");
+        } else {
+            segments.push((osstring_parse::<usize>(&name)?,entry.path()));
+        }
+    }
+    segments.sort();
+    for (_,path) in segments{
+        let bytes=read(&path).context(KVError::IOError, "check_segments3").map_err(|e|e.with_path(&path))?;
+        let (kept,lost)=scan::<T,F>(&bytes, &check);
+        report.segments+=1;
+        report.records+=kept.len();
+        for (range,reason) in lost{
+            let kind=if reason=="checksum mismatch" {ProblemKind::Checksum} else {ProblemKind::Framing};
+            report.problem(kind, &path, Some(range.start as u64), format!("{reason}, {} bytes",range.len()));
+        }
+    }
+    Ok(())
+}
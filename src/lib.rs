@@ -4,14 +4,215 @@ pub mod client;
 
 pub mod kv;
 pub mod sled;
+pub mod memory;
+pub mod raft;
+pub mod simulation;
+pub mod fuzz;
 mod common;
 
 
+use serde::{Deserialize, Serialize};
+use kv::{BloomStats, CacheStats, history::Version, namespace::NamespaceOptions, replication::{LogPosition, ReplicationBatch}, secondary::IndexDefinition, KVError};
+
 pub use kv::{KvStore,Result};
+pub use memory::MemoryStore;
+pub use common::{KILOBYTE,MEGABYTE,GIGABYTE,EXIT_NOT_FOUND,EXIT_USAGE,EXIT_DATA,EXIT_UNAVAILABLE,EXIT_SOFTWARE,EXIT_IO,EXIT_TEMPFAIL,EXIT_CONFIG};
+
+/// Namespace every engine has, requests without one use it.
+pub const DEFAULT_NAMESPACE:&str="default";
+
+/// Key and value pairs read one at a time, from another thread if need be.
+pub type EntryStream = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Internal counters of an engine, each part `None` where the engine does not have it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    pub bloom: Option<BloomStats>,
+    pub cache: Option<CacheStats>,
+}
 
 pub trait KvsEngine {
     fn name(&self)->String;
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn get(&mut self, key: String) -> Result<Option<String>>;
     fn remove(&mut self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    fn keys(&mut self) -> Result<Vec<String>>;
+
+    /// Every live key with its value.
+    fn entries(&mut self) -> Result<Vec<(String, String)>> {
+        self.keys()?
+            .into_iter()
+            .filter_map(|key| match self.get(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+
+    /// Every live key with its value as they are now, read as the iterator goes on, from another
+    /// thread if need be, while writes go on. Engines that cannot read apart from their writes collect them first.
+    fn snapshot_entries(&mut self) -> Result<EntryStream> {
+        Ok(Box::new(self.entries()?.into_iter().map(Ok)))
+    }
+
+    /// Sets every entry, engines that can write several at once override it.
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in entries {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole contents with `entries`.
+    fn restore(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        let live: std::collections::HashSet<&String> = entries.iter().map(|(key, _)| key).collect();
+        for key in self.keys()? {
+            if !live.contains(&key) {
+                self.remove(key)?;
+            }
+        }
+        for (key, value) in entries {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// The engine holding the keys of namespace `name`.
+    fn namespace<'a>(&'a mut self, name: &str) -> Result<Box<dyn KvsEngine + 'a>> {
+        if name == DEFAULT_NAMESPACE {
+            return Ok(Box::new(self));
+        }
+        Err(KVError::NamespaceNotFound(name.to_string()))
+    }
+
+    fn create_namespace(&mut self, _name: &str, _options: NamespaceOptions) -> Result<()> {
+        Err(KVError::Unsupported("the engine has no namespaces"))
+    }
+
+    fn drop_namespace(&mut self, _name: &str) -> Result<()> {
+        Err(KVError::Unsupported("the engine has no namespaces"))
+    }
+
+    fn list_namespaces(&mut self) -> Result<Vec<String>> {
+        Ok(vec![DEFAULT_NAMESPACE.to_string()])
+    }
+
+    /// The retained versions of `key`, oldest first.
+    fn history(&mut self, _key: String) -> Result<Vec<Version>> {
+        Err(KVError::Unsupported("the engine keeps no history"))
+    }
+
+    /// The value `key` had at version `seq`, `None` if it was removed then or the version is gone.
+    fn get_version(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        Ok(self.history(key)?.into_iter().find(|version| version.seq == seq).and_then(|version| version.value))
+    }
+
+    /// The value `key` had at `timestamp`, in milliseconds since the unix epoch.
+    fn get_as_of(&mut self, key: String, timestamp: u64) -> Result<Option<String>> {
+        Ok(self
+            .history(key)?
+            .into_iter()
+            .take_while(|version| version.timestamp <= timestamp)
+            .last()
+            .and_then(|version| version.value))
+    }
+
+    /// Starts indexing the JSON field at `pointer` of every value under `name`.
+    fn create_index(&mut self, _name: &str, _pointer: &str) -> Result<()> {
+        Err(KVError::Unsupported("the engine has no secondary indexes"))
+    }
+
+    fn drop_index(&mut self, _name: &str) -> Result<()> {
+        Err(KVError::Unsupported("the engine has no secondary indexes"))
+    }
+
+    /// Keys whose value has `value` in the field indexed by `name`.
+    fn query_index(&mut self, _name: &str, _value: &str) -> Result<Vec<String>> {
+        Err(KVError::Unsupported("the engine has no secondary indexes"))
+    }
+
+    fn list_indexes(&mut self) -> Result<Vec<IndexDefinition>> {
+        Err(KVError::Unsupported("the engine has no secondary indexes"))
+    }
+
+    /// Returns the changes a follower at `position` is missing, or the page of a snapshot from `snapshot` on.
+    /// Only engines backed by a log can act as a replication leader.
+    fn replicate(&mut self, _position: Option<LogPosition>, _snapshot: Option<LogPosition>) -> Result<ReplicationBatch> {
+        Err(KVError::Unsupported("the engine cannot be replicated"))
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+}
+
+// lets a borrowed engine stand in wherever a namespace hands out a `Box<dyn KvsEngine>`
+impl<T: KvsEngine + ?Sized> KvsEngine for &mut T {
+    fn name(&self)->String {
+        (**self).name()
+    }
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+    fn keys(&mut self) -> Result<Vec<String>> {
+        (**self).keys()
+    }
+    fn entries(&mut self) -> Result<Vec<(String, String)>> {
+        (**self).entries()
+    }
+    fn snapshot_entries(&mut self) -> Result<EntryStream> {
+        (**self).snapshot_entries()
+    }
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        (**self).set_batch(entries)
+    }
+    fn restore(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        (**self).restore(entries)
+    }
+    fn namespace<'a>(&'a mut self, name: &str) -> Result<Box<dyn KvsEngine + 'a>> {
+        (**self).namespace(name)
+    }
+    fn create_namespace(&mut self, name: &str, options: NamespaceOptions) -> Result<()> {
+        (**self).create_namespace(name, options)
+    }
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        (**self).drop_namespace(name)
+    }
+    fn list_namespaces(&mut self) -> Result<Vec<String>> {
+        (**self).list_namespaces()
+    }
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        (**self).history(key)
+    }
+    fn get_version(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        (**self).get_version(key, seq)
+    }
+    fn get_as_of(&mut self, key: String, timestamp: u64) -> Result<Option<String>> {
+        (**self).get_as_of(key, timestamp)
+    }
+    fn create_index(&mut self, name: &str, pointer: &str) -> Result<()> {
+        (**self).create_index(name, pointer)
+    }
+    fn drop_index(&mut self, name: &str) -> Result<()> {
+        (**self).drop_index(name)
+    }
+    fn query_index(&mut self, name: &str, value: &str) -> Result<Vec<String>> {
+        (**self).query_index(name, value)
+    }
+    fn list_indexes(&mut self) -> Result<Vec<IndexDefinition>> {
+        (**self).list_indexes()
+    }
+    fn replicate(&mut self, position: Option<LogPosition>, snapshot: Option<LogPosition>) -> Result<ReplicationBatch> {
+        (**self).replicate(position, snapshot)
+    }
+    fn stats(&self) -> EngineStats {
+        (**self).stats()
+    }
+}
@@ -0,0 +1,172 @@
+use std::{collections::BTreeMap, fs::{rename, DirBuilder, File}, io::{BufReader, BufWriter, ErrorKind, Write}, mem, path::{Path, PathBuf}};
+
+use crate::{kv::{namespace::NamespaceOptions, Context, ErrorContext, KVError}, KvsEngine, Result, DEFAULT_NAMESPACE};
+
+/// File in the directory of a `MemoryStore` holding its snapshot.
+pub const SNAPSHOT_FILE:&str="snapshot.json";
+
+/// An engine keeping every namespace in an ordered map, for tests and caches.
+/// Opened on a directory it starts from the snapshot there and writes a new one when dropped.
+#[derive(Debug,Default)]
+pub struct MemoryStore{
+    //every namespace but the default one
+    namespaces:BTreeMap<String,BTreeMap<String,String>>,
+    default:BTreeMap<String,String>,
+    snapshot:Option<PathBuf>
+}
+
+impl MemoryStore {
+    /// An empty store that is gone once dropped.
+    pub fn new()->MemoryStore{
+        MemoryStore::default()
+    }
+
+    /// Loads the snapshot in `path`, if there is one, and snapshots into it on drop.
+    pub fn open(path:impl Into<PathBuf>)->Result<MemoryStore>{
+        let path=path.into();
+        DirBuilder::new().recursive(true).create(&path)
+        .context(KVError::IOError, "MemoryStore::open1")
+        .map_err(|e|e.with_path(&path))?;
+        let snapshot=path.join(SNAPSHOT_FILE);
+        //the store is made last, so a snapshot that fails to load is not replaced on drop
+        let mut namespaces:BTreeMap<String,BTreeMap<String,String>>=match File::open(&snapshot) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .context(KVError::ParseError, "MemoryStore::open2")
+                .map_err(|e|e.with_path(&snapshot))?,
+            Err(e) if e.kind()==ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(KVError::IOError(ErrorContext::new("MemoryStore::open3").with_path(&snapshot).with_source(e))),
+        };
+        let default=namespaces.remove(DEFAULT_NAMESPACE).unwrap_or_default();
+        Ok(MemoryStore{namespaces,default,snapshot:Some(snapshot)})
+    }
+
+    /// Writes every namespace to the snapshot in one rename, does nothing for a store not opened on a directory.
+    pub fn snapshot(&mut self)->Result<()>{
+        let Some(path)=self.snapshot.clone() else {
+            return Ok(());
+        };
+        //the default namespace is lent to the map for the write
+        self.namespaces.insert(DEFAULT_NAMESPACE.to_string(), mem::take(&mut self.default));
+        let written=write_snapshot(&path, &self.namespaces);
+        self.default=self.namespaces.remove(DEFAULT_NAMESPACE).unwrap_or_default();
+        written
+    }
+}
+
+fn write_snapshot(path:&Path,namespaces:&BTreeMap<String,BTreeMap<String,String>>)->Result<()>{
+    let tmp_path=path.with_extension("tmp");
+    let file=File::create(&tmp_path).context(KVError::IOError, "MemoryStore::snapshot1")?;
+    let mut writer=BufWriter::new(file);
+    serde_json::to_writer(&mut writer, namespaces).context(KVError::WriteError, "MemoryStore::snapshot2")?;
+    writer.flush().context(KVError::WriteError, "MemoryStore::snapshot3")?;
+    writer.get_ref().sync_all().context(KVError::WriteError, "MemoryStore::snapshot4")?;
+    rename(&tmp_path, path).context(KVError::IOError, "MemoryStore::snapshot5")
+}
+
+impl Drop for MemoryStore {
+    fn drop(&mut self){
+        if let Err(error)=self.snapshot(){
+            eprintln!("error: memory snapshot not written: {error}");
+        }
+    }
+}
+
+//a namespace of the memory engine is a plain ordered map
+impl KvsEngine for BTreeMap<String,String> {
+    fn name(&self)->String {
+        "memory".to_string()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(BTreeMap::get(self, &key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        BTreeMap::remove(self, &key).ok_or(KVError::KeyNotFound(key))?;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(BTreeMap::keys(self).cloned().collect())
+    }
+
+    fn entries(&mut self) -> Result<Vec<(String, String)>> {
+        Ok(self.iter().map(|(key,value)|(key.clone(),value.clone())).collect())
+    }
+
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        self.extend(entries);
+        Ok(())
+    }
+
+    fn restore(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        *self=entries.into_iter().collect();
+        Ok(())
+    }
+}
+
+impl KvsEngine for MemoryStore {
+    fn name(&self)->String {
+        "memory".to_string()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(&mut self.default, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(&mut self.default, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvsEngine::remove(&mut self.default, key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        KvsEngine::keys(&mut self.default)
+    }
+
+    fn entries(&mut self) -> Result<Vec<(String, String)>> {
+        KvsEngine::entries(&mut self.default)
+    }
+
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        KvsEngine::set_batch(&mut self.default, entries)
+    }
+
+    fn restore(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        KvsEngine::restore(&mut self.default, entries)
+    }
+
+    fn namespace<'a>(&'a mut self, name: &str) -> Result<Box<dyn KvsEngine + 'a>> {
+        if name==DEFAULT_NAMESPACE{
+            return Ok(Box::new(&mut self.default));
+        }
+        let namespace=self.namespaces.get_mut(name).ok_or_else(||KVError::NamespaceNotFound(name.to_string()))?;
+        Ok(Box::new(namespace))
+    }
+
+    fn create_namespace(&mut self, name: &str, _options: NamespaceOptions) -> Result<()> {
+        if name==DEFAULT_NAMESPACE||self.namespaces.contains_key(name){
+            return Err(KVError::ConfigError("namespace already exists"));
+        }
+        self.namespaces.insert(name.to_string(), BTreeMap::new());
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        if name==DEFAULT_NAMESPACE||self.namespaces.remove(name).is_none(){
+            return Err(KVError::NamespaceNotFound(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn list_namespaces(&mut self) -> Result<Vec<String>> {
+        Ok(std::iter::once(DEFAULT_NAMESPACE.to_string()).chain(self.namespaces.keys().cloned()).collect())
+    }
+}
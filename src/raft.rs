@@ -0,0 +1,157 @@
+use std::{fmt::Debug, io::Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{kv::{Context, KVError, Operation, Result}, EntryStream, KvsEngine};
+
+mod node;
+pub mod storage;
+pub mod transport;
+
+pub use node::{Applied, RaftNode};
+
+pub type NodeId=u64;
+
+//snapshot entries set at once while a snapshot is applied
+const RESTORE_BATCH:usize=1000;
+
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
+pub struct Entry{
+    pub term:u64,
+    //None is the no-op a new leader appends to commit entries from older terms
+    pub command:Option<Operation>
+}
+
+/// Where the state machine contents a snapshot holds stop, up to and including `last_index`.
+/// The entries themselves stay in the `RaftStorage`.
+#[derive(Deserialize,Serialize,Debug,Clone,Copy,Default,PartialEq)]
+pub struct Snapshot{
+    pub last_index:u64,
+    pub last_term:u64
+}
+
+/// The entries of a snapshot, read from the storage as they are applied.
+pub struct SnapshotEntries{
+    pub snapshot:Snapshot,
+    entries:EntryStream
+}
+
+impl SnapshotEntries {
+    fn new(snapshot:Snapshot,reader:Result<Box<dyn Read+Send>>)->SnapshotEntries{
+        let entries:EntryStream=match reader {
+            Ok(reader) => Box::new(
+                serde_json::Deserializer::from_reader(reader)
+                .into_iter()
+                .map(|entry|entry.context(KVError::ParseError, "SnapshotEntries::next"))
+            ),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        };
+        SnapshotEntries{snapshot,entries}
+    }
+}
+
+impl Iterator for SnapshotEntries {
+    type Item=Result<(String,String)>;
+
+    fn next(&mut self)->Option<Self::Item>{
+        self.entries.next()
+    }
+}
+
+impl Debug for SnapshotEntries {
+    fn fmt(&self,f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        f.debug_struct("SnapshotEntries").field("snapshot", &self.snapshot).finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub enum Message{
+    RequestVote{
+        term:u64,
+        last_log_index:u64,
+        last_log_term:u64
+    },
+    Vote{
+        term:u64,
+        granted:bool
+    },
+    AppendEntries{
+        term:u64,
+        prev_index:u64,
+        prev_term:u64,
+        entries:Vec<Entry>,
+        commit:u64
+    },
+    AppendResponse{
+        term:u64,
+        success:bool,
+        //highest index known to match on success, last log index on failure
+        match_index:u64
+    },
+    //one chunk of the snapshot at `last_index`, from byte `offset` to `next` of the leader's stream of its entries
+    InstallSnapshot{
+        term:u64,
+        last_index:u64,
+        last_term:u64,
+        offset:u64,
+        next:u64,
+        entries:Vec<(String,String)>,
+        done:bool
+    },
+    //bytes of the leader's stream of the snapshot at `last_index` received so far
+    SnapshotResponse{
+        term:u64,
+        last_index:u64,
+        received:u64
+    }
+}
+
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct Envelope{
+    pub from:NodeId,
+    pub to:NodeId,
+    pub message:Message
+}
+
+impl Message {
+    pub fn term(&self)->u64{
+        match self {
+            Message::RequestVote { term, .. } => *term,
+            Message::Vote { term, .. } => *term,
+            Message::AppendEntries { term, .. } => *term,
+            Message::AppendResponse { term, .. } => *term,
+            Message::InstallSnapshot { term, .. } => *term,
+            Message::SnapshotResponse { term, .. } => *term,
+        }
+    }
+}
+
+/// Applies a committed entry to `engine` and returns what a `Get` read.
+pub fn apply(engine:&mut dyn KvsEngine,applied:Applied)->Result<Option<String>>{
+    match applied {
+        Applied::Snapshot(entries) => restore(engine, entries).map(|_|None),
+        Applied::Entry { command, .. } => match command {
+            None => Ok(None),
+            Some(Operation::Get(key)) => engine.get(key),
+            Some(Operation::Set(key, value)) => engine.set(key, value).map(|_|None),
+            Some(Operation::Remove(key)) => engine.remove(key).map(|_|None),
+        },
+    }
+}
+
+//replaces the contents of engine with the snapshot a batch at a time, a snapshot that cannot be read leaves them
+fn restore(engine:&mut dyn KvsEngine,entries:SnapshotEntries)->Result<()>{
+    let mut entries=entries.peekable();
+    if let Some(Err(_))=entries.peek(){
+        return entries.next().expect("Peeked").map(|_|());
+    }
+    engine.restore(Vec::new())?;
+    let mut batch=Vec::new();
+    for entry in entries{
+        batch.push(entry?);
+        if batch.len()>=RESTORE_BATCH{
+            engine.set_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    engine.set_batch(batch)
+}
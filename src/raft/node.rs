@@ -0,0 +1,573 @@
+use std::{collections::{HashMap, HashSet}, io::Write, ops::Range};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::kv::{Context, Operation, KVError, Result};
+
+use super::{storage::{HardState, RaftStorage}, Entry, Envelope, Message, NodeId, Snapshot, SnapshotEntries};
+
+const HEARTBEAT_TICKS:u32=3;
+const ELECTION_TICKS:u32=15;
+const MAX_APPEND_ENTRIES:usize=100;
+//bytes of keys and values sent in one snapshot chunk, a chunk holds at least one entry
+const SNAPSHOT_CHUNK_BYTES:usize=1024*1024;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Role{
+    Follower,
+    Candidate,
+    Leader
+}
+
+/// What the state machine has to apply, in log order.
+#[derive(Debug)]
+pub enum Applied{
+    Entry{
+        index:u64,
+        term:u64,
+        command:Option<Operation>
+    },
+    //replaces the whole state machine
+    Snapshot(SnapshotEntries)
+}
+
+//snapshot entries read to send, up to byte `next` of the stream of them
+struct Chunk{
+    entries:Vec<(String,String)>,
+    next:u64,
+    done:bool
+}
+
+//a snapshot from the leader being written to storage, up to byte `received` of the leader's stream
+struct Receiving{
+    snapshot:Snapshot,
+    received:u64,
+    writer:Box<dyn Write+Send>
+}
+
+/// A single raft participant.
+///
+/// The node never touches the network or a clock, the owner calls `tick` at a
+/// fixed interval, feeds it incoming messages through `step` and delivers what
+/// `take_messages` returns. This keeps it usable both by the server and by
+/// tests running a whole cluster in one thread.
+pub struct RaftNode{
+    id:NodeId,
+    peers:Vec<NodeId>,
+    storage:Box<dyn RaftStorage>,
+    rng:StdRng,
+
+    term:u64,
+    voted_for:Option<NodeId>,
+    snapshot:Snapshot,
+    //entries[i] has index snapshot.last_index+1+i
+    entries:Vec<Entry>,
+    commit_index:u64,
+    last_applied:u64,
+    pending_snapshot:Option<Snapshot>,
+    receiving:Option<Receiving>,
+
+    role:Role,
+    leader:Option<NodeId>,
+    elapsed:u32,
+    election_timeout:u32,
+    votes:HashSet<NodeId>,
+    next_index:HashMap<NodeId,u64>,
+    match_index:HashMap<NodeId,u64>,
+    //snapshot index and entries acknowledged by a peer the snapshot is being sent to
+    snapshot_sent:HashMap<NodeId,(u64,u64)>,
+
+    outbox:Vec<Envelope>
+}
+
+impl RaftNode {
+    pub fn new(id:NodeId,peers:Vec<NodeId>,mut storage:Box<dyn RaftStorage>,seed:u64)->Result<RaftNode>{
+        let state=storage.load()?;
+        let mut node=RaftNode{
+            id,
+            peers:peers.into_iter().filter(|peer|*peer!=id).collect(),
+            storage,
+            rng:StdRng::seed_from_u64(seed),
+            term:state.hard_state.term,
+            voted_for:state.hard_state.voted_for,
+            commit_index:state.snapshot.last_index,
+            last_applied:state.snapshot.last_index,
+            snapshot:state.snapshot,
+            entries:state.entries,
+            pending_snapshot:None,
+            receiving:None,
+            role:Role::Follower,
+            leader:None,
+            elapsed:0,
+            election_timeout:ELECTION_TICKS,
+            votes:HashSet::new(),
+            next_index:HashMap::new(),
+            match_index:HashMap::new(),
+            snapshot_sent:HashMap::new(),
+            outbox:Vec::new()
+        };
+        node.reset_election_timer();
+        Ok(node)
+    }
+
+    pub fn id(&self)->NodeId{
+        self.id
+    }
+
+    pub fn term(&self)->u64{
+        self.term
+    }
+
+    pub fn is_leader(&self)->bool{
+        self.role==Role::Leader
+    }
+
+    pub fn leader(&self)->Option<NodeId>{
+        self.leader
+    }
+
+    pub fn commit_index(&self)->u64{
+        self.commit_index
+    }
+
+    pub fn last_applied(&self)->u64{
+        self.last_applied
+    }
+
+    pub fn last_index(&self)->u64{
+        self.snapshot.last_index+self.entries.len() as u64
+    }
+
+    pub fn snapshot_index(&self)->u64{
+        self.snapshot.last_index
+    }
+
+    /// Term of the entry at `index`, `None` once it is compacted into a snapshot.
+    pub fn term_at(&self,index:u64)->Option<u64>{
+        if index==self.snapshot.last_index{
+            Some(self.snapshot.last_term)
+        } else if index<self.snapshot.last_index||index>self.last_index(){
+            None
+        } else {
+            Some(self.entries[(index-self.snapshot.last_index-1) as usize].term)
+        }
+    }
+
+    pub fn tick(&mut self)->Result<()>{
+        self.elapsed+=1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed>=HEARTBEAT_TICKS{
+                    self.elapsed=0;
+                    self.broadcast_append();
+                }
+            },
+            Role::Follower|Role::Candidate => {
+                if self.elapsed>=self.election_timeout{
+                    self.campaign()?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Appends `command` if this node is the leader and returns its index and term.
+    pub fn propose(&mut self,command:Operation)->Result<Option<(u64,u64)>>{
+        if self.role!=Role::Leader{
+            return Ok(None);
+        }
+        let index=self.append_local(Some(command))?;
+        self.broadcast_append();
+        Ok(Some((index,self.term)))
+    }
+
+    pub fn take_messages(&mut self)->Vec<Envelope>{
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Hands out everything committed since the last call and marks it applied.
+    pub fn take_committed(&mut self)->Vec<Applied>{
+        let mut applied=Vec::new();
+        if let Some(snapshot)=self.pending_snapshot.take(){
+            applied.push(Applied::Snapshot(SnapshotEntries::new(snapshot, self.storage.read_snapshot(0))));
+        }
+        while self.last_applied<self.commit_index{
+            self.last_applied+=1;
+            let entry=&self.entries[(self.last_applied-self.snapshot.last_index-1) as usize];
+            applied.push(Applied::Entry{
+                index:self.last_applied,
+                term:entry.term,
+                command:entry.command.clone()
+            });
+        }
+        applied
+    }
+
+    /// Replaces the applied prefix of the log with `entries`, the state machine contents at `last_applied`.
+    pub fn compact(&mut self,entries:Vec<(String,String)>)->Result<()>{
+        let Some((snapshot,mut writer))=self.start_snapshot()? else {
+            return Ok(());
+        };
+        for entry in entries{
+            serde_json::to_writer(&mut writer, &entry).context(KVError::WriteError, "RaftNode::compact1")?;
+        }
+        writer.flush().context(KVError::WriteError, "RaftNode::compact2")?;
+        drop(writer);
+        self.install_snapshot(snapshot)
+    }
+
+    /// Starts a snapshot at `last_applied`, whose entries the state machine contents at that point are
+    /// written to as a stream of json pairs, from another thread if need be, before `install_snapshot`.
+    /// `None` while there is nothing new to compact.
+    pub fn start_snapshot(&mut self)->Result<Option<(Snapshot,Box<dyn Write+Send>)>>{
+        if self.last_applied<=self.snapshot.last_index{
+            return Ok(None);
+        }
+        let snapshot=Snapshot{
+            last_index:self.last_applied,
+            last_term:self.term_at(self.last_applied).expect("Applied entries are in the log")
+        };
+        let writer=self.storage.snapshot_writer(snapshot.last_index)?;
+        Ok(Some((snapshot,writer)))
+    }
+
+    /// Replaces the prefix of the log `snapshot` covers with it once its entries are written,
+    /// unless a snapshot from the leader went past it meanwhile.
+    pub fn install_snapshot(&mut self,snapshot:Snapshot)->Result<()>{
+        if snapshot.last_index<=self.snapshot.last_index{
+            return Ok(());
+        }
+        self.storage.save_snapshot(&snapshot)?;
+        let covered=(snapshot.last_index-self.snapshot.last_index) as usize;
+        self.entries.drain(..covered);
+        self.snapshot=snapshot;
+        Ok(())
+    }
+
+    pub fn step(&mut self,envelope:Envelope)->Result<()>{
+        let Envelope{from,message,..}=envelope;
+        let term=message.term();
+
+        if term>self.term{
+            let leader=match message {
+                Message::AppendEntries { .. }|Message::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader)?;
+        } else if term<self.term{
+            //tell a stale sender about the newer term so it steps down
+            match message {
+                Message::RequestVote { .. } => self.send(from, Message::Vote { term: self.term, granted: false }),
+                Message::AppendEntries { .. }|Message::InstallSnapshot { .. } => {
+                    self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: self.last_index() })
+                },
+                _ => {},
+            }
+            return Ok(());
+        }
+
+        match message {
+            Message::RequestVote { last_log_index, last_log_term, .. } => self.handle_request_vote(from, last_log_index, last_log_term),
+            Message::Vote { granted, .. } => self.handle_vote(from, granted),
+            Message::AppendEntries { prev_index, prev_term, entries, commit, .. } => self.handle_append(from, prev_index, prev_term, entries, commit),
+            Message::AppendResponse { success, match_index, .. } => {
+                self.handle_append_response(from, success, match_index);
+                Ok(())
+            },
+            Message::InstallSnapshot { last_index, last_term, offset, next, entries, done, .. } => {
+                self.handle_install_snapshot(from, Snapshot { last_index, last_term }, offset..next, entries, done)
+            },
+            Message::SnapshotResponse { last_index, received, .. } => {
+                self.handle_snapshot_response(from, last_index, received);
+                Ok(())
+            },
+        }
+    }
+
+    fn handle_request_vote(&mut self,from:NodeId,last_log_index:u64,last_log_term:u64)->Result<()>{
+        let my_last_term=self.term_at(self.last_index()).unwrap_or(self.snapshot.last_term);
+        let up_to_date=last_log_term>my_last_term||(last_log_term==my_last_term&&last_log_index>=self.last_index());
+        let granted=up_to_date&&self.voted_for.is_none_or(|voted|voted==from);
+        if granted{
+            self.voted_for=Some(from);
+            self.save_hard_state()?;
+            self.reset_election_timer();
+        }
+        self.send(from, Message::Vote { term: self.term, granted });
+        Ok(())
+    }
+
+    fn handle_vote(&mut self,from:NodeId,granted:bool)->Result<()>{
+        if self.role!=Role::Candidate||!granted{
+            return Ok(());
+        }
+        self.votes.insert(from);
+        if self.votes.len()>=self.quorum(){
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn handle_append(&mut self,from:NodeId,mut prev_index:u64,prev_term:u64,mut entries:Vec<Entry>,commit:u64)->Result<()>{
+        self.role=Role::Follower;
+        self.leader=Some(from);
+        self.reset_election_timer();
+
+        if prev_index>self.last_index(){
+            self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: self.last_index() });
+            return Ok(());
+        }
+        if prev_index<self.snapshot.last_index{
+            //the snapshot already covers these, they are committed and therefore identical
+            let skip=((self.snapshot.last_index-prev_index) as usize).min(entries.len());
+            entries.drain(..skip);
+            prev_index+=skip as u64;
+        } else if self.term_at(prev_index)!=Some(prev_term){
+            self.send(from, Message::AppendResponse { term: self.term, success: false, match_index: prev_index.saturating_sub(1) });
+            return Ok(());
+        }
+
+        let last_new_index=prev_index+entries.len() as u64;
+        let mut first_new=None;
+        for (offset,entry) in entries.iter().enumerate(){
+            let index=prev_index+1+offset as u64;
+            if self.term_at(index)!=Some(entry.term){
+                first_new=Some(offset);
+                break;
+            }
+        }
+        if let Some(offset)=first_new{
+            let first_index=prev_index+1+offset as u64;
+            self.storage.append(first_index, &entries[offset..])?;
+            self.entries.truncate((first_index-self.snapshot.last_index-1) as usize);
+            self.entries.extend(entries.drain(offset..));
+        }
+
+        if commit>self.commit_index{
+            self.commit_index=commit.min(last_new_index).max(self.commit_index);
+        }
+        self.send(from, Message::AppendResponse { term: self.term, success: true, match_index: last_new_index });
+        Ok(())
+    }
+
+    fn handle_append_response(&mut self,from:NodeId,success:bool,match_index:u64){
+        if self.role!=Role::Leader{
+            return;
+        }
+        if success{
+            let matched=self.match_index.entry(from).or_insert(0);
+            *matched=(*matched).max(match_index);
+            let matched=*matched;
+            self.next_index.insert(from, matched+1);
+            self.snapshot_sent.remove(&from);
+            self.advance_commit();
+            if matched<self.last_index(){
+                self.send_append(from);
+            }
+        } else {
+            let next=self.next_index.get(&from).copied().unwrap_or(1);
+            self.next_index.insert(from, (match_index+1).min(next.saturating_sub(1)).max(1));
+            self.send_append(from);
+        }
+    }
+
+    fn handle_install_snapshot(&mut self,from:NodeId,snapshot:Snapshot,chunk:Range<u64>,entries:Vec<(String,String)>,done:bool)->Result<()>{
+        self.role=Role::Follower;
+        self.leader=Some(from);
+        self.reset_election_timer();
+
+        let last_index=snapshot.last_index;
+        if last_index>self.commit_index{
+            let mut receiving=match self.receiving.take() {
+                Some(receiving) if receiving.snapshot==snapshot => receiving,
+                _ => Receiving{snapshot,received:0,writer:self.storage.snapshot_writer(last_index)?},
+            };
+            //a chunk out of order is dropped, the response tells the leader where to go on from
+            let in_order=chunk.start==receiving.received;
+            if in_order{
+                for entry in entries{
+                    serde_json::to_writer(&mut receiving.writer, &entry).context(KVError::WriteError, "RaftNode::handle_install_snapshot1")?;
+                }
+                receiving.received=chunk.end;
+            }
+            if !(in_order&&done){
+                let received=receiving.received;
+                self.receiving=Some(receiving);
+                self.send(from, Message::SnapshotResponse { term: self.term, last_index, received });
+                return Ok(());
+            }
+            receiving.writer.flush().context(KVError::WriteError, "RaftNode::handle_install_snapshot2")?;
+            drop(receiving);
+
+            if self.term_at(last_index)==Some(snapshot.last_term){
+                let covered=(last_index-self.snapshot.last_index) as usize;
+                self.entries.drain(..covered);
+            } else {
+                self.entries.clear();
+            }
+            self.storage.save_snapshot(&snapshot)?;
+            self.storage.append(last_index+1, &self.entries)?;
+            self.commit_index=last_index;
+            self.last_applied=last_index;
+            self.snapshot=snapshot;
+            self.pending_snapshot=Some(snapshot);
+        }
+        self.send(from, Message::AppendResponse { term: self.term, success: true, match_index: last_index.max(self.commit_index) });
+        Ok(())
+    }
+
+    fn handle_snapshot_response(&mut self,from:NodeId,last_index:u64,received:u64){
+        if self.role!=Role::Leader||last_index!=self.snapshot.last_index{
+            return;
+        }
+        //a repeated response would start a second stream of the same chunks
+        if self.snapshot_sent.insert(from, (last_index,received))!=Some((last_index,received)){
+            self.send_append(from);
+        }
+    }
+
+    fn campaign(&mut self)->Result<()>{
+        self.term+=1;
+        self.role=Role::Candidate;
+        self.leader=None;
+        self.voted_for=Some(self.id);
+        self.save_hard_state()?;
+        self.reset_election_timer();
+        self.votes=HashSet::from([self.id]);
+        if self.votes.len()>=self.quorum(){
+            return self.become_leader();
+        }
+
+        let last_log_index=self.last_index();
+        let last_log_term=self.term_at(last_log_index).unwrap_or(self.snapshot.last_term);
+        for peer in self.peers.clone(){
+            self.send(peer, Message::RequestVote { term: self.term, last_log_index, last_log_term });
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self,term:u64,leader:Option<NodeId>)->Result<()>{
+        self.term=term;
+        self.voted_for=None;
+        self.role=Role::Follower;
+        self.leader=leader;
+        self.save_hard_state()?;
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    fn become_leader(&mut self)->Result<()>{
+        self.role=Role::Leader;
+        self.leader=Some(self.id);
+        self.elapsed=0;
+        let next=self.last_index()+1;
+        self.next_index=self.peers.iter().map(|peer|(*peer,next)).collect();
+        self.match_index=self.peers.iter().map(|peer|(*peer,0)).collect();
+        //entries from older terms only commit once an entry of this term does
+        self.append_local(None)?;
+        self.broadcast_append();
+        Ok(())
+    }
+
+    fn append_local(&mut self,command:Option<Operation>)->Result<u64>{
+        let entry=Entry{term:self.term,command};
+        let index=self.last_index()+1;
+        self.storage.append(index, std::slice::from_ref(&entry))?;
+        self.entries.push(entry);
+        self.advance_commit();
+        Ok(index)
+    }
+
+    fn advance_commit(&mut self){
+        let mut matched:Vec<u64>=self.match_index.values().copied().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a,b|b.cmp(a));
+        let majority_index=matched[self.quorum()-1];
+        if majority_index>self.commit_index&&self.term_at(majority_index)==Some(self.term){
+            self.commit_index=majority_index;
+        }
+    }
+
+    fn broadcast_append(&mut self){
+        for peer in self.peers.clone(){
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self,peer:NodeId){
+        let next=self.next_index.get(&peer).copied().unwrap_or(self.last_index()+1);
+        if next<=self.snapshot.last_index{
+            self.send_snapshot_chunk(peer);
+            return;
+        }
+
+        let prev_index=next-1;
+        let prev_term=self.term_at(prev_index).expect("Entries after the snapshot are in the log");
+        let start=(next-self.snapshot.last_index-1) as usize;
+        let end=(start+MAX_APPEND_ENTRIES).min(self.entries.len());
+        let entries=self.entries[start..end].to_vec();
+        self.send(peer, Message::AppendEntries { term: self.term, prev_index, prev_term, entries, commit: self.commit_index });
+    }
+
+    //reads the chunk from the storage, one that cannot be read is tried again on the next heartbeat
+    fn send_snapshot_chunk(&mut self,peer:NodeId){
+        let offset=match self.snapshot_sent.get(&peer) {
+            Some((last_index,received)) if *last_index==self.snapshot.last_index => *received,
+            _ => 0,
+        };
+        let chunk=match self.read_snapshot_chunk(offset) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("reading the raft snapshot failed: {:?}",e);
+                return;
+            },
+        };
+        let Chunk{entries,next,done}=chunk;
+        self.send(peer, Message::InstallSnapshot {
+            term: self.term,
+            last_index: self.snapshot.last_index,
+            last_term: self.snapshot.last_term,
+            offset,
+            next,
+            entries,
+            done
+        });
+    }
+
+    //the entries from byte offset of the snapshot on, as many as fit in a chunk, with the offset after them
+    fn read_snapshot_chunk(&self,offset:u64)->Result<Chunk>{
+        let mut stream=serde_json::Deserializer::from_reader(self.storage.read_snapshot(offset)?).into_iter::<(String,String)>();
+        let mut entries=Vec::new();
+        let mut bytes=0;
+        loop {
+            let next=offset+stream.byte_offset() as u64;
+            let Some(entry)=stream.next() else {
+                return Ok(Chunk{entries,next,done:true});
+            };
+            let (key,value)=entry.context(KVError::ParseError, "RaftNode::read_snapshot_chunk")?;
+            if !entries.is_empty()&&bytes+key.len()+value.len()>SNAPSHOT_CHUNK_BYTES{
+                return Ok(Chunk{entries,next,done:false});
+            }
+            bytes+=key.len()+value.len();
+            entries.push((key,value));
+        }
+    }
+
+    fn send(&mut self,to:NodeId,message:Message){
+        self.outbox.push(Envelope{from:self.id,to,message});
+    }
+
+    fn quorum(&self)->usize{
+        let members=self.peers.len()+1;
+        members/2+1
+    }
+
+    fn save_hard_state(&mut self)->Result<()>{
+        self.storage.save_hard_state(HardState{term:self.term,voted_for:self.voted_for})
+    }
+
+    fn reset_election_timer(&mut self){
+        self.elapsed=0;
+        self.election_timeout=self.rng.gen_range(ELECTION_TICKS..2*ELECTION_TICKS);
+    }
+}
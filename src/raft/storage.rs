@@ -0,0 +1,312 @@
+use std::{collections::HashMap, fs::{read_dir, remove_file, rename, DirBuilder, File, OpenOptions}, io::{self, BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{filesystem::{FileSystem, OsFileSystem}, Context, ErrorContext, KVError, Result};
+
+use super::{Entry, NodeId, Snapshot};
+
+#[derive(Deserialize,Serialize,Debug,Clone,Copy,Default,PartialEq)]
+pub struct HardState{
+    pub term:u64,
+    pub voted_for:Option<NodeId>
+}
+
+/// Everything a node needs to rejoin its cluster after a restart.
+#[derive(Debug,Clone,Default)]
+pub struct PersistentState{
+    pub hard_state:HardState,
+    pub snapshot:Snapshot,
+    //entries after the snapshot, the first one has index snapshot.last_index+1
+    pub entries:Vec<Entry>
+}
+
+/// Durable storage for the raft log, separate from the engine's own log.
+///
+/// The entries of a snapshot are a stream of json `(key,value)` pairs written and read
+/// apart from the rest, so they never have to be in memory all at once.
+pub trait RaftStorage{
+    fn load(&mut self)->Result<PersistentState>;
+    fn save_hard_state(&mut self,hard_state:HardState)->Result<()>;
+    /// Replaces every entry from `first_index` onwards with `entries`.
+    fn append(&mut self,first_index:u64,entries:&[Entry])->Result<()>;
+    /// Where the entries of the snapshot at `last_index` are written, from another thread if need be,
+    /// they are kept apart until `save_snapshot`.
+    fn snapshot_writer(&mut self,last_index:u64)->Result<Box<dyn Write+Send>>;
+    /// Makes the entries written for `snapshot` the snapshot and drops the log entries it covers.
+    fn save_snapshot(&mut self,snapshot:&Snapshot)->Result<()>;
+    /// The entries of the snapshot from byte `offset` of their stream on.
+    fn read_snapshot(&self,offset:u64)->Result<Box<dyn Read+Send>>;
+}
+
+#[derive(Default,Clone)]
+pub struct MemoryStorage{
+    state:PersistentState,
+    entries:Arc<Vec<u8>>,
+    writing:HashMap<u64,SharedBuffer>
+}
+
+//what a snapshot writer of a memory storage wrote, the storage keeps the other handle
+#[derive(Default,Clone)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self,buf:&[u8])->io::Result<usize>{
+        self.0.lock().map_err(|_|io::Error::other("snapshot writer poisoned"))?.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self)->io::Result<()>{
+        Ok(())
+    }
+}
+
+impl MemoryStorage {
+    pub fn new()->MemoryStorage{
+        MemoryStorage::default()
+    }
+}
+
+impl RaftStorage for MemoryStorage {
+    fn load(&mut self)->Result<PersistentState> {
+        Ok(self.state.clone())
+    }
+
+    fn save_hard_state(&mut self,hard_state:HardState)->Result<()> {
+        self.state.hard_state=hard_state;
+        Ok(())
+    }
+
+    fn append(&mut self,first_index:u64,entries:&[Entry])->Result<()> {
+        truncate_entries(&mut self.state, first_index);
+        self.state.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn snapshot_writer(&mut self,last_index:u64)->Result<Box<dyn Write+Send>> {
+        let buffer=SharedBuffer::default();
+        self.writing.insert(last_index, buffer.clone());
+        Ok(Box::new(buffer))
+    }
+
+    fn save_snapshot(&mut self,snapshot:&Snapshot)->Result<()> {
+        let buffer=self.writing.remove(&snapshot.last_index).ok_or(KVError::WriteError("MemoryStorage::save_snapshot1".into()))?;
+        let entries=buffer.0.lock().map_err(|_|KVError::WriteError("MemoryStorage::save_snapshot2".into()))?;
+        self.entries=Arc::new(entries.clone());
+        self.writing.retain(|last_index,_|*last_index>snapshot.last_index);
+        compact_entries(&mut self.state, snapshot);
+        Ok(())
+    }
+
+    fn read_snapshot(&self,offset:u64)->Result<Box<dyn Read+Send>> {
+        let mut reader=Cursor::new(self.entries.clone());
+        reader.set_position(offset);
+        Ok(Box::new(SharedReader(reader)))
+    }
+}
+
+struct SharedReader(Cursor<Arc<Vec<u8>>>);
+
+impl Read for SharedReader {
+    fn read(&mut self,buf:&mut [u8])->io::Result<usize>{
+        let position=self.0.position().min(self.0.get_ref().len() as u64) as usize;
+        let read=(&self.0.get_ref()[position..]).read(buf)?;
+        self.0.set_position((position+read) as u64);
+        Ok(read)
+    }
+}
+
+/// Keeps the raft state in a directory as `state`, `snapshot`, the entries of the snapshot
+/// at index `n` in `snapshot.n` and a `log` of json lines.
+pub struct FileStorage{
+    directory:PathBuf,
+    state:PersistentState,
+    log_file:File
+}
+
+impl FileStorage {
+    pub fn open(directory:PathBuf)->Result<FileStorage>{
+        DirBuilder::new().recursive(true).create(&directory).context(KVError::IOError, "FileStorage::open1")?;
+        let hard_state=read_json(directory.join("state"))?.unwrap_or_default();
+        let saved:Option<SavedSnapshot>=read_json(directory.join("snapshot"))?;
+        let snapshot=saved.as_ref().map(|saved|Snapshot{last_index:saved.last_index,last_term:saved.last_term}).unwrap_or_default();
+        //snapshots used to keep their entries inline
+        if let Some(entries)=saved.and_then(|saved|saved.entries){
+            let mut writer=BufWriter::new(File::create(snapshot_path(&directory, snapshot.last_index, true)).context(KVError::IOError, "FileStorage::open3")?);
+            for entry in entries{
+                serde_json::to_writer(&mut writer, &entry).context(KVError::WriteError, "FileStorage::open4")?;
+            }
+            writer.flush().context(KVError::WriteError, "FileStorage::open5")?;
+            drop(writer);
+            install_snapshot(&directory, &snapshot)?;
+        }
+        remove_stale_snapshots(&directory, snapshot.last_index)?;
+
+        let log_path=directory.join("log");
+        let mut entries=Vec::new();
+        if log_path.exists(){
+            let file=File::open(&log_path).context(KVError::IOError, "FileStorage::open2")?;
+            let first_index=snapshot.last_index+1;
+            for line in serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter::<(u64,Entry)>(){
+                //a torn write at the tail is dropped, it was never acknowledged
+                let Ok((index,entry))=line else { break };
+                if index<first_index{
+                    continue;
+                }
+                //a later line for the same index means the log was truncated there
+                entries.truncate((index-first_index) as usize);
+                entries.push(entry);
+            }
+        }
+
+        let state=PersistentState{hard_state,snapshot,entries};
+        let log_file=Self::rewrite_log(&directory, &state)?;
+        Ok(FileStorage{directory,state,log_file})
+    }
+
+    fn rewrite_log(directory:&Path,state:&PersistentState)->Result<File>{
+        let tmp_path=directory.join("log.tmp");
+        let file=File::create(&tmp_path).context(KVError::IOError, "FileStorage::rewrite_log1")?;
+        let mut writer=BufWriter::new(&file);
+        for (offset,entry) in state.entries.iter().enumerate(){
+            let index=state.snapshot.last_index+1+offset as u64;
+            serde_json::to_writer(&mut writer, &(index,entry)).context(KVError::WriteError, "FileStorage::rewrite_log2")?;
+        }
+        writer.flush().context(KVError::WriteError, "FileStorage::rewrite_log3")?;
+        drop(writer);
+        file.sync_all().context(KVError::WriteError, "FileStorage::rewrite_log4")?;
+        rename(&tmp_path, directory.join("log")).context(KVError::IOError, "FileStorage::rewrite_log5")?;
+
+        OpenOptions::new()
+        .append(true)
+        .open(directory.join("log"))
+        .context(KVError::IOError, "FileStorage::rewrite_log6")
+    }
+}
+
+impl RaftStorage for FileStorage {
+    fn load(&mut self)->Result<PersistentState> {
+        Ok(self.state.clone())
+    }
+
+    fn save_hard_state(&mut self,hard_state:HardState)->Result<()> {
+        write_json(self.directory.join("state"), &hard_state)?;
+        self.state.hard_state=hard_state;
+        Ok(())
+    }
+
+    fn append(&mut self,first_index:u64,entries:&[Entry])->Result<()> {
+        let last_index=self.state.snapshot.last_index+self.state.entries.len() as u64;
+        truncate_entries(&mut self.state, first_index);
+        if first_index<=last_index{
+            self.state.entries.extend_from_slice(entries);
+            self.log_file=Self::rewrite_log(&self.directory, &self.state)?;
+            return Ok(());
+        }
+
+        let mut bytes=Vec::new();
+        for (offset,entry) in entries.iter().enumerate(){
+            serde_json::to_writer(&mut bytes, &(first_index+offset as u64,entry)).context(KVError::WriteError, "FileStorage::append1")?;
+        }
+        self.log_file.write_all(&bytes).context(KVError::WriteError, "FileStorage::append2")?;
+        self.log_file.sync_data().context(KVError::WriteError, "FileStorage::append3")?;
+        self.state.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn snapshot_writer(&mut self,last_index:u64)->Result<Box<dyn Write+Send>> {
+        let file=File::create(snapshot_path(&self.directory, last_index, true)).context(KVError::IOError, "FileStorage::snapshot_writer")?;
+        Ok(Box::new(BufWriter::new(file)))
+    }
+
+    fn save_snapshot(&mut self,snapshot:&Snapshot)->Result<()> {
+        install_snapshot(&self.directory, snapshot)?;
+        remove_stale_snapshots(&self.directory, snapshot.last_index)?;
+        compact_entries(&mut self.state, snapshot);
+        self.log_file=Self::rewrite_log(&self.directory, &self.state)?;
+        Ok(())
+    }
+
+    fn read_snapshot(&self,offset:u64)->Result<Box<dyn Read+Send>> {
+        let path=snapshot_path(&self.directory, self.state.snapshot.last_index, false);
+        let mut file=match File::open(&path) {
+            Ok(file) => file,
+            //nothing was ever compacted
+            Err(e) if e.kind()==ErrorKind::NotFound => return Ok(Box::new(io::empty())),
+            Err(e) => return Err(KVError::IOError(ErrorContext::new("FileStorage::read_snapshot1").with_path(&path).with_source(e))),
+        };
+        file.seek(SeekFrom::Start(offset)).context(KVError::IOError, "FileStorage::read_snapshot2")?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+//the metadata of a snapshot, with the entries of one saved before they were kept apart
+#[derive(Deserialize)]
+struct SavedSnapshot{
+    last_index:u64,
+    last_term:u64,
+    entries:Option<Vec<(String,String)>>
+}
+
+fn snapshot_path(directory:&Path,last_index:u64,tmp:bool)->PathBuf{
+    if tmp {directory.join(format!("snapshot.{}.tmp",last_index))} else {directory.join(format!("snapshot.{}",last_index))}
+}
+
+//the entries are on disk under their final name before the metadata points at them
+fn install_snapshot(directory:&Path,snapshot:&Snapshot)->Result<()>{
+    let tmp_path=snapshot_path(directory, snapshot.last_index, true);
+    File::open(&tmp_path).and_then(|file|file.sync_all()).context(KVError::WriteError, "raft::storage::install_snapshot1")?;
+    rename(&tmp_path, snapshot_path(directory, snapshot.last_index, false)).context(KVError::IOError, "raft::storage::install_snapshot2")?;
+    OsFileSystem.sync_dir(directory).context(KVError::IOError, "raft::storage::install_snapshot3")?;
+    write_json(directory.join("snapshot"), snapshot)
+}
+
+//entries of older snapshots, and of ones written but never saved, newer ones may still be on their way
+fn remove_stale_snapshots(directory:&Path,last_index:u64)->Result<()>{
+    for entry in read_dir(directory).context(KVError::IOError, "raft::storage::remove_stale_snapshots1")?{
+        let name=entry.context(KVError::IOError, "raft::storage::remove_stale_snapshots2")?.file_name();
+        let Some(index)=name.to_str().and_then(|name|name.strip_prefix("snapshot.")) else {
+            continue;
+        };
+        let (index,tmp)=match index.strip_suffix(".tmp") {
+            Some(index) => (index,true),
+            None => (index,false),
+        };
+        let stale=index.parse::<u64>().is_ok_and(|index|index<last_index||(index==last_index&&tmp));
+        if stale{
+            remove_file(directory.join(&name)).context(KVError::IOError, "raft::storage::remove_stale_snapshots3")?;
+        }
+    }
+    Ok(())
+}
+
+fn truncate_entries(state:&mut PersistentState,first_index:u64){
+    let keep=first_index.saturating_sub(state.snapshot.last_index+1) as usize;
+    state.entries.truncate(keep);
+}
+
+fn compact_entries(state:&mut PersistentState,snapshot:&Snapshot){
+    let covered=snapshot.last_index.saturating_sub(state.snapshot.last_index) as usize;
+    if covered>=state.entries.len(){
+        state.entries.clear();
+    } else {
+        state.entries.drain(..covered);
+    }
+    state.snapshot = *snapshot;
+}
+
+fn read_json<T:serde::de::DeserializeOwned>(path:PathBuf)->Result<Option<T>>{
+    match File::open(path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file)).map(Some).context(KVError::ParseError, "raft::storage::read_json"),
+        Err(_) => Ok(None),
+    }
+}
+
+//write to a temporary file first so a crash never leaves a half written file behind
+fn write_json<T:Serialize>(path:PathBuf,data:&T)->Result<()>{
+    let tmp_path=path.with_extension("tmp");
+    let file=File::create(&tmp_path).context(KVError::IOError, "raft::storage::write_json1")?;
+    serde_json::to_writer(&file, data).context(KVError::WriteError, "raft::storage::write_json2")?;
+    file.sync_all().context(KVError::WriteError, "raft::storage::write_json3")?;
+    rename(tmp_path, path).context(KVError::IOError, "raft::storage::write_json4")
+}
@@ -0,0 +1,59 @@
+use std::collections::{HashSet, VecDeque};
+
+use super::{Envelope, NodeId};
+
+/// In-process network for running a cluster inside a single thread.
+///
+/// Messages between nodes on different sides of a partition, or to and from
+/// an isolated node, are silently dropped like on a real broken link.
+#[derive(Default)]
+pub struct MemoryNetwork{
+    in_flight:VecDeque<Envelope>,
+    cut:HashSet<(NodeId,NodeId)>
+}
+
+impl MemoryNetwork {
+    pub fn new()->MemoryNetwork{
+        MemoryNetwork::default()
+    }
+
+    pub fn send(&mut self,envelope:Envelope){
+        if !self.cut.contains(&(envelope.from,envelope.to)){
+            self.in_flight.push_back(envelope);
+        }
+    }
+
+    pub fn send_all(&mut self,envelopes:impl IntoIterator<Item = Envelope>){
+        for envelope in envelopes{
+            self.send(envelope);
+        }
+    }
+
+    /// Next message still deliverable, links cut after sending also drop messages in flight.
+    pub fn receive(&mut self)->Option<Envelope>{
+        while let Some(envelope)=self.in_flight.pop_front(){
+            if !self.cut.contains(&(envelope.from,envelope.to)){
+                return Some(envelope);
+            }
+        }
+        None
+    }
+
+    /// Cuts every link between a node in `side` and a node in `other`.
+    pub fn partition(&mut self,side:&[NodeId],other:&[NodeId]){
+        for a in side{
+            for b in other{
+                self.cut.insert((*a,*b));
+                self.cut.insert((*b,*a));
+            }
+        }
+    }
+
+    pub fn isolate(&mut self,node:NodeId,cluster:&[NodeId]){
+        self.partition(&[node], cluster);
+    }
+
+    pub fn heal(&mut self){
+        self.cut.clear();
+    }
+}
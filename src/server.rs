@@ -1,23 +1,90 @@
-use std::{error::Error, f32::consts::E, io::{BufReader, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, result, time::Duration};
+use std::{collections::BinaryHeap, error::Error, fmt, fs::{self, File}, io::{self, BufReader, ErrorKind, Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, path::{Path, PathBuf}, result, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{kv::{command::KVCommand, KVError}, KvsEngine};
+use crate::{client::sharded::{in_range, key_hash, HashRange}, kv::{command::{IndexCommand, KVCommand, NamespaceCommand}, export::import, namespace::NamespaceOptions, Context, ErrorContext, KVError}, common::{EXIT_CONFIG, EXIT_DATA, EXIT_IO, EXIT_UNAVAILABLE, MEGABYTE}, EngineStats, KvsEngine, DEFAULT_NAMESPACE};
 
-use self::command::StorageEngine;
+use self::{cluster::{ClusterMember, RaftStats}, command::{AdminCommand, Request, ScanPage, StorageEngine}, replication::{Follower, ReplicationStats}, shutdown::StopHandle};
 
 
 pub mod config;
 pub mod command;
+pub mod replication;
+pub mod cluster;
+pub mod migrate;
+pub mod shutdown;
 
 pub type Result<T>=result::Result<T,ServerError>;
 
 #[derive(Debug)]
 pub enum ServerError{
-    BindError(&'static str),
-    EngineStartUpError(&'static str),
-    EngineOperationError(&'static str),
-    CommandParseError(&'static str)
+    /// The listening socket could not be set up.
+    BindError(ErrorContext),
+    EngineStartUpError(ErrorContext),
+    EngineOperationError(ErrorContext),
+    CommandParseError(ErrorContext),
+    ReplicationError(ErrorContext),
+    /// Arguments or stored settings that cannot work, with what is wrong about them.
+    ConfigError(&'static str)
+}
+
+impl ServerError {
+    pub fn context(&self)->Option<&ErrorContext>{
+        match self {
+            ServerError::BindError(context)
+            |ServerError::EngineStartUpError(context)
+            |ServerError::EngineOperationError(context)
+            |ServerError::CommandParseError(context)
+            |ServerError::ReplicationError(context) => Some(context),
+            ServerError::ConfigError(_) => None,
+        }
+    }
+
+    /// The exit code of the server failing with this error, the one of the engine error underneath if there is one.
+    pub fn exit_code(&self)->u8{
+        let engine_error=self.context()
+        .and_then(|context|context.source.as_deref())
+        .and_then(|source|source.downcast_ref::<KVError>());
+        match (self,engine_error) {
+            (_,Some(error)) => error.exit_code(),
+            (ServerError::BindError(_),_) => EXIT_UNAVAILABLE,
+            (ServerError::EngineStartUpError(_)|ServerError::EngineOperationError(_),_) => EXIT_IO,
+            (ServerError::CommandParseError(_),_) => EXIT_DATA,
+            (ServerError::ReplicationError(_),_) => EXIT_UNAVAILABLE,
+            (ServerError::ConfigError(_),_) => EXIT_CONFIG,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            ServerError::BindError(context) => write!(f,"could not listen{context}"),
+            ServerError::EngineStartUpError(context) => write!(f,"engine failed to start{context}"),
+            ServerError::EngineOperationError(context) => write!(f,"engine operation failed{context}"),
+            ServerError::CommandParseError(context) => write!(f,"malformed request{context}"),
+            ServerError::ReplicationError(context) => write!(f,"replication failed{context}"),
+            ServerError::ConfigError(message) => write!(f,"invalid configuration: {message}"),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self)->Option<&(dyn Error+'static)>{
+        self.context()?.source.as_deref().map(|source|source as &(dyn Error+'static))
+    }
+}
+
+impl From<KVError> for ServerError {
+    fn from(error:KVError)->Self{
+        ServerError::EngineOperationError(ErrorContext::default().with_source(error))
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(error:io::Error)->Self{
+        ServerError::EngineOperationError(ErrorContext::default().with_source(error))
+    }
 }
 
 #[derive(Deserialize,Serialize)]
@@ -30,16 +97,75 @@ pub enum ServerResponse<T>{
 pub enum ErrorType{
     OperationError,
     KeyNotFound,
+    NamespaceNotFound,
+    IndexNotFound,
+    Redirect(SocketAddr),
+    NotLeader(Option<SocketAddr>)
 }
 
-#[derive(Serialize,Deserialize)]
+/// Bytes a request may take, imports are the largest.
+pub const MAX_REQUEST_SIZE:u64=64*MEGABYTE as u64;
+
+const METADATA_FILE:&str="metadata";
+const DEFAULT_DB_DIR:&str="db";
+
+/// Which engine owns a server's data directory, and which directory in it holds the engine's files.
+#[derive(Serialize,Deserialize,Default)]
 pub struct StorageMetaData{
-    pub engine:Option<StorageEngine>
+    pub engine:Option<StorageEngine>,
+    //set once a migration moved the engine out of the default directory
+    #[serde(default,skip_serializing_if="Option::is_none")]
+    pub db:Option<String>
+}
+
+impl StorageMetaData {
+    /// The metadata of the data directory `data_path`, with no engine if none was recorded yet.
+    pub fn load(data_path:&Path)->Result<StorageMetaData>{
+        let meta_path=data_path.join(METADATA_FILE);
+        let bytes=match fs::read(&meta_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind()==ErrorKind::NotFound => return Ok(StorageMetaData::default()),
+            Err(e) => return Err(ServerError::EngineStartUpError(ErrorContext::new("open metadata").with_path(&meta_path).with_source(e))),
+        };
+        if bytes.is_empty(){
+            return Ok(StorageMetaData::default());
+        }
+        serde_json::from_slice(&bytes)
+        .map_err(|e|ServerError::EngineStartUpError(ErrorContext::new("parse metadata").with_path(&meta_path).with_source(e)))
+    }
+
+    /// Replaces the metadata of `data_path` in one rename, so it is either the old or the new one.
+    pub fn save(&self,data_path:&Path)->Result<()>{
+        let meta_path=data_path.join(METADATA_FILE);
+        let tmp_path=data_path.join(format!("{METADATA_FILE}.tmp"));
+        let file=File::create(&tmp_path).context(ServerError::EngineStartUpError, "StorageMetaData::save1")?;
+        serde_json::to_writer(&file, self).context(ServerError::EngineStartUpError, "StorageMetaData::save2")?;
+        file.sync_all().context(ServerError::EngineStartUpError, "StorageMetaData::save3")?;
+        fs::rename(&tmp_path, &meta_path)
+        .map_err(|e|ServerError::EngineStartUpError(ErrorContext::new("StorageMetaData::save4").with_path(&meta_path).with_source(e)))
+    }
+
+    pub fn db_path(&self,data_path:&Path)->PathBuf{
+        data_path.join(self.db.as_deref().unwrap_or(DEFAULT_DB_DIR))
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+pub struct ServerStats{
+    pub engine:String,
+    #[serde(default)]
+    pub engine_stats:EngineStats,
+    pub replication:Option<ReplicationStats>,
+    pub raft:Option<RaftStats>
 }
 
 pub struct Server{
-    socket:TcpListener,
-    engine:Box<dyn KvsEngine>
+    //none for a server answering only what is handed to `handle_request`
+    socket:Option<TcpListener>,
+    engine:Box<dyn KvsEngine>,
+    follower:Option<Follower>,
+    cluster:Option<ClusterMember>,
+    stop:Arc<AtomicBool>
 }
 
 impl Server {   
@@ -48,62 +174,247 @@ impl Server {
         let engine=engine.into();
         eprintln!("{} {} with addr {}",engine.name(),env!("CARGO_PKG_VERSION"),addr);
         Ok(Server{
-            socket:TcpListener::bind(addr).inspect_err(|e|println!("{e}")).map_err(|_|ServerError::BindError("Server::new1"))?,
-            engine
+            socket:Some(TcpListener::bind(addr).context(ServerError::BindError, "Server::new1")?),
+            engine,
+            follower:None,
+            cluster:None,
+            stop:Arc::new(AtomicBool::new(false))
         })
     }
 
+    /// Creates a server without a socket, requests reach it through `handle_request` only.
+    pub fn in_process(engine:impl Into<Box<dyn KvsEngine>>)->Server{
+        Server{
+            socket:None,
+            engine:engine.into(),
+            follower:None,
+            cluster:None,
+            stop:Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Creates a read only server that follows `leader` and redirects writes to it,
+    /// keeping how far it got in `position_file`.
+    pub fn new_replica(addr:impl Into<SocketAddr>,engine:impl Into<Box<dyn KvsEngine>>,leader:SocketAddr,position_file:PathBuf)->Result<Server>{
+        let mut server=Self::new(addr, engine)?;
+        eprintln!("replica of {}",leader);
+        server.listener()?.set_nonblocking(true).context(ServerError::BindError, "Server::new_replica1")?;
+        server.follower=Some(Follower::new(leader, position_file)?);
+        Ok(server)
+    }
+
+    /// Creates a member of a raft cluster with the other members at `peers`.
+    pub fn new_cluster(addr:SocketAddr,engine:impl Into<Box<dyn KvsEngine>>,peers:Vec<SocketAddr>,raft_dir:PathBuf)->Result<Server>{
+        let mut server=Self::new(addr, engine)?;
+        eprintln!("raft cluster with {:?}",peers);
+        server.listener()?.set_nonblocking(true).context(ServerError::BindError, "Server::new_cluster1")?;
+        server.cluster=Some(ClusterMember::new(addr, peers, raft_dir)?);
+        Ok(server)
+    }
+
+    /// Something to stop the server with from another thread.
+    pub fn stop_handle(&self)->Result<StopHandle>{
+        let addr=self.listener()?.local_addr().context(ServerError::BindError, "Server::stop_handle")?;
+        Ok(StopHandle::new(self.stop.clone(), addr))
+    }
+
+    fn listener(&self)->Result<&TcpListener>{
+        self.socket.as_ref().ok_or(ServerError::ConfigError("the server has no socket"))
+    }
+
+    /// Serves requests until stopped through a `StopHandle`, returns at once without a socket.
     pub fn start(&mut self){
-        for mut connection in self.socket.incoming().flatten(){
-            if connection.set_read_timeout(Some(Duration::from_millis(100))).is_err(){
-                continue;
+        while !self.stop.load(Ordering::SeqCst) {
+            let Some(socket)=self.socket.as_ref() else {
+                return;
+            };
+            match socket.accept() {
+                Ok(_) if self.stop.load(Ordering::SeqCst) => break,
+                Ok((connection,_)) => self.handle(connection),
+                Err(e) if e.kind()==ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(_) => continue,
+            }
+
+            if let Some(follower)=self.follower.as_mut(){
+                follower.poll(&mut self.engine);
             }
-            
-            
-            match Self::parse_command(&mut connection){
-                Ok(command) => {
-                    
-                    Self::dispatch(&mut self.engine,&mut connection,command)
-                },
-                Err(_) => {
-                    
-                    let _ = writeln!(connection,"Invalid kv command format");
-                },
+            if let Some(cluster)=self.cluster.as_mut(){
+                cluster.poll(&mut self.engine);
             }
         }
     }
 
-    fn parse_command(connection:&mut TcpStream)->Result<KVCommand>{
-        let buf=BufReader::new(connection);
-        let mut stream_deserializer=serde_json::Deserializer::from_reader(buf).into_iter();
+    fn handle(&mut self,mut connection:TcpStream){
+        if connection.set_nonblocking(false).is_err()||connection.set_read_timeout(Some(Duration::from_millis(100))).is_err(){
+            return;
+        }
+        
+        //a peer keeps sending raft messages on its connection, so what was read past the first one is handed on with it
+        let (request,unread)={
+            let mut reader=BufReader::new((&connection).take(MAX_REQUEST_SIZE));
+            (Self::read_request(&mut reader),reader.buffer().to_vec())
+        };
+        match (request,self.cluster.as_mut()) {
+            (Ok(Request::Kv(command)),Some(cluster)) => {
+                cluster.propose(command, connection)
+            },
+            (Ok(Request::Admin(AdminCommand::Raft(envelope))),Some(cluster)) => {
+                cluster.step(envelope);
+                cluster.accept_peer(connection, unread)
+            },
+            (Ok(request),_) => {
+                self.handle_request(request, &mut connection)
+            },
+            (Err(_),_) => {
+                
+                let _ = writeln!(connection,"Invalid kv command format");
+            },
+        }
+    }
+
+    /// Answers `request` on `out` as it would be answered on a connection.
+    /// A raft member answers its writes once they are applied, so it only takes admin requests here.
+    pub fn handle_request(&mut self,request:Request,out:&mut dyn Write){
+        match (request,self.cluster.is_some()) {
+            (Request::Kv(_),true) => {
+                Self::send_result::<()>(out, Err(ErrorType::OperationError))
+            },
+            (Request::Kv(command),false) => {
+                let leader=self.follower.as_ref().map(Follower::leader);
+                Self::dispatch(&mut self.engine,leader,out,command)
+            },
+            (Request::Admin(command),_) => {
+                self.dispatch_admin(out,command)
+            },
+        }
+    }
+
+    /// Reads one request from `connection`, giving up on one longer than `MAX_REQUEST_SIZE`.
+    pub fn parse_request(connection:impl Read)->Result<Request>{
+        Self::read_request(&mut BufReader::new(connection.take(MAX_REQUEST_SIZE)))
+    }
+
+    //reads one request without reading past its end, so `reader` still holds whatever follows it
+    fn read_request(reader:&mut impl Read)->Result<Request>{
+        let mut stream_deserializer=serde_json::Deserializer::from_reader(reader).into_iter();
         match stream_deserializer.next(){
             Some(Ok(cmd)) => Ok(cmd),
-            _ => Err(ServerError::CommandParseError("Server::parse_command1")),
+            Some(Err(error)) => Err(ServerError::CommandParseError(ErrorContext::new("Server::parse_request1").with_source(error))),
+            None => Err(ServerError::CommandParseError("Server::parse_request2".into())),
         }
     }
 
-    fn dispatch(engine:&mut Box<dyn KvsEngine>,connection:&mut TcpStream,command:KVCommand){
-        match command {
-            KVCommand::Get { key } => {
-                
+    fn dispatch(engine:&mut Box<dyn KvsEngine>,leader:Option<SocketAddr>,connection:&mut dyn Write,command:KVCommand){
+        match (command,leader) {
+            (KVCommand::Ns(command),leader) => Self::dispatch_namespace(engine, leader, connection, command),
+            (KVCommand::History { key, ns },_) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|engine.history(key).map_err(|_|ErrorType::OperationError));
                 Self::send_result(
                     connection,
-                    engine.get(key).map_err(|_|ErrorType::OperationError)
+                    res
                 )
             },
-            KVCommand::Set { key, value } => {
-                
+            (KVCommand::Query { index, value, ns },_) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|match engine.query_index(&index, &value) {
+                    Ok(keys) => Ok(keys),
+                    Err(KVError::IndexNotFound(_)) => Err(ErrorType::IndexNotFound),
+                    _=> Err(ErrorType::OperationError)
+                });
                 Self::send_result(
                     connection,
-                    engine.set(key, value).map_err(|_|ErrorType::OperationError)
+                    res
                 )
             },
-            KVCommand::Rm { key } => {
-                
-                let res=match engine.remove(key) {
+            (KVCommand::Index(_),Some(leader)) => {
+                Self::send_result::<()>(
+                    connection,
+                    Err(ErrorType::Redirect(leader))
+                )
+            },
+            (KVCommand::Index(IndexCommand::Create { name, pointer, ns }),None) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|engine.create_index(&name, &pointer).map_err(|_|ErrorType::OperationError));
+                Self::send_result(
+                    connection,
+                    res
+                )
+            },
+            (KVCommand::Index(IndexCommand::Drop { name, ns }),None) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|match engine.drop_index(&name) {
+                    Ok(_) => Ok(()),
+                    Err(KVError::IndexNotFound(_)) => Err(ErrorType::IndexNotFound),
+                    _=> Err(ErrorType::OperationError)
+                });
+                Self::send_result(
+                    connection,
+                    res
+                )
+            },
+            (KVCommand::Get { key, ns },_) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|engine.get(key).map_err(|_|ErrorType::OperationError));
+                Self::send_result(
+                    connection,
+                    res
+                )
+            },
+            (KVCommand::Set { .. }|KVCommand::Rm { .. },Some(leader)) => {
+                Self::send_result::<()>(
+                    connection,
+                    Err(ErrorType::Redirect(leader))
+                )
+            },
+            (KVCommand::Set { key, value, ns },None) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|engine.set(key, value).map_err(|_|ErrorType::OperationError));
+                Self::send_result(
+                    connection,
+                    res
+                )
+            },
+            (KVCommand::Rm { key, ns },None) => {
+                let res=Self::namespace(engine, ns)
+                .and_then(|mut engine|match engine.remove(key) {
                     Ok(_) => Ok(()),
                     Err(KVError::KeyNotFound(_)) => Err(ErrorType::KeyNotFound),
                     _=> Err(ErrorType::OperationError)
+                });
+                Self::send_result(
+                    connection,
+                    res
+                )
+            },
+        }
+    }
+
+    fn dispatch_namespace(engine:&mut Box<dyn KvsEngine>,leader:Option<SocketAddr>,connection:&mut dyn Write,command:NamespaceCommand){
+        match (command,leader) {
+            (NamespaceCommand::List,_) => {
+                Self::send_result(
+                    connection,
+                    engine.list_namespaces().map_err(|_|ErrorType::OperationError)
+                )
+            },
+            (NamespaceCommand::Create { .. }|NamespaceCommand::Drop { .. },Some(leader)) => {
+                Self::send_result::<()>(
+                    connection,
+                    Err(ErrorType::Redirect(leader))
+                )
+            },
+            (NamespaceCommand::Create { name, merge_threshold },None) => {
+                Self::send_result(
+                    connection,
+                    engine.create_namespace(&name, NamespaceOptions{merge_threshold}).map_err(|_|ErrorType::OperationError)
+                )
+            },
+            (NamespaceCommand::Drop { name },None) => {
+                let res=match engine.drop_namespace(&name) {
+                    Ok(_) => Ok(()),
+                    Err(KVError::NamespaceNotFound(_)) => Err(ErrorType::NamespaceNotFound),
+                    _=> Err(ErrorType::OperationError)
                 };
                 Self::send_result(
                     connection,
@@ -113,7 +424,100 @@ impl Server {
         }
     }
 
-    fn send_result<T>(connection:&mut TcpStream,res:result::Result<T,ErrorType>)
+    fn namespace<'a>(engine:&'a mut Box<dyn KvsEngine>,ns:Option<String>)->result::Result<Box<dyn KvsEngine+'a>,ErrorType>{
+        match engine.namespace(ns.as_deref().unwrap_or(DEFAULT_NAMESPACE)) {
+            Ok(engine) => Ok(engine),
+            Err(KVError::NamespaceNotFound(_)) => Err(ErrorType::NamespaceNotFound),
+            Err(_) => Err(ErrorType::OperationError),
+        }
+    }
+
+    fn scan_page(engine:&mut dyn KvsEngine,ranges:&[HashRange],after:Option<String>,limit:usize)->crate::Result<ScanPage>{
+        Self::page(engine, after, limit, |key|{
+            let hash=key_hash(key.as_bytes());
+            ranges.iter().any(|range|in_range(hash, *range))
+        })
+    }
+
+    //up to `limit` entries whose key `keep` takes, in key order from after the cursor key.
+    //only the keys are listed whole, values are read for the page alone
+    fn page(engine:&mut dyn KvsEngine,after:Option<String>,limit:usize,keep:impl Fn(&str)->bool)->crate::Result<ScanPage>{
+        //the smallest keys seen so far, the largest of them on top to make way for a smaller one
+        let mut page=BinaryHeap::new();
+        let mut more=false;
+        for key in engine.keys()?{
+            if after.as_ref().is_some_and(|after|key<=*after)||!keep(&key){
+                continue;
+            }
+            page.push(key);
+            if page.len()>limit{
+                page.pop();
+                more=true;
+            }
+        }
+        let keys=page.into_sorted_vec();
+        let next=if more {keys.last().cloned()} else {None};
+        let mut entries=Vec::with_capacity(keys.len());
+        for key in keys{
+            //a key removed since the listing is left out
+            if let Some(value)=engine.get(key.clone())?{
+                entries.push((key,value));
+            }
+        }
+        Ok(ScanPage{entries,next})
+    }
+
+    fn dispatch_admin(&mut self,connection:&mut dyn Write,command:AdminCommand){
+        match command {
+            AdminCommand::Replicate { position, snapshot, ns } => {
+                let res=Self::namespace(&mut self.engine, ns)
+                .and_then(|mut engine|engine.replicate(position, snapshot).map_err(|_|ErrorType::OperationError));
+                Self::send_result(connection, res)
+            },
+            AdminCommand::Stats => {
+                let stats=ServerStats{
+                    engine:self.engine.name(),
+                    engine_stats:self.engine.stats(),
+                    replication:self.follower.as_ref().map(Follower::stats),
+                    raft:self.cluster.as_ref().map(ClusterMember::stats)
+                };
+                Self::send_result(
+                    connection,
+                    Ok(stats)
+                )
+            },
+            AdminCommand::Raft(envelope) => {
+                if let Some(cluster)=self.cluster.as_mut(){
+                    cluster.step(envelope);
+                }
+            },
+            AdminCommand::ScanRanges { ranges, after, limit } => {
+                let res=Self::scan_page(&mut *self.engine, &ranges, after, limit);
+                Self::send_result(
+                    connection,
+                    res.map_err(|_|ErrorType::OperationError)
+                )
+            },
+            AdminCommand::Export { prefix, after, limit, ns } => {
+                let res=Self::namespace(&mut self.engine, ns)
+                .and_then(|mut engine|Self::page(&mut *engine, after, limit, |key|key.starts_with(&prefix)).map_err(|_|ErrorType::OperationError));
+                Self::send_result(connection, res)
+            },
+            AdminCommand::Import { entries, on_conflict, ns } => {
+                let res=match (self.follower.as_ref().map(Follower::leader),self.cluster.is_some()) {
+                    (Some(leader),_) => Err(ErrorType::Redirect(leader)),
+                    (None,true) => Err(ErrorType::OperationError),
+                    (None,false) => Self::namespace(&mut self.engine, ns).and_then(|mut engine|{
+                        let batch_size=entries.len();
+                        import(&mut *engine, entries.into_iter().map(Ok), on_conflict, batch_size).map_err(|_|ErrorType::OperationError)
+                    }),
+                };
+                Self::send_result(connection, res)
+            },
+        }
+    }
+
+    fn send_result<T>(connection:&mut dyn Write,res:result::Result<T,ErrorType>)
     where
         T:Serialize
     {   
@@ -123,4 +527,4 @@ impl Server {
         };
         let _=serde_json::to_writer(connection, &response);
     }
-}
\ No newline at end of file
+}
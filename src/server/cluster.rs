@@ -0,0 +1,312 @@
+use std::{collections::HashMap, io::{BufWriter, ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, path::PathBuf, sync::mpsc::{self, SyncSender}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{kv::{command::KVCommand, Context, KVError, Operation}, raft::{self, storage::FileStorage, Applied, Envelope, NodeId, RaftNode, Snapshot}, EntryStream, KvsEngine};
+
+use super::{command::AdminCommand, ErrorType, Result, Server, ServerError, MAX_REQUEST_SIZE};
+
+const TICK_INTERVAL:Duration=Duration::from_millis(10);
+const CONNECT_TIMEOUT:Duration=Duration::from_millis(50);
+const WRITE_TIMEOUT:Duration=Duration::from_millis(500);
+//messages queued for a peer before more are dropped
+const PEER_QUEUE:usize=256;
+//applied entries kept in the raft log before it is compacted into a snapshot
+const SNAPSHOT_THRESHOLD:u64=1000;
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct RaftStats{
+    pub id:NodeId,
+    pub term:u64,
+    pub leader:Option<SocketAddr>,
+    pub commit_index:u64,
+    pub last_applied:u64,
+    pub snapshot_index:u64
+}
+
+enum PendingKind{
+    Get,
+    Set,
+    Remove
+}
+
+//a client waiting for its proposal to be applied
+struct Pending{
+    index:u64,
+    term:u64,
+    kind:PendingKind,
+    connection:TcpStream
+}
+
+//sends the messages to one peer from a thread of its own, so a slow or unreachable peer never holds up the server
+struct Peer{
+    queue:SyncSender<Envelope>
+}
+
+impl Peer {
+    fn spawn(addr:SocketAddr)->Peer{
+        let (queue,messages)=mpsc::sync_channel::<Envelope>(PEER_QUEUE);
+        //ends once the member is dropped along with the sending side
+        thread::spawn(move||{
+            let mut connection:Option<BufWriter<TcpStream>>=None;
+            for envelope in messages{
+                if connection.is_none(){
+                    connection=TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok()
+                    .filter(|connection|connection.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok())
+                    .map(BufWriter::new);
+                }
+                let Some(writer)=connection.as_mut() else {
+                    continue;
+                };
+                //the connection is kept until a message fails on it, the next one reconnects
+                if serde_json::to_writer(&mut *writer, &AdminCommand::Raft(envelope)).is_err()||writer.flush().is_err(){
+                    connection=None;
+                }
+            }
+        });
+        Peer{queue}
+    }
+
+    fn send(&self,envelope:Envelope){
+        //delivery is best effort, a full queue drops the message and raft retries on the next heartbeat
+        let _=self.queue.try_send(envelope);
+    }
+}
+
+//a connection a peer sends its messages on, read without blocking the server
+struct Inbound{
+    connection:TcpStream,
+    unread:Vec<u8>
+}
+
+impl Inbound {
+    //reads what arrived into `envelopes`, false once the connection is closed or sent something else
+    fn receive(&mut self,envelopes:&mut Vec<Envelope>)->bool{
+        let mut open=true;
+        let mut buf=[0;64*1024];
+        loop {
+            match self.connection.read(&mut buf) {
+                Ok(0) => {
+                    open=false;
+                    break;
+                },
+                Ok(read) => self.unread.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind()==ErrorKind::WouldBlock => break,
+                Err(e) if e.kind()==ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    open=false;
+                    break;
+                },
+            }
+        }
+
+        let mut stream=serde_json::Deserializer::from_slice(&self.unread).into_iter::<AdminCommand>();
+        let mut parsed=0;
+        loop {
+            match stream.next() {
+                Some(Ok(AdminCommand::Raft(envelope))) => {
+                    envelopes.push(envelope);
+                    parsed=stream.byte_offset();
+                },
+                //the rest of a message still to arrive
+                Some(Err(e)) if e.is_eof() => break,
+                None => break,
+                Some(_) => return false,
+            }
+        }
+        self.unread.drain(..parsed);
+        open&&(self.unread.len() as u64)<MAX_REQUEST_SIZE
+    }
+}
+
+//a snapshot whose entries are being written from the engine on a thread of its own
+struct SnapshotJob{
+    snapshot:Snapshot,
+    handle:JoinHandle<crate::Result<()>>
+}
+
+impl SnapshotJob {
+    fn spawn(snapshot:Snapshot,entries:EntryStream,mut writer:Box<dyn Write+Send>)->SnapshotJob{
+        let handle=thread::spawn(move||{
+            for entry in entries{
+                serde_json::to_writer(&mut writer, &entry?).context(KVError::WriteError, "SnapshotJob::spawn1")?;
+            }
+            writer.flush().context(KVError::WriteError, "SnapshotJob::spawn2")
+        });
+        SnapshotJob{snapshot,handle}
+    }
+}
+
+/// Runs a raft node inside the server and answers clients once their entry is applied.
+///
+/// Node ids are the positions of the member addresses in sorted order, so every
+/// member derives the same ids from the same `--raft-peers` list.
+pub struct ClusterMember{
+    node:RaftNode,
+    members:Vec<SocketAddr>,
+    peers:HashMap<NodeId,Peer>,
+    last_tick:Instant,
+    pending:Vec<Pending>,
+    inbound:Vec<Inbound>,
+    snapshot_job:Option<SnapshotJob>
+}
+
+impl ClusterMember {
+    pub fn new(addr:SocketAddr,peers:Vec<SocketAddr>,directory:PathBuf)->Result<ClusterMember>{
+        let mut members=peers;
+        members.push(addr);
+        members.sort();
+        members.dedup();
+        let id=members.iter().position(|member|*member==addr).expect("Own address is a member") as NodeId;
+        let ids=(0..members.len() as NodeId).collect();
+        let peers=members.iter().enumerate()
+        .filter(|(_,member)|**member!=addr)
+        .map(|(peer,member)|(peer as NodeId,Peer::spawn(*member)))
+        .collect();
+        let storage=FileStorage::open(directory).context(ServerError::EngineStartUpError, "raft storage")?;
+        let node=RaftNode::new(id, ids, Box::new(storage), addr.port() as u64)
+        .context(ServerError::EngineStartUpError, "raft")?;
+
+        Ok(ClusterMember{
+            node,
+            members,
+            peers,
+            last_tick:Instant::now(),
+            pending:Vec::new(),
+            inbound:Vec::new(),
+            snapshot_job:None
+        })
+    }
+
+    pub fn leader(&self)->Option<SocketAddr>{
+        self.node.leader().map(|id|self.members[id as usize])
+    }
+
+    pub fn propose(&mut self,command:KVCommand,mut connection:TcpStream){
+        //the raft log only carries operations on the default namespace
+        let (operation,kind)=match command {
+            KVCommand::Get { key, ns:None } => (Operation::Get(key),PendingKind::Get),
+            KVCommand::Set { key, value, ns:None } => (Operation::Set(key, value),PendingKind::Set),
+            KVCommand::Rm { key, ns:None } => (Operation::Remove(key),PendingKind::Remove),
+            _ => return Server::send_result::<()>(&mut connection, Err(ErrorType::OperationError)),
+        };
+        match self.node.propose(operation) {
+            Ok(Some((index,term))) => {
+                self.pending.push(Pending{index,term,kind,connection});
+                self.send_messages();
+            },
+            Ok(None) => Server::send_result::<()>(&mut connection, Err(ErrorType::NotLeader(self.leader()))),
+            Err(_) => Server::send_result::<()>(&mut connection, Err(ErrorType::OperationError)),
+        }
+    }
+
+    pub fn step(&mut self,envelope:Envelope){
+        if let Err(e)=self.node.step(envelope){
+            eprintln!("raft step failed: {:?}",e);
+        }
+        self.send_messages();
+    }
+
+    /// Keeps reading the raft messages a peer sends on `connection`, `unread` being what was read past the first one.
+    pub fn accept_peer(&mut self,connection:TcpStream,unread:Vec<u8>){
+        if connection.set_nonblocking(true).is_ok(){
+            self.inbound.push(Inbound{connection,unread});
+        }
+    }
+
+    pub fn poll(&mut self,engine:&mut Box<dyn KvsEngine>){
+        let mut envelopes=Vec::new();
+        self.inbound.retain_mut(|inbound|inbound.receive(&mut envelopes));
+        for envelope in envelopes{
+            self.step(envelope);
+        }
+
+        if self.last_tick.elapsed()>=TICK_INTERVAL{
+            self.last_tick=Instant::now();
+            if let Err(e)=self.node.tick(){
+                eprintln!("raft tick failed: {:?}",e);
+            }
+            self.send_messages();
+        }
+        self.apply(engine);
+
+        if !self.node.is_leader(){
+            let leader=self.leader();
+            for mut pending in self.pending.drain(..){
+                Server::send_result::<()>(&mut pending.connection, Err(ErrorType::NotLeader(leader)));
+            }
+        }
+    }
+
+    pub fn stats(&self)->RaftStats{
+        RaftStats{
+            id:self.node.id(),
+            term:self.node.term(),
+            leader:self.leader(),
+            commit_index:self.node.commit_index(),
+            last_applied:self.node.last_applied(),
+            snapshot_index:self.node.snapshot_index()
+        }
+    }
+
+    fn apply(&mut self,engine:&mut Box<dyn KvsEngine>){
+        for applied in self.node.take_committed(){
+            let entry=match &applied {
+                Applied::Entry { index, term, .. } => Some((*index,*term)),
+                Applied::Snapshot(_) => None,
+            };
+            let res=raft::apply(engine.as_mut(), applied);
+            if let Some((index,term))=entry{
+                self.respond(index, term, res);
+            }
+        }
+
+        if let Err(e)=self.compact(engine){
+            eprintln!("raft compaction failed: {:?}",e);
+        }
+    }
+
+    //installs a snapshot whose entries are written, or starts writing one once enough was applied since the last
+    fn compact(&mut self,engine:&mut Box<dyn KvsEngine>)->crate::Result<()>{
+        if self.snapshot_job.as_ref().is_some_and(|job|job.handle.is_finished()){
+            let job=self.snapshot_job.take().expect("Checked above");
+            job.handle.join().map_err(|_|KVError::WriteError("ClusterMember::compact".into()))??;
+            self.node.install_snapshot(job.snapshot)?;
+        }
+        if self.snapshot_job.is_none()&&self.node.last_applied()-self.node.snapshot_index()>SNAPSHOT_THRESHOLD{
+            //the entries are taken as they are at last_applied, then read and written off the event loop
+            let entries=engine.snapshot_entries()?;
+            if let Some((snapshot,writer))=self.node.start_snapshot()?{
+                self.snapshot_job=Some(SnapshotJob::spawn(snapshot, entries, writer));
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self,index:u64,term:u64,res:crate::Result<Option<String>>){
+        let Some(position)=self.pending.iter().position(|pending|pending.index==index) else {
+            return;
+        };
+        let mut pending=self.pending.swap_remove(position);
+        let connection=&mut pending.connection;
+        if pending.term!=term{
+            //another leader overwrote the entry
+            Server::send_result::<()>(connection, Err(ErrorType::NotLeader(self.leader())));
+            return;
+        }
+        match (pending.kind,res) {
+            (PendingKind::Get,res) => Server::send_result(connection, res.map_err(|_|ErrorType::OperationError)),
+            (PendingKind::Set,res) => Server::send_result(connection, res.map(|_|()).map_err(|_|ErrorType::OperationError)),
+            (PendingKind::Remove,Err(KVError::KeyNotFound(_))) => Server::send_result::<()>(connection, Err(ErrorType::KeyNotFound)),
+            (PendingKind::Remove,res) => Server::send_result(connection, res.map(|_|()).map_err(|_|ErrorType::OperationError)),
+        }
+    }
+
+    fn send_messages(&mut self){
+        for envelope in self.node.take_messages(){
+            if let Some(peer)=self.peers.get(&envelope.to){
+                peer.send(envelope);
+            }
+        }
+    }
+}
@@ -1,11 +1,11 @@
-use std::{fmt::Debug, net::SocketAddr, str::FromStr};
+use std::net::SocketAddr;
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use serde_json::from_str;
-use std::error::Error;
 
-use crate::client::command;
+use super::migrate::DEFAULT_SAMPLES;
+use crate::{client::sharded::HashRange, kv::{command::{KVCommand, StoreArgs}, export::OnConflict, replication::LogPosition}, raft::Envelope};
+
 
 #[derive(Parser)]
 #[command(about,version)]
@@ -13,13 +13,88 @@ pub struct ServerArgs{
     #[arg(long,default_value="127.0.0.1:4000")]
     pub addr:SocketAddr,
     #[arg(long,value_enum)]
-    pub engine:Option<StorageEngine>
+    pub engine:Option<StorageEngine>,
+    #[arg(long,conflicts_with="raft_peers")]
+    pub replica_of:Option<SocketAddr>,
+    /// Addresses of the other members of a raft cluster
+    #[arg(long,value_delimiter=',')]
+    pub raft_peers:Vec<SocketAddr>,
+    /// Keep this many versions of every key (kvs engine only)
+    #[arg(long)]
+    pub max_versions:Option<usize>,
+    /// Keep versions younger than this many seconds (kvs engine only)
+    #[arg(long)]
+    pub retention_secs:Option<u64>,
+    /// Load the memory engine from the data directory on start and write it back on shutdown
+    #[arg(long)]
+    pub snapshot:bool,
+    /// Options of the kvs engine's store
+    #[command(flatten)]
+    pub store:StoreArgs,
+    #[command(subcommand)]
+    pub command:Option<ServerCommand>
 }
 
 
-#[derive(ValueEnum,Clone,Deserialize,Serialize)]
+#[derive(ValueEnum,Clone,Copy,Debug,PartialEq,Deserialize,Serialize)]
 pub enum StorageEngine{
     #[clap(name = "kvs")]
     Kv,
-    Sled
+    Sled,
+    /// Everything in memory, kept across restarts only with --snapshot
+    Memory
+}
+
+impl StorageEngine {
+    pub fn name(&self)->&'static str{
+        match self {
+            StorageEngine::Kv => "kvs",
+            StorageEngine::Sled => "sled",
+            StorageEngine::Memory => "memory",
+        }
+    }
+}
+
+/// What `kvs-server` does instead of serving.
+#[derive(Subcommand)]
+pub enum ServerCommand{
+    /// Copy the data of a stopped server into another engine and switch the server to it
+    Migrate{
+        #[arg(long,value_enum)]
+        from:StorageEngine,
+        #[arg(long,value_enum)]
+        to:StorageEngine,
+        /// Values compared between the engines per namespace after the copy
+        #[arg(long,default_value_t=DEFAULT_SAMPLES)]
+        samples:usize
+    }
+}
+
+/// Requests understood by the server that are not plain kv operations.
+#[derive(Deserialize,Serialize)]
+pub enum AdminCommand{
+    //`snapshot` is where the copy of a snapshot goes on from
+    Replicate{position:Option<LogPosition>,snapshot:Option<LogPosition>,ns:Option<String>},
+    Stats,
+    Raft(Envelope),
+    //up to limit entries whose key hashes into one of the ranges, in key order from after the cursor key
+    ScanRanges{ranges:Vec<HashRange>,after:Option<String>,limit:usize},
+    //up to limit entries of a namespace whose key starts with the prefix, in key order from after the cursor key
+    Export{prefix:String,after:Option<String>,limit:usize,ns:Option<String>},
+    //one batch of an import, written straight to the engine so not on raft clusters
+    Import{entries:Vec<(String,String)>,on_conflict:OnConflict,ns:Option<String>}
+}
+
+/// One page of a `ScanRanges`, `next` is the cursor of the following page if there may be one.
+#[derive(Deserialize,Serialize,Debug,Default,PartialEq)]
+pub struct ScanPage{
+    pub entries:Vec<(String,String)>,
+    pub next:Option<String>
+}
+
+#[derive(Deserialize,Serialize)]
+#[serde(untagged)]
+pub enum Request{
+    Kv(KVCommand),
+    Admin(AdminCommand)
 }
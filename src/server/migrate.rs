@@ -0,0 +1,90 @@
+use std::{fmt::Display, fs::remove_dir_all, path::{Path, PathBuf}};
+
+use crate::{kv::{export::{import, OnConflict, DEFAULT_BATCH_SIZE}, namespace::NamespaceOptions, options::KvStoreOptions, Context, ErrorContext}, KvStore, KvsEngine, MemoryStore, DEFAULT_NAMESPACE};
+
+use super::{command::StorageEngine, Result, ServerError, StorageMetaData};
+
+/// Values compared per namespace after a migration by default.
+pub const DEFAULT_SAMPLES:usize=100;
+
+#[derive(Debug)]
+pub struct MigrationReport{
+    pub from:StorageEngine,
+    pub to:StorageEngine,
+    pub namespaces:usize,
+    pub keys:usize,
+    //values read back from both engines and found equal
+    pub sampled:usize,
+    //the directory of the engine migrated from, left for going back
+    pub previous:PathBuf,
+    pub current:PathBuf
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"migrated {} keys in {} namespaces from {} to {}, {} sampled values match",self.keys,self.namespaces,self.from.name(),self.to.name(),self.sampled)?;
+        write!(f,"\nthe server now uses {}, {} can be removed once it is no longer needed",self.current.display(),self.previous.display())
+    }
+}
+
+/// Copies every namespace of the `from` engine owning `data_path` into a new directory of the
+/// `to` engine, checks the key counts and a sample of values, then switches the metadata to the
+/// copy. The server must be stopped. Version history and secondary indexes are not copied.
+pub fn migrate(data_path:&Path,from:StorageEngine,to:StorageEngine,options:&KvStoreOptions,samples:usize)->Result<MigrationReport>{
+    if from==to{
+        return Err(ServerError::ConfigError("the engines to migrate between are the same"));
+    }
+    let meta=StorageMetaData::load(data_path)?;
+    if meta.engine!=Some(from){
+        return Err(ServerError::ConfigError("the data directory belongs to another engine"));
+    }
+    let previous=meta.db_path(data_path);
+    //the name differs by engine, so it is never the directory migrated from
+    let target_name=format!("db.{}",to.name());
+    let current=data_path.join(&target_name);
+    if current.exists(){
+        //left by an earlier migration away from this engine, or by one cut short
+        remove_dir_all(&current).context(ServerError::EngineStartUpError, "migrate1")?;
+    }
+
+    let mut report=MigrationReport{from,to,namespaces:0,keys:0,sampled:0,previous,current};
+    {
+        let mut source=open_engine(from, &report.previous, &options.clone().read_only(true))?;
+        let mut target=open_engine(to, &report.current, options)?;
+        for name in source.list_namespaces()?{
+            if name!=DEFAULT_NAMESPACE{
+                target.create_namespace(&name, NamespaceOptions::default())?;
+            }
+            copy_namespace(&mut *source.namespace(&name)?, &mut *target.namespace(&name)?, samples, &mut report)?;
+            report.namespaces+=1;
+        }
+    }
+    StorageMetaData{engine:Some(to),db:Some(target_name)}.save(data_path)?;
+    Ok(report)
+}
+
+fn copy_namespace(source:&mut dyn KvsEngine,target:&mut dyn KvsEngine,samples:usize,report:&mut MigrationReport)->Result<()>{
+    let keys=source.keys()?;
+    let entries=keys.iter().filter_map(|key|source.get(key.clone()).transpose().map(|value|value.map(|value|(key.clone(),value))));
+    let imported=import(target, entries, OnConflict::Overwrite, DEFAULT_BATCH_SIZE)?;
+    //counted against the source, a copy missing keys would agree with its own count
+    if target.keys()?.len()!=keys.len(){
+        return Err(ServerError::EngineOperationError(ErrorContext::new("migrate: the key counts differ after the copy").with_path(&report.current)));
+    }
+    for key in keys.iter().step_by((keys.len()/samples.max(1)).max(1)).take(samples){
+        if source.get(key.clone())?!=target.get(key.clone())?{
+            return Err(ServerError::EngineOperationError(ErrorContext::new("migrate: a sampled value differs after the copy").with_path(&report.current)));
+        }
+        report.sampled+=1;
+    }
+    report.keys+=imported.written;
+    Ok(())
+}
+
+fn open_engine(engine:StorageEngine,path:&Path,options:&KvStoreOptions)->Result<Box<dyn KvsEngine>>{
+    Ok(match engine {
+        StorageEngine::Kv => Box::new(KvStore::open_with(path, options.clone())?),
+        StorageEngine::Sled => Box::new(sled::open(path).context(ServerError::EngineStartUpError, "migrate: sled")?),
+        StorageEngine::Memory => Box::new(MemoryStore::open(path)?),
+    })
+}
@@ -0,0 +1,219 @@
+use std::{collections::BTreeMap, fs::{self, File}, io::ErrorKind, net::SocketAddr, path::PathBuf, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{client::Client, kv::{namespace::NamespaceOptions, replication::{LogPosition, ReplicatedSchema, ReplicationBatch}, secondary::IndexDefinition, Context, ErrorContext, KVError, Operation}, KvsEngine, DEFAULT_NAMESPACE};
+
+use super::{Result, ServerError};
+
+const SYNC_INTERVAL:Duration=Duration::from_millis(100);
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct ReplicationStats{
+    pub leader:SocketAddr,
+    pub position:Option<LogPosition>,
+    pub lag_bytes:Option<u64>,
+    pub millis_since_sync:Option<u128>,
+    pub snapshots:usize
+}
+
+//what a follower saves after each batch it applied, namespaces still copying a snapshot are left out
+#[derive(Serialize,Deserialize)]
+struct SavedPosition{
+    leader:SocketAddr,
+    positions:BTreeMap<String,LogPosition>
+}
+
+//how far the follower got in one namespace of the leader
+#[derive(Default)]
+struct Progress{
+    position:Option<LogPosition>,
+    //where the snapshot being copied goes on from
+    snapshot:Option<LogPosition>,
+    lag_bytes:Option<u64>
+}
+
+/// Pulls changes from a leader and applies them to the local engine.
+///
+/// Every namespace of the leader is followed from a position of its own, and the
+/// namespaces and secondary indexes are created and dropped as they are on the leader.
+/// The positions are saved to `position_file` once what they cover is applied, so a
+/// restarted follower goes on from there instead of copying fresh snapshots.
+pub struct Follower{
+    leader_addr:SocketAddr,
+    position_file:PathBuf,
+    progress:BTreeMap<String,Progress>,
+    saved:BTreeMap<String,LogPosition>,
+    last_attempt:Option<Instant>,
+    last_sync:Option<Instant>,
+    snapshots:usize
+}
+
+impl Follower {
+    pub fn new(leader_addr:SocketAddr,position_file:PathBuf)->Result<Follower>{
+        let saved=match fs::read(&position_file) {
+            Ok(bytes) => Some(serde_json::from_slice::<SavedPosition>(&bytes).context(ServerError::EngineStartUpError, "Follower::new1")?),
+            Err(e) if e.kind()==ErrorKind::NotFound => None,
+            Err(e) => return Err(ServerError::EngineStartUpError(ErrorContext::new("Follower::new2").with_path(&position_file).with_source(e))),
+        };
+        //a position in another leader's log means nothing here
+        let saved=saved.filter(|saved|saved.leader==leader_addr).map(|saved|saved.positions).unwrap_or_default();
+        let progress=saved.iter().map(|(name,position)|(name.clone(),Progress{position:Some(*position),..Progress::default()})).collect();
+        Ok(Follower{
+            leader_addr,
+            position_file,
+            progress,
+            saved,
+            last_attempt:None,
+            last_sync:None,
+            snapshots:0
+        })
+    }
+
+    pub fn leader(&self)->SocketAddr{
+        self.leader_addr
+    }
+
+    //syncs when the interval elapsed or the follower is known to be behind
+    pub fn poll(&mut self,engine:&mut Box<dyn KvsEngine>){
+        let behind=self.progress.values().any(|progress|progress.lag_bytes.is_some_and(|lag|lag>0)||progress.snapshot.is_some());
+        let due=self.last_attempt.is_none_or(|at|at.elapsed()>=SYNC_INTERVAL);
+        if behind||due{
+            self.last_attempt=Some(Instant::now());
+            if let Err(e)=self.sync(engine){
+                eprintln!("replication from {} failed: {:?}",self.leader_addr,e);
+            }
+        }
+    }
+
+    /// Pulls one batch of every namespace, the default one first as it lists the others.
+    pub fn sync(&mut self,engine:&mut Box<dyn KvsEngine>)->Result<()>{
+        //the batch holds what the leader had when asked, not when it arrived
+        let requested=Instant::now();
+        let schema=self.sync_namespace(engine, DEFAULT_NAMESPACE)?;
+        self.follow_namespaces(engine, schema.namespaces)?;
+        let names:Vec<String>=self.progress.keys().filter(|name|*name!=DEFAULT_NAMESPACE).cloned().collect();
+        for name in names{
+            self.sync_namespace(engine, &name)?;
+        }
+        self.last_sync=Some(requested);
+        self.save_positions()
+    }
+
+    fn sync_namespace(&mut self,engine:&mut Box<dyn KvsEngine>,name:&str)->Result<ReplicatedSchema>{
+        let progress=self.progress.entry(name.to_string()).or_default();
+        let ns=(name!=DEFAULT_NAMESPACE).then(||name.to_string());
+        let batch=Client::new(self.leader_addr)
+        .with_namespace(ns)
+        .replicate(progress.position, progress.snapshot)
+        .context(ServerError::ReplicationError, "Follower::sync_namespace1")?;
+        let mut engine=engine.namespace(name).context(ServerError::ReplicationError, "Follower::sync_namespace2")?;
+        let position=batch.position();
+        let lag_bytes=batch.lag_bytes();
+        let schema=batch.schema().clone();
+
+        match batch {
+            ReplicationBatch::Snapshot { entries, first:true, next, .. } => {
+                engine.restore(entries).context(ServerError::ReplicationError, "Follower::sync_namespace3")?;
+                self.snapshots+=1;
+                progress.snapshot=next;
+            },
+            ReplicationBatch::Snapshot { entries, next, .. } => {
+                for (key,value) in entries{
+                    Self::apply(&mut *engine, Operation::Set(key, value))?;
+                }
+                progress.snapshot=next;
+            },
+            ReplicationBatch::Records { operations, .. } => {
+                for operation in operations{
+                    Self::apply(&mut *engine, operation)?;
+                }
+            },
+        }
+        Self::follow_indexes(&mut *engine, &schema.indexes)?;
+
+        progress.position=Some(position);
+        progress.lag_bytes=Some(lag_bytes);
+        Ok(schema)
+    }
+
+    //namespaces are created before their first batch is applied, and dropped along with their position
+    fn follow_namespaces(&mut self,engine:&mut Box<dyn KvsEngine>,namespaces:Vec<(String,NamespaceOptions)>)->Result<()>{
+        let current=engine.list_namespaces().context(ServerError::ReplicationError, "Follower::follow_namespaces1")?;
+        for name in current.iter(){
+            if name!=DEFAULT_NAMESPACE&&!namespaces.iter().any(|(leader_name,_)|leader_name==name){
+                engine.drop_namespace(name).context(ServerError::ReplicationError, "Follower::follow_namespaces2")?;
+            }
+        }
+        self.progress.retain(|name,_|name==DEFAULT_NAMESPACE||namespaces.iter().any(|(leader_name,_)|leader_name==name));
+        for (name,options) in namespaces{
+            if !current.contains(&name){
+                engine.create_namespace(&name, options).context(ServerError::ReplicationError, "Follower::follow_namespaces3")?;
+                //a position kept for it belongs to contents that are no longer here
+                self.progress.remove(&name);
+            }
+            self.progress.entry(name).or_default();
+        }
+        Ok(())
+    }
+
+    //an engine without secondary indexes cannot answer queries on them either, so it only follows the keys
+    fn follow_indexes(engine:&mut dyn KvsEngine,indexes:&[IndexDefinition])->Result<()>{
+        let current=match engine.list_indexes() {
+            Ok(current) => current,
+            Err(KVError::Unsupported(_)) => return Ok(()),
+            Err(error) => return Err(ServerError::ReplicationError(ErrorContext::new("Follower::follow_indexes1").with_source(error))),
+        };
+        for definition in current.iter().filter(|definition|!indexes.contains(definition)){
+            engine.drop_index(&definition.name).context(ServerError::ReplicationError, "Follower::follow_indexes2")?;
+        }
+        for definition in indexes.iter().filter(|definition|!current.contains(definition)){
+            engine.create_index(&definition.name, &definition.pointer).context(ServerError::ReplicationError, "Follower::follow_indexes3")?;
+        }
+        Ok(())
+    }
+
+    //replaced in one rename, replaying from an older position only applies the same records again
+    fn save_positions(&mut self)->Result<()>{
+        let positions:BTreeMap<String,LogPosition>=self.progress
+        .iter()
+        .filter(|(_,progress)|progress.snapshot.is_none())
+        .filter_map(|(name,progress)|Some((name.clone(),progress.position?)))
+        .collect();
+        if positions==self.saved{
+            return Ok(());
+        }
+        let tmp_path=self.position_file.with_extension("tmp");
+        let file=File::create(&tmp_path).context(ServerError::ReplicationError, "Follower::save_positions1")?;
+        serde_json::to_writer(&file, &SavedPosition{leader:self.leader_addr,positions:positions.clone()}).context(ServerError::ReplicationError, "Follower::save_positions2")?;
+        file.sync_all().context(ServerError::ReplicationError, "Follower::save_positions3")?;
+        fs::rename(&tmp_path, &self.position_file)
+        .map_err(|e|ServerError::ReplicationError(ErrorContext::new("Follower::save_positions4").with_path(&self.position_file).with_source(e)))?;
+        self.saved=positions;
+        Ok(())
+    }
+
+    /// Position of the default namespace, lag and snapshots of every namespace together.
+    pub fn stats(&self)->ReplicationStats{
+        let default=self.progress.get(DEFAULT_NAMESPACE);
+        ReplicationStats{
+            leader:self.leader_addr,
+            position:default.and_then(|progress|progress.position),
+            lag_bytes:default.and_then(|progress|progress.lag_bytes).map(|_|self.progress.values().filter_map(|progress|progress.lag_bytes).sum()),
+            millis_since_sync:self.last_sync.map(|at|at.elapsed().as_millis()),
+            snapshots:self.snapshots
+        }
+    }
+
+    fn apply(engine:&mut dyn KvsEngine,operation:Operation)->Result<()>{
+        let res=match operation {
+            Operation::Set(key, value) => engine.set(key, value),
+            Operation::Remove(key) => engine.remove(key),
+            Operation::Get(_) => Ok(()),
+        };
+        match res {
+            Ok(_)|Err(KVError::KeyNotFound(_)) => Ok(()),
+            Err(error) => Err(ServerError::ReplicationError(ErrorContext::new("Follower::apply").with_source(error))),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread};
+
+/// Makes a running `Server` return from `start`, which drops its engine.
+#[derive(Clone)]
+pub struct StopHandle{
+    stop:Arc<AtomicBool>,
+    addr:SocketAddr
+}
+
+impl StopHandle {
+    pub(super) fn new(stop:Arc<AtomicBool>,mut addr:SocketAddr)->StopHandle{
+        if addr.ip().is_unspecified(){
+            addr.set_ip(if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(Ipv6Addr::LOCALHOST) });
+        }
+        StopHandle{stop,addr}
+    }
+
+    pub fn stop(&self){
+        self.stop.store(true, Ordering::SeqCst);
+        //a server blocked in accept only looks at the flag once a connection comes in
+        let _=TcpStream::connect(self.addr);
+    }
+}
+
+/// Blocks SIGINT and SIGTERM in the calling thread and every thread it starts afterwards,
+/// so only `stop_on_signal` sees them. Call it before any other thread is started.
+#[cfg(unix)]
+pub fn block_signals()->io::Result<()>{
+    let set=signal_set();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn block_signals()->io::Result<()>{
+    Ok(())
+}
+
+/// Stops the server of `handle` on the first SIGINT or SIGTERM blocked with `block_signals`.
+#[cfg(unix)]
+pub fn stop_on_signal(handle:StopHandle){
+    thread::spawn(move||{
+        let set=signal_set();
+        let mut signal=0;
+        while unsafe { libc::sigwait(&set, &mut signal) }!=0{}
+        handle.stop();
+    });
+}
+
+#[cfg(not(unix))]
+pub fn stop_on_signal(_handle:StopHandle){}
+
+#[cfg(unix)]
+fn signal_set()->libc::sigset_t{
+    unsafe {
+        let mut set=std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        set
+    }
+}
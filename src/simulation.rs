@@ -0,0 +1,352 @@
+use std::{collections::BTreeMap, error::Error, fmt, path::Path, result, sync::Arc};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{kv::{filesystem::simulated::{Fault, SimulatedFileSystem}, options::{Durability, KvStoreOptions}, KVError}, server::{command::Request, ErrorType, Server, ServerResponse}, KvStore, KvsEngine};
+
+use self::linearizability::{find_violation, Call, Operation, Return};
+
+pub mod linearizability;
+
+/// What a simulation runs, every choice it makes while running comes from the seed.
+#[derive(Debug,Clone)]
+pub struct SimulationOptions{
+    seed:u64,
+    clients:usize,
+    operations:usize,
+    keys:usize,
+    max_latency:u64,
+    drop_rate:f64,
+    crash_rate:f64,
+    fault_rate:f64,
+    durability:Durability
+}
+
+impl SimulationOptions {
+    pub fn new(seed:u64)->SimulationOptions{
+        SimulationOptions{
+            seed,
+            clients:4,
+            operations:200,
+            keys:5,
+            max_latency:10,
+            drop_rate:0.05,
+            crash_rate:0.02,
+            fault_rate:0.02,
+            durability:Durability::Sync
+        }
+    }
+
+    /// Clients calling at the same time, each waits for its call before the next one.
+    pub fn clients(mut self,clients:usize)->Self{
+        self.clients=clients;
+        self
+    }
+
+    /// Calls made by all clients together.
+    pub fn operations(mut self,operations:usize)->Self{
+        self.operations=operations;
+        self
+    }
+
+    pub fn keys(mut self,keys:usize)->Self{
+        self.keys=keys.max(1);
+        self
+    }
+
+    /// Virtual milliseconds a message may take, clients give up on a call after twice that.
+    pub fn max_latency(mut self,millis:u64)->Self{
+        self.max_latency=millis.max(1);
+        self
+    }
+
+    /// Chance the network loses a message.
+    pub fn drop_rate(mut self,rate:f64)->Self{
+        self.drop_rate=rate;
+        self
+    }
+
+    /// Chance power is lost after the server handled a request and before it answered.
+    pub fn crash_rate(mut self,rate:f64)->Self{
+        self.crash_rate=rate;
+        self
+    }
+
+    /// Chance the disk fails one of the writes of a request.
+    pub fn fault_rate(mut self,rate:f64)->Self{
+        self.fault_rate=rate;
+        self
+    }
+
+    /// How the store writes its logs, with `Durability::Write` a power loss can take acknowledged writes.
+    pub fn durability(mut self,durability:Durability)->Self{
+        self.durability=durability;
+        self
+    }
+}
+
+/// What a simulation did.
+#[derive(Debug,Clone,PartialEq)]
+pub struct SimulationReport{
+    pub seed:u64,
+    /// Every call in the order it was made, ending with a read of each key after a last power loss.
+    pub history:Vec<Operation>,
+    pub crashes:usize,
+    pub dropped_messages:usize,
+    pub faults:usize
+}
+
+#[derive(Debug)]
+pub enum SimulationError{
+    /// The store could not be opened, at the start or after a power loss.
+    Engine{seed:u64,error:KVError},
+    /// No order of the calls on `key` explains what the clients were told.
+    NotLinearizable{seed:u64,key:String,history:Vec<Operation>}
+}
+
+impl fmt::Display for SimulationError {
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            SimulationError::Engine { seed, error } => write!(f,"seed {seed}: the store failed to open: {error}"),
+            SimulationError::NotLinearizable { seed, key, history } => {
+                write!(f,"seed {seed}: the calls on {key} are not linearizable")?;
+                for operation in history.iter().filter(|operation|&operation.key==key){
+                    write!(f,"\n{operation:?}")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Error for SimulationError {
+    fn source(&self)->Option<&(dyn Error+'static)>{
+        match self {
+            SimulationError::Engine { error, .. } => Some(error),
+            SimulationError::NotLinearizable { .. } => None,
+        }
+    }
+}
+
+/// Runs clients, the server and a `KvStore` on a simulated network and disk, one event at a time
+/// on a virtual clock, then checks the history the clients saw is linearizable.
+/// The logs live on a `SimulatedFileSystem`, the other files of the store in `dir`.
+/// A run only depends on its options, so a failing seed fails the same way every time.
+pub fn run(dir:&Path,options:&SimulationOptions)->result::Result<SimulationReport,SimulationError>{
+    let mut simulation=Simulation::new(dir, options.clone())?;
+    simulation.run()?;
+    let report=simulation.report;
+    match find_violation(&report.history) {
+        Some(key) => Err(SimulationError::NotLinearizable{seed:report.seed,key:key.to_owned(),history:report.history.clone()}),
+        None => Ok(report),
+    }
+}
+
+enum Event{
+    //a client makes its next call
+    Invoke{client:usize},
+    Deliver{client:usize,operation:usize,request:Vec<u8>},
+    Respond{client:usize,operation:usize,response:Vec<u8>},
+    Timeout{client:usize,operation:usize},
+    Restart
+}
+
+struct Simulation<'a>{
+    dir:&'a Path,
+    options:SimulationOptions,
+    rng:StdRng,
+    now:u64,
+    //by when they happen, events at the same time in the order they were scheduled
+    events:BTreeMap<(u64,u64),Event>,
+    scheduled:u64,
+    fs:SimulatedFileSystem,
+    //none while power is out
+    server:Option<Server>,
+    //the call each client waits for
+    waiting:Vec<Option<usize>>,
+    report:SimulationReport
+}
+
+impl<'a> Simulation<'a> {
+    fn new(dir:&'a Path,options:SimulationOptions)->result::Result<Simulation<'a>,SimulationError>{
+        let seed=options.seed;
+        let mut simulation=Simulation{
+            dir,
+            rng:StdRng::seed_from_u64(seed),
+            now:0,
+            events:BTreeMap::new(),
+            scheduled:0,
+            fs:SimulatedFileSystem::new(),
+            server:None,
+            waiting:vec![None;options.clients],
+            report:SimulationReport{seed,history:Vec::new(),crashes:0,dropped_messages:0,faults:0},
+            options
+        };
+        simulation.restart()?;
+        Ok(simulation)
+    }
+
+    fn run(&mut self)->result::Result<(),SimulationError>{
+        for client in 0..self.options.clients{
+            let at=self.rng.gen_range(0..=self.options.max_latency);
+            self.schedule(at, Event::Invoke { client });
+        }
+        while let Some(((at,_),event))=self.events.pop_first(){
+            self.now=at;
+            match event {
+                Event::Invoke { client } => self.invoke(client),
+                Event::Deliver { client, operation, request } => self.deliver(client, operation, &request),
+                Event::Respond { client, operation, response } => self.respond(client, operation, &response),
+                Event::Timeout { client, operation } => {
+                    if self.waiting[client]==Some(operation){
+                        self.waiting[client]=None;
+                        self.think(client);
+                    }
+                },
+                Event::Restart => self.restart()?,
+            }
+        }
+
+        //what was acknowledged has to survive the power going out once more
+        self.crash();
+        self.restart()?;
+        for key_id in 0..self.options.keys{
+            self.now+=1;
+            let mut operation=Operation{
+                client:self.options.clients,
+                key:format!("key{}",key_id),
+                call:Call::Get,
+                invoked_at:self.now,
+                returned_at:self.now,
+                result:None
+            };
+            let request=serde_json::to_vec(&operation.request()).expect("requests serialize");
+            let response=self.handle(&request);
+            operation.result=response.and_then(|response|parse_response(&operation.call, &response));
+            self.report.history.push(operation);
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self,at:u64,event:Event){
+        self.events.insert((at,self.scheduled), event);
+        self.scheduled+=1;
+    }
+
+    //the network loses the message or hands it over after a while
+    fn send(&mut self,event:Event){
+        if self.rng.gen_bool(self.options.drop_rate){
+            self.report.dropped_messages+=1;
+            return;
+        }
+        let at=self.now+self.rng.gen_range(1..=self.options.max_latency);
+        self.schedule(at, event);
+    }
+
+    fn invoke(&mut self,client:usize){
+        if self.report.history.len()>=self.options.operations{
+            return;
+        }
+        let operation=self.report.history.len();
+        let call=match self.rng.gen_range(0..10) {
+            0..=3 => Call::Get,
+            4..=7 => Call::Set(format!("value{}-{}",operation,"x".repeat(self.rng.gen_range(0..20)))),
+            _ => Call::Remove,
+        };
+        let call=Operation{
+            client,
+            key:format!("key{}",self.rng.gen_range(0..self.options.keys)),
+            call,
+            invoked_at:self.now,
+            //a request not delivered by then never is
+            returned_at:self.now+self.options.max_latency,
+            result:None
+        };
+        let request=serde_json::to_vec(&call.request()).expect("requests serialize");
+        self.report.history.push(call);
+        self.waiting[client]=Some(operation);
+        self.send(Event::Deliver { client, operation, request });
+        self.schedule(self.now+2*self.options.max_latency+1, Event::Timeout { client, operation });
+    }
+
+    fn deliver(&mut self,client:usize,operation:usize,request:&[u8]){
+        if self.rng.gen_bool(self.options.fault_rate){
+            let fault=[Fault::NoSpace,Fault::ShortWrite,Fault::SyncFailure][self.rng.gen_range(0..3)];
+            self.fs.inject(fault);
+            self.report.faults+=1;
+        }
+        let Some(response)=self.handle(request) else {
+            return;
+        };
+        if self.rng.gen_bool(self.options.crash_rate){
+            self.crash();
+            let at=self.now+self.rng.gen_range(1..=2*self.options.max_latency);
+            self.schedule(at, Event::Restart);
+        }else{
+            self.send(Event::Respond { client, operation, response });
+        }
+    }
+
+    fn respond(&mut self,client:usize,operation:usize,response:&[u8]){
+        if self.waiting[client]!=Some(operation){
+            return;
+        }
+        self.waiting[client]=None;
+        let call=&mut self.report.history[operation];
+        call.returned_at=self.now;
+        call.result=parse_response(&call.call, response);
+        self.think(client);
+    }
+
+    //the client makes its next call after a while
+    fn think(&mut self,client:usize){
+        let at=self.now+self.rng.gen_range(0..=self.options.max_latency);
+        self.schedule(at, Event::Invoke { client });
+    }
+
+    //the server's answer, none while it is down
+    fn handle(&mut self,request:&[u8])->Option<Vec<u8>>{
+        let server=self.server.as_mut()?;
+        let request:Request=serde_json::from_slice(request).ok()?;
+        let mut response=Vec::new();
+        server.handle_request(request, &mut response);
+        Some(response)
+    }
+
+    fn crash(&mut self){
+        self.fs.power_loss(&mut self.rng);
+        self.server=None;
+        self.report.crashes+=1;
+    }
+
+    fn restart(&mut self)->result::Result<(),SimulationError>{
+        let options=KvStoreOptions::new()
+        .file_system(Arc::new(self.fs.clone()))
+        .durability(self.options.durability)
+        .background_compaction(false)
+        .file_size(300)
+        .merge_size(600);
+        let store=KvStore::open_with(self.dir, options).map_err(|error|SimulationError::Engine{seed:self.options.seed,error})?;
+        self.server=Some(Server::in_process(Box::new(store) as Box<dyn KvsEngine>));
+        Ok(())
+    }
+}
+
+//what the client makes of the server's answer to `call`, `None` for an error
+fn parse_response(call:&Call,response:&[u8])->Option<Return>{
+    match call {
+        Call::Get => match serde_json::from_slice(response).ok()? {
+            ServerResponse::Success(value) => Some(Return::Value(value)),
+            ServerResponse::Error(_) => None,
+        },
+        Call::Set(_) => match serde_json::from_slice(response).ok()? {
+            ServerResponse::Success(()) => Some(Return::Done),
+            ServerResponse::Error(_) => None,
+        },
+        Call::Remove => match serde_json::from_slice(response).ok()? {
+            ServerResponse::Success(()) => Some(Return::Done),
+            ServerResponse::Error(ErrorType::KeyNotFound) => Some(Return::NotFound),
+            ServerResponse::Error(_) => None,
+        },
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{kv::command::KVCommand, server::command::Request};
+
+/// What a client asked for.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Call{
+    Get,
+    Set(String),
+    Remove
+}
+
+/// What a client was told.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Return{
+    Value(Option<String>),
+    Done,
+    /// A remove of a key that was not there.
+    NotFound
+}
+
+/// One call of a client on a key, it took effect at most once between `invoked_at` and `returned_at`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Operation{
+    pub client:usize,
+    pub key:String,
+    pub call:Call,
+    pub invoked_at:u64,
+    /// When the answer came, or without one the last moment the server could have seen the call.
+    pub returned_at:u64,
+    /// `None` when the client got no answer or an error, the call may or may not have taken effect.
+    pub result:Option<Return>
+}
+
+impl Operation {
+    pub fn request(&self)->Request{
+        let key=self.key.clone();
+        Request::Kv(match &self.call {
+            Call::Get => KVCommand::Get { key, ns: None },
+            Call::Set(value) => KVCommand::Set { key, value: value.clone(), ns: None },
+            Call::Remove => KVCommand::Rm { key, ns: None },
+        })
+    }
+
+    //the value of the key after this call if it could have followed `value`
+    fn apply(&self,value:&Option<String>)->Option<Option<String>>{
+        match (&self.call,&self.result) {
+            (Call::Get,Some(Return::Value(read))) => (read==value).then(||value.clone()),
+            (Call::Get,None) => Some(value.clone()),
+            (Call::Set(new),Some(Return::Done)|None) => Some(Some(new.clone())),
+            (Call::Remove,Some(Return::Done)) => value.is_some().then_some(None),
+            (Call::Remove,Some(Return::NotFound)) => value.is_none().then_some(None),
+            (Call::Remove,None) => Some(None),
+            _ => None,
+        }
+    }
+}
+
+/// The first key whose operations cannot be put in one order that respects their real time
+/// and explains every result, `None` if the history is linearizable.
+/// Keys are independent registers, so each is checked on its own.
+pub fn find_violation(history:&[Operation])->Option<&str>{
+    let mut keys:BTreeMap<&str,Vec<&Operation>>=BTreeMap::new();
+    //a read without an answer tells nothing
+    for operation in history.iter().filter(|operation|operation.call!=Call::Get||operation.result.is_some()){
+        keys.entry(&operation.key).or_default().push(operation);
+    }
+    keys.into_iter().find(|(_,operations)|!linearizable(operations)).map(|(key,_)|key)
+}
+
+fn linearizable(operations:&[&Operation])->bool{
+    let mut search=Search{operations,done:vec![false;operations.len()],seen:HashSet::new()};
+    search.extend(None, 0)
+}
+
+struct Search<'a>{
+    operations:&'a [&'a Operation],
+    done:Vec<bool>,
+    //orders already found to lead nowhere, by what they placed and the value they left
+    seen:HashSet<(Vec<bool>,Option<String>)>
+}
+
+impl Search<'_> {
+    //whether the calls not placed yet can follow the ones placed, which left `value` at `frontier` at the earliest
+    fn extend(&mut self,value:Option<String>,frontier:u64)->bool{
+        let deadline=self.operations.iter().zip(&self.done)
+        .filter(|(operation,done)|!**done&&operation.result.is_some())
+        .map(|(operation,_)|operation.returned_at)
+        .min();
+        //calls without an answer may never have happened
+        let Some(deadline)=deadline else {
+            return true;
+        };
+        if !self.seen.insert((self.done.clone(),value.clone())){
+            return false;
+        }
+        for index in 0..self.operations.len(){
+            let operation=self.operations[index];
+            let at=frontier.max(operation.invoked_at);
+            if self.done[index]||at>deadline||at>operation.returned_at{
+                continue;
+            }
+            let Some(next)=operation.apply(&value) else {
+                continue;
+            };
+            self.done[index]=true;
+            if self.extend(next, at){
+                return true;
+            }
+            self.done[index]=false;
+        }
+        false
+    }
+}
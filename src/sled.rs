@@ -1,31 +1,42 @@
-use sled::{Db, Tree};
+use sled::{Batch, Db, Tree};
 
-use crate::{kv::KVError, KvsEngine};
+use crate::{kv::{namespace::NamespaceOptions, Context, KVError}, KvsEngine, DEFAULT_NAMESPACE};
 
 
-impl KvsEngine for Db {
+impl KvsEngine for Tree {
     fn set(&mut self, key: String, value: String) -> crate::Result<()> {
-        self.insert(key, value.as_bytes()).map_err(|_|KVError::WriteError("Sled::set1"))?;
-        self.flush().map_err(|_|KVError::WriteError("Sled::set2"))?;
+        self.insert(key, value.as_bytes()).context(KVError::WriteError, "Sled::set1")?;
+        self.flush().context(KVError::WriteError, "Sled::set2")?;
 
         Ok(())
     }
 
+    //one sled batch and one flush for all of them
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> crate::Result<()> {
+        let mut batch=Batch::default();
+        for (key,value) in entries{
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.apply_batch(batch).context(KVError::WriteError, "Sled::set_batch1")?;
+        self.flush().context(KVError::WriteError, "Sled::set_batch2")?;
+        Ok(())
+    }
+
     fn get(&mut self, key: String) -> crate::Result<Option<String>> {
-        let ivec=Tree::get(self, key).map_err(|_|KVError::ReadError("Sled::get1"))?;
+        let ivec=Tree::get(self, key).context(KVError::ReadError, "Sled::get1")?;
         ivec.map_or(
             Ok(None),
             |ivec|
             String::from_utf8(ivec.to_vec())
-            .map_or(Err(KVError::ReadError("Sled::get2")),|s|Ok(Some(s)))
+            .map_or(Err(KVError::ReadError("Sled::get2".into())),|s|Ok(Some(s)))
         )
     }
 
     fn remove(&mut self, key: String) -> crate::Result<()> {
-        Tree::remove(self, key)
-        .map_err(|_|KVError::WriteError("Sled::remove1"))?
-        .ok_or(KVError::KeyNotFound("Sled::remove2"))?;
-        self.flush().map_err(|_|KVError::WriteError("Sled::remove3"))?;
+        Tree::remove(self, key.as_str())
+        .context(KVError::WriteError, "Sled::remove1")?
+        .ok_or(KVError::KeyNotFound(key))?;
+        self.flush().context(KVError::WriteError, "Sled::remove3")?;
 
         Ok(())
     }
@@ -33,4 +44,84 @@ impl KvsEngine for Db {
     fn name(&self)->String {
         "sled".to_string()
     }
-}
\ No newline at end of file
+
+    fn keys(&mut self)->crate::Result<Vec<String>> {
+        self.iter()
+        .keys()
+        .map(|key|
+            key
+            .context(KVError::ReadError, "Sled::keys1")
+            .and_then(|ivec|String::from_utf8(ivec.to_vec()).context(KVError::ReadError, "Sled::keys2"))
+        )
+        .collect()
+    }
+}
+
+//a namespace is a sled tree, the default namespace is the default tree
+impl KvsEngine for Db {
+    fn set(&mut self, key: String, value: String) -> crate::Result<()> {
+        KvsEngine::set(&mut (**self).clone(), key, value)
+    }
+
+    fn get(&mut self, key: String) -> crate::Result<Option<String>> {
+        KvsEngine::get(&mut (**self).clone(), key)
+    }
+
+    fn set_batch(&mut self, entries: Vec<(String, String)>) -> crate::Result<()> {
+        KvsEngine::set_batch(&mut (**self).clone(), entries)
+    }
+
+    fn remove(&mut self, key: String) -> crate::Result<()> {
+        KvsEngine::remove(&mut (**self).clone(), key)
+    }
+    
+    fn name(&self)->String {
+        "sled".to_string()
+    }
+
+    fn keys(&mut self)->crate::Result<Vec<String>> {
+        KvsEngine::keys(&mut (**self).clone())
+    }
+
+    fn namespace<'a>(&'a mut self,name:&str)->crate::Result<Box<dyn KvsEngine+'a>> {
+        if name==DEFAULT_NAMESPACE{
+            return Ok(Box::new(self));
+        }
+        if !self.tree_names().iter().any(|tree|tree==name.as_bytes()){
+            return Err(KVError::NamespaceNotFound(name.to_string()));
+        }
+        let tree=self.open_tree(name).context(KVError::IOError, "Sled::namespace2")?;
+        Ok(Box::new(tree))
+    }
+
+    fn create_namespace(&mut self,name:&str,_options:NamespaceOptions)->crate::Result<()> {
+        if name==DEFAULT_NAMESPACE||self.tree_names().iter().any(|tree|tree==name.as_bytes()){
+            return Err(KVError::ConfigError("namespace already exists"));
+        }
+        self.open_tree(name).context(KVError::IOError, "Sled::create_namespace1")?;
+        self.flush().context(KVError::WriteError, "Sled::create_namespace2")?;
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self,name:&str)->crate::Result<()> {
+        if name==DEFAULT_NAMESPACE||!self.drop_tree(name).context(KVError::WriteError, "Sled::drop_namespace")?{
+            return Err(KVError::NamespaceNotFound(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn list_namespaces(&mut self)->crate::Result<Vec<String>> {
+        //the default tree shows up under sled's internal name
+        let default_tree=(**self).name();
+        Ok(
+            std::iter::once(DEFAULT_NAMESPACE.to_string())
+            .chain(
+                self.tree_names()
+                .into_iter()
+                .filter(|tree|*tree!=default_tree)
+                .filter_map(|tree|String::from_utf8(tree.to_vec()).ok())
+            )
+            .collect()
+        )
+    }
+}
@@ -0,0 +1,79 @@
+use kvs::kv::{BloomStats, KVError};
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+fn bloom_stats(store: &KvStore) -> BloomStats {
+    store.stats().bloom.expect("kvs engine has a bloom filter")
+}
+
+#[test]
+fn absent_keys_skip_the_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        assert_eq!(store.get(format!("absent{}", key_id))?, None);
+    }
+
+    let stats = bloom_stats(&store);
+    assert_eq!(stats.lookups, 2000);
+    // present keys always pass, absent ones pass only as false positives
+    assert_eq!(stats.hits + stats.false_positives, 1000);
+    assert!(stats.false_positives < 100, "{:?}", stats);
+    Ok(())
+}
+
+#[test]
+fn filter_survives_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("bloom.json").exists());
+
+    // keys written after the filter was saved are added on open
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(bloom_stats(&store).false_positives, 0);
+
+    assert!(matches!(store.set_bloom_false_positive_rate(0.0), Err(KVError::ConfigError(_))));
+    let default_bits = bloom_stats(&store).bits;
+    store.set_bloom_false_positive_rate(0.0001)?;
+    assert!(bloom_stats(&store).bits > default_bits);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(bloom_stats(&store).false_positive_rate, 0.0001);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn merge_drops_removed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    // enough overwrites of one key to merge
+    for iter in 0..1000 {
+        store.set("filler".to_owned(), format!("value{}", iter))?;
+    }
+
+    let before = bloom_stats(&store);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    let after = bloom_stats(&store);
+    assert!(after.hits - before.hits > 90, "{:?}", after);
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use kvs::kv::{Admission, CacheStats};
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+fn cache_stats(store: &KvStore) -> CacheStats {
+    store.stats().cache.expect("kvs engine has a value cache")
+}
+
+#[test]
+fn hot_values_are_cached_until_written() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = cache_stats(&store);
+    assert_eq!((stats.hits, stats.misses, stats.entries), (9, 1, 1));
+    assert_eq!(stats.hit_rate(), 0.9);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(cache_stats(&store).entries, 0);
+    Ok(())
+}
+
+#[test]
+fn capacity_bounds_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_value_cache(1000, Admission::Always);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    for key_id in 0..100 {
+        store.get(format!("key{}", key_id))?;
+        assert!(cache_stats(&store).used_bytes <= 1000);
+    }
+    let entries = cache_stats(&store).entries;
+    assert!(entries > 0 && entries < 10);
+
+    // values larger than the whole cache are never cached
+    store.set("big".to_owned(), "x".repeat(2000))?;
+    let before = cache_stats(&store);
+    store.get("big".to_owned())?;
+    store.get("big".to_owned())?;
+    let after = cache_stats(&store);
+    assert_eq!((after.hits, after.misses), (before.hits, before.misses + 2));
+
+    store.set_value_cache(0, Admission::Always);
+    store.get("key1".to_owned())?;
+    assert_eq!(cache_stats(&store).entries, 0);
+    Ok(())
+}
+
+// reads five hot keys, scans cold ones once, and counts the hot keys still cached
+fn hot_hits_after_scan(admission: Admission) -> Result<u64> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_value_cache(1000, admission);
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    for _ in 0..20 {
+        for key_id in 0..5 {
+            store.get(format!("key{}", key_id))?;
+        }
+    }
+    for key_id in 5..200 {
+        store.get(format!("key{}", key_id))?;
+    }
+    let before = cache_stats(&store).hits;
+    for key_id in 0..5 {
+        store.get(format!("key{}", key_id))?;
+    }
+    Ok(cache_stats(&store).hits - before)
+}
+
+#[test]
+fn tiny_lfu_keeps_hot_values_through_a_scan() -> Result<()> {
+    assert_eq!(hot_hits_after_scan(Admission::Always)?, 0);
+    assert_eq!(hot_hits_after_scan(Admission::TinyLfu)?, 5);
+    Ok(())
+}
@@ -1,3 +1,5 @@
+// these tests predate the lint, their argument borrows are kept as written
+#![allow(clippy::needless_borrows_for_generic_args)]
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
@@ -164,6 +166,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to reap server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,6 +187,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to reap server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +207,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to reap server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -225,6 +230,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to reap server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -306,6 +312,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to reap server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -0,0 +1,188 @@
+use kvs::kv::options::KvStoreOptions;
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn value(key_id: usize, iter: usize) -> String {
+    format!("{}-{}", "x".repeat(100), key_id * 1000 + iter)
+}
+
+// writes until a compaction is running in the background
+fn start_compaction(store: &mut KvStore, bytes_per_sec: u64) -> Result<()> {
+    store.set_compaction_rate(Some(bytes_per_sec));
+    let mut iter = 0;
+    while !store.is_compacting() {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+        }
+        iter += 1;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value(key_id, 0))?;
+    }
+    Ok(())
+}
+
+fn unfinished_compactions(path: &Path) -> usize {
+    fs::read_dir(path.join("data"))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().is_some())
+        .count()
+}
+
+#[test]
+fn writes_proceed_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    start_compaction(&mut store, 20_000)?;
+
+    // the throttled compaction takes seconds, foreground writes do not wait for it
+    for key_id in (0..100).step_by(2) {
+        let start = Instant::now();
+        store.set(format!("key{}", key_id), value(key_id, 1))?;
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+    for key_id in (1..100).step_by(10) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.is_compacting());
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = match key_id {
+                key_id if key_id % 2 == 0 => Some(value(key_id, 1)),
+                key_id if key_id % 10 == 1 => None,
+                key_id => Some(value(key_id, 0)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+
+    store.set_compaction_rate(None);
+    store.compact()?;
+    assert!(!store.is_compacting());
+    check(&mut store)?;
+    assert_eq!(unfinished_compactions(temp_dir.path()), 0);
+
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn dropping_the_store_cancels_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    start_compaction(&mut store, 1_000)?;
+
+    let start = Instant::now();
+    drop(store);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(unfinished_compactions(temp_dir.path()), 0);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 0)));
+    }
+    Ok(())
+}
+
+// a chunk's inputs are deleted as soon as it is done, before the compaction gets to the next one
+#[test]
+fn compaction_goes_a_chunk_at_a_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_chunk(4096).remove_retention(3600);
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    // a remove kept from the first chunk must not undo a later set of its key
+    store.set("again".to_owned(), "value1".to_owned())?;
+    store.remove("again".to_owned())?;
+    store.set("gone".to_owned(), "value1".to_owned())?;
+    store.remove("gone".to_owned())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value(key_id, 0))?;
+    }
+    store.set("again".to_owned(), "value2".to_owned())?;
+    start_compaction(&mut store, 20_000)?;
+
+    let first_segment = temp_dir.path().join("data").join("0");
+    let start = Instant::now();
+    while first_segment.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+        store.set("key0".to_owned(), value(0, 0))?;
+    }
+    assert!(store.is_compacting());
+
+    store.set_compaction_rate(None);
+    store.compact()?;
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 0)));
+        }
+        assert_eq!(store.get("again".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("gone".to_owned())?, None);
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open_with(temp_dir.path(), options)?)
+}
+
+fn segment_sizes(path: &Path) -> Vec<u64> {
+    fs::read_dir(path.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .collect()
+}
+
+#[test]
+fn compaction_splits_output_into_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+        }
+    }
+    store.compact()?;
+
+    // 200 live records of about 160 bytes do not fit one 4 KB segment
+    let sizes = segment_sizes(temp_dir.path());
+    assert!(sizes.iter().filter(|size| **size > 0).count() > 1);
+    assert!(sizes.iter().all(|size| *size <= 4096), "{:?}", sizes);
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 2)));
+    }
+    Ok(())
+}
+
+#[test]
+fn compaction_stamps_legacy_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir_all(temp_dir.path().join("data")).unwrap();
+    fs::write(
+        temp_dir.path().join("data").join("0"),
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Set":["key1","value3"]}"#,
+    )
+    .unwrap();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+
+    let contents: String = fs::read_dir(temp_dir.path().join("data"))
+        .unwrap()
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    assert!(!contents.contains("value1"));
+    assert_eq!(contents.matches(r#""seq":0"#).count(), 2);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
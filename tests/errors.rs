@@ -0,0 +1,91 @@
+use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientError};
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, EXIT_CONFIG, EXIT_NOT_FOUND, EXIT_UNAVAILABLE, EXIT_USAGE};
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn corrupt_log_reports_where() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir_all(temp_dir.path().join("data")).unwrap();
+    fs::write(
+        temp_dir.path().join("data").join("0"),
+        r#"{"Set":["key1","value1"]}{"Set":["key2""#,
+    )
+    .unwrap();
+
+    let error = KvStore::open(temp_dir.path()).err().expect("a truncated record fails the open");
+    let position = error.context().and_then(|context| context.position);
+    assert!(matches!(error, KVError::ParseError(_)), "{:?}", error);
+    assert_eq!(position.map(|position| (position.segment, position.offset)), Some((0, 25)));
+    assert!(error.to_string().contains("at segment 0 offset 25"), "{}", error);
+    assert!(error.source().is_some());
+}
+
+#[test]
+fn missing_key_names_the_key() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let error = store.remove("key1".to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "Key not found: key1");
+    assert_eq!(error.exit_code(), EXIT_NOT_FOUND);
+    Ok(())
+}
+
+#[test]
+fn client_connection_error_keeps_the_cause() {
+    let error = Client::new("127.0.0.1:4020".parse().unwrap()).get("key1").unwrap_err();
+    assert!(matches!(error, ClientError::ConnectionError { .. }), "{:?}", error);
+    assert!(error.to_string().contains("127.0.0.1:4020"), "{}", error);
+    assert!(error.source().is_some());
+    assert_eq!(error.exit_code(), EXIT_UNAVAILABLE);
+}
+
+#[test]
+fn tools_exit_with_the_error_code() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(EXIT_NOT_FOUND as i32)
+        .stderr(predicates::str::contains("Key not found: key1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--file-size", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(EXIT_CONFIG as i32)
+        .stderr(predicates::str::contains("file size must be greater than 0"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--read-only"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(EXIT_USAGE as i32);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(EXIT_UNAVAILABLE as i32);
+}
+
+#[test]
+fn invalid_config_keeps_the_parse_error() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config_path = temp_dir.path().join("config.json");
+    fs::write(&config_path, r#"{"file_size": "big"}"#).unwrap();
+
+    let error = kvs::kv::config::Config::open(config_path.clone()).err().expect("an invalid config fails the open");
+    assert!(matches!(error, KVError::ParseError(_)), "{:?}", error);
+    assert!(error.to_string().contains(&config_path.display().to_string()), "{}", error);
+    assert!(error.source().is_some());
+}
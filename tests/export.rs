@@ -0,0 +1,218 @@
+use assert_cmd::prelude::*;
+use kvs::client::Client;
+use kvs::kv::export::{export, import, EntryReader, EntryWriter, Format, ImportReport, OnConflict};
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const FORMATS: [Format; 3] = [Format::Jsonl, Format::Csv, Format::Binary];
+
+fn awkward_entries() -> Vec<(String, String)> {
+    vec![
+        ("plain".to_owned(), "value".to_owned()),
+        ("comma,key".to_owned(), "a,b".to_owned()),
+        ("quote\"key".to_owned(), "say \"hi\"".to_owned()),
+        ("lines".to_owned(), "one\ntwo\r\nthree".to_owned()),
+        ("unicode".to_owned(), "κλειδί ✓".to_owned()),
+        ("empty".to_owned(), String::new()),
+    ]
+}
+
+fn sorted(mut entries: Vec<(String, String)>) -> Vec<(String, String)> {
+    entries.sort();
+    entries
+}
+
+#[test]
+fn every_format_round_trips() -> Result<()> {
+    for format in FORMATS {
+        let from_dir = TempDir::new().expect("unable to create temporary working directory");
+        let to_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut from = KvStore::open(from_dir.path())?;
+        from.set_batch(awkward_entries())?;
+
+        let mut bytes = Vec::new();
+        assert_eq!(export(&mut from, "", format, &mut bytes)?, 6);
+        let mut to = KvStore::open(to_dir.path())?;
+        let entries = EntryReader::new(format, bytes.as_slice())?;
+        let report = import(&mut to, entries, OnConflict::Overwrite, 4)?;
+        assert_eq!(report, ImportReport { read: 6, written: 6, skipped: 0 });
+        assert_eq!(sorted(to.entries()?), sorted(awkward_entries()), "{:?}", format);
+    }
+    Ok(())
+}
+
+#[test]
+fn sled_round_trips() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sled = sled::open(temp_dir.path().join("from")).unwrap();
+    sled.set_batch(awkward_entries())?;
+    let mut bytes = Vec::new();
+    export(&mut sled, "", Format::Binary, &mut bytes)?;
+
+    let mut to = sled::open(temp_dir.path().join("to")).unwrap();
+    import(&mut to, EntryReader::new(Format::Binary, bytes.as_slice())?, OnConflict::Overwrite, 2)?;
+    assert_eq!(sorted(to.entries()?), sorted(awkward_entries()));
+    Ok(())
+}
+
+#[test]
+fn prefix_limits_the_export() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["user:2", "order:1", "user:1", "user"] {
+        store.set(key.to_owned(), "x".to_owned())?;
+    }
+    let mut bytes = Vec::new();
+    assert_eq!(export(&mut store, "user:", Format::Jsonl, &mut bytes)?, 2);
+    assert_eq!(
+        String::from_utf8(bytes).unwrap(),
+        "{\"key\":\"user:1\",\"value\":\"x\"}\n{\"key\":\"user:2\",\"value\":\"x\"}\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn skip_existing_keeps_current_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "mine".to_owned())?;
+    let incoming = || (0..10).map(|id| Ok((format!("key{}", id), "theirs".to_owned())));
+
+    let report = import(&mut store, incoming(), OnConflict::Skip, 3)?;
+    assert_eq!(report, ImportReport { read: 10, written: 9, skipped: 1 });
+    assert_eq!(store.get("key1".to_owned())?, Some("mine".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("theirs".to_owned()));
+
+    let report = import(&mut store, incoming(), OnConflict::Overwrite, 3)?;
+    assert_eq!(report, ImportReport { read: 10, written: 10, skipped: 0 });
+    assert_eq!(store.get("key1".to_owned())?, Some("theirs".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn malformed_input_is_a_parse_error() {
+    let read_all = |format: Format, input: &[u8]| -> Result<Vec<(String, String)>> {
+        EntryReader::new(format, input)?.collect()
+    };
+    let cases: [(Format, &[u8]); 5] = [
+        (Format::Csv, b"k,v\nkey,value\n"),
+        (Format::Csv, b"key,value\n\"open,value\n"),
+        (Format::Csv, b"key,value\na,b,c\n"),
+        (Format::Binary, b"KVX1\x04\x00\x00\x00ke"),
+        (Format::Jsonl, b"{\"key\":\"k\"}\n"),
+    ];
+    for (format, input) in cases {
+        let result = read_all(format, input);
+        assert!(matches!(result, Err(KVError::ParseError(_))), "{:?} {:?}", format, result);
+    }
+    assert_eq!(read_all(Format::Binary, b"KVX1").unwrap(), vec![]);
+    assert_eq!(
+        read_all(Format::Csv, b"key,value\r\n\"a\"\"b\",\"c\nd\"\r\n\r\ne,f").unwrap(),
+        vec![("a\"b".to_owned(), "c\nd".to_owned()), ("e".to_owned(), "f".to_owned())]
+    );
+}
+
+#[test]
+fn cli_export_then_import() -> Result<()> {
+    let from_dir = TempDir::new().expect("unable to create temporary working directory");
+    let to_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(from_dir.path())?.set_batch(awkward_entries())?;
+    let export_path = to_dir.path().join("export.csv");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output", export_path.to_str().unwrap()])
+        .current_dir(&from_dir)
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("exported 6 entries"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "export.csv", "--format", "csv", "--skip-existing"])
+        .current_dir(&to_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("read 6 entries, wrote 6"));
+
+    assert_eq!(sorted(KvStore::open(to_dir.path())?.entries()?), sorted(awkward_entries()));
+    Ok(())
+}
+
+#[test]
+fn client_export_and_import_through_the_server() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut from = KvStore::open(source.path())?;
+    from.set_batch(awkward_entries())?;
+    let mut bytes = Vec::new();
+    export(&mut from, "", Format::Jsonl, &mut bytes)?;
+    fs::write(temp_dir.path().join("in.jsonl"), bytes).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["import", "in.jsonl", "--batch-size", "4", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("read 6 entries, wrote 6"));
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["export", "--format", "binary", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to reap server");
+
+    assert!(output.status.success());
+    let exported: Vec<(String, String)> =
+        EntryReader::new(Format::Binary, output.stdout.as_slice())?.collect::<Result<_>>()?;
+    assert_eq!(exported, sorted(awkward_entries()));
+    Ok(())
+}
+
+#[test]
+fn client_export_pages_through_every_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut bytes = Vec::new();
+    let mut writer = EntryWriter::new(Format::Jsonl, &mut bytes)?;
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // more than one page, with keys outside the prefix in between
+    let entries: Vec<(String, String)> = (0..2500)
+        .flat_map(|i| [(format!("user:{:04}", i), format!("value{}", i)), (format!("other:{:04}", i), String::new())])
+        .collect();
+    let client = Client::new("127.0.0.1:4033".parse().unwrap());
+    let imported = client.import(entries.clone().into_iter().map(Ok), OnConflict::Overwrite, 1000);
+    let exported = client.export("user:", &mut writer);
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to reap server");
+
+    imported.unwrap();
+    exported.unwrap();
+    assert_eq!(writer.finish()?, 2500);
+    let exported: Vec<(String, String)> =
+        EntryReader::new(Format::Jsonl, bytes.as_slice())?.collect::<Result<_>>()?;
+    let expected: Vec<(String, String)> = entries.into_iter().filter(|(key, _)| key.starts_with("user:")).collect();
+    assert_eq!(exported, expected);
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use kvs::kv::filesystem::simulated::{Fault, SimulatedFileSystem};
+use kvs::kv::filesystem::FileSystem;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::{Durability, KVError};
+use kvs::{KvStore, KvsEngine, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+fn sim_options(fs: &SimulatedFileSystem) -> KvStoreOptions {
+    KvStoreOptions::new()
+        .file_system(Arc::new(fs.clone()))
+        .durability(Durability::Sync)
+        .file_size(300)
+        .merge_size(600)
+}
+
+#[test]
+fn power_loss_keeps_what_was_synced() {
+    let fs = SimulatedFileSystem::new();
+    let dir = Path::new("/sim");
+    fs.create_dir_all(dir).unwrap();
+    let mut file = fs.open_append(&dir.join("0")).unwrap();
+    file.append(b"synced").unwrap();
+    file.sync().unwrap();
+    file.append(b" and not").unwrap();
+    fs.open_append(&dir.join("1")).unwrap().append(b"gone").unwrap();
+    fs.open_append(&dir.join("2")).unwrap();
+    fs.remove(&dir.join("2")).unwrap();
+    fs.sync_dir(dir).unwrap();
+
+    fs.power_loss(&mut StdRng::seed_from_u64(7));
+    assert!(file.append(b"late").is_err());
+    let reopened = fs.open_read(&dir.join("0")).unwrap();
+    let mut bytes = vec![0; 64];
+    let read = reopened.read_at(0, &mut bytes).unwrap();
+    assert!(b"synced and not".starts_with(&bytes[..read]) && read >= 6, "{:?}", &bytes[..read]);
+    let mut names = fs.list(dir).unwrap();
+    names.sort();
+    assert_eq!(names, vec!["0", "1"]);
+}
+
+// a rename or remove not yet synced may be lost on its own, so a remove can outlive the rename before it
+#[test]
+fn unsynced_directory_changes_may_be_lost() {
+    let mut outcomes = std::collections::BTreeSet::new();
+    for seed in 0..32 {
+        let fs = SimulatedFileSystem::new();
+        let dir = Path::new("/sim");
+        fs.create_dir_all(dir).unwrap();
+        fs.open_append(&dir.join("0")).unwrap().append(b"old").unwrap();
+        fs.open_append(&dir.join("1.tmp")).unwrap().append(b"new").unwrap();
+        fs.sync_dir(dir).unwrap();
+        fs.rename(&dir.join("1.tmp"), &dir.join("1")).unwrap();
+        fs.remove(&dir.join("0")).unwrap();
+
+        fs.power_loss(&mut StdRng::seed_from_u64(seed));
+        let mut names = fs.list(dir).unwrap();
+        names.sort();
+        outcomes.insert(names);
+    }
+    assert_eq!(outcomes.len(), 4, "{:?}", outcomes);
+    assert!(outcomes.contains(&vec!["1.tmp".into()]));
+}
+
+#[test]
+fn injected_faults_fail_one_operation_each() {
+    let fs = SimulatedFileSystem::new();
+    fs.create_dir_all(Path::new("/sim")).unwrap();
+    let mut file = fs.open_append(Path::new("/sim/0")).unwrap();
+    fs.inject(Fault::SyncFailure);
+    fs.inject(Fault::ShortWrite);
+    fs.inject(Fault::NoSpace);
+
+    assert!(file.append(b"abcd").is_err());
+    assert_eq!(file.size().unwrap(), 2);
+    let error = file.append(b"abcd").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+    assert_eq!(file.size().unwrap(), 2);
+    assert!(file.sync().is_err());
+    file.sync().unwrap();
+
+    fs.set_capacity(Some(5));
+    assert!(file.append(b"abcd").is_err());
+    assert_eq!(file.size().unwrap(), 5);
+}
+
+#[test]
+fn failed_writes_leave_nothing_behind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = SimulatedFileSystem::new();
+    let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for fault in [Fault::NoSpace, Fault::ShortWrite, Fault::SyncFailure] {
+        fs.inject(fault);
+        let error = store.set("key1".to_owned(), format!("{:?}", fault)).unwrap_err();
+        assert!(matches!(error, KVError::WriteError(_)), "{:?}", error);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // nothing of the failed records is left between the good ones
+    let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // the logs never touched the disk
+    assert!(!temp_dir.path().join("data").exists());
+    Ok(())
+}
+
+#[test]
+fn full_disk_fails_writes_until_there_is_room() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = SimulatedFileSystem::new();
+    let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs).merge_size(1_000_000))?;
+    fs.set_capacity(Some(2000));
+    let mut written = 0;
+    let error = loop {
+        match store.set(format!("key{}", written), "x".repeat(40)) {
+            Ok(()) => written += 1,
+            Err(error) => break error,
+        }
+    };
+    assert!(matches!(error, KVError::WriteError(_)), "{:?}", error);
+    assert!(written > 10);
+    assert_eq!(store.keys()?.len(), written);
+
+    fs.set_capacity(None);
+    store.set("after".to_owned(), "room".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+    assert_eq!(store.keys()?.len(), written + 1);
+    Ok(())
+}
+
+#[test]
+fn namespaces_live_on_the_simulated_fs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = SimulatedFileSystem::new();
+    let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs).mmap_reads(true))?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    for key_id in 0..50 {
+        store.namespace("users")?.set(format!("key{}", key_id), format!("user{}", key_id))?;
+    }
+    assert_eq!(store.namespace("users")?.get("key7".to_owned())?, Some("user7".to_owned()));
+    store.drop_namespace("users")?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    assert!(store.namespace("users")?.keys()?.is_empty());
+    Ok(())
+}
+
+// the values a key may have, more than one after an operation on it failed
+type Model = BTreeMap<String, Vec<Option<String>>>;
+
+fn possible(model: &Model, key: &str) -> Vec<Option<String>> {
+    model.get(key).cloned().unwrap_or_else(|| vec![None])
+}
+
+fn check(store: &mut KvStore, model: &mut Model, seed: u64) -> Result<()> {
+    for (key, values) in model.iter_mut() {
+        let value = store.get(key.clone())?;
+        assert!(values.contains(&value), "seed {} key {} is {:?}, expected one of {:?}", seed, key, value, values);
+        *values = vec![value];
+    }
+    for key in store.keys()? {
+        assert!(model.contains_key(&key), "seed {} key {} came back from nowhere", seed, key);
+    }
+    Ok(())
+}
+
+fn random_fault(rng: &mut StdRng) -> Option<Fault> {
+    match rng.gen_range(0..40) {
+        0 => Some(Fault::NoSpace),
+        1 => Some(Fault::ShortWrite),
+        2 => Some(Fault::SyncFailure),
+        _ => None,
+    }
+}
+
+#[test]
+fn random_workloads_recover_to_the_model() -> Result<()> {
+    for seed in 0..20 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let fs = SimulatedFileSystem::new();
+        let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+        let mut model = Model::new();
+
+        for step in 0..400 {
+            if let Some(fault) = random_fault(&mut rng) {
+                fs.inject(fault);
+            }
+            let key = format!("key{}", rng.gen_range(0..15));
+            let mut values = possible(&model, &key);
+            if rng.gen_bool(0.7) {
+                let value = format!("value{}-{}", step, "x".repeat(rng.gen_range(0..30)));
+                match store.set(key.clone(), value.clone()) {
+                    Ok(()) => values = vec![Some(value)],
+                    Err(_) => values.push(Some(value)),
+                }
+            } else {
+                match store.remove(key.clone()) {
+                    Ok(()) => values = vec![None],
+                    Err(KVError::KeyNotFound(_)) => {
+                        assert!(values.contains(&None), "seed {} key {} was not found", seed, key);
+                        values = vec![None];
+                    },
+                    Err(_) => values.push(None),
+                }
+            }
+            model.insert(key, values);
+
+            if rng.gen_range(0..50) == 0 {
+                fs.power_loss(&mut rng);
+                drop(store);
+                store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+                check(&mut store, &mut model, seed)?;
+            }
+        }
+        fs.clear_faults();
+        check(&mut store, &mut model, seed)?;
+        drop(store);
+        let mut store = KvStore::open_with(temp_dir.path(), sim_options(&fs))?;
+        check(&mut store, &mut model, seed)?;
+    }
+    Ok(())
+}
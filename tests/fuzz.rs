@@ -0,0 +1,145 @@
+// Runs the fuzz targets of fuzz/ offline: every input of their corpus, then seeded mutations of it.
+// `cargo fuzz run <target>` searches much further, inputs it finds belong in fuzz/corpus/<target>.
+use kvs::server::{Server, MAX_REQUEST_SIZE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// counts live bytes and their peak, so an input making the target allocate far more than its size is caught
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+// one target runs at a time, or their allocations would be counted together
+static MEASURE: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+            let live = LIVE.fetch_add(new_size, Ordering::SeqCst) + new_size;
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const MUTATIONS: usize = 1500;
+
+// bytes a target may hold at once beyond what it held before
+fn allocation_limit(input: &[u8]) -> usize {
+    1_000_000 + 64 * input.len()
+}
+
+fn corpus(target: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz").join("corpus").join(target);
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    paths.sort();
+    paths.into_iter().map(|path| fs::read(path).unwrap()).collect()
+}
+
+fn run_one(name: &str, target: fn(&[u8]), input: &[u8]) {
+    let _measuring = MEASURE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let before = LIVE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| target(input)));
+    let allocated = PEAK.load(Ordering::SeqCst).saturating_sub(before);
+    if result.is_err() {
+        panic!("{} panicked on {:?}", name, String::from_utf8_lossy(input));
+    }
+    assert!(
+        allocated <= allocation_limit(input),
+        "{} allocated {} bytes on {:?}",
+        name,
+        allocated,
+        String::from_utf8_lossy(input)
+    );
+}
+
+const TOKENS: &[u8] = b"{}[]\":,\\0123456789-+eE.tfnrul \n\x00\xff";
+
+fn mutate(rng: &mut StdRng, input: &mut Vec<u8>, corpus: &[Vec<u8>]) {
+    for _ in 0..rng.gen_range(1..=4) {
+        let at = rng.gen_range(0..=input.len());
+        match rng.gen_range(0..6) {
+            0 if at < input.len() => input[at] ^= 1 << rng.gen_range(0..8),
+            1 if at < input.len() => input[at] = TOKENS[rng.gen_range(0..TOKENS.len())],
+            2 => input.insert(at, TOKENS[rng.gen_range(0..TOKENS.len())]),
+            3 => {
+                let end = rng.gen_range(at..=input.len().min(at + 16));
+                input.drain(at..end);
+            },
+            4 => {
+                let other = &corpus[rng.gen_range(0..corpus.len())];
+                let start = rng.gen_range(0..=other.len());
+                let end = rng.gen_range(start..=other.len());
+                input.splice(at..at, other[start..end].iter().copied());
+            },
+            _ => input.truncate(at),
+        }
+    }
+}
+
+fn fuzz(name: &str, target: fn(&[u8])) {
+    let corpus = corpus(name);
+    assert!(!corpus.is_empty());
+    for input in &corpus {
+        run_one(name, target, input);
+    }
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..MUTATIONS {
+        let mut input = corpus[rng.gen_range(0..corpus.len())].clone();
+        mutate(&mut rng, &mut input, &corpus);
+        run_one(name, target, &input);
+    }
+}
+
+#[test]
+fn segment_parsing_never_panics() {
+    fuzz("segment", kvs::fuzz::segment);
+}
+
+#[test]
+fn record_decoding_never_panics() {
+    fuzz("record", kvs::fuzz::record);
+}
+
+#[test]
+fn request_decoding_never_panics() {
+    fuzz("request", kvs::fuzz::request);
+}
+
+// a client streaming one endless string is cut off instead of filling the server's memory
+#[test]
+fn endless_request_is_cut_off() {
+    let _measuring = MEASURE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let before = LIVE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let endless = b"{\"Get\":{\"key\":\"".chain(io::repeat(b'a'));
+    assert!(Server::parse_request(endless).is_err());
+    assert!(PEAK.load(Ordering::SeqCst) - before < 4 * MAX_REQUEST_SIZE as usize);
+}
@@ -0,0 +1,96 @@
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::IndexKind;
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+fn exercise(kind: IndexKind) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_index(temp_dir.path(), kind)?;
+
+    // enough overwrites to merge several times
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+    }
+    for key_id in (0..100).step_by(3) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.remove("key0".to_owned()).is_err());
+    store.create_namespace("other", NamespaceOptions::default())?;
+    store.namespace("other")?.set("key0".to_owned(), "other0".to_owned())?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = (key_id % 3 != 0).then(|| format!("value{}-9", key_id));
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        let keys = store.keys()?;
+        assert_eq!(keys.len(), 66);
+        assert_eq!(store.entries()?.len(), 66);
+        assert_eq!(store.namespace("other")?.get("key0".to_owned())?, Some("other0".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    drop(store);
+    let mut store = KvStore::open_with_index(temp_dir.path(), kind)?;
+    check(&mut store)?;
+
+    // the kind is not stored, the same files open with the other index
+    drop(store);
+    let other = if kind == IndexKind::Hash { IndexKind::Compact } else { IndexKind::Hash };
+    check(&mut KvStore::open_with_index(temp_dir.path(), other)?)
+}
+
+#[test]
+fn hash_index() -> Result<()> {
+    exercise(IndexKind::Hash)
+}
+
+#[test]
+fn compact_index() -> Result<()> {
+    exercise(IndexKind::Compact)
+}
+
+// segment serials past what 24 bits hold, as years of compactions skipping serials leave them
+#[test]
+fn compact_index_past_24_bit_serials() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().file_size(4096).index_kind(IndexKind::Compact);
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let data = temp_dir.path().join("data");
+    for entry in fs::read_dir(&data)? {
+        let path = entry?.path();
+        let serial: usize = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+        fs::rename(&path, data.join((serial + (1 << 30)).to_string()))?;
+    }
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..50 {
+        for key_id in (0..100).step_by(7) {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+        store.compact()?;
+    }
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = match key_id % 7 {
+                0 => format!("value{}-49", key_id),
+                _ => format!("value{}", key_id),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open_with(temp_dir.path(), options)?)
+}
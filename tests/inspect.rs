@@ -0,0 +1,99 @@
+use assert_cmd::prelude::*;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result, DEFAULT_NAMESPACE};
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn segments_count_live_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let segments = store.inspector(DEFAULT_NAMESPACE)?.segments()?;
+    assert_eq!(segments.len(), 2);
+    assert_eq!((segments[0].records, segments[0].live), (2, 0));
+    assert_eq!((segments[1].records, segments[1].live), (2, 1));
+    assert_eq!(segments[1].live_ratio, 0.5);
+    assert!(segments.iter().all(|segment| segment.size > 0));
+    Ok(())
+}
+
+#[test]
+fn dump_and_key_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let inspector = store.inspector(DEFAULT_NAMESPACE)?;
+    let records = inspector.dump(0)?;
+    let ops: Vec<(&str, &str)> = records.iter().map(|record| (record.op, record.key.as_str())).collect();
+    assert_eq!(ops, vec![("set", "key1"), ("set", "key2"), ("set", "key1"), ("remove", "key1")]);
+    assert_eq!(records[0].offset, 0);
+    assert!(records.windows(2).all(|pair| pair[0].offset < pair[1].offset && pair[0].seq < pair[1].seq));
+    assert_eq!(records[2].value_len, Some(7));
+    assert_eq!(records[2].value, None);
+    assert!(records[1].live && !records[2].live);
+
+    let history = inspector.key("key1")?;
+    let values: Vec<Option<&str>> = history.iter().map(|record| record.value.as_deref()).collect();
+    assert_eq!(values, vec![Some("value1"), Some("value11"), None]);
+    assert!(history.iter().all(|record| !record.live));
+    assert!(matches!(inspector.dump(7), Err(KVError::ConfigError(_))));
+    Ok(())
+}
+
+#[test]
+fn namespaces_are_inspected_apart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    store.namespace("users")?.set("key1".to_owned(), "user1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert!(store.inspector(DEFAULT_NAMESPACE)?.key("key1")?.is_empty());
+    assert_eq!(store.inspector("users")?.key("key1")?.len(), 1);
+    assert!(matches!(store.inspector("missing"), Err(KVError::NamespaceNotFound(_))));
+    Ok(())
+}
+
+#[test]
+fn cli_inspect() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "key", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("set key1 = value1 live"));
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "segments", "--json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let segments: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(segments[0]["records"], 1);
+    assert_eq!(segments[0]["live"], 1);
+    // inspecting opened the store read only
+    assert_eq!(segments.as_array().unwrap().len(), 1);
+    Ok(())
+}
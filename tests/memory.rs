@@ -0,0 +1,145 @@
+use assert_cmd::prelude::*;
+use kvs::kv::export::{import, OnConflict};
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::KVError;
+use kvs::memory::SNAPSHOT_FILE;
+use kvs::{KvsEngine, MemoryStore, Result, DEFAULT_NAMESPACE};
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn memory_engine_basics() -> Result<()> {
+    let mut store = MemoryStore::new();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value11".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert!(matches!(store.remove("missing".to_owned()), Err(KVError::KeyNotFound(_))));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.entries()?, vec![("key1".to_owned(), "value11".to_owned())]);
+
+    store.restore(vec![("key3".to_owned(), "value3".to_owned())])?;
+    assert_eq!(store.keys()?, vec!["key3".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn memory_namespaces() -> Result<()> {
+    let mut store = MemoryStore::new();
+    store.create_namespace("users", NamespaceOptions::default())?;
+    assert!(matches!(store.create_namespace("users", NamespaceOptions::default()), Err(KVError::ConfigError(_))));
+    store.namespace("users")?.set("key1".to_owned(), "user1".to_owned())?;
+    store.namespace(DEFAULT_NAMESPACE)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.list_namespaces()?, vec![DEFAULT_NAMESPACE.to_owned(), "users".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.namespace("users")?.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert!(matches!(store.namespace("missing"), Err(KVError::NamespaceNotFound(_))));
+
+    store.drop_namespace("users")?;
+    assert!(matches!(store.drop_namespace("users"), Err(KVError::NamespaceNotFound(_))));
+    assert!(matches!(store.drop_namespace(DEFAULT_NAMESPACE), Err(KVError::NamespaceNotFound(_))));
+    Ok(())
+}
+
+// code taking a KvsEngine can be handed a plain map
+#[test]
+fn ordered_map_is_an_engine() -> Result<()> {
+    let mut map = BTreeMap::new();
+    let entries = (0..5).rev().map(|id| Ok((format!("key{}", id), format!("value{}", id))));
+    import(&mut map, entries, OnConflict::Overwrite, 2)?;
+    assert_eq!(KvsEngine::keys(&mut map)?, vec!["key0", "key1", "key2", "key3", "key4"]);
+    Ok(())
+}
+
+#[test]
+fn snapshot_survives_reopening() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MemoryStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    store.namespace("users")?.set("key1".to_owned(), "user1".to_owned())?;
+    drop(store);
+
+    let mut store = MemoryStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.namespace("users")?.get("key1".to_owned())?, Some("user1".to_owned()));
+    store.remove("key1".to_owned())?;
+    store.snapshot()?;
+    assert_eq!(MemoryStore::open(temp_dir.path())?.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn bad_snapshot_is_left_alone() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join(SNAPSHOT_FILE);
+    fs::write(&snapshot, "{\"default\":").unwrap();
+    assert!(matches!(MemoryStore::open(temp_dir.path()), Err(KVError::ParseError(_))));
+    assert_eq!(fs::read_to_string(&snapshot).unwrap(), "{\"default\":");
+}
+
+fn start_server(dir: &TempDir, args: &[&str]) -> Child {
+    let child = Command::cargo_bin("kvs-server").unwrap().args(args).current_dir(dir).spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn client(dir: &TempDir, args: &[&str]) -> String {
+    let output = Command::cargo_bin("kvs-client").unwrap().args(args).current_dir(dir).output().unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn server_snapshots_on_shutdown() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let args = ["--engine", "memory", "--snapshot", "--addr", "127.0.0.1:4024"];
+    let mut server = start_server(&temp_dir, &args);
+    client(&temp_dir, &["set", "key1", "value1", "--addr", "127.0.0.1:4024"]);
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(server.wait().expect("failed to reap server").success());
+    assert!(temp_dir.path().join("data").join("db").join(SNAPSHOT_FILE).exists());
+
+    let mut server = start_server(&temp_dir, &args);
+    let value = client(&temp_dir, &["get", "key1", "--addr", "127.0.0.1:4024"]);
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to reap server");
+    assert_eq!(value, "value1\n");
+
+    // the data directory now belongs to the memory engine
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_without_snapshot_keeps_nothing() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--snapshot", "--addr", "127.0.0.1:4025"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let args = ["--engine", "memory", "--addr", "127.0.0.1:4025"];
+    let mut server = start_server(&temp_dir, &args);
+    client(&temp_dir, &["set", "key1", "value1", "--addr", "127.0.0.1:4025"]);
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGINT) };
+    assert!(server.wait().expect("failed to reap server").success());
+    assert!(!temp_dir.path().join("data").join("metadata").exists());
+
+    let mut server = start_server(&temp_dir, &args);
+    let value = client(&temp_dir, &["get", "key1", "--addr", "127.0.0.1:4025"]);
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to reap server");
+    assert_eq!(value, "Key not found\n");
+}
@@ -0,0 +1,115 @@
+use assert_cmd::prelude::*;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::options::KvStoreOptions;
+use kvs::server::command::StorageEngine;
+use kvs::server::migrate::migrate;
+use kvs::server::{ServerError, StorageMetaData};
+use kvs::{KvStore, KvsEngine};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// a data directory the way kvs-server leaves it after serving with the kvs engine
+fn kvs_data_dir(data_path: &Path) -> kvs::Result<()> {
+    let mut store = KvStore::open(data_path.join("db"))?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    for key_id in 0..300 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.namespace("users")?.set("alice".to_owned(), "admin".to_owned())?;
+    StorageMetaData { engine: Some(StorageEngine::Kv), db: None }.save(data_path).unwrap();
+    Ok(())
+}
+
+fn check_contents(engine: &mut dyn KvsEngine) -> kvs::Result<()> {
+    assert_eq!(engine.keys()?.len(), 300);
+    assert_eq!(engine.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(engine.namespace("users")?.get("alice".to_owned())?, Some("admin".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn migrate_there_and_back() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path();
+    kvs_data_dir(data_path)?;
+    let options = KvStoreOptions::default();
+
+    let report = migrate(data_path, StorageEngine::Kv, StorageEngine::Sled, &options, 10).unwrap();
+    assert_eq!((report.keys, report.namespaces, report.sampled), (301, 2, 11));
+    let meta = StorageMetaData::load(data_path).unwrap();
+    assert_eq!(meta.engine, Some(StorageEngine::Sled));
+    assert_eq!(meta.db_path(data_path), data_path.join("db.sled"));
+    {
+        let mut sled = sled::open(data_path.join("db.sled")).unwrap();
+        check_contents(&mut sled)?;
+    }
+    // the old store is left alone
+    check_contents(&mut KvStore::open(data_path.join("db"))?)?;
+
+    migrate(data_path, StorageEngine::Sled, StorageEngine::Kv, &options, 10).unwrap();
+    assert_eq!(StorageMetaData::load(data_path).unwrap().db_path(data_path), data_path.join("db.kvs"));
+    check_contents(&mut KvStore::open(data_path.join("db.kvs"))?)?;
+
+    // a second trip replaces the stale copy
+    migrate(data_path, StorageEngine::Kv, StorageEngine::Sled, &options, 10).unwrap();
+    let mut sled = sled::open(data_path.join("db.sled")).unwrap();
+    check_contents(&mut sled)?;
+    Ok(())
+}
+
+#[test]
+fn migrate_checks_the_engines() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    kvs_data_dir(temp_dir.path())?;
+    let options = KvStoreOptions::default();
+    for (from, to) in [(StorageEngine::Sled, StorageEngine::Kv), (StorageEngine::Kv, StorageEngine::Kv)] {
+        assert!(matches!(
+            migrate(temp_dir.path(), from, to, &options, 10),
+            Err(ServerError::ConfigError(_))
+        ));
+    }
+    assert_eq!(StorageMetaData::load(temp_dir.path()).unwrap().engine, Some(StorageEngine::Kv));
+    assert!(!temp_dir.path().join("db.sled").exists());
+    Ok(())
+}
+
+#[test]
+fn server_starts_on_the_migrated_engine() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    kvs_data_dir(&temp_dir.path().join("data"))?;
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("migrated 301 keys in 2 namespaces from kvs to sled"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "alice", "--ns", "users", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to reap server");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "admin\n");
+    Ok(())
+}
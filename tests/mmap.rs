@@ -0,0 +1,38 @@
+use kvs::kv::Admission;
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+fn check(store: &mut KvStore, iter: usize) -> Result<()> {
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}-{}", key_id, iter)));
+    }
+    Ok(())
+}
+
+#[test]
+fn mmap_reads_match_file_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}-0", key_id))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    // every read goes to the log
+    store.set_value_cache(0, Admission::Always);
+    store.set_mmap_reads(true)?;
+    check(&mut store, 0)?;
+
+    // segments sealed while writing, and the ones merged into, are read as well
+    for iter in 1..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+        check(&mut store, iter)?;
+    }
+
+    store.set_mmap_reads(false)?;
+    check(&mut store, 19)?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::{IndexKind, KVError};
+use kvs::{KvStore, KvsEngine, Result};
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+// keys are picked from a small pool so operations hit the same ones
+#[derive(Debug, Clone)]
+enum Op {
+    Set(usize, String),
+    Get(usize),
+    Remove(usize),
+    Reopen,
+    Compact,
+}
+
+#[derive(Debug, Clone)]
+struct Case {
+    keys: Vec<String>,
+    ops: Vec<Op>,
+    file_size: usize,
+    merge_size: usize,
+    compaction_chunk: usize,
+    index_kind: IndexKind,
+    background_compaction: bool,
+    keep_removes: bool,
+}
+
+impl Case {
+    fn options(&self) -> KvStoreOptions {
+        let options = KvStoreOptions::new()
+            .file_size(self.file_size)
+            .merge_size(self.merge_size)
+            .compaction_chunk(self.compaction_chunk)
+            .index_kind(self.index_kind)
+            .background_compaction(self.background_compaction);
+        if self.keep_removes {
+            options.remove_retention(3600)
+        } else {
+            options
+        }
+    }
+
+    fn open(&self, path: &Path) -> Result<KvStore> {
+        KvStore::open_with(path, self.options())
+    }
+}
+
+fn key() -> impl Strategy<Value = String> {
+    prop_oneof![".{0,3}", ".{20,80}"]
+}
+
+fn value() -> impl Strategy<Value = String> {
+    prop_oneof![".{0,10}", ".{50,400}"]
+}
+
+fn op(keys: usize) -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..keys, value()).prop_map(|(key, value)| Op::Set(key, value)),
+        3 => (0..keys).prop_map(Op::Get),
+        2 => (0..keys).prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn case(max_ops: usize) -> impl Strategy<Value = Case> {
+    let settings = (
+        64usize..1024,
+        64usize..2048,
+        0usize..4096,
+        prop_oneof![Just(IndexKind::Hash), Just(IndexKind::Compact)],
+        any::<bool>(),
+        any::<bool>(),
+    );
+    (prop::collection::vec(key(), 1..8), settings).prop_flat_map(move |(keys, (file_size, merge_size, compaction_chunk, index_kind, background_compaction, keep_removes))| {
+        prop::collection::vec(op(keys.len()), 1..max_ops).prop_map(move |ops| Case {
+            keys: keys.clone(),
+            ops,
+            file_size,
+            merge_size,
+            compaction_chunk,
+            index_kind,
+            background_compaction,
+            keep_removes,
+        })
+    })
+}
+
+fn fail(error: KVError) -> TestCaseError {
+    TestCaseError::fail(error.to_string())
+}
+
+// the store holds exactly what the model does
+fn check_contents(store: &mut KvStore, model: &BTreeMap<String, String>) -> std::result::Result<(), TestCaseError> {
+    let mut entries = store.entries().map_err(fail)?;
+    entries.sort();
+    let expected: Vec<_> = model.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+    prop_assert_eq!(entries, expected);
+    Ok(())
+}
+
+// runs the case on a store and a map side by side, reopening the store after every step if asked
+fn run(case: &Case, reopen_every_step: bool) -> std::result::Result<(), TestCaseError> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = case.open(temp_dir.path()).map_err(fail)?;
+    let mut model = BTreeMap::new();
+
+    for op in &case.ops {
+        match op {
+            Op::Set(key, value) => {
+                store.set(case.keys[*key].clone(), value.clone()).map_err(fail)?;
+                model.insert(case.keys[*key].clone(), value.clone());
+            },
+            Op::Get(key) => {
+                let key = &case.keys[*key];
+                prop_assert_eq!(store.get(key.clone()).map_err(fail)?, model.get(key).cloned());
+            },
+            Op::Remove(key) => {
+                let key = &case.keys[*key];
+                match (store.remove(key.clone()), model.remove(key)) {
+                    (Ok(()), Some(_)) | (Err(KVError::KeyNotFound(_)), None) => {},
+                    (result, expected) => prop_assert!(false, "remove gave {:?}, the model had {:?}", result, expected),
+                }
+            },
+            Op::Reopen => {
+                drop(store);
+                store = case.open(temp_dir.path()).map_err(fail)?;
+                check_contents(&mut store, &model)?;
+            },
+            Op::Compact => {
+                store.compact().map_err(fail)?;
+                check_contents(&mut store, &model)?;
+            },
+        }
+        if reopen_every_step {
+            drop(store);
+            store = case.open(temp_dir.path()).map_err(fail)?;
+            check_contents(&mut store, &model)?;
+        }
+    }
+    check_contents(&mut store, &model)?;
+    drop(store);
+    let mut store = case.open(temp_dir.path()).map_err(fail)?;
+    check_contents(&mut store, &model)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn store_matches_model(case in case(120)) {
+        run(&case, false)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(48))]
+
+    // rebuilding the index from the logs after each step gives back the same contents
+    #[test]
+    fn reopening_after_every_step_matches_model(case in case(40)) {
+        run(&case, true)?;
+    }
+}
@@ -0,0 +1,115 @@
+use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientError};
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Runs the same checks against any engine that supports namespaces.
+fn namespaces_are_isolated(engine: &mut dyn KvsEngine) -> Result<()> {
+    engine.create_namespace("users", NamespaceOptions::default())?;
+    assert!(engine.create_namespace("users", NamespaceOptions::default()).is_err());
+    assert!(engine.create_namespace("default", NamespaceOptions::default()).is_err());
+
+    engine.set("key1".to_owned(), "default1".to_owned())?;
+    engine.namespace("users")?.set("key1".to_owned(), "users1".to_owned())?;
+    engine.namespace("users")?.set("key2".to_owned(), "users2".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("default1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.namespace("default")?.get("key1".to_owned())?, Some("default1".to_owned()));
+    assert_eq!(engine.namespace("users")?.get("key1".to_owned())?, Some("users1".to_owned()));
+    assert_eq!(engine.namespace("users")?.keys()?.len(), 2);
+    assert!(matches!(engine.namespace("missing"), Err(KVError::NamespaceNotFound(_))));
+    assert_eq!(engine.list_namespaces()?, vec!["default".to_owned(), "users".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn kv_store_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    namespaces_are_isolated(&mut store)?;
+    store.create_namespace("small", NamespaceOptions { merge_threshold: Some(1024) })?;
+    assert!(store.create_namespace("bad/name", NamespaceOptions::default()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_namespaces()?, vec!["default", "small", "users"]);
+    assert_eq!(store.namespace("users")?.get("key1".to_owned())?, Some("users1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("default1".to_owned()));
+
+    // Dropping removes the files at once instead of waiting for compaction
+    let dir = temp_dir.path().join("namespaces").join("users");
+    assert!(dir.exists());
+    store.drop_namespace("users")?;
+    assert!(!dir.exists());
+    assert!(matches!(store.drop_namespace("users"), Err(KVError::NamespaceNotFound(_))));
+    assert_eq!(store.get("key1".to_owned())?, Some("default1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.list_namespaces()?, vec!["default", "small"]);
+    Ok(())
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut db = sled::open(temp_dir.path()).unwrap();
+    namespaces_are_isolated(&mut db)?;
+    db.drop_namespace("users")?;
+    assert_eq!(db.list_namespaces()?, vec!["default"]);
+    assert!(matches!(db.namespace("users"), Err(KVError::NamespaceNotFound(_))));
+    Ok(())
+}
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn server_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4016"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let client_cmd = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", "127.0.0.1:4016"]);
+        cmd
+    };
+    client_cmd(&["ns", "create", "orders"]).assert().success();
+    client_cmd(&["set", "key1", "order1", "--ns", "orders"]).assert().success();
+    client_cmd(&["set", "key1", "value1"]).assert().success();
+    client_cmd(&["get", "key1", "--ns", "orders"]).assert().success().stdout("order1\n");
+    client_cmd(&["get", "key1"]).assert().success().stdout("value1\n");
+    client_cmd(&["ns", "list"]).assert().success().stdout("default\norders\n");
+    client_cmd(&["get", "key1", "--ns", "missing"]).assert().failure();
+
+    let client = Client::new("127.0.0.1:4016".parse().unwrap()).with_namespace(Some("orders".to_owned()));
+    assert!(matches!(client.remove("key2"), Err(ClientError::KeyNotFound(_))));
+    client.remove("key1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), None);
+    client.drop_namespace("orders").unwrap();
+    assert!(matches!(client.get("key1"), Err(ClientError::NamespaceNotFound(_))));
+    assert!(matches!(client.drop_namespace("orders"), Err(ClientError::NamespaceNotFound(_))));
+    client_cmd(&["get", "key1"]).assert().success().stdout("value1\n");
+}
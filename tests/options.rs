@@ -0,0 +1,146 @@
+use clap::Parser;
+use kvs::kv::command::KVArgs;
+use kvs::kv::config::Config;
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::{Durability, KVError};
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn file_size_bounds_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().file_size(1000).merge_size(1_000_000);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "x".repeat(50))?;
+    }
+
+    let sizes: Vec<u64> = fs::read_dir(temp_dir.path().join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .collect();
+    assert!(sizes.len() > 5);
+    assert!(sizes.iter().all(|size| *size <= 1000), "{:?}", sizes);
+    assert!(!store.is_compacting());
+    Ok(())
+}
+
+#[test]
+fn invalid_options_are_rejected() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let invalid = [
+        KvStoreOptions::new().file_size(0),
+        KvStoreOptions::new().merge_size(0),
+        KvStoreOptions::new().bloom_false_positive_rate(1.5),
+        KvStoreOptions::new().compaction_rate(0),
+        KvStoreOptions::new().read_only(true).error_if_exists(true),
+    ];
+    for options in invalid {
+        assert!(matches!(options.validate(), Err(KVError::ConfigError(_))));
+        assert!(matches!(
+            KvStore::open_with(temp_dir.path(), options),
+            Err(KVError::ConfigError(_))
+        ));
+    }
+    // nothing was created by the rejected opens
+    assert!(!temp_dir.path().join("data").exists());
+}
+
+#[test]
+fn existence_checks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = KvStoreOptions::new().create_if_missing(false);
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), missing.clone()),
+        Err(KVError::ConfigError("store does not exist"))
+    ));
+
+    let exclusive = KvStoreOptions::new().error_if_exists(true);
+    KvStore::open_with(temp_dir.path(), exclusive.clone())?;
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), exclusive),
+        Err(KVError::ConfigError("store already exists"))
+    ));
+    KvStore::open_with(temp_dir.path(), missing)?;
+    Ok(())
+}
+
+#[test]
+fn read_only_store_rejects_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions::new().read_only(true);
+    assert!(KvStore::open_with(temp_dir.path(), read_only.clone()).is_err());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = |dir: &TempDir| fs::read_dir(dir.path().join("data")).unwrap().count();
+    let before = files(&temp_dir);
+
+    let mut store = KvStore::open_with(temp_dir.path(), read_only)?;
+    assert!(store.is_read_only());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(KVError::Unsupported(_))
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KVError::Unsupported(_))
+    ));
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(files(&temp_dir), before);
+    Ok(())
+}
+
+#[test]
+fn sync_durability_writes_through() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(Durability::Sync);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn flags_override_environment_override_config() {
+    let config: Config =
+        serde_json::from_str(r#"{"file_size":1000,"merge_size":2000,"cache_size":3000}"#).unwrap();
+    std::env::set_var("KVS_MERGE_SIZE", "5000");
+    std::env::set_var("KVS_CACHE_SIZE", "6000");
+    let args = KVArgs::try_parse_from(["kvs", "get", "key", "--cache-size", "7000"]).unwrap();
+    std::env::remove_var("KVS_MERGE_SIZE");
+    std::env::remove_var("KVS_CACHE_SIZE");
+
+    let expected = KvStoreOptions::new()
+        .file_size(1000)
+        .merge_size(5000)
+        .cache_size(7000);
+    assert_eq!(args.store.options(&config), expected);
+    assert_eq!(
+        KVArgs::try_parse_from(["kvs", "get", "key"]).unwrap().store.options(&Config::default()),
+        KvStoreOptions::default()
+    );
+}
+
+#[test]
+fn flags_turn_off_what_the_config_turns_on() {
+    let config: Config =
+        serde_json::from_str(r#"{"read_only":true,"create_if_missing":false,"error_if_exists":true}"#).unwrap();
+    let args = KVArgs::try_parse_from(["kvs", "get", "key", "--read-only=false", "--no-create=false", "--error-if-exists=false"])
+        .unwrap();
+    assert_eq!(args.store.options(&config), KvStoreOptions::default());
+
+    let args = KVArgs::try_parse_from(["kvs", "get", "key", "--read-only", "--no-create"]).unwrap();
+    assert_eq!(args.store.options(&Config::default()), KvStoreOptions::new().read_only(true).create_if_missing(false));
+    assert!(KVArgs::try_parse_from(["kvs", "get", "key", "--read-only=maybe"]).is_err());
+}
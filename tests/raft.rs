@@ -0,0 +1,407 @@
+use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientError};
+use kvs::kv::Operation;
+use kvs::raft::storage::{FileStorage, MemoryStorage, RaftStorage};
+use kvs::raft::transport::MemoryNetwork;
+use kvs::raft::{apply, Entry, Envelope, Message, NodeId, RaftNode, Snapshot};
+use kvs::server::command::AdminCommand;
+use kvs::{KvStore, KvsEngine};
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A whole cluster driven tick by tick in one thread over an in-process network.
+struct Cluster {
+    ids: Vec<NodeId>,
+    nodes: Vec<RaftNode>,
+    engines: Vec<KvStore>,
+    network: MemoryNetwork,
+    // entries of every snapshot chunk delivered
+    snapshot_chunks: Vec<usize>,
+    _dirs: Vec<TempDir>,
+}
+
+impl Cluster {
+    fn new(size: u64) -> Cluster {
+        let ids: Vec<NodeId> = (0..size).collect();
+        let dirs: Vec<TempDir> = ids.iter().map(|_| TempDir::new().unwrap()).collect();
+        let nodes = ids
+            .iter()
+            .map(|id| RaftNode::new(*id, ids.clone(), Box::new(MemoryStorage::new()), *id).unwrap())
+            .collect();
+        let engines = dirs.iter().map(|dir| KvStore::open(dir.path()).unwrap()).collect();
+        Cluster {
+            ids,
+            nodes,
+            engines,
+            network: MemoryNetwork::new(),
+            snapshot_chunks: Vec::new(),
+            _dirs: dirs,
+        }
+    }
+
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.iter_mut() {
+                node.tick().unwrap();
+                self.network.send_all(node.take_messages());
+            }
+            while let Some(envelope) = self.network.receive() {
+                if let Message::InstallSnapshot { entries, .. } = &envelope.message {
+                    self.snapshot_chunks.push(entries.len());
+                }
+                let node = &mut self.nodes[envelope.to as usize];
+                node.step(envelope).unwrap();
+                self.network.send_all(node.take_messages());
+            }
+            for (node, engine) in self.nodes.iter_mut().zip(self.engines.iter_mut()) {
+                for applied in node.take_committed() {
+                    let _ = apply(engine, applied);
+                }
+            }
+        }
+    }
+
+    // The leader of the highest term, there can be stale leaders in a minority partition.
+    fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|node| node.is_leader())
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    fn propose(&mut self, id: NodeId, command: Operation) -> Option<(u64, u64)> {
+        let node = &mut self.nodes[id as usize];
+        let proposed = node.propose(command).unwrap();
+        self.network.send_all(node.take_messages());
+        proposed
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let leader = self.leader().expect("no leader");
+        self.propose(leader, Operation::Set(key.to_owned(), value.to_owned()))
+            .expect("leader rejected proposal");
+    }
+
+    fn get(&mut self, id: NodeId, key: &str) -> Option<String> {
+        self.engines[id as usize].get(key.to_owned()).unwrap()
+    }
+}
+
+#[test]
+fn elects_single_leader() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(100);
+    let leader = cluster.leader().expect("no leader elected");
+    let term = cluster.nodes[leader as usize].term();
+    let leaders = cluster
+        .nodes
+        .iter()
+        .filter(|node| node.is_leader() && node.term() == term)
+        .count();
+    assert_eq!(leaders, 1);
+    for node in cluster.nodes.iter() {
+        assert_eq!(node.leader(), Some(leader));
+    }
+}
+
+#[test]
+fn replicates_committed_writes() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(100);
+    cluster.set("key1", "value1");
+    cluster.set("key2", "value2");
+    cluster.run(10);
+    for id in cluster.ids.clone() {
+        assert_eq!(cluster.get(id, "key1"), Some("value1".to_owned()));
+        assert_eq!(cluster.get(id, "key2"), Some("value2".to_owned()));
+        assert_eq!(cluster.nodes[id as usize].last_applied(), cluster.nodes[id as usize].commit_index());
+    }
+}
+
+#[test]
+fn minority_leader_cannot_commit() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(100);
+    let old_leader = cluster.leader().unwrap();
+    let old_term = cluster.nodes[old_leader as usize].term();
+    cluster.set("key1", "value1");
+    cluster.run(10);
+
+    // old leader with one follower on one side, the other three elect a new leader
+    let minority: Vec<NodeId> = vec![old_leader, (old_leader + 1) % 5];
+    let majority: Vec<NodeId> = cluster.ids.iter().copied().filter(|id| !minority.contains(id)).collect();
+    cluster.network.partition(&minority, &majority);
+    let (stale_index, _) = cluster
+        .propose(old_leader, Operation::Set("key1".to_owned(), "lost".to_owned()))
+        .unwrap();
+    cluster.run(100);
+
+    let new_leader = cluster.leader().unwrap();
+    assert!(majority.contains(&new_leader));
+    assert!(cluster.nodes[new_leader as usize].term() > old_term);
+    assert!(cluster.nodes[old_leader as usize].commit_index() < stale_index);
+    cluster.set("key1", "value2");
+    cluster.run(10);
+
+    cluster.network.heal();
+    cluster.run(100);
+    assert!(!cluster.nodes[old_leader as usize].is_leader() || cluster.leader() == Some(old_leader));
+    for id in cluster.ids.clone() {
+        assert_eq!(cluster.get(id, "key1"), Some("value2".to_owned()));
+    }
+}
+
+#[test]
+fn lagging_follower_catches_up_from_snapshot() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(100);
+    let leader = cluster.leader().unwrap();
+    let lagging = (leader + 1) % 3;
+    cluster.network.isolate(lagging, &cluster.ids.clone());
+
+    for key_id in 0..50 {
+        cluster.set(&format!("key{}", key_id), &format!("value{}", key_id));
+        cluster.run(1);
+    }
+    cluster.run(10);
+    let entries = cluster.engines[leader as usize].entries().unwrap();
+    cluster.nodes[leader as usize].compact(entries).unwrap();
+    assert!(cluster.nodes[leader as usize].snapshot_index() > cluster.nodes[lagging as usize].last_index());
+
+    cluster.network.heal();
+    cluster.run(50);
+    for key_id in 0..50 {
+        assert_eq!(cluster.get(lagging, &format!("key{}", key_id)), Some(format!("value{}", key_id)));
+    }
+    let leader = cluster.leader().unwrap();
+    assert_eq!(
+        cluster.nodes[lagging as usize].commit_index(),
+        cluster.nodes[leader as usize].commit_index()
+    );
+}
+
+// a snapshot larger than a chunk reaches the follower a chunk at a time
+#[test]
+fn snapshot_is_sent_in_chunks() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(100);
+    let leader = cluster.leader().unwrap();
+    let lagging = (leader + 1) % 3;
+    cluster.network.isolate(lagging, &cluster.ids.clone());
+
+    let value = "v".repeat(100_000);
+    for key_id in 0..40 {
+        cluster.set(&format!("key{}", key_id), &value);
+        cluster.run(1);
+    }
+    cluster.run(10);
+    let entries = cluster.engines[leader as usize].entries().unwrap();
+    cluster.nodes[leader as usize].compact(entries).unwrap();
+
+    cluster.network.heal();
+    cluster.run(100);
+    for key_id in 0..40 {
+        assert_eq!(cluster.get(lagging, &format!("key{}", key_id)), Some(value.clone()));
+    }
+    assert!(cluster.snapshot_chunks.len() >= 4, "{:?}", cluster.snapshot_chunks);
+    assert!(cluster.snapshot_chunks.iter().all(|entries| *entries <= 11), "{:?}", cluster.snapshot_chunks);
+}
+
+#[test]
+fn file_storage_survives_restart() {
+    let dir = TempDir::new().unwrap();
+    let mut node = RaftNode::new(0, vec![0], Box::new(FileStorage::open(dir.path().to_owned()).unwrap()), 0).unwrap();
+    for _ in 0..100 {
+        node.tick().unwrap();
+    }
+    assert!(node.is_leader());
+    node.propose(Operation::Set("key1".to_owned(), "value1".to_owned())).unwrap();
+    node.propose(Operation::Set("key2".to_owned(), "value2".to_owned())).unwrap();
+    let applied = node.take_committed();
+    assert_eq!(applied.len(), 3);
+    node.compact(vec![("key1".to_owned(), "value1".to_owned())]).unwrap();
+    node.propose(Operation::Remove("key1".to_owned())).unwrap();
+    let term = node.term();
+    let last_index = node.last_index();
+    drop(node);
+
+    let mut storage = FileStorage::open(dir.path().to_owned()).unwrap();
+    let state = storage.load().unwrap();
+    assert_eq!(state.hard_state.term, term);
+    assert_eq!(state.hard_state.voted_for, Some(0));
+    assert_eq!(state.snapshot.last_index, 3);
+    assert_eq!(state.entries.len(), 1);
+    assert_eq!(state.entries[0].command, Some(Operation::Remove("key1".to_owned())));
+
+    let node = RaftNode::new(0, vec![0], Box::new(storage), 0).unwrap();
+    assert_eq!(node.last_index(), last_index);
+    assert_eq!(node.term(), term);
+}
+
+// the entries of a snapshot are written and read as a stream on disk, from any offset in it
+#[test]
+fn file_storage_keeps_snapshot_entries_on_disk() {
+    let dir = TempDir::new().unwrap();
+    let mut storage = FileStorage::open(dir.path().to_owned()).unwrap();
+    storage.append(1, &[Entry { term: 1, command: None }, Entry { term: 1, command: None }]).unwrap();
+    let mut writer = storage.snapshot_writer(2).unwrap();
+    let entries: Vec<_> = (0..100).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))).collect();
+    for entry in entries.iter() {
+        serde_json::to_writer(&mut writer, entry).unwrap();
+    }
+    writer.flush().unwrap();
+    drop(writer);
+    // the log entries stay until the snapshot is saved
+    assert_eq!(storage.load().unwrap().entries.len(), 2);
+    storage.save_snapshot(&Snapshot { last_index: 2, last_term: 1 }).unwrap();
+    drop(storage);
+
+    let mut storage = FileStorage::open(dir.path().to_owned()).unwrap();
+    let state = storage.load().unwrap();
+    assert_eq!(state.snapshot, Snapshot { last_index: 2, last_term: 1 });
+    assert!(state.entries.is_empty());
+    let read = |offset: u64| -> Vec<(String, String)> {
+        serde_json::Deserializer::from_reader(storage.read_snapshot(offset).unwrap())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .collect()
+    };
+    assert_eq!(read(0), entries);
+    let second = serde_json::to_vec(&entries[0]).unwrap().len() as u64;
+    assert_eq!(read(second), entries[1..]);
+
+    // a snapshot saved with its entries inline is moved out of the metadata
+    fs::write(
+        dir.path().join("snapshot"),
+        r#"{"last_index":5,"last_term":2,"entries":[["key1","value1"]]}"#,
+    )
+    .unwrap();
+    let storage = FileStorage::open(dir.path().to_owned()).unwrap();
+    let entries: Vec<(String, String)> = serde_json::Deserializer::from_reader(storage.read_snapshot(0).unwrap())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(entries, vec![("key1".to_owned(), "value1".to_owned())]);
+    assert!(!dir.path().join("snapshot.2").exists());
+}
+
+// the entries of a snapshot are read from the log as it was, while writes and compactions go on
+#[test]
+fn snapshot_entries_read_apart_from_writes() -> kvs::Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let entries = store.snapshot_entries()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "later".to_owned())?;
+    }
+    store.compact()?;
+
+    let mut entries = thread::spawn(move || entries.collect::<kvs::Result<Vec<_>>>()).join().unwrap()?;
+    entries.sort();
+    let mut expected: Vec<_> = (0..100).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))).collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+    Ok(())
+}
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn server_cluster_follows_leader() {
+    let addrs = ["127.0.0.1:4010", "127.0.0.1:4011", "127.0.0.1:4012"];
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Option<ServerGuard>> = addrs
+        .iter()
+        .zip(dirs.iter())
+        .map(|(addr, dir)| {
+            let peers: Vec<&str> = addrs.iter().copied().filter(|peer| peer != addr).collect();
+            let child = Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", "kvs", "--addr", addr, "--raft-peers", &peers.join(",")])
+                .current_dir(dir)
+                .spawn()
+                .unwrap();
+            Some(ServerGuard(child))
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(2));
+
+    let leader_of = |addr: &str| {
+        Client::new(addr.parse().unwrap())
+            .stats()
+            .unwrap()
+            .raft
+            .expect("server is not a cluster member")
+            .leader
+    };
+    let leader: SocketAddr = leader_of(addrs[0]).expect("no leader elected");
+    for addr in addrs {
+        assert_eq!(leader_of(addr), Some(leader));
+    }
+
+    // talk to a follower, requests end up at the leader
+    let follower = addrs.iter().find(|addr| addr.parse::<SocketAddr>().unwrap() != leader).unwrap();
+    let client = Client::new(follower.parse().unwrap());
+    client.set("key1", "value1").unwrap();
+    client.set("key2", "value2").unwrap();
+    client.remove("key2").unwrap();
+    assert!(matches!(client.remove("key2"), Err(ClientError::KeyNotFound(_))));
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+
+    // losing the leader elects a new one that still has every committed write
+    let leader_pos = addrs.iter().position(|addr| addr.parse::<SocketAddr>().unwrap() == leader).unwrap();
+    servers[leader_pos].take();
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(client.get("key2").unwrap(), None);
+    client.set("key3", "value3").unwrap();
+    let new_leader = leader_of(follower).unwrap();
+    assert_ne!(new_leader, leader);
+    assert_eq!(client.get("key3").unwrap(), Some("value3".to_owned()));
+}
+
+#[test]
+fn server_keeps_reading_a_peer_connection() {
+    // 4031 is node 0 and the unreachable 4032 node 1
+    let dir = TempDir::new().unwrap();
+    let _server = ServerGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4031", "--raft-peers", "127.0.0.1:4032"])
+            .current_dir(&dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let vote = |term: u64| {
+        let message = Message::RequestVote { term, last_log_index: 0, last_log_term: 0 };
+        serde_json::to_vec(&AdminCommand::Raft(Envelope { from: 1, to: 0, message })).unwrap()
+    };
+    let mut connection = TcpStream::connect("127.0.0.1:4031").unwrap();
+    connection.write_all(&vote(100)).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    // later messages on the same connection still reach the node
+    connection.write_all(&[vote(1000), vote(2000)].concat()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let raft = Client::new("127.0.0.1:4031".parse().unwrap()).stats().unwrap().raft.unwrap();
+    assert!(raft.term >= 2000, "term {}", raft.term);
+}
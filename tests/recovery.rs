@@ -0,0 +1,277 @@
+use assert_cmd::prelude::*;
+use kvs::kv::history::VersionPolicy;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::recovery::{recover, Cutoff};
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+fn copy_store(from: &Path, to: &Path) {
+    fs::create_dir_all(to.join("data")).unwrap();
+    for entry in fs::read_dir(from.join("data")).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), to.join("data").join(entry.file_name())).unwrap();
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[test]
+fn cutoff_parsing() {
+    assert_eq!("seq:42".parse(), Ok(Cutoff::Seq(42)));
+    assert_eq!("1700000000000".parse(), Ok(Cutoff::Timestamp(1700000000000)));
+    assert!("seq:x".parse::<Cutoff>().is_err());
+    assert!("yesterday".parse::<Cutoff>().is_err());
+}
+
+#[test]
+fn recover_until_seq_and_timestamp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("good{}", key_id))?;
+    }
+    store.remove("key9".to_owned())?;
+    thread::sleep(Duration::from_millis(5));
+    let before_deploy = now_millis();
+    thread::sleep(Duration::from_millis(5));
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "garbage".to_owned())?;
+    }
+    drop(store);
+
+    // ten sets and a remove are seqs 1 to 11
+    let by_seq = TempDir::new().unwrap();
+    let report = recover(&[temp_dir.path().to_owned()], Cutoff::Seq(11), by_seq.path().to_owned())?;
+    assert_eq!(report.replayed, 11);
+    assert_eq!(report.skipped, 10);
+    assert_eq!(report.last_seq, Some(11));
+
+    let by_time = TempDir::new().unwrap();
+    recover(&[temp_dir.path().to_owned()], Cutoff::Timestamp(before_deploy), by_time.path().to_owned())?;
+
+    for dir in [&by_seq, &by_time] {
+        let mut recovered = KvStore::open(dir.path())?;
+        for key_id in 0..9 {
+            assert_eq!(recovered.get(format!("key{}", key_id))?, Some(format!("good{}", key_id)));
+        }
+        assert_eq!(recovered.get("key9".to_owned())?, None);
+        // new writes continue after the replayed seqs
+        recovered.set("key10".to_owned(), "value10".to_owned())?;
+    }
+
+    // never replays into an existing store
+    assert!(matches!(
+        recover(&[temp_dir.path().to_owned()], Cutoff::Seq(11), by_seq.path().to_owned()),
+        Err(KVError::ConfigError(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn backup_covers_merged_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("good{}", key_id))?;
+    }
+    drop(store);
+    copy_store(temp_dir.path(), backup_dir.path());
+
+    // enough garbage to merge away every good record
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("garbage{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let without_backup = TempDir::new().unwrap();
+    let report = recover(&[temp_dir.path().to_owned()], Cutoff::Seq(20), without_backup.path().to_owned())?;
+    assert_eq!(report.replayed, 0);
+
+    let with_backup = TempDir::new().unwrap();
+    let sources = [backup_dir.path().to_owned(), temp_dir.path().to_owned()];
+    let report = recover(&sources, Cutoff::Seq(20), with_backup.path().to_owned())?;
+    assert_eq!(report.replayed, 20);
+    let mut recovered = KvStore::open(with_backup.path())?;
+    for key_id in 0..20 {
+        assert_eq!(recovered.get(format!("key{}", key_id))?, Some(format!("good{}", key_id)));
+    }
+    Ok(())
+}
+
+// set, backup, remove, compact: the replay must not bring the removed key back
+#[test]
+fn removes_compacted_after_a_backup() -> Result<()> {
+    // without a retention the remove is dropped and the replay refused, with one it is kept
+    for (options, kept) in [(KvStoreOptions::new(), false), (KvStoreOptions::new().remove_retention(3600), true)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        copy_store(temp_dir.path(), backup_dir.path());
+
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.remove("key1".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let target = TempDir::new().unwrap();
+        let sources = [backup_dir.path().to_owned(), temp_dir.path().to_owned()];
+        let recovered = recover(&sources, Cutoff::Seq(u64::MAX), target.path().to_owned());
+        if !kept {
+            assert!(matches!(recovered, Err(KVError::ConfigError(_))), "{:?}", recovered);
+            continue;
+        }
+        assert_eq!(recovered?.last_seq, Some(3));
+        let mut recovered = KvStore::open(target.path())?;
+        assert_eq!(recovered.get("key1".to_owned())?, None);
+        assert_eq!(recovered.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// a kept remove is written again after what compaction copied, the log goes back in seqs there
+#[test]
+fn recover_merges_kept_removes_in_seq_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().remove_retention(3600))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    // the first set of key1 went with the compaction
+    for (until, key1, replayed) in [(3, None, 2), (4, None, 3), (5, Some("value5".to_owned()), 4)] {
+        let target = TempDir::new().unwrap();
+        let report = recover(&[temp_dir.path().to_owned()], Cutoff::Seq(until), target.path().to_owned())?;
+        assert_eq!((report.replayed, report.last_seq), (replayed, Some(until)));
+        let mut recovered = KvStore::open(target.path())?;
+        assert_eq!(recovered.get("key1".to_owned())?, key1);
+        assert_eq!(recovered.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(recovered.get("key3".to_owned())?, (until >= 4).then(|| "value3".to_owned()));
+    }
+    Ok(())
+}
+
+#[test]
+fn recover_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let options = NamespaceOptions { merge_threshold: Some(4096) };
+    store.create_namespace("other", options.clone())?;
+    store.namespace("other")?.set("key1".to_owned(), "other1".to_owned())?;
+    thread::sleep(Duration::from_millis(5));
+    let before = now_millis();
+    thread::sleep(Duration::from_millis(5));
+    store.namespace("other")?.set("key1".to_owned(), "later".to_owned())?;
+    drop(store);
+
+    // namespace seqs start over, a seq cannot cut them with the rest
+    let target = TempDir::new().unwrap();
+    assert!(matches!(
+        recover(&[temp_dir.path().to_owned()], Cutoff::Seq(1), target.path().to_owned()),
+        Err(KVError::ConfigError(_))
+    ));
+
+    let report = recover(&[temp_dir.path().to_owned()], Cutoff::Timestamp(before), target.path().to_owned())?;
+    assert_eq!((report.replayed, report.skipped), (2, 1));
+    let mut recovered = KvStore::open(target.path())?;
+    assert_eq!(recovered.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(recovered.namespace("other")?.get("key1".to_owned())?, Some("other1".to_owned()));
+    assert_eq!(recovered.list_namespaces()?, vec!["default".to_owned(), "other".to_owned()]);
+    Ok(())
+}
+
+// the recovered store keeps versions and secondary indexes up to the cutoff
+#[test]
+fn recover_versioned_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.enable_versioning(VersionPolicy {
+        max_versions: Some(3),
+        retention_secs: None,
+    })?;
+    store.create_index("by_user", "/user")?;
+    for value_id in 0..5 {
+        store.set("key1".to_owned(), format!(r#"{{"user":"user{}"}}"#, value_id))?;
+        store.set("key2".to_owned(), format!(r#"{{"user":"user{}"}}"#, value_id + 1))?;
+    }
+    store.remove("key2".to_owned())?;
+    let history = store.history("key1".to_owned())?;
+    store.set("key1".to_owned(), r#"{"user":"later"}"#.to_owned())?;
+    drop(store);
+
+    let target = TempDir::new().unwrap();
+    recover(&[temp_dir.path().to_owned()], Cutoff::Seq(11), target.path().to_owned())?;
+    for _ in 0..2 {
+        let mut recovered = KvStore::open(target.path())?;
+        assert_eq!(recovered.history("key1".to_owned())?, history);
+        let values: Vec<_> = recovered.history("key2".to_owned())?.into_iter().map(|version| version.value).collect();
+        assert_eq!(values, vec![Some(r#"{"user":"user4"}"#.to_owned()), Some(r#"{"user":"user5"}"#.to_owned()), None]);
+        assert_eq!(recovered.query_index("by_user", "user4")?, vec!["key1".to_owned()]);
+        assert_eq!(recovered.query_index("by_user", "user5")?, Vec::<String>::new());
+        assert_eq!(recovered.query_index("by_user", "later")?, Vec::<String>::new());
+    }
+    Ok(())
+}
+
+#[test]
+fn recover_detects_corrupt_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("data").join("0");
+    let contents = fs::read_to_string(&segment).unwrap();
+    fs::write(&segment, contents.replace("value1", "value2")).unwrap();
+
+    let target = TempDir::new().unwrap();
+    assert!(recover(&[temp_dir.path().to_owned()], Cutoff::Seq(1), target.path().to_owned()).is_err());
+    Ok(())
+}
+
+#[test]
+fn cli_recover() {
+    let temp_dir = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key1", "garbage")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["recover", "--until", "seq:2", "--to"])
+        .arg(target.path().join("store"))
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("replayed 2 records, skipped 1"));
+
+    let mut recovered = KvStore::open(target.path().join("store")).unwrap();
+    assert_eq!(recovered.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    assert_eq!(recovered.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+}
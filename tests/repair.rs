@@ -0,0 +1,156 @@
+use assert_cmd::prelude::*;
+use kvs::kv::repair::LOST_FOUND_FILE;
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result, EXIT_DATA};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+// the segment holding `needle`
+fn segment_with(dir: &Path, needle: &str) -> PathBuf {
+    fs::read_dir(dir.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| fs::read_to_string(path).unwrap().contains(needle))
+        .expect("no segment holds the needle")
+}
+
+fn three_keys(dir: &Path) -> Result<()> {
+    let mut store = KvStore::open(dir)?;
+    for key_id in 1..=3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn truncated_tail_is_moved_to_lost_found() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    three_keys(temp_dir.path())?;
+    let segment = segment_with(temp_dir.path(), "value3");
+    let tail = r#"{"seq":4,"timestamp":1,"crc":1,"op":{"Set":["key4","val"#;
+    OpenOptions::new().append(true).open(&segment).unwrap().write_all(tail.as_bytes()).unwrap();
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.lost.len(), 1);
+    assert_eq!(report.lost[0].reason, "truncated record");
+    assert_eq!(report.lost[0].length, tail.len() as u64);
+    assert_eq!(report.rewritten, 1);
+    assert_eq!(report.keys, 3);
+
+    let lost_found = fs::read_to_string(temp_dir.path().join(LOST_FOUND_FILE)).unwrap();
+    assert!(lost_found.contains("truncated record"), "{}", lost_found);
+    assert!(lost_found.contains(tail), "{}", lost_found);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 1..=3 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn damaged_records_in_the_middle_lose_only_themselves() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    three_keys(temp_dir.path())?;
+    let segment = segment_with(temp_dir.path(), "value2");
+    // the record of key2 no longer matches its crc, garbage follows it
+    let contents = fs::read_to_string(&segment).unwrap();
+    let damaged = contents.replace("value2", "valueX").replace(
+        r#"{"seq":3"#,
+        r#"@@garbage@@{"seq":3"#,
+    );
+    fs::write(&segment, damaged).unwrap();
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.lost.len(), 1, "{}", report);
+    assert_eq!(report.lost[0].reason, "checksum mismatch");
+    assert_eq!(report.keys, 2);
+    let lost_found = fs::read_to_string(temp_dir.path().join(LOST_FOUND_FILE)).unwrap();
+    assert!(lost_found.contains("valueX") && lost_found.contains("@@garbage@@"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // a second repair finds nothing left to do
+    let report = KvStore::repair(temp_dir.path())?;
+    assert!(report.lost.is_empty());
+    assert_eq!(report.rewritten, 0);
+    Ok(())
+}
+
+#[test]
+fn remove_of_an_unseen_key_opens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir_all(temp_dir.path().join("data")).unwrap();
+    fs::write(
+        temp_dir.path().join("data").join("0"),
+        r#"{"Remove":"key9"}{"Set":["key1","value1"]}"#,
+    )
+    .unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn get_in_the_log_is_an_error_repair_removes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir_all(temp_dir.path().join("data")).unwrap();
+    fs::write(
+        temp_dir.path().join("data").join("0"),
+        r#"{"Set":["key1","value1"]}{"Get":"key1"}"#,
+    )
+    .unwrap();
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KVError::ReadError(_))));
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.lost[0].reason, "a get in the log");
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn repair_needs_a_store() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(KvStore::repair(temp_dir.path()), Err(KVError::ConfigError(_))));
+}
+
+#[test]
+fn cli_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    three_keys(temp_dir.path())?;
+    let segment = segment_with(temp_dir.path(), "value3");
+    OpenOptions::new().append(true).open(&segment).unwrap().write_all(b"{\"seq\":4,").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(EXIT_DATA as i32)
+        .stderr(predicates::str::contains("kvs repair"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("truncated record"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientError};
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::replication::{LogPosition, ReplicationBatch};
+use kvs::{KvStore, KvsEngine};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(dir: &TempDir, args: &[&str]) -> ServerGuard {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    ServerGuard(child)
+}
+
+// Polls the follower until a sync started after this call left it with no lag.
+fn wait_caught_up(follower: &Client) {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        let stats = follower.stats().unwrap();
+        let replication = stats.replication.expect("server is not a replica");
+        let synced_since_start = replication
+            .millis_since_sync
+            .is_some_and(|millis| millis < start.elapsed().as_millis());
+        if synced_since_start && replication.lag_bytes == Some(0) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("follower did not catch up");
+}
+
+fn replicate_to(follower_engine: &str, leader_addr: &str, follower_addr: &str) {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let _leader_server = spawn_server(&leader_dir, &["--engine", "kvs", "--addr", leader_addr]);
+    let leader = Client::new(leader_addr.parse().unwrap());
+    leader.set("key1", "value1").unwrap();
+    leader.set("key2", "value2").unwrap();
+
+    // bootstrap from a snapshot
+    let _follower_server = spawn_server(
+        &follower_dir,
+        &["--engine", follower_engine, "--addr", follower_addr, "--replica-of", leader_addr],
+    );
+    let follower = Client::new(follower_addr.parse().unwrap());
+    wait_caught_up(&follower);
+    assert_eq!(follower.get("key1").unwrap(), Some("value1".to_owned()));
+    assert_eq!(follower.get("key2").unwrap(), Some("value2".to_owned()));
+
+    // tail new records
+    leader.set("key1", "value3").unwrap();
+    leader.remove("key2").unwrap();
+    leader.set("key3", "value4").unwrap();
+    wait_caught_up(&follower);
+    assert_eq!(follower.get("key1").unwrap(), Some("value3".to_owned()));
+    assert_eq!(follower.get("key2").unwrap(), None);
+    assert_eq!(follower.get("key3").unwrap(), Some("value4".to_owned()));
+
+    // writes are redirected to the leader
+    let leader_sock: SocketAddr = leader_addr.parse().unwrap();
+    assert!(matches!(follower.set("key4", "value5"), Err(ClientError::Redirect(addr)) if addr == leader_sock));
+    assert!(matches!(follower.remove("key1"), Err(ClientError::Redirect(addr)) if addr == leader_sock));
+    assert_eq!(follower.get("key4").unwrap(), None);
+
+    // compaction on the leader forces a new snapshot
+    let padding = "x".repeat(200);
+    for iter in 0..100 {
+        leader.set(&format!("key{}", iter % 10), &format!("{}{}", iter, padding)).unwrap();
+    }
+    wait_caught_up(&follower);
+    for key_id in 0..10 {
+        assert_eq!(
+            follower.get(&format!("key{}", key_id)).unwrap(),
+            Some(format!("{}{}", 90 + key_id, padding))
+        );
+    }
+    let replication = follower.stats().unwrap().replication.unwrap();
+    assert_eq!(replication.leader, leader_sock);
+    assert!(replication.snapshots >= 2);
+    assert!(leader.stats().unwrap().replication.is_none());
+}
+
+#[test]
+fn replicate_kvs_to_kvs() {
+    replicate_to("kvs", "127.0.0.1:4006", "127.0.0.1:4007");
+}
+
+#[test]
+fn replicate_kvs_to_sled() {
+    replicate_to("sled", "127.0.0.1:4008", "127.0.0.1:4009");
+}
+
+// a snapshot larger than a page is copied a page at a time, and a restarted follower goes on from where it was
+#[test]
+fn follower_pages_the_snapshot_and_resumes_after_a_restart() {
+    let (leader_addr, follower_addr) = ("127.0.0.1:4027", "127.0.0.1:4028");
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let _leader_server = spawn_server(&leader_dir, &["--engine", "kvs", "--addr", leader_addr]);
+    let leader = Client::new(leader_addr.parse().unwrap());
+    for key_id in 0..2500 {
+        leader.set(&format!("key{}", key_id), &format!("value{}", key_id)).unwrap();
+    }
+
+    let follower_args = ["--engine", "kvs", "--addr", follower_addr, "--replica-of", leader_addr];
+    let follower_server = spawn_server(&follower_dir, &follower_args);
+    let follower = Client::new(follower_addr.parse().unwrap());
+    wait_caught_up(&follower);
+    for key_id in (0..2500).step_by(50).chain([999, 1000, 2499]) {
+        assert_eq!(follower.get(&format!("key{}", key_id)).unwrap(), Some(format!("value{}", key_id)));
+    }
+    assert_eq!(follower.stats().unwrap().replication.unwrap().snapshots, 1);
+
+    drop(follower_server);
+    leader.set("key1", "value2").unwrap();
+    leader.remove("key2").unwrap();
+    let _follower_server = spawn_server(&follower_dir, &follower_args);
+    wait_caught_up(&follower);
+    assert_eq!(follower.get("key1").unwrap(), Some("value2".to_owned()));
+    assert_eq!(follower.get("key2").unwrap(), None);
+    assert_eq!(follower.get("key2499").unwrap(), Some("value2499".to_owned()));
+    assert_eq!(follower.stats().unwrap().replication.unwrap().snapshots, 0);
+}
+
+// namespaces, their keys and secondary indexes are set up on the follower as they are on the leader
+#[test]
+fn namespaces_and_indexes_are_replicated() {
+    let (leader_addr, follower_addr) = ("127.0.0.1:4029", "127.0.0.1:4030");
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let _leader_server = spawn_server(&leader_dir, &["--engine", "kvs", "--addr", leader_addr]);
+    let leader = Client::new(leader_addr.parse().unwrap());
+    let namespaced = |addr: &str, ns: &str| Client::new(addr.parse().unwrap()).with_namespace(Some(ns.to_owned()));
+    leader.create_namespace("orders", NamespaceOptions::default()).unwrap();
+    let leader_orders = namespaced(leader_addr, "orders");
+    leader_orders.set("order1", r#"{"user":"alice"}"#).unwrap();
+    leader_orders.create_index("by_user", "/user").unwrap();
+    leader.set("key1", r#"{"user":"bob"}"#).unwrap();
+    leader.create_index("by_user", "/user").unwrap();
+
+    let _follower_server = spawn_server(
+        &follower_dir,
+        &["--engine", "kvs", "--addr", follower_addr, "--replica-of", leader_addr],
+    );
+    let follower = Client::new(follower_addr.parse().unwrap());
+    wait_caught_up(&follower);
+    let follower_orders = namespaced(follower_addr, "orders");
+    assert_eq!(follower.list_namespaces().unwrap(), vec!["default", "orders"]);
+    assert_eq!(follower_orders.get("order1").unwrap(), Some(r#"{"user":"alice"}"#.to_owned()));
+    assert_eq!(follower_orders.query_index("by_user", "alice").unwrap(), vec!["order1"]);
+    assert_eq!(follower.query_index("by_user", "bob").unwrap(), vec!["key1"]);
+
+    // writes to a namespace follow, so do namespaces and indexes going away
+    leader_orders.set("order2", r#"{"user":"alice"}"#).unwrap();
+    leader.create_namespace("carts", NamespaceOptions::default()).unwrap();
+    namespaced(leader_addr, "carts").set("cart1", "value1").unwrap();
+    leader.drop_index("by_user").unwrap();
+    wait_caught_up(&follower);
+    assert_eq!(follower_orders.query_index("by_user", "alice").unwrap(), vec!["order1", "order2"]);
+    assert_eq!(namespaced(follower_addr, "carts").get("cart1").unwrap(), Some("value1".to_owned()));
+    assert!(matches!(follower.query_index("by_user", "bob"), Err(ClientError::IndexNotFound(_))));
+
+    leader.drop_namespace("orders").unwrap();
+    wait_caught_up(&follower);
+    assert_eq!(follower.list_namespaces().unwrap(), vec!["default", "carts"]);
+    assert!(matches!(follower_orders.get("order1"), Err(ClientError::NamespaceNotFound(_))));
+}
+
+#[test]
+fn snapshot_pages_cover_every_key_written_before_it() -> kvs::Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path())?;
+    for key_id in 0..2500 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+
+    let mut keys = BTreeSet::new();
+    let mut cursor: Option<(LogPosition, LogPosition)> = None;
+    for page in 0.. {
+        let batch = match cursor {
+            Some((position, next)) => store.replicate(Some(position), Some(next))?,
+            None => store.replicate(None, None)?,
+        };
+        let ReplicationBatch::Snapshot { entries, position, next, first, .. } = batch else {
+            panic!("not a snapshot page");
+        };
+        assert_eq!(first, page == 0);
+        keys.extend(entries.into_iter().map(|(key, _)| key));
+        // writes between pages are past the snapshot and left to the records after it
+        store.set(format!("new{}", page), "new".to_owned())?;
+        match next {
+            Some(next) => cursor = Some((position, next)),
+            None => break,
+        }
+    }
+    let expected: BTreeSet<String> = (0..2500).map(|key_id| format!("key{}", key_id)).collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[test]
+fn replicated_schema_comes_from_the_open_namespaces() -> kvs::Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path())?;
+    let options = NamespaceOptions { merge_threshold: Some(4096) };
+    store.create_namespace("logs", options.clone())?;
+    // polls do not go back to the options file
+    std::fs::remove_file(dir.path().join("namespaces").join("logs").join("options.json")).unwrap();
+
+    let ReplicationBatch::Snapshot { schema, .. } = store.replicate(None, None)? else {
+        panic!("not a snapshot page");
+    };
+    assert_eq!(schema.namespaces, vec![("logs".to_owned(), options)]);
+    Ok(())
+}
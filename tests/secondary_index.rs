@@ -0,0 +1,114 @@
+use assert_cmd::prelude::*;
+use kvs::client::{Client, ClientError};
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn order(user_id: &str, total: u32) -> String {
+    format!(r#"{{"user_id":"{}","total":{},"item":{{"sku":"a1"}}}}"#, user_id, total)
+}
+
+#[test]
+fn index_follows_set_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("order1".to_owned(), order("alice", 10))?;
+    store.set("plain".to_owned(), "not json".to_owned())?;
+
+    // existing values are indexed on creation
+    store.create_index("by_user", "/user_id")?;
+    store.create_index("by_total", "/total")?;
+    store.create_index("by_sku", "/item/sku")?;
+    assert!(store.create_index("by_user", "/user_id").is_err());
+    assert!(store.create_index("bad", "user_id").is_err());
+    assert_eq!(store.query_index("by_user", "alice")?, vec!["order1"]);
+
+    store.set("order2".to_owned(), order("bob", 10))?;
+    store.set("order3".to_owned(), order("alice", 25))?;
+    assert_eq!(store.query_index("by_user", "alice")?, vec!["order1", "order3"]);
+    assert_eq!(store.query_index("by_total", "10")?, vec!["order1", "order2"]);
+    assert_eq!(store.query_index("by_sku", "a1")?.len(), 3);
+
+    // overwriting moves the key, removing drops it
+    store.set("order1".to_owned(), order("bob", 10))?;
+    store.remove("order2".to_owned())?;
+    assert_eq!(store.query_index("by_user", "alice")?, vec!["order3"]);
+    assert_eq!(store.query_index("by_user", "bob")?, vec!["order1"]);
+    assert_eq!(store.query_index("by_user", "carol")?, Vec::<String>::new());
+    assert!(matches!(store.query_index("missing", "alice"), Err(KVError::IndexNotFound(_))));
+
+    // definitions are persisted, entries rebuilt from the log
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_definitions().len(), 3);
+    assert_eq!(store.query_index("by_user", "alice")?, vec!["order3"]);
+    assert_eq!(store.query_index("by_user", "bob")?, vec!["order1"]);
+
+    store.drop_index("by_total")?;
+    assert!(matches!(store.query_index("by_total", "10"), Err(KVError::IndexNotFound(_))));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_definitions().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn index_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.create_index("by_user", "/user_id")?;
+    for iter in 0..200 {
+        for key_id in 0..20 {
+            let user = if key_id % 2 == 0 { "even" } else { "odd" };
+            store.set(format!("key{}", key_id), order(user, iter))?;
+        }
+    }
+    assert_eq!(store.query_index("by_user", "even")?.len(), 10);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.query_index("by_user", "odd")?.len(), 10);
+    Ok(())
+}
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn server_query_index() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4017"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let client = Client::new("127.0.0.1:4017".parse().unwrap());
+    client.create_index("by_user", "/user_id").unwrap();
+    client.set("order1", &order("alice", 10)).unwrap();
+    client.set("order2", &order("bob", 12)).unwrap();
+    assert_eq!(client.query_index("by_user", "bob").unwrap(), vec!["order2"]);
+    assert!(matches!(client.query_index("missing", "bob"), Err(ClientError::IndexNotFound(_))));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["query", "by_user", "alice", "--addr", "127.0.0.1:4017"])
+        .assert()
+        .success()
+        .stdout("order1\n");
+    client.drop_index("by_user").unwrap();
+    assert!(matches!(client.drop_index("by_user"), Err(ClientError::IndexNotFound(_))));
+}
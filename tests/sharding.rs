@@ -0,0 +1,143 @@
+use assert_cmd::prelude::*;
+use kvs::client::sharded::{HashRing, ShardedClient};
+use kvs::client::Client;
+use predicates::str::contains;
+use std::net::SocketAddr;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard {
+    child: Child,
+    _dir: TempDir,
+}
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn spawn_server(addr: &str) -> ServerGuard {
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    ServerGuard { child, _dir: temp_dir }
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn ring_moves_only_keys_of_new_node() {
+    let mut ring = HashRing::new(64);
+    ring.add(addr(1));
+    ring.add(addr(2));
+    let keys: Vec<String> = (0..2000).map(|key_id| format!("key{}", key_id)).collect();
+    let before: Vec<_> = keys.iter().map(|key| ring.node_for(key).unwrap()).collect();
+
+    ring.add(addr(3));
+    let mut moved = 0;
+    for (key, old_owner) in keys.iter().zip(before.iter()) {
+        let owner = ring.node_for(key).unwrap();
+        if owner != *old_owner {
+            assert_eq!(owner, addr(3));
+            moved += 1;
+        }
+    }
+    // roughly a third of the keys, virtual nodes keep the split even
+    assert!(moved > 400 && moved < 950, "moved {} keys", moved);
+
+    ring.remove(addr(3));
+    for (key, old_owner) in keys.iter().zip(before.iter()) {
+        assert_eq!(ring.node_for(key).unwrap(), *old_owner);
+    }
+}
+
+#[test]
+fn rebalance_between_servers() {
+    let _servers = [spawn_server("127.0.0.1:4013"), spawn_server("127.0.0.1:4014"), spawn_server("127.0.0.1:4015")];
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = ShardedClient::new(&[addr(4013), addr(4014)]);
+    for key_id in 0..100 {
+        client.set(&format!("key{}", key_id), &format!("value{}", key_id)).unwrap();
+    }
+
+    let report = client.add_node(addr(4015)).unwrap();
+    assert!(report.moved_keys > 0);
+    let on_new_node = Client::new(addr(4015));
+    let mut found = 0;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id);
+        assert_eq!(client.get(&key).unwrap(), Some(format!("value{}", key_id)));
+        if on_new_node.get(&key).unwrap().is_some() {
+            found += 1;
+            // moved keys no longer live on the old owner
+            for old in [addr(4013), addr(4014)] {
+                assert_eq!(Client::new(old).get(&key).unwrap(), None);
+            }
+        }
+    }
+    assert_eq!(found, report.moved_keys);
+
+    let report = client.remove_node(addr(4013)).unwrap();
+    assert!(report.moved_keys > 0);
+    assert_eq!(client.nodes(), vec![addr(4014), addr(4015)]);
+    for key_id in 0..100 {
+        assert_eq!(client.get(&format!("key{}", key_id)).unwrap(), Some(format!("value{}", key_id)));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key7", "--cluster", "127.0.0.1:4014,127.0.0.1:4015"])
+        .assert()
+        .success()
+        .stdout("value7\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--cluster", "127.0.0.1:4014,127.0.0.1:4015", "add-node", "127.0.0.1:4013"])
+        .assert()
+        .success()
+        .stdout(contains("moved"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key7", "--cluster", "127.0.0.1:4013,127.0.0.1:4014,127.0.0.1:4015"])
+        .assert()
+        .success()
+        .stdout("value7\n");
+}
+
+// a scan is read a page at a time, each page going on from the last key of the one before
+#[test]
+fn scan_ranges_pages_through_every_entry() {
+    let _server = spawn_server("127.0.0.1:4026");
+    thread::sleep(Duration::from_secs(1));
+    let client = Client::new(addr(4026));
+    for key_id in 0..250 {
+        client.set(&format!("key{:03}", key_id), &format!("value{}", key_id)).unwrap();
+    }
+
+    let (mut entries, mut pages, mut after) = (Vec::new(), 0, None);
+    loop {
+        let page = client.scan_ranges(vec![(0, 0)], after, 100).unwrap();
+        assert!(page.entries.len() <= 100);
+        entries.extend(page.entries);
+        pages += 1;
+        after = page.next;
+        if after.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    let expected: Vec<_> = (0..250).map(|key_id| (format!("key{:03}", key_id), format!("value{}", key_id))).collect();
+    assert_eq!(entries, expected);
+}
@@ -0,0 +1,125 @@
+use kvs::kv::Durability;
+use kvs::server::command::Request;
+use kvs::server::Server;
+use kvs::simulation::linearizability::{find_violation, Call, Operation, Return};
+use kvs::simulation::{run, SimulationOptions};
+use kvs::{KvsEngine, MemoryStore};
+use tempfile::TempDir;
+
+fn operation(client: usize, call: Call, invoked_at: u64, returned_at: u64, result: Option<Return>) -> Operation {
+    Operation { client, key: "key1".to_owned(), call, invoked_at, returned_at, result }
+}
+
+fn set(value: &str) -> Call {
+    Call::Set(value.to_owned())
+}
+
+fn read(value: Option<&str>) -> Option<Return> {
+    Some(Return::Value(value.map(str::to_owned)))
+}
+
+#[test]
+fn linearizable_histories_pass() {
+    let history = vec![
+        operation(0, set("a"), 0, 10, Some(Return::Done)),
+        // concurrent with the set, may see either value
+        operation(1, Call::Get, 1, 2, read(None)),
+        operation(2, Call::Get, 3, 4, read(Some("a"))),
+        // never answered, may or may not have happened
+        operation(1, set("b"), 20, 30, None),
+        operation(2, Call::Get, 40, 41, read(Some("b"))),
+        operation(2, Call::Remove, 50, 51, Some(Return::Done)),
+        operation(0, Call::Remove, 60, 61, Some(Return::NotFound)),
+        operation(0, Call::Get, 70, 71, read(None)),
+    ];
+    assert_eq!(find_violation(&history), None);
+}
+
+#[test]
+fn lost_and_stale_writes_are_found() {
+    let lost = vec![
+        operation(0, set("a"), 0, 5, Some(Return::Done)),
+        operation(1, Call::Get, 10, 12, read(None)),
+    ];
+    assert_eq!(find_violation(&lost), Some("key1"));
+
+    let stale = vec![
+        operation(0, set("a"), 0, 5, Some(Return::Done)),
+        operation(0, set("b"), 6, 8, Some(Return::Done)),
+        operation(1, Call::Get, 10, 12, read(Some("a"))),
+    ];
+    assert_eq!(find_violation(&stale), Some("key1"));
+
+    // once a read saw the new value, a later one cannot see the old one
+    let flicker = vec![
+        operation(0, set("b"), 0, 20, Some(Return::Done)),
+        operation(1, Call::Get, 3, 4, read(Some("b"))),
+        operation(2, Call::Get, 5, 6, read(None)),
+    ];
+    assert_eq!(find_violation(&flicker), Some("key1"));
+
+    // an unanswered write can only have happened before the server stopped seeing it
+    let late = vec![
+        operation(0, set("a"), 0, 5, None),
+        operation(1, Call::Get, 10, 12, read(None)),
+        operation(1, Call::Get, 13, 14, read(Some("a"))),
+    ];
+    assert_eq!(find_violation(&late), Some("key1"));
+}
+
+#[test]
+fn simulated_runs_are_linearizable() {
+    let (mut crashes, mut dropped, mut faults) = (0, 0, 0);
+    for seed in 0..20 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let report = run(temp_dir.path(), &SimulationOptions::new(seed)).unwrap_or_else(|error| panic!("{}", error));
+        assert_eq!(report.history.iter().filter(|operation| operation.client < 4).count(), 200);
+        crashes += report.crashes;
+        dropped += report.dropped_messages;
+        faults += report.faults;
+    }
+    // every run ends with a power loss, some lose it on the way too
+    assert!(crashes > 20, "{}", crashes);
+    assert!(dropped > 0 && faults > 0);
+}
+
+#[test]
+fn a_seed_replays_the_same_run() {
+    let options = SimulationOptions::new(7).crash_rate(0.05);
+    let first = run(TempDir::new().unwrap().path(), &options).unwrap();
+    let second = run(TempDir::new().unwrap().path(), &options).unwrap();
+    assert_eq!(first, second);
+
+    let other = run(TempDir::new().unwrap().path(), &SimulationOptions::new(8).crash_rate(0.05)).unwrap();
+    assert_ne!(first.history, other.history);
+}
+
+// acknowledging writes before they are synced loses them, or leaves a torn log, on a power loss
+#[test]
+fn unsynced_writes_are_caught() {
+    let options = |seed| SimulationOptions::new(seed).durability(Durability::Write).fault_rate(0.0);
+    let (seed, failure) = (0..20)
+        .find_map(|seed| {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            run(temp_dir.path(), &options(seed)).err().map(|error| (seed, error))
+        })
+        .expect("no seed lost a write");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replay = run(temp_dir.path(), &options(seed)).unwrap_err();
+    assert_eq!(replay.to_string(), failure.to_string());
+    assert!(replay.to_string().starts_with(&format!("seed {}: ", seed)));
+}
+
+#[test]
+fn in_process_server_answers_without_a_socket() {
+    let mut store = MemoryStore::new();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut server = Server::in_process(Box::new(store) as Box<dyn KvsEngine>);
+    assert!(server.stop_handle().is_err());
+    server.start();
+
+    let request: Request = serde_json::from_str(r#"{"Get":{"key":"key1"}}"#).unwrap();
+    let mut response = Vec::new();
+    server.handle_request(request, &mut response);
+    assert_eq!(String::from_utf8(response).unwrap(), r#"{"Success":"value1"}"#);
+}
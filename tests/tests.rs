@@ -1,3 +1,5 @@
+// these tests predate the lint, their argument borrows are kept as written
+#![allow(clippy::needless_borrows_for_generic_args)]
 use assert_cmd::prelude::*;
 use kvs::{KvStore, Result};
 use predicates::ord::eq;
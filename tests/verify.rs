@@ -0,0 +1,151 @@
+use assert_cmd::prelude::*;
+use kvs::kv::namespace::NamespaceOptions;
+use kvs::kv::verify::ProblemKind;
+use kvs::{KvStore, KvsEngine, Result, EXIT_DATA};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn populate(dir: &Path) -> Result<()> {
+    let mut store = KvStore::open(dir)?;
+    store.create_namespace("users", NamespaceOptions::default())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.namespace("users")?.set(format!("key{}", key_id), format!("user{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    Ok(())
+}
+
+// every file under `dir` with its contents
+fn snapshot(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(snapshot(&path));
+        } else {
+            files.push((path.display().to_string(), fs::read(&path).unwrap()));
+        }
+    }
+    files.sort();
+    files
+}
+
+fn kinds(report: &kvs::kv::verify::VerifyReport) -> Vec<ProblemKind> {
+    report.problems.iter().map(|problem| problem.kind).collect()
+}
+
+#[test]
+fn healthy_store_verifies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let before = snapshot(temp_dir.path());
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.keys, 19);
+    assert_eq!(report.records, 41);
+    assert_eq!(snapshot(temp_dir.path()), before);
+    Ok(())
+}
+
+#[test]
+fn damaged_segments_are_reported_not_changed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let data = temp_dir.path().join("data");
+    let segment = fs::read_dir(&data)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| fs::read_to_string(path).unwrap().contains("value5"))
+        .unwrap();
+    let contents = fs::read_to_string(&segment).unwrap();
+    fs::write(&segment, contents.replace("value5", "valueX") + r#"{"seq":99,"#).unwrap();
+    fs::write(data.join("notes.txt"), "hello").unwrap();
+    fs::write(data.join("7.tmp"), "").unwrap();
+    let before = snapshot(temp_dir.path());
+
+    let report = KvStore::verify(temp_dir.path())?;
+    let kinds = kinds(&report);
+    for kind in [
+        ProblemKind::Checksum,
+        ProblemKind::Framing,
+        ProblemKind::FileName,
+        ProblemKind::UnfinishedCompaction,
+    ] {
+        assert!(kinds.contains(&kind), "{:?}", report);
+    }
+    let checksum = report
+        .problems
+        .iter()
+        .find(|problem| problem.kind == ProblemKind::Checksum)
+        .unwrap();
+    assert_eq!(checksum.path, segment);
+    assert_eq!(
+        checksum.offset,
+        contents[..contents.find("value5").unwrap()].rfind(r#"{"seq""#).map(|offset| offset as u64)
+    );
+    assert_eq!(snapshot(temp_dir.path()), before);
+    Ok(())
+}
+
+#[test]
+fn bloom_filter_must_agree_with_the_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let bloom_path = temp_dir.path().join("bloom.json");
+    let mut bloom: serde_json::Value = serde_json::from_slice(&fs::read(&bloom_path).unwrap()).unwrap();
+
+    // a filter claiming to cover keys it does not hold
+    let bits = bloom["filter"]["bits"].as_array().unwrap().len();
+    bloom["filter"]["bits"] = serde_json::json!(vec![0; bits]);
+    let (segment, length) = fs::read_dir(temp_dir.path().join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .map(|entry| {
+            let serial: usize = entry.file_name().to_str().unwrap().parse().unwrap();
+            (serial, entry.metadata().unwrap().len())
+        })
+        .max()
+        .unwrap();
+    bloom["covers"] = serde_json::json!({"segment": segment, "offset": length});
+    fs::write(&bloom_path, bloom.to_string()).unwrap();
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(kinds(&report).contains(&ProblemKind::Bloom), "{:?}", report);
+
+    // a filter covering a segment that is gone
+    bloom["covers"] = serde_json::json!({"segment": 99, "offset": 0});
+    fs::write(&bloom_path, bloom.to_string()).unwrap();
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(kinds(&report), vec![ProblemKind::Bloom], "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn cli_verify_prints_json_and_fails_on_problems() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["problems"], serde_json::json!([]));
+    assert_eq!(report["keys"], 19);
+
+    fs::write(temp_dir.path().join("data").join("junk"), "junk").unwrap();
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(EXIT_DATA as i32));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["problems"][0]["kind"], "file_name");
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use assert_cmd::prelude::*;
+use kvs::kv::history::VersionPolicy;
+use kvs::kv::options::KvStoreOptions;
+use kvs::kv::recovery::{recover, Cutoff};
+use kvs::kv::KVError;
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn history_off_by_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(store.history("key1".to_owned()), Err(KVError::Unsupported(_))));
+    assert_eq!(store.version_policy(), None);
+    Ok(())
+}
+
+#[test]
+fn versions_and_as_of_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    store.enable_versioning(VersionPolicy::default())?;
+
+    for value_id in 1..4 {
+        thread::sleep(Duration::from_millis(5));
+        store.set("key1".to_owned(), format!("value{}", value_id))?;
+    }
+    thread::sleep(Duration::from_millis(5));
+    store.remove("key1".to_owned())?;
+
+    // the value from before versioning was turned on is the first version
+    let history = store.history("key1".to_owned())?;
+    let values: Vec<_> = history.iter().map(|version| version.value.clone()).collect();
+    assert_eq!(
+        values,
+        vec![
+            Some("value0".to_owned()),
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+            Some("value3".to_owned()),
+            None
+        ]
+    );
+    assert!(history.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    assert_eq!(store.get_version("key1".to_owned(), history[2].seq)?, Some("value2".to_owned()));
+    assert_eq!(store.get_version("key1".to_owned(), history[4].seq)?, None);
+    assert_eq!(store.get_as_of("key1".to_owned(), history[1].timestamp)?, Some("value1".to_owned()));
+    assert_eq!(store.get_as_of("key1".to_owned(), history[3].timestamp + 1)?, Some("value3".to_owned()));
+    assert_eq!(store.get_as_of("key1".to_owned(), history[4].timestamp)?, None);
+    assert_eq!(store.get_as_of("key1".to_owned(), history[0].timestamp - 1)?, None);
+    assert_eq!(store.history("key2".to_owned())?, vec![]);
+
+    // history survives a restart
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1".to_owned())?, history);
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    assert!(store.history("key1".to_owned())?.last().unwrap().seq > history[4].seq);
+
+    store.disable_versioning()?;
+    assert!(matches!(store.history("key1".to_owned()), Err(KVError::Unsupported(_))));
+    assert!(!temp_dir.path().join("history").exists());
+    Ok(())
+}
+
+#[test]
+fn retention_policy_limits_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.enable_versioning(VersionPolicy {
+        max_versions: Some(3),
+        retention_secs: None,
+    })?;
+
+    // enough writes to compact both the data log and the history log
+    for value_id in 0..1500 {
+        store.set(format!("key{}", value_id % 2), format!("value{}", value_id))?;
+    }
+    let values: Vec<_> = store
+        .history("key1".to_owned())?
+        .into_iter()
+        .map(|version| version.value.unwrap())
+        .collect();
+    assert_eq!(values, vec!["value1495", "value1497", "value1499"]);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key0".to_owned())?.len(), 3);
+    assert_eq!(store.get("key0".to_owned())?, Some("value1498".to_owned()));
+
+    // a window that already passed keeps only the latest version
+    store.enable_versioning(VersionPolicy {
+        max_versions: None,
+        retention_secs: Some(0),
+    })?;
+    thread::sleep(Duration::from_millis(5));
+    let history = store.history("key0".to_owned())?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some("value1498".to_owned()));
+    Ok(())
+}
+
+// a version's seq is the one of its record in the data log, so it is where a recovery can stop
+#[test]
+fn version_seqs_are_log_seqs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    store.set("key2".to_owned(), "value0".to_owned())?;
+    store.enable_versioning(VersionPolicy::default())?;
+    for value_id in 1..4 {
+        store.set("key1".to_owned(), format!("value{}", value_id))?;
+        store.set("key2".to_owned(), format!("value{}", value_id))?;
+    }
+    let history = store.history("key1".to_owned())?;
+    assert_eq!(history.iter().map(|version| version.seq).collect::<Vec<_>>(), vec![1, 3, 5, 7]);
+    drop(store);
+
+    let target = TempDir::new().unwrap();
+    recover(&[temp_dir.path().to_owned()], Cutoff::Seq(history[2].seq), target.path().to_owned())?;
+    let mut recovered = KvStore::open(target.path())?;
+    assert_eq!(recovered.get("key1".to_owned())?, history[2].value);
+    Ok(())
+}
+
+// the history log is opened with the store's options, so reading a versioned store leaves it as it was
+#[test]
+fn history_follows_the_store_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let history_dir = temp_dir.path().join("history");
+    let segments = || fs::read_dir(&history_dir).unwrap().count();
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().file_size(200))?;
+    store.enable_versioning(VersionPolicy::default())?;
+    for value_id in 0..20 {
+        store.set("key1".to_owned(), format!("value{}", value_id))?;
+    }
+    drop(store);
+    assert!(segments() > 2, "{}", segments());
+
+    let before = segments();
+    KvStore::open(temp_dir.path())?;
+    KvStore::open(temp_dir.path())?;
+    assert_eq!(segments(), before + 1);
+
+    let files = |dir: &std::path::Path| {
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        files.into_iter().map(|path| (fs::read(&path).unwrap(), path)).collect::<Vec<_>>()
+    };
+    let before = files(&history_dir);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.history("key1".to_owned())?.len(), 20);
+    drop(store);
+    assert_eq!(files(&history_dir), before);
+    Ok(())
+}
+
+// a write rewrites at most one segment of the history log, yet its garbage stays bounded
+#[test]
+fn history_compacts_a_segment_at_a_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let history_dir = temp_dir.path().join("history");
+    let segments = || {
+        fs::read_dir(&history_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.file_name(), entry.metadata().unwrap().len()))
+            .collect::<Vec<_>>()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().file_size(500))?;
+    store.enable_versioning(VersionPolicy {
+        max_versions: Some(2),
+        retention_secs: None,
+    })?;
+
+    for value_id in 0..3000 {
+        let before = segments();
+        store.set(format!("key{}", value_id % 10), format!("value{}", value_id))?;
+        let after = segments();
+        let removed = before.iter().filter(|(name, _)| !after.iter().any(|(other, _)| other == name)).count();
+        assert!(removed <= 1, "{} segments rewritten by one write", removed);
+    }
+    let size: u64 = segments().iter().map(|(_, len)| len).sum();
+    assert!(size < 20_000, "{} bytes of history for 20 versions", size);
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            let values: Vec<_> = store
+                .history(format!("key{}", key_id))?
+                .into_iter()
+                .map(|version| version.value.unwrap())
+                .collect();
+            assert_eq!(values, vec![format!("value{}", 2980 + key_id), format!("value{}", 2990 + key_id)]);
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+// Kills the server when dropped so a failed assertion does not leak it.
+struct ServerGuard(Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn client_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerGuard(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4018", "--max-versions", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let client_cmd = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", "127.0.0.1:4018"]);
+        cmd
+    };
+    client_cmd(&["set", "key1", "value1"]).assert().success();
+    client_cmd(&["set", "key1", "value2"]).assert().success();
+    client_cmd(&["rm", "key1"]).assert().success();
+    let output = client_cmd(&["history", "key1"]).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" value2"));
+    assert!(lines[1].ends_with(" (removed)"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4019", "--max-versions", "2"])
+        .current_dir(TempDir::new().unwrap().path())
+        .assert()
+        .failure()
+        .stderr(contains("versioning needs the kvs engine"));
+}